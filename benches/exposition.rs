@@ -0,0 +1,157 @@
+//! Benchmarks of the exposition of the metrics and of the collection pipeline, to validate the
+//! performance-motivated changes (e.g. streaming the encoding, caching) with numbers.
+//!
+//! Run with `cargo bench`, or `cargo bench -- encode` for the encoding only.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::RwLock;
+
+use fitbit_exporter::fitbit::exposition::ExpositionFormat;
+use fitbit_exporter::fitbit::mock::MockFitbitApi;
+use fitbit_exporter::{update_current_metrics, FitbitMetrics};
+
+/// Numbers of timestamped points of the steps, e.g. 365 for a year of history pushed by /history or the backfill.
+const HISTORY_SIZES: [usize; 3] = [30, 365, 3650];
+
+/// Size of the chunks of a streamed response, as served by /metrics with --stream-exposition.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Builds metrics with `days` daily points of the steps, one day apart.
+fn metrics_with_history(days: usize) -> FitbitMetrics {
+    let fitbit_metrics = FitbitMetrics::new();
+    let start = Duration::from_secs(1_677_801_600);
+    for day in 0..days {
+        fitbit_metrics.steps.push(8000 + (day % 5000) as i64, Some(start + Duration::from_secs(day as u64 * 86_400)));
+    }
+    fitbit_metrics
+}
+
+fn bench_encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for days in HISTORY_SIZES {
+        let fitbit_metrics = metrics_with_history(days);
+        group.bench_with_input(BenchmarkId::new("openmetrics", days), &fitbit_metrics, |b, fitbit_metrics| {
+            b.iter(|| ExpositionFormat::OpenMetrics.encode(&fitbit_metrics.registry()).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("text", days), &fitbit_metrics, |b, fitbit_metrics| {
+            b.iter(|| ExpositionFormat::Text.encode(&fitbit_metrics.registry()).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("chunks", days), &fitbit_metrics, |b, fitbit_metrics| {
+            b.iter(|| {
+                ExpositionFormat::OpenMetrics
+                    .encode_chunks(&fitbit_metrics.registry(), CHUNK_SIZE, |chunk| {
+                        black_box(chunk);
+                        Ok(())
+                    })
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The recent activity logs, as returned by the Fitbit API.
+fn activity_logs(count: u64) -> Value {
+    Value::Array(
+        (0..count)
+            .map(|i| {
+                json!({
+                    "logId": 5_423_456_789u64 + i,
+                    "activityName": "Course",
+                    "activityTypeId": 90009,
+                    "startTime": "2023-03-04T07:00:00.000+01:00",
+                    "activeDuration": 1_800_000,
+                    "calories": 312,
+                    "averageHeartRate": 151,
+                    "distance": 5.2,
+                    "distanceUnit": "Kilometer"
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Last night's sleep log, with its stages.
+fn sleep_log() -> Value {
+    json!({
+        "sleep": [{
+            "logId": 39_742_102_251u64,
+            "dateOfSleep": "2023-03-04",
+            "duration": 27_720_000,
+            "efficiency": 93,
+            "isMainSleep": true,
+            "startTime": "2023-03-03T23:12:30.000",
+            "endTime": "2023-03-04T06:54:30.000",
+            "timeInBed": 462,
+            "minutesAsleep": 420,
+            "minutesAwake": 42,
+            "minutesAfterWakeup": 3,
+            "levels": {
+                "summary": {
+                    "deep": { "count": 4, "minutes": 82 },
+                    "light": { "count": 28, "minutes": 241 },
+                    "rem": { "count": 6, "minutes": 97 },
+                    "wake": { "count": 30, "minutes": 42 }
+                },
+                "data": [
+                    { "dateTime": "2023-03-03T23:12:30.000", "level": "light", "seconds": 1800 },
+                    { "dateTime": "2023-03-03T23:42:30.000", "level": "deep", "seconds": 2400 }
+                ]
+            }
+        }],
+        "summary": { "totalMinutesAsleep": 420, "totalTimeInBed": 462 }
+    })
+}
+
+/// Runs every collector enabled by default against canned responses, i.e. the mapping of the responses to the
+/// metrics and the bookkeeping of the collectors, without the latency of the Fitbit API.
+fn bench_collection(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let api = Arc::new(RwLock::new(
+        MockFitbitApi::new()
+            .with_response("fetch_steps", json!(8123))
+            .with_response("fetch_water", json!(1250.0))
+            .with_response("fetch_food_summary", json!({ "calories": 1840, "carbs": 212.4, "fat": 61.3, "fiber": 24.1, "protein": 96.7, "sodium": 2310, "water": 1250 }))
+            .with_response("fetch_sleep", sleep_log())
+            .with_response("fetch_activity_summary", json!({
+                "caloriesOut": 2417,
+                "distances": [{ "activity": "total", "distance": 6.12 }, { "activity": "tracker", "distance": 6.12 }],
+                "floors": 9,
+                "fairlyActiveMinutes": 14,
+                "veryActiveMinutes": 27,
+                "steps": 8123
+            }))
+            .with_response("fetch_activity_logs", activity_logs(10))
+            .with_response("fetch_activity_goals", json!({ "activeMinutes": 30, "caloriesOut": 2500, "distance": 8.05, "floors": 10, "steps": 10000 }))
+            .with_response("fetch_badges", json!([{ "badgeType": "DAILY_STEPS", "value": 25000, "timesAchieved": 3, "dateTime": "2023-02-18" }]))
+            .with_response("fetch_lifetime_stats", json!({
+                "lifetime": { "distance": 5423.7, "floors": 8712, "steps": 7_894_521 },
+                "best": { "steps": { "date": "2022-07-09", "value": 41_233 } }
+            }))
+            .with_response("fetch_ecg_readings", json!([{ "startTime": "2023-03-02T21:14:37.000", "averageHeartRate": 64, "resultClassification": "Normal Sinus Rhythm" }]))
+            .with_response("fetch_irn_alerts", json!([]))
+            .with_response("fetch_cardio_score", json!({ "dateTime": "2023-03-04", "value": { "vo2Max": "46.3" } }))
+            .with_response("fetch_breathing_rate", json!({ "fullSleepSummary": { "breathingRate": 14.6 }, "deepSleepSummary": { "breathingRate": 13.8 } }))
+            .with_response("fetch_skin_temperature", json!({ "dateTime": "2023-03-04", "value": { "nightlyRelative": 0.4 } }))
+            .with_response("fetch_core_temperature", json!({ "dateTime": "2023-03-04T07:45:00", "value": 36.7 }))
+            .with_response("fetch_devices", json!([{
+                "id": "2570612980",
+                "deviceVersion": "Charge 6",
+                "type": "TRACKER",
+                "battery": "High",
+                "batteryLevel": 84,
+                "lastSyncTime": "2023-03-04T08:15:42.000"
+            }])),
+    ));
+    let fitbit_metrics = Arc::new(FitbitMetrics::new());
+
+    c.bench_function("collect/update_current_metrics", |b| {
+        b.to_async(&runtime).iter(|| update_current_metrics(api.clone(), fitbit_metrics.clone()))
+    });
+}
+
+criterion_group!(benches, bench_encoding, bench_collection);
+criterion_main!(benches);
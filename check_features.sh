@@ -0,0 +1,30 @@
+#!/bin/bash
+#
+# Checks that fitbit_exporter builds, and that its tests pass, with each set of cargo features below. In particular the
+# lean `--no-default-features` build (pull-only exporter, without TLS serving nor database backends) used on small ARM
+# boards such as a Raspberry Pi, which nothing else builds.
+#
+# Usage:
+#    `$ ./check_features.sh`
+#
+# Like `cargo run`, it requires the custom client_rust in `dependencies` (see build_docker_image.sh).
+
+set -euo pipefail
+
+FEATURE_SETS=(
+    "--no-default-features"
+    ""
+    "--no-default-features --features sled"
+    "--no-default-features --features redis"
+    "--no-default-features --features sqlite"
+    "--no-default-features --features parquet"
+    "--all-features"
+)
+
+for features in "${FEATURE_SETS[@]}"; do
+    echo "==> ${features:-default features}"
+    # shellcheck disable=SC2086 # the features are separate arguments
+    cargo check --all-targets $features
+    # shellcheck disable=SC2086
+    cargo test $features
+done
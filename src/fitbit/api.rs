@@ -0,0 +1,182 @@
+use chrono::{DateTime, Utc};
+use hyper::{header, Body, Response, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::fitbit::exposition::{parse_labels, Sample};
+use crate::fitbit::storage::{run_blocking, SampleStore};
+
+/// Range returned when the query doesn't give a start.
+const DEFAULT_RANGE_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+/// A query of `/api/series`, e.g. `?metric=steps&start=2023-03-01T00:00:00Z&end=1677888000`.
+///
+/// The metric can be given with or without the `fitbit_` prefix, as `metric` or `query`. The start and the end are
+/// RFC 3339 times or Unix timestamps in seconds, as in the Prometheus API, and default to the last 7 days.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeriesQuery {
+    /// The metric family, e.g. "fitbit_steps".
+    pub family: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+}
+
+impl SeriesQuery {
+    /// Parses the query string of a request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message if the metric is missing, or a time is invalid.
+    pub fn parse(query: Option<&str>, now_ms: i64) -> Result<Self, String> {
+        let mut metric = None;
+        let mut start_ms = None;
+        let mut end_ms = None;
+        for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "metric" | "query" => metric = Some(value.trim().to_string()),
+                "start" => start_ms = Some(parse_time(&value)?),
+                "end" => end_ms = Some(parse_time(&value)?),
+                _ => {}
+            }
+        }
+
+        let metric = metric.filter(|metric| !metric.is_empty()).ok_or("Missing metric parameter")?;
+        let family = if metric.starts_with("fitbit_") { metric } else { format!("fitbit_{}", metric) };
+        let end_ms = end_ms.unwrap_or(now_ms);
+        let start_ms = start_ms.unwrap_or(end_ms - DEFAULT_RANGE_MS);
+        if start_ms > end_ms {
+            return Err("The start must not be after the end".to_string());
+        }
+        Ok(SeriesQuery { family, start_ms, end_ms })
+    }
+}
+
+/// Parses a time given as an RFC 3339 string or a Unix timestamp in seconds, into milliseconds.
+fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Ok((seconds * 1000.0).round() as i64);
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.timestamp_millis())
+        .map_err(|err| format!("Invalid time {}: {}", value, err))
+}
+
+/// The body of the responses, in the format of the range queries of the Prometheus API, so that the JSON
+/// datasources of Grafana can plot it.
+/// FYI: https://prometheus.io/docs/prometheus/latest/querying/api/#range-queries
+#[derive(Debug, Serialize)]
+struct ApiResponse {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<MatrixData>,
+    #[serde(rename = "errorType", skip_serializing_if = "Option::is_none")]
+    error_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MatrixData {
+    #[serde(rename = "resultType")]
+    result_type: &'static str,
+    result: Vec<Series>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Series {
+    /// The name (`__name__`) and the labels of the series.
+    metric: BTreeMap<String, String>,
+    /// The timestamps in seconds and the values, as strings.
+    values: Vec<(f64, String)>,
+}
+
+/// Groups the samples, ordered by series and timestamp, into series.
+fn group_series(samples: Vec<Sample>) -> Vec<Series> {
+    let mut series: Vec<(String, String, Series)> = Vec::new();
+    for sample in samples {
+        let point = (sample.timestamp_ms.unwrap_or_default() as f64 / 1000.0, sample.value.to_string());
+        match series.last_mut() {
+            Some((name, labels, last)) if *name == sample.name && *labels == sample.labels => last.values.push(point),
+            _ => {
+                let mut metric: BTreeMap<String, String> = parse_labels(&sample.labels).into_iter().collect();
+                metric.insert("__name__".to_string(), sample.name.clone());
+                series.push((sample.name, sample.labels, Series { metric, values: vec![point] }));
+            }
+        }
+    }
+    series.into_iter().map(|(_, _, series)| series).collect()
+}
+
+/// Serves `/api/series` from the sample store.
+///
+/// # Arguments
+///
+/// * `sample_store` - The sample store, if any. Without one, the endpoint answers 404.
+/// * `query` - The query string of the request (see `SeriesQuery`).
+pub async fn series_response(sample_store: Option<&Arc<dyn SampleStore>>, query: Option<&str>) -> Response<Body> {
+    let sample_store = match sample_store {
+        Some(sample_store) => sample_store,
+        None => return error_response(StatusCode::NOT_FOUND, "not_found", "The series require a sample store (--sample-store)".to_string()),
+    };
+    let query = match SeriesQuery::parse(query, Utc::now().timestamp_millis()) {
+        Ok(query) => query,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, "bad_data", err),
+    };
+    match run_blocking(sample_store, move |sample_store| sample_store.series(&query.family, query.start_ms, query.end_ms)).await {
+        Ok(samples) => json_response(
+            StatusCode::OK,
+            &ApiResponse {
+                status: "success",
+                data: Some(MatrixData { result_type: "matrix", result: group_series(samples) }),
+                error_type: None,
+                error: None,
+            },
+        ),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal", err.to_string()),
+    }
+}
+
+fn error_response(status: StatusCode, error_type: &'static str, error: String) -> Response<Body> {
+    json_response(status, &ApiResponse { status: "error", data: None, error_type: Some(error_type), error: Some(error) })
+}
+
+fn json_response(status: StatusCode, body: &ApiResponse) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(body).unwrap()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_series_query() {
+        assert_eq!(
+            SeriesQuery::parse(Some("metric=steps&start=2023-03-04T00:00:00Z&end=1677974400"), 0),
+            Ok(SeriesQuery { family: "fitbit_steps".to_string(), start_ms: 1677888000000, end_ms: 1677974400000 })
+        );
+        assert_eq!(
+            SeriesQuery::parse(Some("query=fitbit_water_ml"), DEFAULT_RANGE_MS + 1),
+            Ok(SeriesQuery { family: "fitbit_water_ml".to_string(), start_ms: 1, end_ms: DEFAULT_RANGE_MS + 1 })
+        );
+        assert!(SeriesQuery::parse(None, 0).is_err());
+        assert!(SeriesQuery::parse(Some("metric=steps&start=yesterday"), 0).is_err());
+        assert!(SeriesQuery::parse(Some("metric=steps&start=2&end=1"), 0).is_err());
+    }
+
+    #[test]
+    fn samples_are_grouped_by_series() {
+        let sample = |labels: &str, value: f64, timestamp_ms: i64| Sample::new("fitbit_sleep_stage_seconds", labels, value, Some(timestamp_ms));
+        let series = group_series(vec![sample("stage=\"deep\"", 4980.0, 1000), sample("stage=\"deep\"", 5100.0, 2000), sample("stage=\"rem\"", 600.0, 1000)]);
+
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[0].metric.get("__name__").map(String::as_str), Some("fitbit_sleep_stage_seconds"));
+        assert_eq!(series[0].metric.get("stage").map(String::as_str), Some("deep"));
+        assert_eq!(series[0].values, vec![(1.0, "4980".to_string()), (2.0, "5100".to_string())]);
+        assert_eq!(series[1].values, vec![(1.0, "600".to_string())]);
+    }
+}
@@ -0,0 +1,304 @@
+use chrono::{DateTime, Duration as ChronoDuration, FixedOffset, NaiveDate, Utc};
+use tracing::{error, info};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use url::Url;
+
+use crate::fitbit::{FitbitApi, FitbitMetrics};
+use crate::fitbit::client::retry_rate_limited;
+use crate::fitbit::events::Event;
+use crate::fitbit::cmd::{PlaceholderDays, TimestampPosition};
+use crate::fitbit::history::{push_elevation_range, push_floors_range, push_resting_heart_rates, push_steps_range, push_weight_logs};
+use crate::fitbit::storage::run_blocking;
+
+/// Options of the backfill of the gaps at startup, built from the command line arguments
+/// (see `cmd::Args::backfill_options`).
+#[derive(Debug, Clone)]
+pub struct BackfillOptions {
+    /// The maximum number of days to backfill, counted back from yesterday.
+    pub max_days: u32,
+    /// The time of day at which the backfilled daily values are stamped.
+    pub timestamp_position: TimestampPosition,
+    /// The handling of the placeholder days of the backfilled ranges.
+    pub placeholder_days: PlaceholderDays,
+    /// The Prometheus server to ask for the last sample of each family. When `None`, the sample store is used.
+    pub prometheus_url: Option<Url>,
+}
+
+/// The metric families that can be backfilled, i.e. that have a date range endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BackfilledFamily {
+    Steps,
+    RestingHeartRate,
+    Floors,
+    Elevation,
+    Weight,
+}
+
+impl BackfilledFamily {
+    const ALL: [BackfilledFamily; 5] = [
+        BackfilledFamily::Steps,
+        BackfilledFamily::RestingHeartRate,
+        BackfilledFamily::Floors,
+        BackfilledFamily::Elevation,
+        BackfilledFamily::Weight,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            BackfilledFamily::Steps => "fitbit_steps",
+            BackfilledFamily::RestingHeartRate => "fitbit_resting_heart_rate_bpm",
+            BackfilledFamily::Floors => "fitbit_daily_floors",
+            BackfilledFamily::Elevation => "fitbit_daily_elevation_meters",
+            BackfilledFamily::Weight => "fitbit_weight_grams",
+        }
+    }
+
+    /// Returns the first day missing after the day of the last sample. The day of the last weigh-in is fetched
+    /// again, as more weigh-ins may have been logged on it after the outage.
+    fn first_missing_date(&self, last_date: NaiveDate) -> NaiveDate {
+        match self {
+            BackfilledFamily::Weight => last_date,
+            _ => last_date + ChronoDuration::days(1),
+        }
+    }
+
+    /// Fetches the values from `start_date` to `end_date` (inclusive) and pushes them with their timestamp.
+    ///
+    /// The rate limits are waited out (see `retry_rate_limited`), without holding the client meanwhile, so that the
+    /// access token can still be refreshed.
+    ///
+    /// # Returns
+    ///
+    /// The number of backfilled days, i.e. of days with a value (or with a weigh-in).
+    async fn backfill(
+        &self,
+        fitbit_client: &RwLock<dyn FitbitApi>,
+        fitbit_metrics: &FitbitMetrics,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+        timestamp_position: TimestampPosition,
+        placeholder_days: PlaceholderDays,
+    ) -> Result<usize, Box<dyn Error>> {
+        match self {
+            BackfilledFamily::Steps => {
+                let steps_range = retry_rate_limited(|| async { fitbit_client.read().await.fetch_steps_range(start_date, end_date).await }).await?;
+                Ok(push_steps_range(fitbit_metrics, steps_range, timestamp_position, placeholder_days).len())
+            }
+            BackfilledFamily::RestingHeartRate => {
+                let resting_heart_rates =
+                    retry_rate_limited(|| async { fitbit_client.read().await.fetch_resting_heart_rate_range(start_date, end_date).await }).await?;
+                Ok(push_resting_heart_rates(fitbit_metrics, resting_heart_rates, timestamp_position))
+            }
+            BackfilledFamily::Floors => {
+                let floors_range = retry_rate_limited(|| async { fitbit_client.read().await.fetch_floors_range(start_date, end_date).await }).await?;
+                Ok(push_floors_range(fitbit_metrics, floors_range, timestamp_position).len())
+            }
+            BackfilledFamily::Elevation => {
+                let elevation_range = retry_rate_limited(|| async { fitbit_client.read().await.fetch_elevation_range(start_date, end_date).await }).await?;
+                Ok(push_elevation_range(fitbit_metrics, elevation_range, timestamp_position).len())
+            }
+            BackfilledFamily::Weight => {
+                let weight_logs = retry_rate_limited(|| async { fitbit_client.read().await.fetch_weight_range(start_date, end_date).await }).await?;
+                let weight_logs = push_weight_logs(fitbit_metrics, weight_logs);
+                Ok(weight_logs.iter().map(|weight_log| weight_log.date).collect::<BTreeSet<_>>().len())
+            }
+        }
+    }
+}
+
+/// Response of the Prometheus instant query API.
+/// FYI: https://prometheus.io/docs/prometheus/latest/querying/api/#instant-queries
+#[derive(Debug, Deserialize)]
+struct PrometheusResponse {
+    data: PrometheusData,
+}
+
+#[derive(Debug, Deserialize)]
+struct PrometheusData {
+    result: Vec<PrometheusSample>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PrometheusSample {
+    /// The evaluation timestamp and the value, e.g. `[1700000000.0, "1677888000"]`.
+    value: (f64, String),
+}
+
+/// Fetches the days missing since the last sample of each backfilled family, e.g. after an outage of the exporter,
+/// so that the dashboards don't keep a hole.
+///
+/// The last sample is looked up in Prometheus if `prometheus_url` is set, and in the sample store otherwise.
+/// The backfilled points are pushed with their timestamp, and recorded in the sample store if any. Note that
+/// Prometheus only ingests them if they're within its out-of-order time window.
+///
+/// # Arguments
+///
+/// * `fitbit_client` - An `Arc<RwLock<dyn FitbitApi>>` that provides access to the shared Fitbit client.
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+/// * `options` - The `BackfillOptions`.
+pub async fn backfill_gaps(fitbit_client: Arc<RwLock<dyn FitbitApi>>, fitbit_metrics: Arc<FitbitMetrics>, options: BackfillOptions) {
+    if let Err(err) = try_backfill_gaps(&fitbit_client, &fitbit_metrics, &options).await {
+        error!("[backfill_gaps] Error backfilling the gaps: {}", err);
+    }
+}
+
+async fn try_backfill_gaps(
+    fitbit_client: &RwLock<dyn FitbitApi>,
+    fitbit_metrics: &FitbitMetrics,
+    options: &BackfillOptions,
+) -> Result<(), Box<dyn Error>> {
+    // Fitbit's days are the ones of the user's timezone, so is the last complete one
+    let yesterday = fitbit_metrics.user_today().pred_opt().unwrap();
+    let earliest_date = yesterday - ChronoDuration::days(options.max_days.saturating_sub(1) as i64);
+
+    for family in BackfilledFamily::ALL {
+        let start_date = match last_sample_date(fitbit_metrics, options, family).await? {
+            Some(last_date) => family.first_missing_date(last_date).max(earliest_date),
+            None => earliest_date,
+        };
+        if start_date > yesterday {
+            info!("[backfill_gaps] No gap in {}", family.name());
+            continue;
+        }
+
+        let days = family
+            .backfill(fitbit_client, fitbit_metrics, start_date, yesterday, options.timestamp_position, options.placeholder_days)
+            .await?;
+        info!("[backfill_gaps] Backfilled {} days of {} from {} to {}", days, family.name(), start_date, yesterday);
+        fitbit_metrics.events.emit(Event::BackfillFinished { family: family.name().to_string(), days });
+    }
+
+    fitbit_metrics.record_samples().await
+}
+
+/// Returns the day of a sample stamped at `timestamp` (in seconds), in the user's timezone.
+///
+/// The daily values are stamped within their day in the user's timezone (see `daily_timestamp`), and the
+/// weigh-ins at their time in it (see `push_weight_logs`).
+fn sample_date(timestamp: i64, utc_offset: FixedOffset) -> Option<NaiveDate> {
+    Some(DateTime::<Utc>::from_timestamp(timestamp, 0)?.with_timezone(&utc_offset).date_naive())
+}
+
+/// Returns the date of the last sample of the family, or `None` if there's none.
+async fn last_sample_date(fitbit_metrics: &FitbitMetrics, options: &BackfillOptions, family: BackfilledFamily) -> Result<Option<NaiveDate>, Box<dyn Error>> {
+    let last_timestamp = match (&options.prometheus_url, &fitbit_metrics.sample_store) {
+        (Some(prometheus_url), _) => query_last_timestamp(prometheus_url, family.name(), options.max_days).await?,
+        (None, Some(sample_store)) => {
+            let family = family.name().to_string();
+            run_blocking(sample_store, move |sample_store| sample_store.last_timestamp(&family)).await?.map(|timestamp_ms| timestamp_ms / 1000)
+        }
+        (None, None) => return Err("Backfilling the gaps requires a sample store or a Prometheus URL".into()),
+    };
+    Ok(last_timestamp.and_then(|timestamp| sample_date(timestamp, fitbit_metrics.utc_offset())))
+}
+
+/// Queries Prometheus for the timestamp of the last sample of the family within the last `max_days` days.
+async fn query_last_timestamp(prometheus_url: &Url, family: &str, max_days: u32) -> Result<Option<i64>, Box<dyn Error>> {
+    let mut url = prometheus_url.join("api/v1/query")?;
+    url.query_pairs_mut()
+        .append_pair("query", &format!("max(max_over_time(timestamp({})[{}d:1h]))", family, max_days));
+    let response: PrometheusResponse = reqwest::get(url).await?.error_for_status()?.json().await?;
+    Ok(response
+        .data
+        .result
+        .iter()
+        .filter_map(|sample| sample.value.1.parse::<f64>().ok())
+        .map(|timestamp| timestamp as i64)
+        .max())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::exposition::{MetricFamily, Sample};
+    use crate::fitbit::history::daily_timestamp;
+    use crate::fitbit::metrics::MetricsOptions;
+    use crate::fitbit::mock::MockFitbitApi;
+    use crate::fitbit::storage::{SampleStore, StorageError};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    /// A sample store that only knows the last timestamp (in milliseconds) of each family.
+    #[derive(Debug)]
+    struct LastTimestamps(HashMap<&'static str, i64>);
+
+    impl SampleStore for LastTimestamps {
+        fn record(&self, _families: &[MetricFamily], _recorded_at_ms: i64) -> Result<(), StorageError> {
+            Ok(())
+        }
+
+        fn latest(&self) -> Result<Vec<MetricFamily>, StorageError> {
+            Ok(Vec::new())
+        }
+
+        fn last_timestamp(&self, family: &str) -> Result<Option<i64>, StorageError> {
+            Ok(self.0.get(family).copied())
+        }
+
+        fn series(&self, _family: &str, _start_ms: i64, _end_ms: i64) -> Result<Vec<Sample>, StorageError> {
+            Ok(Vec::new())
+        }
+
+        fn prune(&self, _before_ms: i64) -> Result<usize, StorageError> {
+            Ok(0)
+        }
+    }
+
+    fn options() -> BackfillOptions {
+        BackfillOptions { max_days: 30, timestamp_position: TimestampPosition::EndOfDay, placeholder_days: PlaceholderDays::Keep, prometheus_url: None }
+    }
+
+    #[test]
+    fn sample_date_is_the_day_of_the_user() {
+        let utc_offset = FixedOffset::west_opt(5 * 3600).unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        // 2024-03-10 23:59:59 at UTC-5 is 2024-03-11 in UTC
+        let timestamp = daily_timestamp(date, TimestampPosition::EndOfDay, utc_offset).as_secs() as i64;
+        assert_eq!(sample_date(timestamp, utc_offset), Some(date));
+        assert_eq!(BackfilledFamily::Steps.first_missing_date(date), date + ChronoDuration::days(1));
+
+        // A weigh-in at 22:30 at UTC-5 is on the next day in UTC
+        let weigh_in = (date.and_hms_opt(22, 30, 0).unwrap() - utc_offset).and_utc().timestamp();
+        assert_eq!(sample_date(weigh_in, utc_offset), Some(date));
+        assert_eq!(BackfilledFamily::Weight.first_missing_date(date), date);
+    }
+
+    #[tokio::test]
+    async fn gaps_of_every_family_are_backfilled() {
+        let utc_offset = FixedOffset::west_opt(5 * 3600).unwrap();
+        let yesterday = Utc::now().with_timezone(&utc_offset).date_naive().pred_opt().unwrap();
+        let stamped = |date: NaiveDate| daily_timestamp(date, TimestampPosition::EndOfDay, utc_offset).as_millis() as i64;
+        // The steps are up to date, the floors miss their last 2 days and the others were never sampled
+        let sample_store = LastTimestamps(HashMap::from([
+            ("fitbit_steps", stamped(yesterday)),
+            ("fitbit_daily_floors", stamped(yesterday - ChronoDuration::days(2))),
+        ]));
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { sample_store: Some(Arc::new(sample_store)), ..Default::default() });
+        fitbit_metrics.set_utc_offset(utc_offset);
+        let api = RwLock::new(
+            MockFitbitApi::new()
+                .with_response("fetch_resting_heart_rate_range", json!([[yesterday, 58.0]]))
+                .with_response("fetch_floors_range", json!([[yesterday - ChronoDuration::days(1), 12], [yesterday, 10]]))
+                .with_response("fetch_elevation_range", json!([[yesterday, 36.58]]))
+                .with_response("fetch_weight_range", json!([{"logId": 1, "date": yesterday, "time": "07:30:00", "weight": 70.5}])),
+        );
+
+        try_backfill_gaps(&api, &fitbit_metrics, &options()).await.unwrap();
+        assert_eq!(
+            api.read().await.calls(),
+            vec!["fetch_resting_heart_rate_range", "fetch_floors_range", "fetch_elevation_range", "fetch_weight_range"]
+        );
+        assert_eq!(fitbit_metrics.resting_heart_rate.metric_points().len(), 1);
+        assert_eq!(fitbit_metrics.daily_floors.metric_points().len(), 2);
+        assert_eq!(fitbit_metrics.daily_elevation_meters.metric_points().len(), 1);
+        assert_eq!(fitbit_metrics.weight_grams.metric_points().len(), 1);
+        let finished: Vec<String> = fitbit_metrics.events.log().recent().into_iter().map(|logged| logged.message).collect();
+        assert!(finished.contains(&"Backfilled 2 days of fitbit_daily_floors".to_string()), "{:?}", finished);
+        assert!(finished.contains(&"Backfilled 1 days of fitbit_weight_grams".to_string()), "{:?}", finished);
+        assert!(!finished.iter().any(|message| message.contains("fitbit_steps")), "{:?}", finished);
+    }
+}
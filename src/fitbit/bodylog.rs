@@ -0,0 +1,125 @@
+use chrono::Utc;
+use tracing::error;
+use tracing::level_filters::LevelFilter;
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Replacement of the redacted values.
+const REDACTED: &str = "***";
+
+/// Log of the bodies of the responses of the Fitbit API, in a separate file, to diagnose parse failures.
+///
+/// The bodies are only logged while the level is `debug` or `trace`, so that the log can be kept configured and
+/// switched on at runtime through `/admin/loglevel`, without restarting with another `RUST_LOG`. The tokens are
+/// redacted: the values of the JSON fields whose name contains "token", and any of the given secrets.
+#[derive(Debug)]
+pub struct BodyLog {
+    file: Mutex<File>,
+    level: Mutex<LevelFilter>,
+}
+
+impl BodyLog {
+    /// Opens (or creates) the log file, appending to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an I/O error if the file cannot be opened.
+    pub fn open(path: &Path, level: LevelFilter) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file), level: Mutex::new(level) })
+    }
+
+    pub fn level(&self) -> LevelFilter {
+        *self.level.lock().unwrap()
+    }
+
+    pub fn set_level(&self, level: LevelFilter) {
+        *self.level.lock().unwrap() = level;
+    }
+
+    /// Returns true if the bodies are logged at the current level.
+    pub fn is_enabled(&self) -> bool {
+        self.level() >= LevelFilter::DEBUG
+    }
+
+    /// Logs a request and the body of its response, if enabled. A failure to write is logged, not returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The method of the request, e.g. "GET".
+    /// * `url` - The URL of the request.
+    /// * `status` - The status of the response.
+    /// * `body` - The body of the response.
+    /// * `secrets` - The values to redact wherever they appear, e.g. the access token.
+    pub fn log(&self, method: &str, url: &str, status: u16, body: &[u8], secrets: &[&str]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let entry = format!(
+            "{} {} {} -> {}\nAuthorization: Bearer {}\n{}\n\n",
+            Utc::now().to_rfc3339(),
+            method,
+            redact(url, secrets),
+            status,
+            REDACTED,
+            redact_body(body, secrets),
+        );
+        if let Err(err) = self.file.lock().unwrap().write_all(entry.as_bytes()) {
+            error!("Error writing the body log: {}", err);
+        }
+    }
+}
+
+/// Redacts the token fields of a JSON body, and the secrets of any body.
+fn redact_body(body: &[u8], secrets: &[&str]) -> String {
+    let body = match serde_json::from_slice::<Value>(body) {
+        Ok(mut json) => {
+            redact_token_fields(&mut json);
+            json.to_string()
+        }
+        // Logged as is, since that's what failed to parse
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    };
+    redact(&body, secrets)
+}
+
+fn redact_token_fields(json: &mut Value) {
+    match json {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                if name.to_ascii_lowercase().contains("token") && !value.is_null() {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_token_fields(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_token_fields),
+        _ => {}
+    }
+}
+
+fn redact(s: &str, secrets: &[&str]) -> String {
+    secrets
+        .iter()
+        .filter(|secret| !secret.is_empty())
+        .fold(s.to_string(), |s, secret| s.replace(secret, REDACTED))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_redacted() {
+        let body = br#"{"access_token":"abc","user":{"refresh_token":"def","name":"Alice abc"},"errors":[{"token":null}]}"#;
+        assert_eq!(
+            redact_body(body, &["abc"]),
+            r#"{"access_token":"***","errors":[{"token":null}],"user":{"name":"Alice ***","refresh_token":"***"}}"#
+        );
+        assert_eq!(redact_body(b"<html>abc</html>", &["abc", ""]), "<html>***</html>");
+    }
+}
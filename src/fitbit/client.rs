@@ -1,15 +1,40 @@
-use chrono::NaiveDate;
-use log::{debug, error};
-use oauth2::{AccessToken, AuthUrl, ClientId, ClientSecret, RefreshToken, TokenResponse, TokenUrl};
-use oauth2::basic::{BasicClient, BasicErrorResponseType};
+use base64::engine::general_purpose;
+use base64::Engine;
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, NaiveTime, Utc};
+use tracing::field::Empty;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use oauth2::{AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl};
+use oauth2::basic::{BasicClient, BasicErrorResponseType, BasicTokenResponse};
 use oauth2::reqwest::async_http_client;
-use reqwest::Url;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::sync::RwLock;
+use zeroize::Zeroizing;
 
+use crate::fitbit::bodylog::BodyLog;
+use crate::fitbit::dns::{CachingResolver, DnsCacheMetrics};
+use crate::fitbit::events::{Event, EventLog};
+use crate::fitbit::metrics::FitbitMetrics;
+use crate::fitbit::scheduler::count_request;
+use crate::fitbit::storage::{Storage, StorageError};
+use crate::fitbit::models::{Bpm, BreathingRateSeries, BreathingRateSummary, ActivityGoals, ActivityGoalsResponse, ActivityLog, ActivityLogList, ActivitySummary, Badge, BadgesResponse, CardioScoreDay, CardioScoreResponse, DailyActivityResponse, Device, ElevationSeries, FloorsSeries, EcgLogList, EcgReading, FoodLog, FoodSummary, CoreTemperatureLog, CoreTemperatureSeries, HeartRateIntradayResponse, HeartRateSample, HeartRateSeries, HeartRateSummary, HrvSeries, Meters, IrnAlert, IrnAlertList, LeaderboardRank, LifetimeStats, LifetimeStatsResponse, LeaderboardResponse, SkinTemperatureDay, SkinTemperatureSeries, SleepLogResponse, Steps, StepsSeries, Subscription, UserProfile, UserProfileResponse, WaterLog, WeightLog, WeightLogList};
 
 // Define the FitbitError
 #[derive(Debug, Error)]
@@ -17,31 +42,278 @@ pub enum FitbitError {
     #[error("HTTP error: {0}")]
     HttpError(reqwest::Error),
 
+    #[error("Connection error: {0}")]
+    ConnectError(String),
+
     #[error("URL error: {0}")]
     UrlError(url::ParseError),
 
     #[error("Invalid data format")]
     InvalidData,
 
+    #[error("Unexpected response from {endpoint} at `{path}`: {message}")]
+    UnexpectedResponse { endpoint: String, path: String, message: String },
+
+    #[error("Response from {endpoint} exceeds the limit of {limit} bytes")]
+    ResponseTooLarge { endpoint: String, limit: usize },
+
+    #[error("The {collector} collector didn't finish before the deadline of the scrape")]
+    DeadlineExceeded { collector: String },
+
+    #[error("Rate limited by the Fitbit API, retry after {0:?}")]
+    RateLimited(Duration),
+
     #[error("Access token expired")]
     AccessTokenExpired,
 
+    #[error("Invalid access token: {0}")]
+    InvalidAccessToken(String),
+
+    #[error("Insufficient permissions to access {endpoint}: {message} (re-authorize with the missing scopes)")]
+    InsufficientScope { endpoint: String, message: String },
+
     #[error("Invalid grant - e.g. invalid refresh token")]
     InvalidGrant,
 
     #[error("Token error: {0}")]
     TokenError(String),
+
+    #[error("Storage error: {0}")]
+    StorageError(StorageError),
+}
+
+impl FitbitError {
+    /// The kind of the error, e.g. "rate_limited", recorded as the `outcome` of the spans of the requests.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FitbitError::HttpError(_) => "http_error",
+            FitbitError::ConnectError(_) => "connect_error",
+            FitbitError::UrlError(_) => "url_error",
+            FitbitError::InvalidData => "invalid_data",
+            FitbitError::UnexpectedResponse { .. } => "unexpected_response",
+            FitbitError::ResponseTooLarge { .. } => "response_too_large",
+            FitbitError::DeadlineExceeded { .. } => "deadline_exceeded",
+            FitbitError::RateLimited(_) => "rate_limited",
+            FitbitError::AccessTokenExpired => "access_token_expired",
+            FitbitError::InvalidAccessToken(_) => "invalid_access_token",
+            FitbitError::InsufficientScope { .. } => "insufficient_scope",
+            FitbitError::InvalidGrant => "invalid_grant",
+            FitbitError::TokenError(_) => "token_error",
+            FitbitError::StorageError(_) => "storage_error",
+        }
+    }
+}
+
+/// The outcome of a request recorded in its span: "ok", or the kind of the error.
+fn outcome<T>(result: &Result<T, FitbitError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(err) => err.kind(),
+    }
+}
+
+// Token endpoint of the Fitbit OAuth2 API
+// FYI: https://dev.fitbit.com/build/reference/web-api/authorization/refresh-token/
+const TOKEN_URL: &str = "https://api.fitbit.com/oauth2/token";
+const API_URL: &str = "https://api.fitbit.com";
+
+// Margin before the expiry of the access token at which it's refreshed
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+// Minimum delay between two refreshes, e.g. after a failed refresh of an expired token
+const MIN_REFRESH_DELAY: Duration = Duration::from_secs(60);
+
+// Key of the tokens in the token store
+const TOKENS_KEY: &str = "tokens";
+
+/// Key of the tokens of the secondary application in the token store (see `FitbitClient::with_secondary`).
+pub const SECONDARY_TOKENS_KEY: &str = "tokens.secondary";
+
+// Maximum duration of the lock of the tokens taken by a refresh, after which another replica can take it, e.g. if
+// the one holding it crashed
+const TOKENS_LOCK_TTL: Duration = Duration::from_secs(30);
+
+// Delay between two attempts to take the lock of the tokens held by another replica
+const TOKENS_LOCK_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Delay before retrying a rate limited request whose response has no Retry-After header
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// Maximum number of times a request of a dump or a backfill waits out a rate limit before giving up
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+/// Maximum number of days of a request of the weight log by date range.
+/// FYI: https://dev.fitbit.com/build/reference/web-api/body/get-weight-log/
+const WEIGHT_LOG_MAX_DAYS: i64 = 31;
+
+/// The tokens persisted in the token store, so that a restart doesn't reuse a refresh token that was already
+/// exchanged (Fitbit refresh tokens can only be used once).
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredTokens {
+    access_token: Zeroizing<String>,
+    refresh_token: Option<Zeroizing<String>>,
+    // Absent from the tokens stored by the earlier releases
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Labels of the token metrics, e.g. `fitbit_token_expiry_timestamp_seconds{client_id="23ABCD"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TokenLabels {
+    pub client_id: String,
+}
+
+/// Expiry of the access token, to alert before the authorization breaks.
+///
+/// The expiry is absent while it's unknown, rather than 0, which would read as an expired token.
+#[derive(Debug, Clone, Default)]
+pub struct TokenMetrics {
+    expiry_timestamp_seconds: Family<TokenLabels, Gauge>,
+}
+
+impl TokenMetrics {
+    /// Registers the token metrics in the given registry.
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register("fitbit_token_expiry_timestamp_seconds", "Expiry of the access token as UNIX timestamp, absent while unknown", self.expiry_timestamp_seconds.clone());
+    }
+}
+
+/// Returns the expiry of an access token from the `exp` claim of its payload, as the access tokens of Fitbit are JWTs,
+/// or `None` if the token isn't one.
+fn jwt_expiry(access_token: &str) -> Option<DateTime<Utc>> {
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: i64,
+    }
+
+    let payload = access_token.split('.').nth(1)?;
+    let payload = general_purpose::URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+    DateTime::from_timestamp(claims.exp, 0)
+}
+
+/// Options for the outbound HTTP client used to call the Fitbit API.
+///
+/// Transient failures (timeouts, connection errors and 5xx responses) are retried up to `max_retries` times
+/// with a jittered exponential backoff starting at `initial_backoff` and capped at `max_backoff`.
+///
+/// Idle connections are kept in a pool (at most `pool_max_idle_per_host` of them, each for up to `pool_idle_timeout`)
+/// so that subsequent calls to api.fitbit.com don't pay for a new TCP and TLS handshake.
+///
+/// The bodies of the responses are read up to `max_response_bytes`, so that a large response (e.g. of an intraday
+/// endpoint) can't exhaust the memory of a small device like a Raspberry Pi.
+///
+/// When `dns_cache_ttl` is set or `dns_overrides` is not empty, host names are resolved by a `CachingResolver`
+/// instead of the system resolver on every connection.
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: Option<Duration>,
+    pub http2_prior_knowledge: bool,
+    pub dns_overrides: HashMap<String, Vec<SocketAddr>>,
+    pub dns_cache_ttl: Option<Duration>,
+    /// The maximum size of a response body. `None` doesn't limit it.
+    pub max_response_bytes: Option<usize>,
+    /// The metrics of the DNS cache of the `CachingResolver`.
+    pub dns_cache_metrics: DnsCacheMetrics,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            pool_max_idle_per_host: 8,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            http2_prior_knowledge: false,
+            dns_overrides: HashMap::new(),
+            dns_cache_ttl: None,
+            max_response_bytes: Some(10 * 1024 * 1024),
+            dns_cache_metrics: DnsCacheMetrics::default(),
+        }
+    }
+}
+
+impl HttpOptions {
+    /// Builds a `reqwest::Client` configured with these options.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::HttpError` if the client cannot be built (e.g. the TLS backend fails to initialize).
+    pub fn build_client(&self) -> Result<reqwest::Client, FitbitError> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout);
+        if self.http2_prior_knowledge {
+            // Speak HTTP/2 right away instead of starting with HTTP/1.1. api.fitbit.com supports HTTP/2,
+            // which lets concurrent requests share a single connection.
+            builder = builder.http2_prior_knowledge();
+        }
+        if self.dns_cache_ttl.is_some() || !self.dns_overrides.is_empty() {
+            let ttl = self.dns_cache_ttl.unwrap_or(Duration::ZERO);
+            builder = builder.dns_resolver(Arc::new(CachingResolver::new(self.dns_overrides.clone(), ttl, self.dns_cache_metrics.clone())));
+        }
+        builder.build().map_err(FitbitError::HttpError)
+    }
+
+    /// Returns the delay before the given retry attempt (0-based), using exponential backoff with jitter.
+    ///
+    /// The delay is picked randomly between half and the full exponential value, so that concurrent
+    /// callers don't retry in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.initial_backoff.saturating_mul(2u32.saturating_pow(attempt));
+        let capped = exponential.min(self.max_backoff);
+        let millis = capped.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+    }
 }
 
 /// A client for interacting with the Fitbit API.
 ///
 /// The `FitbitClient` provides methods for refreshing access tokens, fetching data from the Fitbit API,
 /// and fetching specific data, such as the number of steps.
+///
+/// The secrets (client secret and tokens) are zeroized when dropped, and are only copied into the types of the
+/// oauth2 crate for the duration of a refresh.
 #[derive(Clone)]
 pub struct FitbitClient {
-    client: BasicClient,
-    pub refresh_token: Option<RefreshToken>,
-    access_token: AccessToken,
+    client_id: String,
+    client_secret: Zeroizing<String>,
+    pub refresh_token: Option<Zeroizing<String>>,
+    access_token: Zeroizing<String>,
+    // Built once and reused for all the API calls, so that connections are pooled.
+    http_client: reqwest::Client,
+    http_options: HttpOptions,
+    token_store: Option<Arc<dyn Storage>>,
+    body_log: Option<Arc<BodyLog>>,
+    // Log of the significant events served by /status, in which the token refreshes and the failovers are recorded
+    event_log: Option<Arc<EventLog>>,
+    // Directory of the canned responses served instead of calling the Fitbit API, in offline mode
+    fixtures_dir: Option<PathBuf>,
+    token_url: String,
+    api_url: String,
+    // Expiry of the access token, from the `expires_in` of the token response, or else from the token itself (see
+    // `jwt_expiry`). Unknown for an initial token that isn't a JWT.
+    access_token_expires_at: Option<DateTime<Utc>>,
+    token_metrics: TokenMetrics,
+    // Encoded ID of the user, logged with the requests. Known once the access token was checked.
+    user_id: Option<String>,
+    // Key of the tokens in the token store
+    tokens_key: String,
+    // Client of a second application authorized by the same user, to which the requests fail over while the hourly
+    // quota of this one is exhausted, and the end of the last rate limit of this one
+    secondary: Option<Box<FitbitClient>>,
+    rate_limited_until: Arc<Mutex<Option<Instant>>>,
 }
 
 // Implement methods for the FitbitClient struct
@@ -54,17 +326,281 @@ impl FitbitClient {
     /// * `access_token` - The access token for the Fitbit API.
     /// * `refresh_token` - The refresh token for the Fitbit API.
     pub fn new(client_id: &str, client_secret: &str, refresh_token: &Option<String>, initial_access_token: &str) -> Self {
-        let client = BasicClient::new(
-            ClientId::new(client_id.to_string()),
-            Some(ClientSecret::new(client_secret.to_string())),
+        let http_options = HttpOptions::default();
+
+        let mut fitbit_client = Self {
+            client_id: client_id.to_string(),
+            client_secret: Zeroizing::new(client_secret.to_string()),
+            refresh_token: refresh_token.as_ref().map(|token| Zeroizing::new(token.to_string())),
+            access_token: Zeroizing::new(initial_access_token.to_string()),
+            http_client: http_options.build_client().expect("Failed to build the HTTP client"),
+            http_options,
+            token_store: None,
+            body_log: None,
+            event_log: None,
+            fixtures_dir: None,
+            token_url: TOKEN_URL.to_string(),
+            api_url: API_URL.to_string(),
+            access_token_expires_at: None,
+            token_metrics: TokenMetrics::default(),
+            user_id: None,
+            tokens_key: TOKENS_KEY.to_string(),
+            secondary: None,
+            rate_limited_until: Arc::new(Mutex::new(None)),
+        };
+        fitbit_client.set_access_token_expiry(jwt_expiry(initial_access_token));
+        fitbit_client
+    }
+
+    /// Replaces the HTTP client with one built from the given options (timeouts, retries and backoff).
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::HttpError` if the HTTP client cannot be built.
+    pub fn with_http_options(mut self, http_options: HttpOptions) -> Result<Self, FitbitError> {
+        self.http_client = http_options.build_client()?;
+        self.http_options = http_options;
+        Ok(self)
+    }
+
+    /// Replaces the HTTP client with a custom one, e.g. to use a proxy or custom TLS settings.
+    /// The retry and backoff settings of the current `HttpOptions` still apply.
+    pub fn with_http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
+    /// Persists the tokens in the given storage, so that they survive a restart.
+    ///
+    /// Tokens found in the storage replace the initial ones, since they come from a later refresh. The tokens are
+    /// saved again after every refresh.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::StorageError` if the stored tokens cannot be read.
+    pub fn with_token_store(mut self, storage: Arc<dyn Storage>) -> Result<Self, FitbitError> {
+        if let Some(tokens) = storage.get_json::<StoredTokens>(&self.tokens_key).map_err(FitbitError::StorageError)? {
+            debug!("Using the tokens found in the token store");
+            self.adopt_stored_tokens(tokens);
+        }
+        self.token_store = Some(storage);
+        Ok(self)
+    }
+
+    fn adopt_stored_tokens(&mut self, tokens: StoredTokens) {
+        self.access_token = tokens.access_token;
+        if let Some(refresh_token) = tokens.refresh_token {
+            self.refresh_token = Some(refresh_token);
+        }
+        // The tokens stored by the earlier releases have no expiry
+        let expires_at = tokens.expires_at.or_else(|| jwt_expiry(&self.access_token));
+        self.set_access_token_expiry(expires_at);
+    }
+
+    /// Stores the tokens under the given key of the token store instead of "tokens", e.g. `SECONDARY_TOKENS_KEY` for
+    /// the client of the secondary application. To be called before `with_token_store`.
+    pub fn with_tokens_key(mut self, key: &str) -> Self {
+        self.tokens_key = key.to_string();
+        self
+    }
+
+    /// Fails over to the client of a second application (another client ID and secret, authorized by the same user)
+    /// while the hourly quota of this one is exhausted, i.e. until the `Retry-After` of its last 429 response, which
+    /// doubles the requests available per hour. The tokens of both are refreshed together.
+    pub fn with_secondary(mut self, secondary: FitbitClient) -> Self {
+        self.secondary = Some(Box::new(secondary));
+        self
+    }
+
+    /// Records the token refreshes and the failovers to the secondary application in the given `EventLog`, e.g. the
+    /// one of `FitbitMetrics::events`. To be called after `with_secondary`, whose refreshes are recorded as well.
+    pub fn with_event_log(mut self, event_log: Arc<EventLog>) -> Self {
+        if let Some(secondary) = self.secondary.take() {
+            self.secondary = Some(Box::new(secondary.with_event_log(event_log.clone())));
+        }
+        self.event_log = Some(event_log);
+        self
+    }
+
+    /// Logs the bodies of the responses in the given `BodyLog`, e.g. to diagnose parse failures.
+    pub fn with_body_log(mut self, body_log: Arc<BodyLog>) -> Self {
+        self.body_log = Some(body_log);
+        self
+    }
+
+    /// Reads the responses of the Fitbit API from canned JSON fixtures instead of calling it, e.g. to develop the
+    /// dashboards or to run integration tests without credentials nor rate limit consumption.
+    ///
+    /// The fixture of an endpoint is the file at its URL path under `dir`, e.g.
+    /// `<dir>/1/user/-/activities/steps/date/today/1d.json`. The dates of the path can be written as `today`, so
+    /// that the fixtures stay valid from one day to the next.
+    pub fn with_fixtures(mut self, dir: &Path) -> Self {
+        self.fixtures_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// The metrics of the tokens, to register in the registry of the metrics.
+    pub fn token_metrics(&self) -> TokenMetrics {
+        self.token_metrics.clone()
+    }
+
+    fn set_access_token_expiry(&mut self, expires_at: Option<DateTime<Utc>>) {
+        self.access_token_expires_at = expires_at;
+        let labels = TokenLabels { client_id: self.client_id.clone() };
+        match expires_at {
+            Some(expires_at) => {
+                self.token_metrics.expiry_timestamp_seconds.get_or_create(&labels).set(expires_at.timestamp());
+            }
+            None => {
+                self.token_metrics.expiry_timestamp_seconds.remove(&labels);
+            }
+        }
+    }
+
+    /// Returns the delay until the access token should be refreshed: `TOKEN_REFRESH_MARGIN` before it expires, but
+    /// at least `MIN_REFRESH_DELAY` from now, or `default` if its expiry is unknown (e.g. an initial token that isn't a
+    /// JWT).
+    ///
+    /// With a secondary application, the delay is the shortest of the two.
+    pub fn next_refresh_delay(&self, default: Duration) -> Duration {
+        let delay = match self.access_token_expires_at {
+            Some(expires_at) => (expires_at - Utc::now()).to_std().unwrap_or_default().saturating_sub(TOKEN_REFRESH_MARGIN).max(MIN_REFRESH_DELAY),
+            None => default,
+        };
+        match &self.secondary {
+            Some(secondary) => delay.min(secondary.next_refresh_delay(default)),
+            None => delay,
+        }
+    }
+
+    /// Refreshes the tokens at the given token endpoint instead of Fitbit's, e.g. a fake OAuth2 server in the tests.
+    pub fn with_token_url(mut self, token_url: &str) -> Self {
+        self.token_url = token_url.to_string();
+        self
+    }
+
+    /// Calls the given API server instead of Fitbit's, e.g. a fake API server in the tests.
+    pub fn with_api_url(mut self, api_url: &str) -> Self {
+        self.api_url = api_url.to_string();
+        self
+    }
+
+    /// Builds the OAuth2 client used to refresh the tokens.
+    fn oauth_client(&self) -> BasicClient {
+        BasicClient::new(
+            ClientId::new(self.client_id.clone()),
+            Some(ClientSecret::new(self.client_secret.to_string())),
             AuthUrl::new("https://www.fitbit.com/oauth2/authorize".to_string()).expect("Invalid authorization endpoint URL"),
-            Some(TokenUrl::new("https://api.fitbit.com/oauth2/token".to_string()).expect("Invalid token endpoint URL")),
-        );
+            Some(TokenUrl::new(self.token_url.clone()).expect("Invalid token endpoint URL")),
+        )
+    }
 
-        Self {
-            client,
-            refresh_token: refresh_token.as_ref().map(|token| RefreshToken::new(token.to_string())),
-            access_token: AccessToken::new(initial_access_token.to_string()),
+    /// Saves the tokens in the token store, if any.
+    async fn persist_tokens(&self, tokens: &StoredTokens) -> Result<(), FitbitError> {
+        if let Some(storage) = &self.token_store {
+            let (key, tokens) = (self.tokens_key.clone(), tokens.clone());
+            run_blocking(storage, move |storage| storage.put_json(&key, &tokens)).await?;
+        }
+        Ok(())
+    }
+
+    /// Adopts the tokens of the token store if another replica sharing it (e.g. in Redis) already exchanged the
+    /// current refresh token.
+    ///
+    /// # Returns
+    ///
+    /// True if the stored tokens were adopted.
+    async fn adopt_tokens_refreshed_elsewhere(&mut self, storage: &Arc<dyn Storage>) -> Result<bool, FitbitError> {
+        let key = self.tokens_key.clone();
+        let stored = run_blocking(storage, move |storage| storage.get_json::<StoredTokens>(&key)).await?;
+        match stored {
+            Some(tokens) if tokens.refresh_token.is_some() && tokens.refresh_token != self.refresh_token => {
+                info!("The tokens were already refreshed by another replica");
+                self.adopt_stored_tokens(tokens);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Adopts the tokens of a token response, persisting them first (write-ahead): Fitbit refresh tokens can only be
+    /// used once, so a crash between the response and its persistence would leave only the exchanged refresh token in
+    /// the token store, which gets `invalid_grant` after the restart.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::StorageError` if the tokens cannot be persisted. They're adopted anyway, since the
+    /// previous refresh token was already exchanged.
+    async fn adopt_token_response(&mut self, token_result: &BasicTokenResponse) -> Result<(), FitbitError> {
+        let expires_at = token_result
+            .expires_in()
+            .and_then(|expires_in| ChronoDuration::from_std(expires_in).ok())
+            .map(|expires_in| Utc::now() + expires_in)
+            .or_else(|| jwt_expiry(token_result.access_token().secret()));
+        // The response should includes a new "refresh" token as well, which we need to store for the next refresh.
+        // FYI: https://dev.fitbit.com/build/reference/web-api/authorization/refresh-token/
+        let tokens = StoredTokens {
+            access_token: Zeroizing::new(token_result.access_token().secret().to_string()),
+            refresh_token: token_result.refresh_token().map(|refresh_token| Zeroizing::new(refresh_token.secret().to_string())).or_else(|| self.refresh_token.clone()),
+            expires_at,
+        };
+        let persisted = self.persist_tokens(&tokens).await;
+        if let Err(err) = &persisted {
+            error!("The new tokens could not be persisted, and will be lost on restart: {}", err);
+        }
+        self.access_token = tokens.access_token;
+        self.refresh_token = tokens.refresh_token;
+        self.set_access_token_expiry(expires_at);
+        persisted
+    }
+
+    /// Returns the URL of the Fitbit authorization page to re-authorize the exporter with the Authorization Code Flow
+    /// (with PKCE), e.g. after the refresh token was revoked, or lost in a crash.
+    ///
+    /// # Arguments
+    ///
+    /// * `redirect_url` - The redirect URL registered for the application, to which Fitbit redirects with the code.
+    /// * `scopes` - The scopes to request, e.g. "activity".
+    ///
+    /// # Returns
+    ///
+    /// The URL, the state to check in the redirect, and the PKCE verifier to exchange the code with.
+    pub fn authorization_url(&self, redirect_url: &str, scopes: &[String]) -> Result<(Url, CsrfToken, PkceCodeVerifier), FitbitError> {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (url, state) = self
+            .oauth_client()
+            .set_redirect_uri(RedirectUrl::new(redirect_url.to_string()).map_err(FitbitError::UrlError)?)
+            .authorize_url(CsrfToken::new_random)
+            .add_scopes(scopes.iter().map(|scope| Scope::new(scope.clone())))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+        Ok((url, state, pkce_verifier))
+    }
+
+    /// Exchanges the code of an authorization (see `authorization_url`) for new tokens, which replace the current
+    /// ones and are persisted in the token store.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::InvalidGrant` if the code is invalid (e.g. expired or already used),
+    /// `FitbitError::TokenError` on the other errors of the exchange, or `FitbitError::StorageError` if the tokens
+    /// cannot be persisted.
+    pub async fn exchange_authorization_code(&mut self, code: &str, pkce_verifier: PkceCodeVerifier, redirect_url: &str) -> Result<(), FitbitError> {
+        let token_result = self
+            .oauth_client()
+            .set_redirect_uri(RedirectUrl::new(redirect_url.to_string()).map_err(FitbitError::UrlError)?)
+            .exchange_code(AuthorizationCode::new(code.to_string()))
+            .set_pkce_verifier(pkce_verifier)
+            .request_async(async_http_client)
+            .await;
+        match token_result {
+            Ok(token_result) => {
+                info!("The exporter was re-authorized");
+                self.adopt_token_response(&token_result).await
+            }
+            Err(oauth2::RequestTokenError::ServerResponse(err_resp)) if *err_resp.error() == BasicErrorResponseType::InvalidGrant => Err(FitbitError::InvalidGrant),
+            Err(oauth2::RequestTokenError::ServerResponse(err_resp)) => Err(FitbitError::TokenError(format!("Server response error: {}", err_resp))),
+            Err(err) => Err(FitbitError::TokenError(format!("Request token error: {}", err))),
         }
     }
 
@@ -78,34 +614,84 @@ impl FitbitClient {
     ///
     /// Returns an error variant of `FitbitError` if the token refresh fails or encounters an issue.
     pub async fn refresh_access_token(&mut self) -> Result<(), FitbitError> {
+        let span = info_span!("refresh_access_token", duration_ms = Empty, outcome = Empty);
+        let started = Instant::now();
+        let result = self.exchange_refresh_token().instrument(span.clone()).await;
+        span.record("duration_ms", started.elapsed().as_millis() as u64);
+        span.record("outcome", outcome(&result));
+        if let Some(event_log) = &self.event_log {
+            match &result {
+                Ok(()) if self.refresh_token.is_some() => event_log.record("token_refreshed", format!("Refreshed the access token of the application {}", self.client_id)),
+                Ok(()) => {}
+                Err(err) => event_log.record("token_refresh_failed", format!("Error refreshing the access token of the application {}: {}", self.client_id, err)),
+            }
+        }
+        // A broken secondary application doesn't prevent using the primary one
+        if let Some(secondary) = &mut self.secondary {
+            if let Err(err) = Box::pin(secondary.refresh_access_token()).await {
+                error!("Error refreshing the access token of the secondary application: {}", err);
+            }
+        }
+        result
+    }
+
+    /// Exchanges the refresh token for a new access token, within the span of `refresh_access_token`.
+    ///
+    /// Replicas sharing the token store must not exchange the same refresh token, which can only be used once: the
+    /// exchange is made under the lock of the tokens, and skipped if the stored tokens show that another replica
+    /// already made it.
+    async fn exchange_refresh_token(&mut self) -> Result<(), FitbitError> {
+        let Some(storage) = self.token_store.clone() else {
+            return self.exchange_refresh_token_unlocked().await;
+        };
+        let lock_key = format!("{}.lock", self.tokens_key);
+        let deadline = Instant::now() + TOKENS_LOCK_TTL;
+        loop {
+            let key = lock_key.clone();
+            if run_blocking(&storage, move |storage| storage.try_lock(&key, TOKENS_LOCK_TTL)).await? {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(FitbitError::TokenError("The tokens are being refreshed by another replica".to_string()));
+            }
+            debug!("The tokens are being refreshed by another replica. Waiting...");
+            tokio::time::sleep(TOKENS_LOCK_RETRY_DELAY).await;
+        }
+        let result = match self.adopt_tokens_refreshed_elsewhere(&storage).await {
+            Ok(true) => Ok(()),
+            Ok(false) => self.exchange_refresh_token_unlocked().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = run_blocking(&storage, move |storage| storage.unlock(&lock_key)).await {
+            warn!("Could not release the lock of the tokens, which expires in {:?}: {}", TOKENS_LOCK_TTL, err);
+        }
+        result
+    }
+
+    /// Exchanges the refresh token, once no other replica can.
+    async fn exchange_refresh_token_unlocked(&mut self) -> Result<(), FitbitError> {
         debug!("Refreshing access token...");
         // If the refresh token is set, proceed with the token refresh. Otherwise, print a warning message and return early.
         if let Some(refresh_token) = &self.refresh_token {
-            let token_result = self.client
-                .exchange_refresh_token(&RefreshToken::new(refresh_token.secret().to_string()))
+            let token_result = self.oauth_client()
+                .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
                 .request_async(async_http_client)
                 .await;
 
             match token_result {
                 Ok(token_result) => {
-                    self.access_token = token_result.access_token().clone();
+                    self.adopt_token_response(&token_result).await?;
                     debug!("Access token successfully refreshed");
-
-                    // The response should includes a new "refresh" token as well, which we need to store for the next refresh.
-                    // FYI: https://dev.fitbit.com/build/reference/web-api/authorization/refresh-token/
-                    if let Some(new_refresh_token) = token_result.refresh_token() {
-                        self.refresh_token = Some(new_refresh_token.clone());
-                        debug!("New refresh token received and updated");
-                    }
                 }
                 Err(oauth2::RequestTokenError::ServerResponse(err_resp)) => {
                     if *err_resp.error() == BasicErrorResponseType::InvalidGrant {
                         return Err(FitbitError::InvalidGrant);
                     } else {
-                        return Err(FitbitError::TokenError(format!("Server response error: {:?}", err_resp)));
+                        return Err(FitbitError::TokenError(format!("Server response error: {}", err_resp)));
                     }
                 },
-                Err(err) => return Err(FitbitError::TokenError(format!("Request token error: {:?}", err))),
+                // The Display of the error leaves out the response body, which may contain the tokens.
+                Err(err) => return Err(FitbitError::TokenError(format!("Request token error: {}", err))),
             }
     
         } else {
@@ -115,6 +701,47 @@ impl FitbitClient {
         Ok(())
     }
 
+    /// Checks the access token by fetching the profile of the user, and refreshes it right away if it's expired, e.g.
+    /// at startup with a token older than 8 hours, instead of failing every request until the periodic refresh.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::AccessTokenExpired` (or `FitbitError::InvalidAccessToken`, e.g. if it was revoked) if the
+    /// token can't be used and there is no refresh token, `FitbitError::InvalidGrant` if the refresh token is invalid
+    /// as well (e.g. revoked), or any error of the refresh
+    /// but `FitbitError::StorageError`: the refreshed tokens are used even if they couldn't be persisted, since the
+    /// exchanged refresh token is no longer valid. The other errors of the check (e.g. a network error, or a token
+    /// without the `profile` scope) are logged and ignored, since the token may still be valid.
+    pub async fn ensure_valid_access_token(&mut self) -> Result<(), FitbitError> {
+        match self.fetch_user_id().await {
+            Ok(user_id) => {
+                self.user_id = Some(user_id);
+                Ok(())
+            }
+            Err(err @ (FitbitError::AccessTokenExpired | FitbitError::InvalidAccessToken(_))) if self.refresh_token.is_none() => Err(err),
+            Err(err @ (FitbitError::AccessTokenExpired | FitbitError::InvalidAccessToken(_))) => {
+                info!("{}. Refreshing the access token right away...", err);
+                match self.refresh_access_token().await {
+                    Err(FitbitError::StorageError(err)) => {
+                        warn!("Running with refreshed tokens that could not be persisted: {}", err);
+                        Ok(())
+                    }
+                    result => result,
+                }
+            }
+            Err(err) => {
+                warn!("Could not check the access token: {}", err);
+                Ok(())
+            }
+        }?;
+        if let Some(secondary) = &mut self.secondary {
+            if let Err(err) = Box::pin(secondary.ensure_valid_access_token()).await {
+                error!("The access token of the secondary application is invalid: {}", err);
+            }
+        }
+        Ok(())
+    }
+
     /// Fetches data from the Fitbit API for the given endpoint.
     ///
     /// This is a general-purpose method that takes an API endpoint as a parameter and returns the
@@ -127,20 +754,86 @@ impl FitbitClient {
     /// # Errors
     ///
     /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
-    /// an expired token or invalid data.
+    /// an expired token or invalid data, or `FitbitError::RateLimited` on a 429 (Too Many Requests) response.
+    ///
+    /// # Returns
+    ///
+    /// The JSON response. The request is made within a `fetch_data` span with the endpoint, the status, the duration,
+    /// the outcome and the user, which are fields of the JSON logs (`--log-format json`) and of the traces
+    /// (`--debug-traces`). With a secondary application, the request is made by its client while this one is rate
+    /// limited.
     async fn fetch_data(&self, endpoint: &str) -> Result<Value, FitbitError> {
+        let Some(secondary) = &self.secondary else {
+            return self.fetch_data_once(endpoint).await;
+        };
+        let rate_limited = self.rate_limited_until.lock().unwrap().is_some_and(|until| Instant::now() < until);
+        if rate_limited {
+            return Box::pin(secondary.fetch_data(endpoint)).await;
+        }
+        match self.fetch_data_once(endpoint).await {
+            Err(FitbitError::RateLimited(retry_after)) => {
+                warn!("The primary application is rate limited for {} seconds. Failing over to the secondary application", retry_after.as_secs());
+                *self.rate_limited_until.lock().unwrap() = Some(Instant::now() + retry_after);
+                if let Some(event_log) = &self.event_log {
+                    event_log.record("failover", format!("Failing over to the secondary application for {} seconds", retry_after.as_secs()));
+                }
+                Box::pin(secondary.fetch_data(endpoint)).await
+            }
+            result => result,
+        }
+    }
+
+    /// Makes the request of `fetch_data` with this client.
+    async fn fetch_data_once(&self, endpoint: &str) -> Result<Value, FitbitError> {
+        let span = info_span!(
+            "fetch_data",
+            endpoint,
+            user = self.user_id.as_deref().unwrap_or("-"),
+            status = Empty,
+            duration_ms = Empty,
+            outcome = Empty
+        );
+        let started = Instant::now();
+        let result = self.request_json(endpoint).instrument(span.clone()).await;
+        span.record("duration_ms", started.elapsed().as_millis() as u64);
+        span.record("outcome", outcome(&result));
+        result
+    }
+
+    /// Requests the endpoint, within the span of `fetch_data`.
+    async fn request_json(&self, endpoint: &str) -> Result<Value, FitbitError> {
     // async fn fetch_data(&mut self, endpoint: &str) -> Result<Value, FitbitError> {
         debug!("Fetching data from endpoint: {}", endpoint);
         let url = Url::parse(endpoint).map_err(FitbitError::UrlError)?;
-        let response = reqwest::Client::new()
-            .get(url.clone())
-            .bearer_auth(self.access_token.secret())
-            .send()
-            .await
-            .map_err(FitbitError::HttpError)?;
-
-        let json: Value = response.json().await.map_err(FitbitError::HttpError)?;
-        if json["errors"][0]["errorType"].as_str() == Some("expired_token") {
+        if let Some(fixtures_dir) = &self.fixtures_dir {
+            return read_fixture(fixtures_dir, endpoint, &url);
+        }
+        let url = if self.api_url == API_URL { url } else { Url::parse(&endpoint.replacen(API_URL, &self.api_url, 1)).map_err(FitbitError::UrlError)? };
+        let response = self.send_with_retry(Method::GET, &url, HeaderMap::new()).await?;
+        let status = response.status();
+        Span::current().record("status", status.as_u16());
+        debug!("Fetched {} ({})", endpoint, status);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = retry_after(response.headers());
+            debug!("Rate limited by the Fitbit API. Retry after {:?}", retry_after);
+            return Err(FitbitError::RateLimited(retry_after));
+        }
+
+        let json = self.read_json(Method::GET, endpoint, response).await?;
+        let error_type = json["errors"][0]["errorType"].as_str();
+        let message = |default: &str| json["errors"][0]["message"].as_str().unwrap_or(default).to_string();
+        // FYI: Fitbit responds with 401 and an `expired_token` error when the access token expired, `invalid_token` when
+        // it can't be used otherwise (e.g. revoked), and `insufficient_scope` (or 403 and `insufficient_permissions`)
+        // when the token wasn't granted the scope of the resource, e.g. `sleep`.
+        // See https://dev.fitbit.com/build/reference/web-api/troubleshooting-guide/error-messages/
+        if status == StatusCode::FORBIDDEN || matches!(error_type, Some("insufficient_scope" | "insufficient_permissions")) {
+            return Err(FitbitError::InsufficientScope { endpoint: endpoint.to_string(), message: message("forbidden") });
+        }
+        if error_type == Some("invalid_token") {
+            debug!("Access token invalid.");
+            return Err(FitbitError::InvalidAccessToken(message("invalid token")));
+        }
+        if status == StatusCode::UNAUTHORIZED || error_type == Some("expired_token") {
             debug!("Access token expired.");
             return Err(FitbitError::AccessTokenExpired);
         }
@@ -148,26 +841,127 @@ impl FitbitClient {
         Ok(json)
     }
 
+    /// Reads the body of a response as JSON, logging it in the body log if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::HttpError` if the body cannot be read, `FitbitError::ResponseTooLarge` if it exceeds
+    /// `HttpOptions::max_response_bytes`, or `FitbitError::UnexpectedResponse` if it's not JSON.
+    async fn read_json(&self, method: Method, endpoint: &str, response: reqwest::Response) -> Result<Value, FitbitError> {
+        let status = response.status();
+        let body = self.read_body(endpoint, response).await?;
+        if let Some(body_log) = &self.body_log {
+            let secrets = [self.access_token.as_str(), self.refresh_token.as_ref().map_or("", |token| token.as_str())];
+            body_log.log(method.as_str(), endpoint, status.as_u16(), &body, &secrets);
+        }
+        serde_json::from_slice(&body).map_err(|err| FitbitError::UnexpectedResponse {
+            endpoint: endpoint.to_string(),
+            path: String::new(),
+            message: err.to_string(),
+        })
+    }
+
+    /// Reads the body of a response chunk by chunk, failing as soon as it exceeds `HttpOptions::max_response_bytes`
+    /// instead of buffering it whole.
+    async fn read_body(&self, endpoint: &str, mut response: reqwest::Response) -> Result<Vec<u8>, FitbitError> {
+        let too_large = |limit| FitbitError::ResponseTooLarge { endpoint: endpoint.to_string(), limit };
+        let limit = self.http_options.max_response_bytes;
+        if let (Some(limit), Some(content_length)) = (limit, response.content_length()) {
+            if content_length > limit as u64 {
+                return Err(too_large(limit));
+            }
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(FitbitError::HttpError)? {
+            if limit.is_some_and(|limit| body.len() + chunk.len() > limit) {
+                return Err(too_large(limit.unwrap_or_default()));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// Fetches data from the Fitbit API for the given endpoint and deserializes it into `T`, e.g. `StepsSeries`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::UnexpectedResponse` with the path of the offending field (e.g. `activities-steps[0].value`)
+    /// if the response doesn't match `T`, or any error returned by `fetch_data`.
+    async fn fetch_json<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T, FitbitError> {
+        let json = self.fetch_data(endpoint).await?;
+        serde_path_to_error::deserialize(json).map_err(|err| FitbitError::UnexpectedResponse {
+            endpoint: endpoint.to_string(),
+            path: err.path().to_string(),
+            message: err.into_inner().to_string(),
+        })
+    }
+
+    /// Sends a request to the given URL, retrying transient failures.
+    ///
+    /// Timeouts, connection errors and 5xx responses are retried with a jittered exponential backoff, up to
+    /// `HttpOptions::max_retries` times. A 429 (Too Many Requests) is retried after its `Retry-After`, since retrying
+    /// it earlier would only extend the rate limit, unless that's longer than `HttpOptions::max_backoff`: the
+    /// exhausted hourly quota is then returned as is, to fail over or keep the last values instead of stalling the
+    /// update. Any other response is returned as is.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::HttpError` if the last attempt fails with a transport error.
+    async fn send_with_retry(&self, method: Method, url: &Url, headers: HeaderMap) -> Result<reqwest::Response, FitbitError> {
+        let mut attempt = 0;
+        loop {
+            count_request();
+            let result = self.http_client
+                .request(method.clone(), url.clone())
+                .headers(headers.clone())
+                .bearer_auth(self.access_token.as_str())
+                .send()
+                .await;
+
+            let retryable = match &result {
+                Ok(response) => is_retryable_status(response.status()),
+                Err(err) => err.is_timeout() || err.is_connect(),
+            };
+            if !retryable || attempt >= self.http_options.max_retries {
+                return result.map_err(map_transport_error);
+            }
+
+            let delay = match &result {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    let retry_after = retry_after(response.headers());
+                    if retry_after > self.http_options.max_backoff {
+                        return result.map_err(map_transport_error);
+                    }
+                    retry_after
+                }
+                _ => self.http_options.backoff(attempt),
+            };
+            match &result {
+                Ok(response) => debug!("Transient response status {} from {}. Retrying in {:?}...", response.status(), url, delay),
+                Err(err) => debug!("Transient error calling {}: {}. Retrying in {:?}...", url, err, delay),
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
     /// Fetches the number of steps from the Fitbit API, by using:
     /// https://dev.fitbit.com/build/reference/web-api/activity-timeseries/get-activity-timeseries-by-date/
     ///
-    /// This method calls the `fetch_data` internally and extracts the number of steps from the JSON response.
+    /// This method calls the `fetch_json` internally and extracts the number of steps from the `StepsSeries`.
     ///
     /// # Errors
     ///
     /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
     /// an expired token or invalid data.
-    pub async fn fetch_steps(&self) -> Result<u64, FitbitError> {
+    pub async fn fetch_steps(&self) -> Result<Steps, FitbitError> {
     // pub async fn fetch_steps(&mut self) -> Result<u64, FitbitError> {
         debug!("Fetching steps data...");
-        let json = self
-            .fetch_data("https://api.fitbit.com/1/user/-/activities/steps/date/today/1d.json")
+        let series: StepsSeries = self
+            .fetch_json("https://api.fitbit.com/1/user/-/activities/steps/date/today/1d.json")
             .await?;
-        let steps = json["activities-steps"][0]["value"]
-            .as_str()
-            .ok_or(FitbitError::InvalidData)?
-            .parse::<u64>()
-            .map_err(|_| FitbitError::InvalidData)?;
+        let steps = series.steps.first().map(|entry| entry.value).ok_or(FitbitError::InvalidData)?;
         debug!("Fetched steps: {}", steps);
         Ok(steps)
     }
@@ -190,82 +984,1345 @@ impl FitbitClient {
     //     Ok(steps)
     // }
 
-    pub async fn fetch_sleep(&self) -> Result<Value, FitbitError> {
-        let json = self
-            // .fetch_data("https://api.fitbit.com/1.2/user/-/sleep/date/today.json") // FIXME
-            .fetch_data("https://api.fitbit.com/1.2/user/-/sleep/date/2023-03-04.json")
+    /// Fetches today's sleep logs from the Fitbit API, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/sleep/get-sleep-log-by-date/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_sleep(&self) -> Result<SleepLogResponse, FitbitError> {
+        let sleep: SleepLogResponse = self
+            .fetch_json("https://api.fitbit.com/1.2/user/-/sleep/date/today.json")
             .await?;
-        debug!("Fetched sleep: {:?}", json);
-        Ok(json)
+        debug!("Fetched sleep: {:?}", sleep);
+        Ok(sleep)
     }
 
-    // pub async fn fetch_weight(&self) -> Result<Value, FitbitError> {
-    //     let json = self
-    //         .fetch_data("https://api.fitbit.com/1/user/-/body/log/weight/date/today.json")
-    //         .await?;
-    //     Ok(json)
-    // }
+    /// Fetches today's heart rate summary (resting heart rate and heart rate zones), by using:
+    /// https://dev.fitbit.com/build/reference/web-api/heartrate-timeseries/get-heartrate-timeseries-by-date/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_heart_rate(&self) -> Result<HeartRateSummary, FitbitError> {
+        let series: HeartRateSeries = self
+            .fetch_json("https://api.fitbit.com/1/user/-/activities/heart/date/today/1d.json")
+            .await?;
+        let summary = series.days.into_iter().next().map(|day| day.value).ok_or(FitbitError::InvalidData)?;
+        debug!("Fetched heart rate: {:?}", summary);
+        Ok(summary)
+    }
 
+    /// Fetches today's heart rate of each minute, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/intraday/get-heartrate-intraday-by-date/
+    ///
+    /// The intraday data is only available to the personal applications, or with the approval of Fitbit.
+    ///
+    /// # Arguments
+    ///
+    /// * `since` - Only fetches the minutes from this time of the day on (the time window of the endpoint), instead of
+    ///   the whole day, which makes the response much smaller late in the day. `None` fetches the whole day.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an application without access to the intraday data.
+    pub async fn fetch_heart_rate_intraday(&self, since: Option<NaiveTime>) -> Result<Vec<HeartRateSample>, FitbitError> {
+        let url = match since {
+            Some(start) => format!("https://api.fitbit.com/1/user/-/activities/heart/date/today/1d/1min/time/{}/23:59.json", start.format("%H:%M")),
+            None => "https://api.fitbit.com/1/user/-/activities/heart/date/today/1d/1min.json".to_string(),
+        };
+        let response: HeartRateIntradayResponse = self.fetch_json(&url).await?;
+        debug!("Fetched {} intraday heart rate samples", response.intraday.dataset.len());
+        Ok(response.intraday.dataset)
+    }
 
+    /// Fetches the latest Cardio Fitness Score (VO2 Max) of the user, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/cardio-fitness-score/get-vo2max-summary-by-interval/
+    ///
+    /// The score of the last 30 days is requested, since it's only computed on the days with enough heart rate data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `cardio_fitness` scope.
+    ///
+    /// # Returns
+    ///
+    /// The most recent day with a score, or `None` if there's none in the last 30 days.
+    pub async fn fetch_cardio_score(&self) -> Result<Option<CardioScoreDay>, FitbitError> {
+        let end_date = Utc::now().date_naive() + ChronoDuration::days(1);
+        let start_date = end_date - ChronoDuration::days(30);
+        let response: CardioScoreResponse = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/cardioscore/date/{}/{}.json",
+                start_date.format("%Y-%m-%d"),
+                end_date.format("%Y-%m-%d"),
+            ))
+            .await?;
+        let latest = response.days.into_iter().max_by_key(|day| day.date_time);
+        debug!("Fetched cardio score: {:?}", latest);
+        Ok(latest)
+    }
 
-    pub async fn fetch_steps_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, u64)>, FitbitError> {
-        debug!("Fetching historical steps data from {} to {}", start_date, end_date);
-    
-        let start_date_str = start_date.format("%Y-%m-%d").to_string();
-        let end_date_str = end_date.format("%Y-%m-%d").to_string();
-        let endpoint = format!("https://api.fitbit.com/1/user/-/activities/steps/date/{}/{}.json", start_date_str, end_date_str);
-    
-        let json = self.fetch_data(&endpoint).await?;
-    
-        let steps_data = json["activities-steps"]
-            .as_array()
-            .ok_or(FitbitError::InvalidData)?;
-    
-        let mut results: Vec<(NaiveDate, u64)> = Vec::new();
-    
-        for entry in steps_data {
-            let date_str = entry["dateTime"]
-                .as_str()
-                .ok_or(FitbitError::InvalidData)?;
-    
-            let date = NaiveDate::parse_from_str(date_str, "%Y-%m-%d")
-                .map_err(|_| FitbitError::InvalidData)?;
+    /// Fetches the daily resting heart rates from `start_date` to `end_date` (inclusive, at most 1 year), oldest
+    /// first, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/heartrate-timeseries/get-heartrate-timeseries-by-date-range/
+    ///
+    /// The days without enough data to compute the resting heart rate are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_resting_heart_rate_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, Bpm)>, FitbitError> {
+        let series: HeartRateSeries = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/activities/heart/date/{}/{}.json",
+                start_date.format("%Y-%m-%d"),
+                end_date.format("%Y-%m-%d"),
+            ))
+            .await?;
+        let resting_heart_rates: Vec<(NaiveDate, Bpm)> = series
+            .days
+            .into_iter()
+            .filter_map(|day| day.value.resting_heart_rate.map(|bpm| (day.date_time, bpm)))
+            .collect();
+        debug!("Fetched resting heart rates: {:?}", resting_heart_rates);
+        Ok(resting_heart_rates)
+    }
+
+    /// Fetches the daily HRV (RMSSD of the main sleep, in milliseconds) from `start_date` to `end_date` (inclusive,
+    /// at most 30 days), oldest first, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/heartrate-variability/get-hrv-summary-by-interval/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `heartrate` scope.
+    pub async fn fetch_hrv_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, f64)>, FitbitError> {
+        let series: HrvSeries = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/hrv/date/{}/{}.json",
+                start_date.format("%Y-%m-%d"),
+                end_date.format("%Y-%m-%d"),
+            ))
+            .await?;
+        let mut hrv: Vec<(NaiveDate, f64)> = series.hrv.into_iter().map(|day| (day.date_time, day.value.daily_rmssd)).collect();
+        hrv.sort_by_key(|(date, _)| *date);
+        debug!("Fetched HRV: {:?}", hrv);
+        Ok(hrv)
+    }
+
+    /// Fetches last night's breathing rates, per sleep stage, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/intraday/get-br-intraday-by-date/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `respiratory_rate` scope.
+    ///
+    /// # Returns
+    ///
+    /// The breathing rates of the main sleep ending today, or `None` if there's none yet.
+    pub async fn fetch_breathing_rate(&self) -> Result<Option<BreathingRateSummary>, FitbitError> {
+        let series: BreathingRateSeries = self
+            .fetch_json("https://api.fitbit.com/1/user/-/br/date/today/all.json")
+            .await?;
+        let latest = series.days.into_iter().max_by_key(|day| day.date_time).map(|day| day.value);
+        debug!("Fetched breathing rate: {:?}", latest);
+        Ok(latest)
+    }
+
+    /// Fetches the latest nightly skin temperature deviation of the last 7 days, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/temperature/get-temperature-skin-summary-by-interval/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `temperature` scope.
+    ///
+    /// # Returns
+    ///
+    /// The most recent night with a measurement, or `None` if there's none in the last 7 days.
+    pub async fn fetch_skin_temperature(&self) -> Result<Option<SkinTemperatureDay>, FitbitError> {
+        let end_date = Utc::now().date_naive() + ChronoDuration::days(1);
+        let start_date = end_date - ChronoDuration::days(7);
+        let series: SkinTemperatureSeries = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/temp/skin/date/{}/{}.json",
+                start_date.format("%Y-%m-%d"),
+                end_date.format("%Y-%m-%d"),
+            ))
+            .await?;
+        let latest = series.days.into_iter().max_by_key(|day| day.date_time);
+        debug!("Fetched skin temperature: {:?}", latest);
+        Ok(latest)
+    }
+
+    /// Fetches the latest core temperature logged by the user in the last 7 days, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/temperature/get-temperature-core-summary-by-interval/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `temperature` scope.
+    ///
+    /// # Returns
+    ///
+    /// The most recent log, or `None` if there's none in the last 7 days.
+    pub async fn fetch_core_temperature(&self) -> Result<Option<CoreTemperatureLog>, FitbitError> {
+        let end_date = Utc::now().date_naive() + ChronoDuration::days(1);
+        let start_date = end_date - ChronoDuration::days(7);
+        let series: CoreTemperatureSeries = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/temp/core/date/{}/{}.json",
+                start_date.format("%Y-%m-%d"),
+                end_date.format("%Y-%m-%d"),
+            ))
+            .await?;
+        let latest = series.logs.into_iter().max_by_key(|log| log.date_time);
+        debug!("Fetched core temperature: {:?}", latest);
+        Ok(latest)
+    }
+
+    /// Fetches the devices paired with the account, e.g. to know when the tracker last synced, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/devices/get-devices/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_devices(&self) -> Result<Vec<Device>, FitbitError> {
+        let devices: Vec<Device> = self
+            .fetch_json("https://api.fitbit.com/1/user/-/devices.json")
+            .await?;
+        debug!("Fetched devices: {:?}", devices);
+        Ok(devices)
+    }
+
+    /// Fetches the friends leaderboard, i.e. the user and their friends ranked by their steps of the last 7 days, by
+    /// using: https://dev.fitbit.com/build/reference/web-api/friends/get-friends-leaderboard/
+    /// Requires the `social` scope.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    ///
+    /// # Returns
+    ///
+    /// The ranked users, in the order of their rank. The inactive friends are left out.
+    pub async fn fetch_leaderboard(&self) -> Result<Vec<LeaderboardRank>, FitbitError> {
+        let leaderboard: LeaderboardResponse = self
+            .fetch_json("https://api.fitbit.com/1.1/user/-/leaderboard/friends.json")
+            .await?;
+        let ranks = leaderboard.into_ranks();
+        // The names of the friends are personal data, which the logs don't need
+        debug!("Fetched the leaderboard of {} users", ranks.len());
+        Ok(ranks)
+    }
+
+    /// Fetches the lifetime totals (steps, distance and floors) and the best days, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/activity/get-lifetime-stats/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_lifetime_stats(&self) -> Result<LifetimeStats, FitbitError> {
+        let response: LifetimeStatsResponse = self
+            .fetch_json("https://api.fitbit.com/1/user/-/activities.json")
+            .await?;
+        let stats = LifetimeStats::from(response);
+        debug!("Fetched lifetime stats: {:?}", stats);
+        Ok(stats)
+    }
+
+    /// Fetches the badges earned over the lifetime of the account, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/user/get-badges/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_badges(&self) -> Result<Vec<Badge>, FitbitError> {
+        let response: BadgesResponse = self
+            .fetch_json("https://api.fitbit.com/1/user/-/badges.json")
+            .await?;
+        debug!("Fetched badges: {:?}", response.badges);
+        Ok(response.badges)
+    }
+
+    /// Fetches the profile of the user, i.e. its ID (e.g. "ABC123") and the offset of its timezone, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/user/get-profile/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `profile` scope.
+    pub async fn fetch_profile(&self) -> Result<UserProfile, FitbitError> {
+        let profile: UserProfileResponse = self
+            .fetch_json("https://api.fitbit.com/1/user/-/profile.json")
+            .await?;
+        debug!("Fetched profile: {:?}", profile.user);
+        Ok(profile.user)
+    }
+
+    /// Fetches the ID of the user (e.g. "ABC123"), see `fetch_profile`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `profile` scope.
+    pub async fn fetch_user_id(&self) -> Result<String, FitbitError> {
+        Ok(self.fetch_profile().await?.encoded_id)
+    }
+
+    /// Fetches the daily activity goals of the user (steps, calories out, distance, floors, active minutes), by using:
+    /// https://dev.fitbit.com/build/reference/web-api/activity/get-activity-goals/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_activity_goals(&self) -> Result<ActivityGoals, FitbitError> {
+        let response: ActivityGoalsResponse = self
+            .fetch_json("https://api.fitbit.com/1/user/-/activities/goals/daily.json")
+            .await?;
+        debug!("Fetched activity goals: {:?}", response.goals);
+        Ok(response.goals)
+    }
+
+    /// Fetches today's activity summary (calories out, distance, floors, active minutes), by using:
+    /// https://dev.fitbit.com/build/reference/web-api/activity/get-daily-activity-summary/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_activity_summary(&self) -> Result<ActivitySummary, FitbitError> {
+        let response: DailyActivityResponse = self
+            .fetch_json("https://api.fitbit.com/1/user/-/activities/date/today.json")
+            .await?;
+        debug!("Fetched activity summary: {:?}", response.summary);
+        Ok(response.summary)
+    }
+
+    /// Fetches the most recent activity logs (workouts), newest first, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/activity/get-activity-log-list/
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of activity logs to fetch (at most 100).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_activity_logs(&self, limit: u32) -> Result<Vec<ActivityLog>, FitbitError> {
+        // The endpoint requires either beforeDate or afterDate. Two days after today (in UTC) includes today's
+        // activities whatever the timezone of the user.
+        let before_date = Utc::now().date_naive() + ChronoDuration::days(2);
+        let list: ActivityLogList = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/activities/list.json?beforeDate={}&sort=desc&offset=0&limit={}",
+                before_date.format("%Y-%m-%d"),
+                limit.min(100),
+            ))
+            .await?;
+        debug!("Fetched {} activity logs", list.activities.len());
+        Ok(list.activities)
+    }
+
+    /// Fetches the most recent ECG readings, newest first, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/electrocardiogram/get-ecg-log-list/
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of readings to fetch (at most 10).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `electrocardiogram` scope.
+    pub async fn fetch_ecg_readings(&self, limit: u32) -> Result<Vec<EcgReading>, FitbitError> {
+        // Same as the activity logs, the endpoint requires either beforeDate or afterDate.
+        let before_date = Utc::now().date_naive() + ChronoDuration::days(2);
+        let list: EcgLogList = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/ecg/list.json?beforeDate={}&sort=desc&offset=0&limit={}",
+                before_date.format("%Y-%m-%d"),
+                limit.min(10),
+            ))
+            .await?;
+        debug!("Fetched {} ECG readings", list.readings.len());
+        Ok(list.readings)
+    }
+
+    /// Fetches the most recent irregular rhythm notifications (AFib alerts), newest first, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/irregular-rhythm-notifications/get-irn-alerts-list/
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of alerts to fetch (at most 10).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// a token without the `irregular_rhythm_notifications` scope.
+    pub async fn fetch_irn_alerts(&self, limit: u32) -> Result<Vec<IrnAlert>, FitbitError> {
+        let before_date = Utc::now().date_naive() + ChronoDuration::days(2);
+        let list: IrnAlertList = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/irn/alerts/list.json?beforeDate={}&sort=desc&offset=0&limit={}",
+                before_date.format("%Y-%m-%d"),
+                limit.min(10),
+            ))
+            .await?;
+        debug!("Fetched {} irregular rhythm notifications", list.alerts.len());
+        Ok(list.alerts)
+    }
+
+    // NOTE: no fetcher for the menstrual health (cycle phase, cycle day, predicted period start). The Female Health
+    // Tracking data logged in the Fitbit app isn't exposed by the Web API: there's no endpoint nor scope for it.
+    // https://dev.fitbit.com/build/reference/web-api/explore/
+    // Same for the sleep score and its components (revitalization, duration, composition): they're only computed in
+    // the app. The closest exported metrics are fitbit_sleep_efficiency and the stages of fitbit_sleep_stage_seconds.
+
+    /// Fetches the weigh-ins from `start_date` to `end_date` (inclusive), oldest first, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/body/get-weight-log/
+    ///
+    /// The range is fetched in chunks of `WEIGHT_LOG_MAX_DAYS` days, the longest range of the endpoint.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with any of the requests, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_weight_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<WeightLog>, FitbitError> {
+        let mut weight_logs = Vec::new();
+        let mut chunk_start = start_date;
+        while chunk_start <= end_date {
+            let chunk_end = (chunk_start + ChronoDuration::days(WEIGHT_LOG_MAX_DAYS - 1)).min(end_date);
+            let list: WeightLogList = self
+                .fetch_json(&format!(
+                    "https://api.fitbit.com/1/user/-/body/log/weight/date/{}/{}.json",
+                    chunk_start.format("%Y-%m-%d"),
+                    chunk_end.format("%Y-%m-%d"),
+                ))
+                .await?;
+            weight_logs.extend(list.weight);
+            chunk_start = chunk_end + ChronoDuration::days(1);
+        }
+        weight_logs.sort_by_key(WeightLog::logged_at);
+        debug!("Fetched weight logs: {:?}", weight_logs);
+        Ok(weight_logs)
+    }
+
+    /// Fetches today's total water consumption in milliliters, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/nutrition/get-water-log/
+    ///
+    /// FYI: Fitbit returns metric units (milliliters) as long as no `Accept-Language` header is sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_water(&self) -> Result<f64, FitbitError> {
+        self.fetch_water_for("today").await
+    }
+
+    /// Same as `fetch_water`, for the given date instead of today.
+    pub async fn fetch_water_on(&self, date: NaiveDate) -> Result<f64, FitbitError> {
+        self.fetch_water_for(&date.format("%Y-%m-%d").to_string()).await
+    }
+
+    async fn fetch_water_for(&self, date: &str) -> Result<f64, FitbitError> {
+        debug!("Fetching water data for {}...", date);
+        let log: WaterLog = self
+            .fetch_json(&format!("https://api.fitbit.com/1/user/-/foods/log/water/date/{}.json", date))
+            .await?;
+        let water = log.summary.water;
+        debug!("Fetched water: {}", water);
+        Ok(water)
+    }
+
+    /// Fetches today's food log summary (calories in and nutrients), by using:
+    /// https://dev.fitbit.com/build/reference/web-api/nutrition/get-food-log/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_food_summary(&self) -> Result<FoodSummary, FitbitError> {
+        self.fetch_food_summary_for("today").await
+    }
+
+    /// Same as `fetch_food_summary`, for the given date instead of today.
+    pub async fn fetch_food_summary_on(&self, date: NaiveDate) -> Result<FoodSummary, FitbitError> {
+        self.fetch_food_summary_for(&date.format("%Y-%m-%d").to_string()).await
+    }
+
+    async fn fetch_food_summary_for(&self, date: &str) -> Result<FoodSummary, FitbitError> {
+        debug!("Fetching food log summary for {}...", date);
+        let log: FoodLog = self
+            .fetch_json(&format!("https://api.fitbit.com/1/user/-/foods/log/date/{}.json", date))
+            .await?;
+        debug!("Fetched food log summary: {:?}", log.summary);
+        Ok(log.summary)
+    }
+
+    /// Fetches the daily steps of the last 7 days up to today. Unlike `fetch_steps_range`, the range is relative to
+    /// Fitbit's "today" in the user's timezone, rather than to dates computed by the exporter.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_steps_last_week(&self) -> Result<Vec<(NaiveDate, Steps)>, FitbitError> {
+        debug!("Fetching steps data of the last 7 days...");
+        let series: StepsSeries = self
+            .fetch_json("https://api.fitbit.com/1/user/-/activities/steps/date/today/7d.json")
+            .await?;
+        let results = series.into_pairs();
+        debug!("Fetched steps data of the last 7 days: {:?}", results);
+        Ok(results)
+    }
+
+    pub async fn fetch_steps_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, Steps)>, FitbitError> {
+        debug!("Fetching historical steps data from {} to {}", start_date, end_date);
     
-            let steps = entry["value"]
-                .as_str()
-                .ok_or(FitbitError::InvalidData)?
-                .parse::<u64>()
-                .map_err(|_| FitbitError::InvalidData)?;
+        let start_date_str = start_date.format("%Y-%m-%d").to_string();
+        let end_date_str = end_date.format("%Y-%m-%d").to_string();
+        let endpoint = format!("https://api.fitbit.com/1/user/-/activities/steps/date/{}/{}.json", start_date_str, end_date_str);
     
-            results.push((date, steps));
-        }
+        let series: StepsSeries = self.fetch_json(&endpoint).await?;
+        let results = series.into_pairs();
     
         debug!("Fetched historical steps data: {:?}", results);
         Ok(results)
     }
+
+    /// Fetches the daily elevation climbed from `start_date` to `end_date` (inclusive, at most 1095 days), oldest
+    /// first, by using:
+    /// https://dev.fitbit.com/build/reference/web-api/activity-timeseries/get-activity-timeseries-by-date-range/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_elevation_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, Meters)>, FitbitError> {
+        let series: ElevationSeries = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/activities/elevation/date/{}/{}.json",
+                start_date.format("%Y-%m-%d"),
+                end_date.format("%Y-%m-%d"),
+            ))
+            .await?;
+        let results = series.into_pairs();
+        debug!("Fetched historical elevation data: {:?}", results);
+        Ok(results)
+    }
+
+    /// Fetches the daily floors climbed from `start_date` to `end_date` (inclusive, at most 1095 days), oldest first,
+    /// by using:
+    /// https://dev.fitbit.com/build/reference/web-api/activity-timeseries/get-activity-timeseries-by-date-range/
+    ///
+    /// # Errors
+    ///
+    /// Returns an error variant of `FitbitError` if there is a problem with the request, such as
+    /// an expired token or invalid data.
+    pub async fn fetch_floors_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, u64)>, FitbitError> {
+        let series: FloorsSeries = self
+            .fetch_json(&format!(
+                "https://api.fitbit.com/1/user/-/activities/floors/date/{}/{}.json",
+                start_date.format("%Y-%m-%d"),
+                end_date.format("%Y-%m-%d"),
+            ))
+            .await?;
+        let results = series.into_pairs();
+        debug!("Fetched historical floors data: {:?}", results);
+        Ok(results)
+    }
+
+    /// Subscribes to the notifications of a collection (e.g. "sleep"), by using:
+    /// https://dev.fitbit.com/build/reference/web-api/subscription/create-subscription/
+    ///
+    /// An existing subscription with the same ID is kept as is, so this can be called on every start.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The collection to subscribe to: activities, body, foods, sleep or userRevokedAccess.
+    /// * `subscription_id` - The ID of the subscription, unique per user and collection.
+    /// * `subscriber_id` - The subscriber to notify. `None` uses the default subscriber of the application.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FitbitError::InvalidData` if a subscription with the same ID exists for another subscriber,
+    /// `FitbitError::HttpError` for any other error status, or any other error variant of `FitbitError`
+    /// if there is a problem with the request.
+    pub async fn create_subscription(&self, collection: &str, subscription_id: &str, subscriber_id: Option<&str>) -> Result<Subscription, FitbitError> {
+        debug!("Creating the {} subscription of the {} collection...", subscription_id, collection);
+        let endpoint = format!("https://api.fitbit.com/1/user/-/{}/apiSubscriptions/{}.json", collection, subscription_id);
+        let url = Url::parse(&endpoint).map_err(FitbitError::UrlError)?;
+        let mut headers = HeaderMap::new();
+        if let Some(subscriber_id) = subscriber_id {
+            let value = subscriber_id.parse().map_err(|_| FitbitError::InvalidData)?;
+            headers.insert("X-Fitbit-Subscriber-Id", value);
+        }
+
+        // 201 for a new subscription, 200 if it already exists for the same subscriber
+        let response = self.send_with_retry(Method::POST, &url, headers).await?;
+        if response.status() == StatusCode::CONFLICT {
+            error!("The {} subscription already exists for another subscriber", subscription_id);
+            return Err(FitbitError::InvalidData);
+        }
+        let response = response.error_for_status().map_err(FitbitError::HttpError)?;
+        let json = self.read_json(Method::POST, &endpoint, response).await?;
+        serde_path_to_error::deserialize(json).map_err(|err| FitbitError::UnexpectedResponse {
+            endpoint,
+            path: err.path().to_string(),
+            message: err.into_inner().to_string(),
+        })
+    }
     
 }
 
+/// A pending response of the Fitbit API, boxed so that `FitbitApi` can be used as a trait object.
+pub type ApiFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FitbitError>> + Send + 'a>>;
+
+/// The reads of the Fitbit API the metrics depend on, implemented by `FitbitClient`.
+///
+/// The metrics, the server and the history depend on this trait rather than on `FitbitClient`, so that the mapping
+/// of the responses to the metrics can be tested with a `MockFitbitApi` instead of live tokens. The methods are
+/// documented on `FitbitClient`. The token refresh and the subscriptions stay on `FitbitClient`.
+pub trait FitbitApi: Send + Sync {
+    fn fetch_steps(&self) -> ApiFuture<'_, Steps>;
+    fn fetch_water(&self) -> ApiFuture<'_, f64>;
+    fn fetch_food_summary(&self) -> ApiFuture<'_, FoodSummary>;
+    fn fetch_sleep(&self) -> ApiFuture<'_, SleepLogResponse>;
+    fn fetch_activity_summary(&self) -> ApiFuture<'_, ActivitySummary>;
+    fn fetch_activity_logs(&self, limit: u32) -> ApiFuture<'_, Vec<ActivityLog>>;
+    fn fetch_activity_goals(&self) -> ApiFuture<'_, ActivityGoals>;
+    fn fetch_devices(&self) -> ApiFuture<'_, Vec<Device>>;
+    fn fetch_leaderboard(&self) -> ApiFuture<'_, Vec<LeaderboardRank>>;
+    fn fetch_badges(&self) -> ApiFuture<'_, Vec<Badge>>;
+    fn fetch_lifetime_stats(&self) -> ApiFuture<'_, LifetimeStats>;
+    fn fetch_heart_rate_intraday(&self, since: Option<NaiveTime>) -> ApiFuture<'_, Vec<HeartRateSample>>;
+    fn fetch_ecg_readings(&self, limit: u32) -> ApiFuture<'_, Vec<EcgReading>>;
+    fn fetch_irn_alerts(&self, limit: u32) -> ApiFuture<'_, Vec<IrnAlert>>;
+    fn fetch_cardio_score(&self) -> ApiFuture<'_, Option<CardioScoreDay>>;
+    fn fetch_breathing_rate(&self) -> ApiFuture<'_, Option<BreathingRateSummary>>;
+    fn fetch_skin_temperature(&self) -> ApiFuture<'_, Option<SkinTemperatureDay>>;
+    fn fetch_core_temperature(&self) -> ApiFuture<'_, Option<CoreTemperatureLog>>;
+    fn fetch_water_on(&self, date: NaiveDate) -> ApiFuture<'_, f64>;
+    fn fetch_food_summary_on(&self, date: NaiveDate) -> ApiFuture<'_, FoodSummary>;
+    fn fetch_steps_last_week(&self) -> ApiFuture<'_, Vec<(NaiveDate, Steps)>>;
+    fn fetch_steps_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Steps)>>;
+    fn fetch_elevation_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Meters)>>;
+    fn fetch_floors_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, u64)>>;
+    fn fetch_weight_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<WeightLog>>;
+    fn fetch_resting_heart_rate_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Bpm)>>;
+    fn fetch_hrv_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, f64)>>;
+    fn fetch_user_id(&self) -> ApiFuture<'_, String>;
+    fn fetch_profile(&self) -> ApiFuture<'_, UserProfile>;
+}
+
+impl FitbitApi for FitbitClient {
+    fn fetch_steps(&self) -> ApiFuture<'_, Steps> {
+        Box::pin(FitbitClient::fetch_steps(self))
+    }
+
+    fn fetch_water(&self) -> ApiFuture<'_, f64> {
+        Box::pin(FitbitClient::fetch_water(self))
+    }
+
+    fn fetch_food_summary(&self) -> ApiFuture<'_, FoodSummary> {
+        Box::pin(FitbitClient::fetch_food_summary(self))
+    }
+
+    fn fetch_sleep(&self) -> ApiFuture<'_, SleepLogResponse> {
+        Box::pin(FitbitClient::fetch_sleep(self))
+    }
+
+    fn fetch_activity_summary(&self) -> ApiFuture<'_, ActivitySummary> {
+        Box::pin(FitbitClient::fetch_activity_summary(self))
+    }
+
+    fn fetch_activity_logs(&self, limit: u32) -> ApiFuture<'_, Vec<ActivityLog>> {
+        Box::pin(FitbitClient::fetch_activity_logs(self, limit))
+    }
+
+    fn fetch_activity_goals(&self) -> ApiFuture<'_, ActivityGoals> {
+        Box::pin(FitbitClient::fetch_activity_goals(self))
+    }
+
+    fn fetch_devices(&self) -> ApiFuture<'_, Vec<Device>> {
+        Box::pin(FitbitClient::fetch_devices(self))
+    }
+
+    fn fetch_leaderboard(&self) -> ApiFuture<'_, Vec<LeaderboardRank>> {
+        Box::pin(FitbitClient::fetch_leaderboard(self))
+    }
+
+    fn fetch_badges(&self) -> ApiFuture<'_, Vec<Badge>> {
+        Box::pin(FitbitClient::fetch_badges(self))
+    }
+
+    fn fetch_lifetime_stats(&self) -> ApiFuture<'_, LifetimeStats> {
+        Box::pin(FitbitClient::fetch_lifetime_stats(self))
+    }
+
+    fn fetch_heart_rate_intraday(&self, since: Option<NaiveTime>) -> ApiFuture<'_, Vec<HeartRateSample>> {
+        Box::pin(FitbitClient::fetch_heart_rate_intraday(self, since))
+    }
+
+    fn fetch_ecg_readings(&self, limit: u32) -> ApiFuture<'_, Vec<EcgReading>> {
+        Box::pin(FitbitClient::fetch_ecg_readings(self, limit))
+    }
+
+    fn fetch_irn_alerts(&self, limit: u32) -> ApiFuture<'_, Vec<IrnAlert>> {
+        Box::pin(FitbitClient::fetch_irn_alerts(self, limit))
+    }
+
+    fn fetch_cardio_score(&self) -> ApiFuture<'_, Option<CardioScoreDay>> {
+        Box::pin(FitbitClient::fetch_cardio_score(self))
+    }
+
+    fn fetch_breathing_rate(&self) -> ApiFuture<'_, Option<BreathingRateSummary>> {
+        Box::pin(FitbitClient::fetch_breathing_rate(self))
+    }
+
+    fn fetch_skin_temperature(&self) -> ApiFuture<'_, Option<SkinTemperatureDay>> {
+        Box::pin(FitbitClient::fetch_skin_temperature(self))
+    }
+
+    fn fetch_core_temperature(&self) -> ApiFuture<'_, Option<CoreTemperatureLog>> {
+        Box::pin(FitbitClient::fetch_core_temperature(self))
+    }
+
+    fn fetch_water_on(&self, date: NaiveDate) -> ApiFuture<'_, f64> {
+        Box::pin(FitbitClient::fetch_water_on(self, date))
+    }
+
+    fn fetch_food_summary_on(&self, date: NaiveDate) -> ApiFuture<'_, FoodSummary> {
+        Box::pin(FitbitClient::fetch_food_summary_on(self, date))
+    }
+
+    fn fetch_steps_last_week(&self) -> ApiFuture<'_, Vec<(NaiveDate, Steps)>> {
+        Box::pin(FitbitClient::fetch_steps_last_week(self))
+    }
+
+    fn fetch_steps_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Steps)>> {
+        Box::pin(FitbitClient::fetch_steps_range(self, start_date, end_date))
+    }
+
+    fn fetch_elevation_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Meters)>> {
+        Box::pin(FitbitClient::fetch_elevation_range(self, start_date, end_date))
+    }
+
+    fn fetch_floors_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, u64)>> {
+        Box::pin(FitbitClient::fetch_floors_range(self, start_date, end_date))
+    }
+
+    fn fetch_weight_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<WeightLog>> {
+        Box::pin(FitbitClient::fetch_weight_range(self, start_date, end_date))
+    }
+
+    fn fetch_resting_heart_rate_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Bpm)>> {
+        Box::pin(FitbitClient::fetch_resting_heart_rate_range(self, start_date, end_date))
+    }
+
+    fn fetch_hrv_range(&self, start_date: NaiveDate, end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, f64)>> {
+        Box::pin(FitbitClient::fetch_hrv_range(self, start_date, end_date))
+    }
+
+    fn fetch_user_id(&self) -> ApiFuture<'_, String> {
+        Box::pin(FitbitClient::fetch_user_id(self))
+    }
+
+    fn fetch_profile(&self) -> ApiFuture<'_, UserProfile> {
+        Box::pin(FitbitClient::fetch_profile(self))
+    }
+}
+
+
+/// Maps a transport error to a `FitbitError`.
+///
+/// Connection failures (including DNS lookup failures) are reported as `FitbitError::ConnectError` with the whole
+/// chain of causes, since reqwest's own message ("error sending request") doesn't tell what went wrong.
+fn map_transport_error(err: reqwest::Error) -> FitbitError {
+    if !err.is_connect() {
+        return FitbitError::HttpError(err);
+    }
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    FitbitError::ConnectError(message)
+}
+
+/// Reads the fixture of the endpoint in offline mode (see `FitbitClient::with_fixtures`): the file at the URL path,
+/// or else at the same path with the dates replaced by `today`. The query string is ignored.
+///
+/// # Errors
+///
+/// Returns `FitbitError::UnexpectedResponse` if there's no fixture for the endpoint, or if it's not JSON.
+fn read_fixture(fixtures_dir: &Path, endpoint: &str, url: &Url) -> Result<Value, FitbitError> {
+    let unexpected = |message: String| FitbitError::UnexpectedResponse { endpoint: endpoint.to_string(), path: String::new(), message };
+    let path = url.path().trim_start_matches('/');
+    let undated_path = path
+        .split('/')
+        .map(|segment| {
+            let (stem, extension) = segment.split_once('.').map_or((segment, None), |(stem, extension)| (stem, Some(extension)));
+            match (NaiveDate::parse_from_str(stem, "%Y-%m-%d"), extension) {
+                (Ok(_), Some(extension)) => format!("today.{}", extension),
+                (Ok(_), None) => "today".to_string(),
+                (Err(_), _) => segment.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let fixture = [path, undated_path.as_str()]
+        .iter()
+        .map(|path| fixtures_dir.join(path))
+        .find(|fixture| fixture.is_file())
+        .ok_or_else(|| unexpected(format!("No fixture at {}", fixtures_dir.join(path).display())))?;
+    debug!("Reading the fixture {}", fixture.display());
+    let body = std::fs::read(&fixture).map_err(|err| unexpected(format!("Cannot read the fixture {}: {}", fixture.display(), err)))?;
+    serde_json::from_slice(&body).map_err(|err| unexpected(format!("Invalid fixture {}: {}", fixture.display(), err)))
+}
 
-/// Refresh the access token periodically at the specified interval.
+/// Runs an operation of the token store on the blocking threads, since a network backend (Redis) blocks.
+///
+/// # Errors
+///
+/// Returns `FitbitError::StorageError` if the operation fails.
+async fn run_blocking<T: Send + 'static>(
+    storage: &Arc<dyn Storage>,
+    operation: impl FnOnce(&Arc<dyn Storage>) -> Result<T, StorageError> + Send + 'static,
+) -> Result<T, FitbitError> {
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || operation(&storage))
+        .await
+        .map_err(|err| FitbitError::StorageError(StorageError::Backend(err.to_string())))?
+        .map_err(FitbitError::StorageError)
+}
+
+/// Returns true for the response statuses that are worth retrying: 5xx and 429 (Too Many Requests).
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Returns the delay after which a rate limited request can be retried, from the `Retry-After` header (in seconds)
+/// or else the `Fitbit-Rate-Limit-Reset` header, i.e. the seconds until the hourly limit resets.
+/// FYI: https://dev.fitbit.com/build/reference/web-api/developer-guide/application-design/#Rate-Limits
+fn retry_after(headers: &HeaderMap) -> Duration {
+    ["retry-after", "fitbit-rate-limit-reset"]
+        .iter()
+        .find_map(|name| headers.get(*name)?.to_str().ok()?.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+/// Runs a request of a long-running job (a history dump or a backfill), waiting out the `Retry-After` of a rate
+/// limit instead of aborting the whole job, at most `MAX_RATE_LIMIT_RETRIES` times. The scrapes don't wait: their
+/// collectors keep their last values until the rate limit ends (see `FitbitMetrics::record_rate_limit`).
+///
+/// # Errors
+///
+/// Returns the error of the request, or `FitbitError::RateLimited` if it's still rate limited after the retries.
+pub async fn retry_rate_limited<T, F, Fut>(mut request: F) -> Result<T, FitbitError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, FitbitError>>,
+{
+    let mut retries = 0;
+    loop {
+        match request().await {
+            Err(FitbitError::RateLimited(retry_after)) if retries < MAX_RATE_LIMIT_RETRIES => {
+                retries += 1;
+                warn!("Rate limited by the Fitbit API, retrying in {} seconds ({}/{})", retry_after.as_secs(), retries, MAX_RATE_LIMIT_RETRIES);
+                tokio::time::sleep(retry_after).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+
+/// Refresh the access token periodically, shortly before it expires.
 ///
 /// This function is designed to run in an async loop, refreshing the access token
 /// before it expires to ensure continuous access to the Fitbit API.
+/// The next refresh is scheduled from the `expires_in` of the last token response (see
+/// `FitbitClient::next_refresh_delay`), and after `interval` while the expiry is unknown.
+/// A failed refresh emits `Event::AuthBroken`, since the collectors fail once the access token expires.
 ///
 /// # Arguments
 ///
 /// * `fitbit_client` - An `Arc<RwLock<FitbitClient>>` that provides access to the shared Fitbit client.
-/// * `interval` - A `Duration` that specifies the interval between token refresh attempts.
-pub async fn refresh_token_periodically(fitbit_client: Arc<RwLock<FitbitClient>>, interval: Duration) {
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` whose event bus receives the refresh failures.
+/// * `interval` - A `Duration` that specifies the interval between token refresh attempts when the expiry is unknown.
+pub async fn refresh_token_periodically(fitbit_client: Arc<RwLock<FitbitClient>>, fitbit_metrics: Arc<FitbitMetrics>, interval: Duration) {
     loop {
-        debug!("[refresh_token_periodically] The spawned refreshing task is sleeping for {} seconds before refreshing the access token...", interval.as_secs());
-        tokio::time::sleep(interval).await;
+        let delay = fitbit_client.read().await.next_refresh_delay(interval);
+        debug!("[refresh_token_periodically] The spawned refreshing task is sleeping for {} seconds before refreshing the access token...", delay.as_secs());
+        tokio::time::sleep(delay).await;
         debug!("[refresh_token_periodically] Sleep ended. Trying to aquire write lock on fitbit_client (Arc<RwLock<FitbitClient>>");
         let mut write_locked_client = fitbit_client.write().await;
         debug!("[refresh_token_periodically] Refreshing the access token by calling refresh_access_token()...");
         match write_locked_client.refresh_access_token().await {
             Ok(_) => debug!("[refresh_token_periodically] Access token successfully refreshed."),
-            Err(err) => error!("[refresh_token_periodically] Error refreshing access token: {:?}", err),
+            Err(err) => {
+                match err {
+                    FitbitError::InvalidGrant => error!("[refresh_token_periodically] The refresh token is invalid. Re-authorize the exporter (at /oauth2/authorize with --oauth-redirect-url)."),
+                    _ => error!("[refresh_token_periodically] Error refreshing access token: {:?}", err),
+                }
+                fitbit_metrics.events.emit(Event::AuthBroken { reason: err.to_string() });
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::storage::MemoryStorage;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    /// Starts a fake token endpoint rotating the refresh tokens like Fitbit: `refresh-<n>` is exchanged once for
+    /// `access-<n + 1>` and `refresh-<n + 1>`, starting from `refresh-1`. Any other refresh token gets `invalid_grant`.
+    /// The authorization code `code-1` is exchanged for `access-reauthorized` and `refresh-reauthorized`.
+    ///
+    /// # Returns
+    ///
+    /// The URL of the endpoint.
+    async fn start_fake_token_endpoint() -> String {
+        let generation = Arc::new(Mutex::new(1));
+        let make_svc = make_service_fn(move |_| {
+            let generation = generation.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let generation = generation.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let param = |name: &str| url::form_urlencoded::parse(&body).find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+                        let refresh_token = param("refresh_token");
+                        let mut generation = generation.lock().unwrap();
+                        let response = if param("code").as_deref() == Some("code-1") {
+                            let tokens = r#"{"access_token":"access-reauthorized","refresh_token":"refresh-reauthorized","token_type":"Bearer","expires_in":28800}"#;
+                            Response::builder().status(200).header("content-type", "application/json").body(Body::from(tokens))
+                        } else if refresh_token == Some(format!("refresh-{}", *generation)) {
+                            *generation += 1;
+                            let tokens = format!(
+                                r#"{{"access_token":"access-{0}","refresh_token":"refresh-{0}","token_type":"Bearer","expires_in":28800,"user_id":"ABCDEF"}}"#,
+                                *generation
+                            );
+                            Response::builder().status(200).header("content-type", "application/json").body(Body::from(tokens))
+                        } else {
+                            Response::builder()
+                                .status(400)
+                                .header("content-type", "application/json")
+                                .body(Body::from(r#"{"error":"invalid_grant","error_description":"Refresh token invalid"}"#))
+                        };
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let token_url = format!("http://{}/oauth2/token", server.local_addr());
+        tokio::spawn(server);
+        token_url
+    }
+
+    /// Starts a fake API server answering every request with the given status and JSON body.
+    ///
+    /// # Returns
+    ///
+    /// The URL of the server.
+    async fn start_fake_api(status: u16, body: &'static str) -> String {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::builder().status(status).header("content-type", "application/json").body(Body::from(body)).unwrap())
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let api_url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        api_url
+    }
+
+    /// Starts a fake Fitbit API answering the requests with `responses` in turn (status, `Retry-After`, body), the last
+    /// one answering the remaining requests. Returns its URL and the number of requests received.
+    async fn start_fake_api_sequence(responses: Vec<(u16, Option<&'static str>, &'static str)>) -> (String, Arc<Mutex<usize>>) {
+        let requests = Arc::new(Mutex::new(0));
+        let make_svc = make_service_fn({
+            let requests = requests.clone();
+            move |_| {
+                let requests = requests.clone();
+                let responses = responses.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                        let mut requests = requests.lock().unwrap();
+                        let (status, retry_after, body) = responses[(*requests).min(responses.len() - 1)];
+                        *requests += 1;
+                        let mut response = Response::builder().status(status).header("content-type", "application/json");
+                        if let Some(retry_after) = retry_after {
+                            response = response.header("retry-after", retry_after);
+                        }
+                        let response = response.body(Body::from(body)).unwrap();
+                        async move { Ok::<_, Infallible>(response) }
+                    }))
+                }
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let api_url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (api_url, requests)
+    }
+
+    /// Storage whose writes fail, e.g. a full disk.
+    #[derive(Debug)]
+    struct ReadOnlyStorage;
+
+    impl Storage for ReadOnlyStorage {
+        fn get(&self, _key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+            Ok(None)
+        }
+
+        fn put(&self, _key: &str, _value: &[u8]) -> Result<(), StorageError> {
+            Err(StorageError::Backend("read-only".to_string()))
+        }
+
+        fn delete(&self, _key: &str) -> Result<(), StorageError> {
+            Ok(())
+        }
+    }
+
+    fn stored_refresh_token(storage: &Arc<dyn Storage>) -> Option<String> {
+        let tokens = storage.get_json::<StoredTokens>(TOKENS_KEY).unwrap()?;
+        tokens.refresh_token.map(|token| token.to_string())
+    }
+
+    #[test]
+    fn refresh_of_an_initial_jwt_is_scheduled_from_its_expiry() {
+        let expires_at = Utc::now().timestamp() + 3 * 60 * 60;
+        let payload = general_purpose::URL_SAFE_NO_PAD.encode(format!(r#"{{"aud":"23ABCD","sub":"ABCDEF","exp":{}}}"#, expires_at));
+        let access_token = format!("eyJhbGciOiJIUzI1NiJ9.{}.signature", payload);
+        let fitbit_client = FitbitClient::new("client", "secret", &Some("refresh-1".to_string()), &access_token);
+
+        let delay = fitbit_client.next_refresh_delay(Duration::from_secs(7 * 60 * 60));
+        assert!(delay > Duration::from_secs(3 * 60 * 60) - TOKEN_REFRESH_MARGIN - Duration::from_secs(5) && delay <= Duration::from_secs(3 * 60 * 60) - TOKEN_REFRESH_MARGIN);
+        let labels = TokenLabels { client_id: "client".to_string() };
+        assert_eq!(fitbit_client.token_metrics().expiry_timestamp_seconds.get_or_create(&labels).get(), expires_at);
+        assert_eq!(jwt_expiry("access-1"), None);
+    }
+
+    #[tokio::test]
+    async fn refreshed_tokens_are_rotated_and_stored() {
+        let token_url = start_fake_token_endpoint().await;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut fitbit_client = FitbitClient::new("client", "secret", &Some("refresh-1".to_string()), "access-1")
+            .with_token_url(&token_url)
+            .with_token_store(storage.clone())
+            .unwrap();
+
+        let token_metrics = fitbit_client.token_metrics();
+        assert_eq!(fitbit_client.next_refresh_delay(Duration::from_secs(7 * 60 * 60)), Duration::from_secs(7 * 60 * 60));
+        // The expiry of the initial token, which isn't a JWT, is unknown: no value rather than 0
+        let mut txt = String::new();
+        let mut registry = Registry::default();
+        token_metrics.register(&mut registry);
+        prometheus_client::encoding::text::encode(&mut txt, &registry).unwrap();
+        assert!(!txt.contains("fitbit_token_expiry_timestamp_seconds{"), "{}", txt);
+
+        fitbit_client.refresh_access_token().await.unwrap();
+        assert_eq!(fitbit_client.access_token.as_str(), "access-2");
+        assert_eq!(stored_refresh_token(&storage).as_deref(), Some("refresh-2"));
+        // The next refresh is scheduled from the `expires_in` of 8 hours
+        let delay = fitbit_client.next_refresh_delay(Duration::from_secs(7 * 60 * 60));
+        assert!(delay > Duration::from_secs(28800) - TOKEN_REFRESH_MARGIN - Duration::from_secs(5) && delay <= Duration::from_secs(28800) - TOKEN_REFRESH_MARGIN);
+        let expiry = token_metrics.expiry_timestamp_seconds.get_or_create(&TokenLabels { client_id: "client".to_string() }).get();
+        assert!((expiry - (Utc::now().timestamp() + 28800)).abs() <= 5);
+
+        // The next refresh uses the rotated refresh token
+        fitbit_client.refresh_access_token().await.unwrap();
+        assert_eq!(stored_refresh_token(&storage).as_deref(), Some("refresh-3"));
+
+        // After a restart, the stored tokens replace the initial ones, which were already exchanged
+        let mut restarted_client = FitbitClient::new("client", "secret", &Some("refresh-1".to_string()), "access-1")
+            .with_token_url(&token_url)
+            .with_token_store(storage.clone())
+            .unwrap();
+        assert_eq!(restarted_client.access_token.as_str(), "access-3");
+        assert!(restarted_client.access_token_expires_at.is_some());
+        restarted_client.refresh_access_token().await.unwrap();
+        assert_eq!(stored_refresh_token(&storage).as_deref(), Some("refresh-4"));
+    }
+
+    #[tokio::test]
+    async fn replicas_sharing_the_token_store_exchange_the_refresh_token_once() {
+        let token_url = start_fake_token_endpoint().await;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let replica = || {
+            FitbitClient::new("client", "secret", &Some("refresh-1".to_string()), "access-1")
+                .with_token_url(&token_url)
+                .with_token_store(storage.clone())
+                .unwrap()
+        };
+        let (mut first, mut second) = (replica(), replica());
+
+        first.refresh_access_token().await.unwrap();
+        // `refresh-1` was exchanged by the first replica, so the second one adopts its tokens instead of getting
+        // `invalid_grant`
+        second.refresh_access_token().await.unwrap();
+        assert_eq!(second.access_token.as_str(), "access-2");
+        assert_eq!(stored_refresh_token(&storage).as_deref(), Some("refresh-2"));
+
+        second.refresh_access_token().await.unwrap();
+        assert_eq!(second.access_token.as_str(), "access-3");
+        first.refresh_access_token().await.unwrap();
+        assert_eq!(first.access_token.as_str(), "access-3");
+    }
+
+    #[tokio::test]
+    async fn invalid_grant_leaves_the_tokens_untouched() {
+        let token_url = start_fake_token_endpoint().await;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut fitbit_client = FitbitClient::new("client", "secret", &Some("revoked".to_string()), "access-1")
+            .with_token_url(&token_url)
+            .with_token_store(storage.clone())
+            .unwrap();
+
+        assert!(matches!(fitbit_client.refresh_access_token().await, Err(FitbitError::InvalidGrant)));
+        assert_eq!(fitbit_client.access_token.as_str(), "access-1");
+        assert_eq!(stored_refresh_token(&storage), None);
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_refreshed_at_startup_even_if_the_new_tokens_cannot_be_persisted() {
+        let token_url = start_fake_token_endpoint().await;
+        let api_url = start_fake_api(401, r#"{"errors":[{"errorType":"expired_token","message":"Access token expired"}]}"#).await;
+        let mut fitbit_client = FitbitClient::new("client", "secret", &Some("refresh-1".to_string()), "access-1")
+            .with_token_url(&token_url)
+            .with_api_url(&api_url)
+            .with_token_store(Arc::new(ReadOnlyStorage))
+            .unwrap();
+
+        // The exchanged refresh token is gone, so the exporter keeps running with the new one
+        fitbit_client.ensure_valid_access_token().await.unwrap();
+        assert_eq!(fitbit_client.access_token.as_str(), "access-2");
+        assert_eq!(fitbit_client.refresh_token.as_deref().map(String::as_str), Some("refresh-2"));
+    }
+
+    const EXPIRED_TOKEN: &str = r#"{"errors":[{"errorType":"expired_token","message":"Access token expired"}]}"#;
+    const INVALID_TOKEN: &str = r#"{"errors":[{"errorType":"invalid_token","message":"Access token invalid"}]}"#;
+    const INSUFFICIENT_SCOPE: &str = r#"{"errors":[{"errorType":"insufficient_scope","message":"This application does not have permission to access profile data"}]}"#;
+
+    #[tokio::test]
+    async fn unauthorized_responses_are_told_apart() {
+        let fetch_user_id = |body: &'static str| async move {
+            let api_url = start_fake_api(401, body).await;
+            FitbitClient::new("client", "secret", &None, "access-1").with_api_url(&api_url).fetch_user_id().await
+        };
+
+        assert!(matches!(fetch_user_id(EXPIRED_TOKEN).await, Err(FitbitError::AccessTokenExpired)));
+        assert!(matches!(fetch_user_id(INVALID_TOKEN).await, Err(FitbitError::InvalidAccessToken(message)) if message == "Access token invalid"));
+        assert!(matches!(fetch_user_id(INSUFFICIENT_SCOPE).await, Err(FitbitError::InsufficientScope { .. })));
+        assert!(matches!(fetch_user_id(r#"{}"#).await, Err(FitbitError::AccessTokenExpired)));
+    }
+
+    #[tokio::test]
+    async fn only_unusable_tokens_are_refreshed_at_startup() {
+        let token_url = start_fake_token_endpoint().await;
+        let client = |refresh_token: Option<&str>, api_url: &str| {
+            FitbitClient::new("client", "secret", &refresh_token.map(str::to_string), "access-1")
+                .with_token_url(&token_url)
+                .with_api_url(api_url)
+                .with_token_store(Arc::new(MemoryStorage::default()))
+                .unwrap()
+        };
+
+        // A revoked access token is replaced, as an expired one
+        let mut fitbit_client = client(Some("refresh-1"), &start_fake_api(401, INVALID_TOKEN).await);
+        fitbit_client.ensure_valid_access_token().await.unwrap();
+        assert_eq!(fitbit_client.access_token.as_str(), "access-2");
+
+        // A missing scope isn't fixed by a refresh
+        let mut fitbit_client = client(Some("refresh-2"), &start_fake_api(401, INSUFFICIENT_SCOPE).await);
+        fitbit_client.ensure_valid_access_token().await.unwrap();
+        assert_eq!(fitbit_client.access_token.as_str(), "access-1");
+
+        // Without a valid refresh token, the startup fails
+        let expired_api_url = start_fake_api(401, EXPIRED_TOKEN).await;
+        assert!(matches!(client(None, &expired_api_url).ensure_valid_access_token().await, Err(FitbitError::AccessTokenExpired)));
+        assert!(matches!(client(Some("revoked"), &expired_api_url).ensure_valid_access_token().await, Err(FitbitError::InvalidGrant)));
+    }
+
+    #[tokio::test]
+    async fn reauthorization_replaces_the_revoked_tokens() {
+        let token_url = start_fake_token_endpoint().await;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut fitbit_client = FitbitClient::new("client", "secret", &Some("revoked".to_string()), "access-1")
+            .with_token_url(&token_url)
+            .with_token_store(storage.clone())
+            .unwrap();
+        assert!(matches!(fitbit_client.refresh_access_token().await, Err(FitbitError::InvalidGrant)));
+
+        let redirect_url = "http://localhost:8080/oauth2/callback";
+        let (_, _, pkce_verifier) = fitbit_client.authorization_url(redirect_url, &["activity".to_string()]).unwrap();
+        fitbit_client.exchange_authorization_code("code-1", pkce_verifier, redirect_url).await.unwrap();
+        assert_eq!(fitbit_client.access_token.as_str(), "access-reauthorized");
+        assert_eq!(stored_refresh_token(&storage).as_deref(), Some("refresh-reauthorized"));
+
+        let (_, _, pkce_verifier) = fitbit_client.authorization_url(redirect_url, &[]).unwrap();
+        assert!(matches!(fitbit_client.exchange_authorization_code("used", pkce_verifier, redirect_url).await, Err(FitbitError::InvalidGrant)));
+    }
+
+    #[tokio::test]
+    async fn offline_mode_reads_the_fixtures() {
+        let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let fitbit_client = FitbitClient::new("", "", &None, "").with_fixtures(&fixtures_dir);
+
+        assert_eq!(fitbit_client.fetch_steps().await.unwrap(), Steps(8123));
+        assert_eq!(fitbit_client.fetch_leaderboard().await.unwrap().len(), 2);
+        assert_eq!(fitbit_client.fetch_badges().await.unwrap().len(), 2);
+        assert_eq!(fitbit_client.fetch_lifetime_stats().await.unwrap().lifetime.steps, 7894521);
+        assert_eq!(fitbit_client.fetch_heart_rate_intraday(None).await.unwrap().len(), 5);
+        // The dates of the URL fall back to the fixture written with `today`
+        assert_eq!(fitbit_client.fetch_water_on(NaiveDate::from_ymd_opt(2023, 3, 4).unwrap()).await.unwrap(), 1250.0);
+        assert!(matches!(
+            fitbit_client.fetch_json::<Value>("https://api.fitbit.com/1/user/-/spo2/date/today.json").await,
+            Err(FitbitError::UnexpectedResponse { .. })
+        ));
+        // 46 days are fetched in 2 chunks
+        let weight_logs = fitbit_client.fetch_weight_range(NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(), NaiveDate::from_ymd_opt(2024, 2, 15).unwrap()).await.unwrap();
+        assert_eq!(weight_logs.iter().map(|log| log.log_id).collect::<Vec<_>>(), vec![1704094270000, 1704180100000, 1707117855000]);
+    }
+
+    #[tokio::test]
+    async fn offline_mode_has_a_fixture_for_every_collector() {
+        let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let fitbit_client = FitbitClient::new("", "", &None, "").with_fixtures(&fixtures_dir);
+        let today = Utc::now().date_naive();
+
+        assert_eq!(fitbit_client.fetch_heart_rate().await.unwrap().resting_heart_rate, Some(Bpm(58.0)));
+        assert_eq!(fitbit_client.fetch_resting_heart_rate_range(today, today).await.unwrap().len(), 2);
+        assert_eq!(fitbit_client.fetch_hrv_range(today, today).await.unwrap().len(), 3);
+        assert!(fitbit_client.fetch_cardio_score().await.unwrap().is_some());
+        assert_eq!(fitbit_client.fetch_breathing_rate().await.unwrap().unwrap().by_stage().len(), 4);
+        assert!(fitbit_client.fetch_skin_temperature().await.unwrap().is_some());
+        assert!(fitbit_client.fetch_core_temperature().await.unwrap().is_some());
+        assert_eq!(fitbit_client.fetch_devices().await.unwrap().len(), 1);
+        assert_eq!(fitbit_client.fetch_activity_goals().await.unwrap().steps, Some(Steps(10000)));
+        assert_eq!(fitbit_client.fetch_activity_summary().await.unwrap().steps, Steps(8123));
+        assert_eq!(fitbit_client.fetch_activity_logs(10).await.unwrap().len(), 2);
+        assert_eq!(fitbit_client.fetch_ecg_readings(10).await.unwrap().len(), 1);
+        assert!(fitbit_client.fetch_irn_alerts(10).await.unwrap().is_empty());
+        assert_eq!(fitbit_client.fetch_weight_range(today, today).await.unwrap().len(), 1);
+        assert_eq!(fitbit_client.fetch_steps_last_week().await.unwrap().len(), 7);
+        assert_eq!(fitbit_client.fetch_steps_range(today, today).await.unwrap().len(), 3);
+        assert_eq!(fitbit_client.fetch_elevation_range(today, today).await.unwrap().len(), 3);
+        assert_eq!(fitbit_client.fetch_floors_range(today, today).await.unwrap().len(), 3);
+        assert!(fitbit_client.fetch_sleep().await.unwrap().main_sleep().is_some());
+        assert!(fitbit_client.fetch_food_summary().await.unwrap().calories > 0.0);
+        assert!(fitbit_client.fetch_profile().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_retried_after_their_retry_after() {
+        const TOO_MANY_REQUESTS: &str = r#"{"errors":[{"errorType":"request","message":"Too Many Requests"}]}"#;
+        const STEPS: &str = r#"{"activities-steps":[{"dateTime":"2023-03-04","value":"8123"}]}"#;
+        let (api_url, requests) = start_fake_api_sequence(vec![(429, Some("1"), TOO_MANY_REQUESTS), (200, None, STEPS)]).await;
+        let fitbit_client = FitbitClient::new("client", "secret", &None, "access-1").with_api_url(&api_url);
+
+        let started = Instant::now();
+        assert_eq!(fitbit_client.fetch_steps().await.unwrap(), Steps(8123));
+        assert_eq!(*requests.lock().unwrap(), 2);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // A rate limit longer than the maximum backoff is not waited out
+        let (api_url, requests) = start_fake_api_sequence(vec![(429, Some("3600"), TOO_MANY_REQUESTS), (200, None, STEPS)]).await;
+        let fitbit_client = FitbitClient::new("client", "secret", &None, "access-1").with_api_url(&api_url);
+        assert!(matches!(fitbit_client.fetch_steps().await, Err(FitbitError::RateLimited(retry_after)) if retry_after == Duration::from_secs(3600)));
+        assert_eq!(*requests.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_primary_fails_over_to_the_secondary() {
+        let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let api_url = start_fake_api(429, r#"{"errors":[{"errorType":"request","message":"Too Many Requests"}]}"#).await;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let event_log = Arc::new(EventLog::default());
+        let secondary = FitbitClient::new("secondary", "secret", &None, "access-2")
+            .with_tokens_key(SECONDARY_TOKENS_KEY)
+            .with_token_store(storage.clone())
+            .unwrap()
+            .with_fixtures(&fixtures_dir);
+        let fitbit_client = FitbitClient::new("primary", "secret", &None, "access-1")
+            .with_api_url(&api_url)
+            .with_secondary(secondary)
+            .with_event_log(event_log.clone());
+        let failovers = || event_log.recent().iter().filter(|entry| entry.event == "failover").count();
+
+        // The 429 of the primary application is answered by the secondary one
+        assert_eq!(fitbit_client.fetch_steps().await.unwrap(), Steps(8123));
+        assert_eq!(failovers(), 1);
+        // Until the quota of the primary application resets, without calling it
+        assert_eq!(fitbit_client.fetch_steps().await.unwrap(), Steps(8123));
+        assert_eq!(failovers(), 1);
+        *fitbit_client.rate_limited_until.lock().unwrap() = Some(Instant::now());
+        assert_eq!(fitbit_client.fetch_steps().await.unwrap(), Steps(8123));
+        assert_eq!(failovers(), 2);
+    }
+
+    #[test]
+    fn retry_after_prefers_the_retry_after_header() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            pairs.iter().map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap())).collect::<HeaderMap>()
+        };
+        assert_eq!(retry_after(&headers(&[("retry-after", "120"), ("fitbit-rate-limit-reset", "1800")])), Duration::from_secs(120));
+        assert_eq!(retry_after(&headers(&[("fitbit-rate-limit-reset", " 1800 ")])), Duration::from_secs(1800));
+        // An HTTP date isn't supported, and falls back to the default like a missing header
+        assert_eq!(retry_after(&headers(&[("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")])), DEFAULT_RETRY_AFTER);
+        assert_eq!(retry_after(&HeaderMap::new()), DEFAULT_RETRY_AFTER);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_requests_are_retried_after_the_delay() {
+        let attempts = Mutex::new(0);
+        let started = tokio::time::Instant::now();
+        let steps = retry_rate_limited(|| async {
+            *attempts.lock().unwrap() += 1;
+            match *attempts.lock().unwrap() {
+                1 | 2 => Err(FitbitError::RateLimited(Duration::from_secs(600))),
+                _ => Ok(Steps(8123)),
+            }
+        })
+        .await;
+        assert_eq!(steps.unwrap(), Steps(8123));
+        assert_eq!(started.elapsed(), Duration::from_secs(1200));
+
+        // Until it gives up
+        *attempts.lock().unwrap() = 0;
+        let steps = retry_rate_limited(|| async {
+            *attempts.lock().unwrap() += 1;
+            Err::<Steps, _>(FitbitError::RateLimited(Duration::from_secs(600)))
+        })
+        .await;
+        assert!(matches!(steps, Err(FitbitError::RateLimited(_))));
+        assert_eq!(*attempts.lock().unwrap(), MAX_RATE_LIMIT_RETRIES + 1);
+    }
+}
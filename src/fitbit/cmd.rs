@@ -1,6 +1,36 @@
-use chrono::NaiveDate;
+use chrono::{FixedOffset, NaiveDate};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
+use url::Url;
+use zeroize::Zeroizing;
+
+use crate::fitbit::backfill::BackfillOptions;
+use crate::fitbit::client::HttpOptions;
+use crate::fitbit::collector::ErrorBudget;
+use crate::fitbit::events::EventSinkConfig;
+use crate::fitbit::exposition::{ConstLabel, ExpositionRelabel, MetricPrefix};
+use crate::fitbit::graphite::GraphiteOptions;
+use crate::fitbit::logging::LogFormat;
+use crate::fitbit::metrics::MetricsOptions;
+use crate::fitbit::pushgateway::PushgatewayOptions;
+use crate::fitbit::profile::{ScrapeProfile, ScrapeProfiles, COLLECTORS, OPT_IN_COLLECTORS};
+use crate::fitbit::recovery::RecoveryWeights;
+use crate::fitbit::query::{ArchiveQuery, QueryAggregation};
+use crate::fitbit::report::{ReportFormat, ReportWeek};
+use crate::fitbit::schedule::PollSchedule;
+use crate::fitbit::statsd::StatsdOptions;
+use crate::fitbit::storage::{SampleStoreConfig, StorageConfig};
+use crate::fitbit::server::{BearerTokenCommand, MetricsAuth, ServerOptions, TlsOptions, LISTEN_ADDR};
+use crate::fitbit::status::StartupSummary;
+use crate::fitbit::webhook::WebhookOptions;
+
+/// Maximum size of a Fitbit API response with `--lite`.
+const LITE_MAX_RESPONSE_SIZE: usize = 2 * 1024 * 1024;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "fitbit_exporter")]
@@ -17,7 +47,828 @@ pub struct Args {
     #[structopt(short = "e", long = "end-date", requires = "dump-historical-metrics")]
     pub end_date: Option<NaiveDate>,
 
-    /// Output file path for historical data export. Defaults to "fitbit_historical_metrics.prom"
+    /// Output file path for historical data export. Defaults to "fitbit_historical_metrics.<format>"
     #[structopt(short = "o", long = "output-file", parse(from_os_str), requires = "dump-historical-metrics")]
     pub output_file: Option<PathBuf>,
+
+    /// Continue an interrupted historical dump (e.g. by a rate limit) from its checkpoint file, instead of starting
+    /// over. The date range of the interrupted dump is used.
+    #[structopt(long = "resume", requires = "dump-historical-metrics")]
+    pub resume: bool,
+
+    /// Checkpoint file of the historical dump, recording the chunks fetched so far. Defaults to the output file with
+    /// a ".checkpoint.json" suffix. Deleted once the dump is written.
+    #[structopt(long = "checkpoint-file", parse(from_os_str), requires = "dump-historical-metrics")]
+    pub checkpoint_file: Option<PathBuf>,
+
+    /// Output format for historical data export: "prom", "csv", "json", "apple-health-xml", "google-fit-csv"
+    /// or "parquet" (`parquet` feature).
+    // Not `requires = "dump-historical-metrics"`, which its default value would always trigger
+    #[structopt(short = "f", long = "format", default_value = "prom")]
+    pub format: OutputFormat,
+
+    /// Run one of the offline commands instead of the server.
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+
+    /// Offset of the user's timezone from UTC, e.g. "+09:00", in which the days of the daily values start.
+    /// Defaults to the one of the Fitbit profile, or to the one of the host for the offline commands.
+    #[structopt(long = "utc-offset", env = "FITBIT_UTC_OFFSET")]
+    pub utc_offset: Option<FixedOffset>,
+
+    /// Time of day used to stamp historical daily values: "midnight", "noon" or "end-of-day" (23:59:59), in the
+    /// timezone of the user's Fitbit profile. Applies to both the historical dump and the /history endpoint.
+    #[structopt(long = "timestamp-position", env = "FITBIT_TIMESTAMP_POSITION", default_value = "midnight")]
+    pub timestamp_position: TimestampPosition,
+
+    /// Handling of the placeholder days of the historical date ranges, i.e. the zero-filled days Fitbit returns before
+    /// the first day with steps (e.g. before the device was set up): "keep" them, "skip" them, or expose them as
+    /// "estimated" (`fitbit_steps_estimated`, and the `estimated=true` label in the CSV/JSON dumps).
+    /// Applies to the historical dump, the /history endpoint and the backfill.
+    #[structopt(long = "placeholder-days", env = "FITBIT_PLACEHOLDER_DAYS", default_value = "keep")]
+    pub placeholder_days: PlaceholderDays,
+
+    /// Format of the log lines: "text", or "json" (one object per line, with fields such as the endpoint, status,
+    /// duration and user of the requests), e.g. to ship the logs of a container to Loki or ELK.
+    #[structopt(long = "log-format", env = "FITBIT_LOG_FORMAT", default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Record the last traces of the requests (to /metrics and the other endpoints, and to the Fitbit API) in memory,
+    /// and serve them at /debug/traces, e.g. to find which requests to the Fitbit API slow down the scrapes.
+    #[structopt(long = "debug-traces")]
+    pub debug_traces: bool,
+
+    /// Connect timeout in seconds for the requests to the Fitbit API.
+    #[structopt(long = "http-connect-timeout", env = "FITBIT_HTTP_CONNECT_TIMEOUT", default_value = "5")]
+    pub http_connect_timeout: u64,
+
+    /// Total timeout in seconds for each request to the Fitbit API, including reading the response body.
+    #[structopt(long = "http-request-timeout", env = "FITBIT_HTTP_REQUEST_TIMEOUT", default_value = "30")]
+    pub http_request_timeout: u64,
+
+    /// Maximum number of retries for transient failures (timeouts, 5xx and 429 responses) when calling the Fitbit
+    /// API. A 429 (rate limited) response is retried after its Retry-After, unless that's longer than the maximum
+    /// backoff (30 seconds): the collectors pause until then instead.
+    #[structopt(long = "http-max-retries", env = "FITBIT_HTTP_MAX_RETRIES", default_value = "3")]
+    pub http_max_retries: u32,
+
+    /// Maximum number of idle connections kept in the pool for the Fitbit API.
+    #[structopt(long = "http-pool-max-idle", env = "FITBIT_HTTP_POOL_MAX_IDLE", default_value = "8")]
+    pub http_pool_max_idle: usize,
+
+    /// Seconds an idle pooled connection is kept alive before being closed. 0 keeps idle connections indefinitely.
+    #[structopt(long = "http-pool-idle-timeout", env = "FITBIT_HTTP_POOL_IDLE_TIMEOUT", default_value = "90")]
+    pub http_pool_idle_timeout: u64,
+
+    /// Use HTTP/2 for the Fitbit API without negotiating it first (prior knowledge).
+    #[structopt(long = "http2")]
+    pub http2: bool,
+
+    /// Static DNS overrides for outbound calls, as comma separated `host=ip` pairs (e.g. "api.fitbit.com=203.0.113.10").
+    #[structopt(long = "dns-override", env = "FITBIT_DNS_OVERRIDE", use_delimiter = true)]
+    pub dns_overrides: Vec<DnsOverride>,
+
+    /// Seconds to cache DNS lookups for outbound calls. When a lookup fails, the last known addresses are used.
+    /// 0 resolves on every new connection with the system resolver.
+    #[structopt(long = "dns-cache-ttl", env = "FITBIT_DNS_CACHE_TTL", default_value = "0")]
+    pub dns_cache_ttl: u64,
+
+    /// Maximum size in bytes of a Fitbit API response. Larger responses fail the call instead of exhausting the
+    /// memory. 0 doesn't limit the size.
+    #[structopt(long = "http-max-response-size", env = "FITBIT_HTTP_MAX_RESPONSE_SIZE", default_value = "10485760")]
+    pub http_max_response_size: usize,
+
+    /// Offline mode: read the responses of the Fitbit API from the canned JSON fixtures of the given directory (see
+    /// `fixtures/`) instead of calling it. No credentials are needed, e.g. to develop dashboards or run integration
+    /// tests. The fixture of an endpoint is the file at its URL path, with its dates written as `today`.
+    #[structopt(long = "offline", env = "FITBIT_OFFLINE")]
+    pub offline: Option<PathBuf>,
+
+    /// File in which the bodies of the Fitbit API responses are logged, with the tokens redacted, e.g. to diagnose
+    /// parse failures. The log follows the level set at runtime through `PUT /admin/loglevel?level=debug`, which
+    /// requires --metrics-bearer-token or --metrics-username.
+    #[structopt(long = "http-body-log", env = "FITBIT_HTTP_BODY_LOG")]
+    pub http_body_log: Option<PathBuf>,
+
+    /// Initial level of --http-body-log. The bodies are logged at the `debug` and `trace` levels.
+    #[structopt(long = "http-body-log-level", env = "FITBIT_HTTP_BODY_LOG_LEVEL", default_value = "debug")]
+    pub http_body_log_level: tracing::level_filters::LevelFilter,
+
+    /// Username required (with --metrics-password) to access the HTTP endpoints via Basic auth.
+    #[structopt(long = "metrics-username", env = "FITBIT_METRICS_USERNAME", requires = "metrics-password")]
+    pub metrics_username: Option<String>,
+
+    /// Password required (with --metrics-username) to access the HTTP endpoints via Basic auth.
+    #[structopt(long = "metrics-password", env = "FITBIT_METRICS_PASSWORD", hide_env_values = true, requires = "metrics-username")]
+    pub metrics_password: Option<String>,
+
+    /// Static bearer token required to access the HTTP endpoints (`Authorization: Bearer <token>`).
+    #[structopt(long = "metrics-bearer-token", env = "FITBIT_METRICS_BEARER_TOKEN", hide_env_values = true)]
+    pub metrics_bearer_token: Option<String>,
+
+    /// Shell command printing the bearer token required to access the HTTP endpoints, e.g.
+    /// "vault kv get -field=token secret/fitbit_exporter", instead of --metrics-bearer-token. Run at startup and
+    /// every --metrics-bearer-token-refresh-interval seconds, and killed if it runs for more than 30 seconds.
+    #[structopt(long = "metrics-bearer-token-command", env = "FITBIT_METRICS_BEARER_TOKEN_COMMAND", conflicts_with = "metrics-bearer-token")]
+    pub metrics_bearer_token_command: Option<String>,
+
+    /// Seconds between two runs of --metrics-bearer-token-command, to pick up a rotated token.
+    #[structopt(long = "metrics-bearer-token-refresh-interval", env = "FITBIT_METRICS_BEARER_TOKEN_REFRESH_INTERVAL", default_value = "300")]
+    pub metrics_bearer_token_refresh_interval: u64,
+
+    /// Prefix of the served metric names, replacing "fitbit_", e.g. "health_" for `health_steps`, so that the metrics
+    /// of several exporters scraped by the same Prometheus don't collide. Applies to /metrics, /history, the
+    /// Pushgateway, Graphite, StatsD and the events; the archived samples keep the "fitbit_" names.
+    #[structopt(long = "metric-prefix", env = "FITBIT_METRIC_PREFIX", default_value = "fitbit_")]
+    pub metric_prefix: MetricPrefix,
+
+    /// Constant labels added to every served sample, as `name=value` separated by `,`, e.g. "instance=home,person=alice",
+    /// to tell the exporters apart without relabeling rules. Applies to /metrics, /history, the Pushgateway, Graphite,
+    /// StatsD and the events. The names of the labels of the metrics (e.g. "date" or "source") are rejected.
+    #[structopt(long = "const-label", env = "FITBIT_CONST_LABELS", value_delimiter = ",")]
+    pub const_labels: Vec<ConstLabel>,
+
+    /// Redirect URL registered for the application and served by the exporter, e.g.
+    /// "http://localhost:8080/oauth2/callback". Enables the re-authorization at /oauth2/authorize, e.g. after the
+    /// refresh token got `invalid_grant`, instead of setting new tokens and restarting. Requires the credentials of the
+    /// endpoints (--metrics-bearer-token or --metrics-username), which /oauth2/authorize asks for.
+    #[structopt(long = "oauth-redirect-url", env = "FITBIT_OAUTH_REDIRECT_URL")]
+    pub oauth_redirect_url: Option<String>,
+
+    /// Scopes requested by the re-authorization, separated by `,`. Defaults to those of all the collectors.
+    /// FYI: https://dev.fitbit.com/build/reference/web-api/developer-guide/application-design/#Scopes
+    #[structopt(
+        long = "oauth-scopes",
+        env = "FITBIT_OAUTH_SCOPES",
+        use_delimiter = true,
+        default_value = "activity,cardio_fitness,electrocardiogram,heartrate,irregular_rhythm_notifications,location,nutrition,oxygen_saturation,profile,respiratory_rate,settings,sleep,social,temperature,weight"
+    )]
+    pub oauth_scopes: Vec<String>,
+
+    /// Path to the PEM encoded certificate chain to serve HTTPS with (requires --tls-key).
+    #[structopt(long = "tls-cert", env = "FITBIT_TLS_CERT", parse(from_os_str), requires = "tls-key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key to serve HTTPS with (requires --tls-cert).
+    #[structopt(long = "tls-key", env = "FITBIT_TLS_KEY", parse(from_os_str), requires = "tls-cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Number of consecutive failed scrapes after which a collector (e.g. sleep) is disabled. 0 never disables collectors.
+    #[structopt(long = "collector-max-failures", env = "FITBIT_COLLECTOR_MAX_FAILURES", default_value = "5")]
+    pub collector_max_failures: u32,
+
+    /// Seconds a collector stays disabled after running out of its error budget.
+    #[structopt(long = "collector-cooldown", env = "FITBIT_COLLECTOR_COOLDOWN", default_value = "3600")]
+    pub collector_cooldown: u64,
+
+    /// Low-memory preset for Raspberry Pi Zero-class hardware: the historical points of /history and the backfill
+    /// are dropped once served, /metrics is streamed instead of encoded whole in memory, at most one idle connection
+    /// is kept to the Fitbit API, and the responses of the Fitbit API are limited to 2 MiB.
+    #[structopt(long = "lite")]
+    pub lite: bool,
+
+    /// Check the exposition of /metrics, /history and the .prom dumps (metric and label names, escaping, duplicate
+    /// samples...) before serving or writing it, and fail with the problems found instead of serving output that
+    /// Prometheus would reject. Disables the streaming of `--lite`, since the whole exposition is checked first.
+    #[structopt(long = "strict-exposition")]
+    pub strict_exposition: bool,
+
+    /// Reset the daily metrics (steps, water, food, activity) to 0 at midnight in the user's timezone (of their
+    /// Fitbit profile), and expose the final steps of the previous day with its timestamp, instead of serving
+    /// yesterday's totals until the tracker syncs again.
+    #[structopt(long = "midnight-rollover")]
+    pub midnight_rollover: bool,
+
+    /// Weights of the components of `fitbit_recovery_score`, e.g. "rhr=0.4,hrv=0.4,sleep=0.2": the deviations of the
+    /// resting heart rate and the HRV from their 30-day baselines, and the sleep efficiency. Not computed by default.
+    #[structopt(long = "recovery-weights", env = "FITBIT_RECOVERY_WEIGHTS")]
+    pub recovery_weights: Option<RecoveryWeights>,
+
+    /// Whether the naps count in the sleep totals: include (the totals of all the sleep logs), exclude (the totals of
+    /// the main sleep, without exporting the naps) or separate (the totals of the main sleep, and the naps in their
+    /// own families).
+    #[structopt(long = "sleep-naps", env = "FITBIT_SLEEP_NAPS", default_value = "include")]
+    pub sleep_naps: SleepNaps,
+
+    /// Also label the workout metrics with the raw activity name (e.g. `activity_name="Course"`), which depends on
+    /// the locale of the account, next to the canonical `activity_type` (e.g. "run").
+    #[structopt(long = "activity-name-label")]
+    pub activity_name_label: bool,
+
+    /// Only fetch the intraday heart rate since the last minute fetched today (the time window of the endpoint),
+    /// instead of the whole day on each update, which makes the responses much smaller late in the day.
+    #[structopt(long = "intraday-window", env = "FITBIT_INTRADAY_WINDOW")]
+    pub intraday_window: bool,
+
+    /// Maximum number of distinct label sets per labelled family (workouts, devices, friends, distance sources). The
+    /// series over it are collapsed into an `other` bucket, protecting Prometheus from a cardinality explosion. 0
+    /// doesn't cap them.
+    #[structopt(long = "max-series-per-family", env = "FITBIT_MAX_SERIES_PER_FAMILY", default_value = "100")]
+    pub max_series_per_family: usize,
+
+    /// Maximum number of collectors run at once by an update of the metrics, so that a scrape with many collectors
+    /// isn't as slow as the sum of their requests. 1 runs them one after the other.
+    #[structopt(long = "collector-concurrency", env = "FITBIT_COLLECTOR_CONCURRENCY", default_value = "4")]
+    pub collector_concurrency: usize,
+
+    /// Maximum number of requests to the Fitbit API made by the collectors per hour, shared by the scrapes and the
+    /// poller. The collectors over it keep their last-known values. 0 doesn't limit them. Fitbit allows 150.
+    #[structopt(long = "hourly-request-budget", env = "FITBIT_HOURLY_REQUEST_BUDGET", default_value = "0")]
+    pub hourly_request_budget: usize,
+
+    /// Maximum age in seconds of the historical points (pushed by /history, the backfill and the midnight rollover)
+    /// kept in memory, checked every `--history-prune-interval` seconds. 0 keeps them whatever their age.
+    #[structopt(long = "history-max-age", env = "FITBIT_HISTORY_MAX_AGE", default_value = "0")]
+    pub history_max_age: u64,
+
+    /// Maximum number of historical points kept in memory per family, the oldest ones being dropped beyond it. 0
+    /// doesn't cap them.
+    #[structopt(long = "max-history-points", env = "FITBIT_MAX_HISTORY_POINTS", default_value = "1000")]
+    pub max_history_points: usize,
+
+    /// Interval in seconds between two prunings of the historical points by `--history-max-age` and
+    /// `--max-history-points`, and of the sample store by `--sample-store-max-age`.
+    #[structopt(long = "history-prune-interval", env = "FITBIT_HISTORY_PRUNE_INTERVAL", default_value = "600")]
+    pub history_prune_interval: u64,
+
+    /// Maximum age in seconds of the last sync of the devices for `fitbit_synced_recently` to be 1.
+    #[structopt(long = "synced-recently-max-age", env = "FITBIT_SYNCED_RECENTLY_MAX_AGE", default_value = "3600")]
+    pub synced_recently_max_age: u64,
+
+    /// Also expose yesterday's values of the daily metrics (steps, water, calories in) as `*_by_date` metrics
+    /// with a `date` label, so that PromQL queries over day boundaries don't miss the final value of the previous day.
+    #[structopt(long = "expose-previous-day")]
+    pub expose_previous_day: bool,
+
+    /// Verification code of the Fitbit subscriber. Enables the webhook receiving the subscription notifications.
+    #[structopt(long = "webhook-verification-code", env = "FITBIT_WEBHOOK_VERIFICATION_CODE", hide_env_values = true)]
+    pub webhook_verification_code: Option<String>,
+
+    /// Path prefix of the webhook, which must match the subscriber endpoint registered at Fitbit.
+    #[structopt(long = "webhook-path", env = "FITBIT_WEBHOOK_PATH", default_value = "/webhook")]
+    pub webhook_path: String,
+
+    /// Port of a dedicated listener for the webhook, so that it can be exposed to the internet without /metrics.
+    /// If not set, the webhook is served on the same port as the metrics endpoints. Served over HTTPS with the same
+    /// certificate as the metrics endpoints when --tls-cert and --tls-key are set.
+    #[structopt(long = "webhook-port", env = "FITBIT_WEBHOOK_PORT")]
+    pub webhook_port: Option<u16>,
+
+    /// External URL of the webhook, e.g. the one of a tunnel. When set, the subscriber verification is checked
+    /// through it and the subscriptions are created at startup.
+    #[structopt(long = "webhook-public-url", env = "FITBIT_WEBHOOK_PUBLIC_URL", requires = "webhook-verification-code")]
+    pub webhook_public_url: Option<Url>,
+
+    /// Comma separated collections to subscribe to when --webhook-public-url is set:
+    /// activities, body, foods, sleep or userRevokedAccess.
+    #[structopt(long = "webhook-collections", env = "FITBIT_WEBHOOK_COLLECTIONS", use_delimiter = true, default_value = "activities,foods,sleep")]
+    pub webhook_collections: Vec<String>,
+
+    /// ID of the subscriber to notify, if the application has several. Defaults to the default subscriber.
+    #[structopt(long = "webhook-subscriber-id", env = "FITBIT_WEBHOOK_SUBSCRIBER_ID")]
+    pub webhook_subscriber_id: Option<String>,
+
+    /// Seconds between updates of the metrics by a background poller. /metrics then serves the last-known values.
+    /// 0 updates the metrics on every scrape instead, unless --poll-schedule is set.
+    #[structopt(long = "poll-interval", env = "FITBIT_POLL_INTERVAL", default_value = "0")]
+    pub poll_interval: u64,
+
+    /// Total time in seconds given to the collectors of a scrape of /metrics. The collectors that don't finish in time
+    /// are skipped, keeping their last-known values, and counted by `fitbit_collector_timeout_total`. Keep it below
+    /// the scrape timeout of Prometheus (10s by default). 0 doesn't limit the scrapes.
+    #[structopt(long = "scrape-deadline", env = "FITBIT_SCRAPE_DEADLINE", default_value = "8")]
+    pub scrape_deadline: u64,
+
+    /// Cron expressions (minute hour day month weekday, in local time) of the background poller, separated by `;`.
+    /// E.g. "*/5 7-22 * * *; 0 23,0-6 * * *" polls every 5 minutes during waking hours and hourly overnight.
+    /// Takes precedence over --poll-interval.
+    #[structopt(long = "poll-schedule", env = "FITBIT_POLL_SCHEDULE")]
+    pub poll_schedule: Option<PollSchedule>,
+
+    /// Maximum random delay in seconds added to each update of the background poller.
+    #[structopt(long = "poll-jitter", env = "FITBIT_POLL_JITTER", default_value = "0")]
+    pub poll_jitter: u64,
+
+    /// Storage of the state that must survive a restart, e.g. the rotated tokens: "memory", "file:<directory>",
+    /// "sled:<path>" (`sled` feature) or "redis://<host>" (`redis` feature) to share it between replicas.
+    #[structopt(long = "storage", env = "FITBIT_STORAGE", default_value = "memory")]
+    pub storage: StorageConfig,
+
+    /// Archive every fetched sample in "sqlite:<path>" (`sqlite` feature), and serve /metrics from it, so that the
+    /// last values survive a restart and the history is kept independently of the Prometheus retention.
+    #[structopt(long = "sample-store", env = "FITBIT_SAMPLE_STORE")]
+    pub sample_store: Option<SampleStoreConfig>,
+
+    /// Maximum age in seconds of the samples kept in the sample store, checked every `--history-prune-interval`
+    /// seconds. 0 keeps them whatever their age.
+    #[structopt(long = "sample-store-max-age", env = "FITBIT_SAMPLE_STORE_MAX_AGE", default_value = "0")]
+    pub sample_store_max_age: u64,
+
+    /// At startup, fetch the days missing since the last sample of each historical family (steps, resting heart rate,
+    /// floors, elevation and weight), looked up
+    /// in Prometheus if --backfill-prometheus-url is set, and in the sample store otherwise.
+    #[structopt(long = "backfill-gaps")]
+    pub backfill_gaps: bool,
+
+    /// Maximum number of days backfilled by --backfill-gaps, counted back from yesterday.
+    #[structopt(long = "backfill-max-days", env = "FITBIT_BACKFILL_MAX_DAYS", default_value = "30")]
+    pub backfill_max_days: u32,
+
+    /// URL of the Prometheus server to ask for the last sample of each family, e.g. "http://prometheus:9090/".
+    #[structopt(long = "backfill-prometheus-url", env = "FITBIT_BACKFILL_PROMETHEUS_URL")]
+    pub backfill_prometheus_url: Option<Url>,
+
+    /// Address of the plaintext listener of Graphite/Carbon, e.g. "graphite:2003". Enables the push of the metrics.
+    /// Requires --poll-interval or --poll-schedule.
+    #[structopt(long = "graphite-address", env = "FITBIT_GRAPHITE_ADDRESS")]
+    pub graphite_address: Option<String>,
+
+    /// Prefix of the metric paths pushed to Graphite, e.g. "health.alice".
+    #[structopt(long = "graphite-prefix", env = "FITBIT_GRAPHITE_PREFIX", default_value = "")]
+    pub graphite_prefix: String,
+
+    /// Seconds between two pushes to Graphite.
+    #[structopt(long = "graphite-flush-interval", env = "FITBIT_GRAPHITE_FLUSH_INTERVAL", default_value = "60")]
+    pub graphite_flush_interval: u64,
+
+    /// URL of a Prometheus Pushgateway, e.g. "http://pushgateway:9091", for a Prometheus that can't scrape the
+    /// exporter. Enables the push of the metrics, grouped by the `user` label of the Fitbit user ID. Requires
+    /// --poll-interval or --poll-schedule.
+    #[structopt(long = "pushgateway-url", env = "FITBIT_PUSHGATEWAY_URL")]
+    pub pushgateway_url: Option<Url>,
+
+    /// `job` label of the metrics pushed to the Pushgateway.
+    #[structopt(long = "pushgateway-job", env = "FITBIT_PUSHGATEWAY_JOB", default_value = "fitbit_exporter")]
+    pub pushgateway_job: String,
+
+    /// Seconds between two pushes to the Pushgateway.
+    #[structopt(long = "pushgateway-interval", env = "FITBIT_PUSHGATEWAY_INTERVAL", default_value = "60")]
+    pub pushgateway_interval: u64,
+
+    /// Address of a StatsD server or Datadog agent, e.g. "localhost:8125". Enables the emission of the metrics as
+    /// gauges over UDP after every update. Requires --poll-interval or --poll-schedule.
+    #[structopt(long = "statsd-address", env = "FITBIT_STATSD_ADDRESS")]
+    pub statsd_address: Option<String>,
+
+    /// Prefix of the metric names sent to StatsD, e.g. "health.".
+    #[structopt(long = "statsd-prefix", env = "FITBIT_STATSD_PREFIX", default_value = "")]
+    pub statsd_prefix: String,
+
+    /// Send the labels as DogStatsD tags instead of appending their values to the metric names.
+    #[structopt(long = "dogstatsd")]
+    pub dogstatsd: bool,
+
+    /// Sinks of the events (broken authorization, exhausted rate limit, finished backfill, reached goal), separated
+    /// by `;`: "log", "webhook:<url>" (POSTed as JSON), "mqtt://<host>[:<port>]/<topic>", "ntfy:<topic url>" or
+    /// "pushover:<app token>:<user key>". A repeated event is only sent once an hour.
+    #[structopt(long = "event-sink", env = "FITBIT_EVENT_SINKS", value_delimiter = ";")]
+    pub event_sinks: Vec<EventSinkConfig>,
+
+    /// Comma separated collectors to enable, e.g. "steps,sleep,activity". The others don't call the Fitbit API and
+    /// their metrics are not exposed, which leaves rate limit headroom. Every collector but leaderboard (which
+    /// requires the `social` scope) and heart_rate_intraday (which requires a personal application) is enabled by
+    /// default.
+    #[structopt(long = "collectors", env = "FITBIT_COLLECTORS", use_delimiter = true, possible_values = &COLLECTORS)]
+    pub collectors: Vec<String>,
+
+    /// Scrape profiles selectable with `collect[]=profile:<name>` (e.g. in the `params` of a Prometheus job), as
+    /// `<name>=<collector>,...[@<cadence in seconds>]` separated by `;`, e.g. "sleep=sleep,goals@3600".
+    /// Collectors: steps, water, food, sleep, activity, activity_logs, goals, devices, leaderboard, badges, lifetime,
+    /// ecg, irn, cardio_score, temperature, breathing_rate, heart_rate_intraday, by_date and recovery.
+    /// The built-in "cheap" (daily totals) and "full" (every collector) profiles can be overridden.
+    #[structopt(long = "scrape-profile", env = "FITBIT_SCRAPE_PROFILES", value_delimiter = ";")]
+    pub scrape_profiles: Vec<ScrapeProfile>,
+}
+
+/// The offline commands, which read the samples archived in the sample store (--sample-store) instead of calling
+/// the Fitbit API.
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Write the weekly summary (totals, averages and goal adherence) of an ISO week.
+    Report {
+        /// The ISO week, e.g. "2024-W21".
+        week: ReportWeek,
+
+        /// Format of the weekly summary: "markdown" or "html".
+        #[structopt(long = "format", default_value = "markdown")]
+        format: ReportFormat,
+
+        /// Output file of the weekly summary. Printed to the standard output by default.
+        #[structopt(long = "output-file", parse(from_os_str))]
+        output_file: Option<PathBuf>,
+    },
+    /// Print the aggregated daily values of a metric.
+    Query {
+        /// The metric, e.g. "steps" or "fitbit_resting_heart_rate".
+        metric: String,
+
+        /// First day of the query (inclusive). Defaults to the first archived sample.
+        #[structopt(long = "start")]
+        start: Option<NaiveDate>,
+
+        /// Last day of the query (inclusive). Defaults to the last archived sample.
+        #[structopt(long = "end")]
+        end: Option<NaiveDate>,
+
+        /// Aggregation of the daily values: "sum", "avg", "min", "max" or "count".
+        #[structopt(long = "agg", default_value = "sum")]
+        aggregation: QueryAggregation,
+    },
+}
+
+impl Command {
+    /// Builds the query of the archive of the `query` command.
+    pub fn archive_query(&self) -> Option<ArchiveQuery> {
+        match self {
+            Command::Query { metric, start, end, aggregation } => {
+                Some(ArchiveQuery { metric: metric.clone(), start: *start, end: *end, aggregation: *aggregation })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Args {
+    /// Builds the options of the metrics from the command line arguments.
+    pub fn metrics_options(&self) -> MetricsOptions {
+        MetricsOptions {
+            error_budget: ErrorBudget::new(self.collector_max_failures, Duration::from_secs(self.collector_cooldown)),
+            expose_previous_day: self.expose_previous_day,
+            // Opened by the caller, since opening it can fail
+            sample_store: None,
+            collectors: Some(self.enabled_collectors()),
+            drop_served_history: self.lite,
+            recovery_weights: self.recovery_weights,
+            activity_name_label: self.activity_name_label,
+            intraday_window: self.intraday_window,
+            sleep_naps: self.sleep_naps,
+            max_series_per_family: Some(self.max_series_per_family),
+            synced_recently_max_age: Some(Duration::from_secs(self.synced_recently_max_age)),
+            max_concurrent_collectors: self.collector_concurrency,
+            hourly_request_budget: match self.hourly_request_budget {
+                0 => None,
+                budget => Some(budget),
+            },
+            history_max_age: match self.history_max_age {
+                0 => None,
+                max_age => Some(Duration::from_secs(max_age)),
+            },
+            max_history_points: match self.max_history_points {
+                0 => None,
+                max => Some(max),
+            },
+        }
+    }
+
+    /// The interval between two prunings of the historical points, or `None` if no retention is set.
+    pub fn history_prune_interval(&self) -> Option<Duration> {
+        (self.history_max_age > 0 || self.max_history_points > 0).then(|| Duration::from_secs(self.history_prune_interval))
+    }
+
+    /// The retention of the sample store, or `None` if its samples are kept whatever their age.
+    pub fn sample_store_max_age(&self) -> Option<Duration> {
+        (self.sample_store_max_age > 0).then(|| Duration::from_secs(self.sample_store_max_age))
+    }
+
+    /// The enabled collectors: those of --collectors, or every collector but the opt-in ones by default.
+    fn enabled_collectors(&self) -> Vec<String> {
+        if self.collectors.is_empty() {
+            COLLECTORS.iter().filter(|collector| !OPT_IN_COLLECTORS.contains(collector)).map(|collector| collector.to_string()).collect()
+        } else {
+            self.collectors.clone()
+        }
+    }
+
+    /// Builds the options of the HTTP server from the command line arguments.
+    pub fn server_options(&self) -> ServerOptions {
+        let basic = match (&self.metrics_username, &self.metrics_password) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            _ => None,
+        };
+        // Run by the caller, since running it can fail
+        let bearer_token_command = self.metrics_bearer_token_command.as_deref().map(|command| Arc::new(BearerTokenCommand::new(command)));
+        let auth = match (basic, &self.metrics_bearer_token, bearer_token_command) {
+            (None, None, None) => None,
+            (basic, bearer_token, bearer_token_command) => Some(MetricsAuth { basic, bearer_token: bearer_token.clone(), bearer_token_command }),
+        };
+
+        let tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsOptions { cert_path: cert_path.clone(), key_path: key_path.clone() }),
+            _ => None,
+        };
+
+        ServerOptions {
+            timestamp_position: self.timestamp_position,
+            placeholder_days: self.placeholder_days,
+            auth,
+            tls,
+            webhook: None,
+            background_polling: self.poll_schedule().is_some(),
+            scrape_profiles: Arc::new(ScrapeProfiles::new(self.scrape_profiles.clone())),
+            warm_up: None,
+            // Opened by the caller, since opening it can fail
+            body_log: None,
+            // Set by the caller, which initializes the logger
+            log_filter: None,
+            traces: None,
+            status: None,
+            stream_exposition: self.lite && !self.strict_exposition,
+            strict_exposition: self.strict_exposition,
+            scrape_deadline: match self.scrape_deadline {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            relabel: self.exposition_relabel(),
+            // Built by the caller, which owns the client
+            reauthorization: None,
+        }
+    }
+
+    /// Builds the summary of the effective configuration, without the user ID, which is fetched by the caller.
+    pub fn startup_summary(&self) -> StartupSummary {
+        StartupSummary {
+            version: env!("CARGO_PKG_VERSION"),
+            listen_addr: LISTEN_ADDR.to_string(),
+            webhook_port: self.webhook_verification_code.as_ref().and(self.webhook_port),
+            tls: self.tls_cert.is_some() && self.tls_key.is_some(),
+            auth: (self.metrics_username.is_some() && self.metrics_password.is_some())
+                || self.metrics_bearer_token.is_some()
+                || self.metrics_bearer_token_command.is_some(),
+            collectors: self.enabled_collectors(),
+            poll: match self.poll_schedule() {
+                None => "on scrape".to_string(),
+                Some(PollSchedule::Interval(interval)) => format!("every {}s", interval.as_secs()),
+                Some(PollSchedule::Cron(expressions)) => format!("cron ({} expressions)", expressions.len()),
+            },
+            user_id: None,
+        }
+    }
+
+    /// The schedule of the background poller, or `None` if the metrics are updated on every scrape.
+    pub fn poll_schedule(&self) -> Option<PollSchedule> {
+        match (&self.poll_schedule, self.poll_interval) {
+            (Some(schedule), _) => Some(schedule.clone()),
+            (None, 0) => None,
+            (None, secs) => Some(PollSchedule::Interval(Duration::from_secs(secs))),
+        }
+    }
+
+    /// The options enabling a push of the metrics (Graphite, the Pushgateway or StatsD) which are set, e.g.
+    /// "--graphite-address".
+    pub fn metrics_pushers(&self) -> Vec<&'static str> {
+        [
+            ("--graphite-address", self.graphite_address.is_some()),
+            ("--pushgateway-url", self.pushgateway_url.is_some()),
+            ("--statsd-address", self.statsd_address.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(option, set)| set.then_some(option))
+        .collect()
+    }
+
+    /// Builds the options of the backfill of the gaps, or `None` if --backfill-gaps is not set.
+    pub fn backfill_options(&self) -> Option<BackfillOptions> {
+        if !self.backfill_gaps {
+            return None;
+        }
+        Some(BackfillOptions {
+            max_days: self.backfill_max_days,
+            timestamp_position: self.timestamp_position,
+            placeholder_days: self.placeholder_days,
+            prometheus_url: self.backfill_prometheus_url.clone(),
+        })
+    }
+
+    /// Builds the options of the push to Graphite, or `None` if --graphite-address is not set.
+    pub fn graphite_options(&self) -> Option<GraphiteOptions> {
+        self.graphite_address.as_ref().map(|address| GraphiteOptions {
+            address: address.clone(),
+            prefix: self.graphite_prefix.clone(),
+            flush_interval: Duration::from_secs(self.graphite_flush_interval.max(1)),
+            relabel: self.exposition_relabel(),
+        })
+    }
+
+    /// Builds the options of the push to the Pushgateway, grouped by `user`, or `None` if --pushgateway-url is not
+    /// set.
+    pub fn pushgateway_options(&self, user: Option<String>) -> Option<PushgatewayOptions> {
+        self.pushgateway_url.as_ref().map(|url| PushgatewayOptions {
+            url: url.clone(),
+            job: self.pushgateway_job.clone(),
+            user,
+            interval: Duration::from_secs(self.pushgateway_interval.max(1)),
+            relabel: self.exposition_relabel(),
+        })
+    }
+
+    /// Builds the metric name prefix and the constant labels of the served expositions.
+    pub fn exposition_relabel(&self) -> ExpositionRelabel {
+        ExpositionRelabel::new(&self.metric_prefix, &self.const_labels)
+    }
+
+    /// Builds the options of the StatsD emitter, or `None` if --statsd-address is not set.
+    pub fn statsd_options(&self) -> Option<StatsdOptions> {
+        self.statsd_address.as_ref().map(|address| StatsdOptions {
+            address: address.clone(),
+            prefix: self.statsd_prefix.clone(),
+            dogstatsd: self.dogstatsd,
+            relabel: self.exposition_relabel(),
+        })
+    }
+
+    /// Builds the options of the webhook from the command line arguments, or `None` if the webhook is disabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_secret` - The client secret of the application, used to verify the signature of the notifications.
+    pub fn webhook_options(&self, client_secret: &str) -> Option<WebhookOptions> {
+        self.webhook_verification_code.as_ref().map(|verification_code| WebhookOptions {
+            port: self.webhook_port,
+            path: self.webhook_path.clone(),
+            verification_code: verification_code.clone(),
+            client_secret: Zeroizing::new(client_secret.to_string()),
+            public_url: self.webhook_public_url.clone(),
+            collections: self.webhook_collections.clone(),
+            subscriber_id: self.webhook_subscriber_id.clone(),
+        })
+    }
+
+    /// Builds the options of the outbound HTTP client from the command line arguments.
+    pub fn http_options(&self) -> HttpOptions {
+        HttpOptions {
+            connect_timeout: Duration::from_secs(self.http_connect_timeout),
+            request_timeout: Duration::from_secs(self.http_request_timeout),
+            max_retries: self.http_max_retries,
+            pool_max_idle_per_host: if self.lite { self.http_pool_max_idle.min(1) } else { self.http_pool_max_idle },
+            pool_idle_timeout: match self.http_pool_idle_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            http2_prior_knowledge: self.http2,
+            dns_overrides: self.dns_overrides.iter().fold(HashMap::new(), |mut overrides, dns_override| {
+                overrides
+                    .entry(dns_override.host.clone())
+                    .or_insert_with(Vec::new)
+                    .push(SocketAddr::new(dns_override.ip, 0));
+                overrides
+            }),
+            dns_cache_ttl: match self.dns_cache_ttl {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            max_response_bytes: match (self.http_max_response_size, self.lite) {
+                (0, false) => None,
+                (0, true) => Some(LITE_MAX_RESPONSE_SIZE),
+                (bytes, true) => Some(bytes.min(LITE_MAX_RESPONSE_SIZE)),
+                (bytes, false) => Some(bytes),
+            },
+            ..HttpOptions::default()
+        }
+    }
+}
+
+/// Whether the naps (the sleep logs other than the main sleep) count in the sleep totals, e.g.
+/// `fitbit_sleep_total_asleep_seconds`, since a nap skews the "time asleep" of the night.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SleepNaps {
+    /// The totals are those of all the sleep logs, as summarized by Fitbit.
+    #[default]
+    Include,
+    /// The totals are those of the main sleep, and the naps aren't exported.
+    Exclude,
+    /// The totals are those of the main sleep, and the naps are exported in their own families, e.g.
+    /// `fitbit_sleep_nap_asleep_seconds`.
+    Separate,
+}
+
+impl FromStr for SleepNaps {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "include" => Ok(SleepNaps::Include),
+            "exclude" => Ok(SleepNaps::Exclude),
+            "separate" => Ok(SleepNaps::Separate),
+            _ => Err(format!("Invalid sleep naps handling: {} (expected include, exclude or separate)", s)),
+        }
+    }
+}
+
+/// The time of day at which a daily value (e.g. the total steps of a day) is stamped.
+///
+/// Midnight-stamped totals visually attach a day's value to the previous day in some Grafana setups,
+/// so stamping them at noon or at the end of the day can be preferable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPosition {
+    Midnight,
+    Noon,
+    EndOfDay,
+}
+
+impl FromStr for TimestampPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "midnight" => Ok(TimestampPosition::Midnight),
+            "noon" => Ok(TimestampPosition::Noon),
+            "end-of-day" => Ok(TimestampPosition::EndOfDay),
+            _ => Err(format!("Invalid timestamp position: {} (expected midnight, noon or end-of-day)", s)),
+        }
+    }
+}
+
+/// How the placeholder days of a historical date range are emitted, i.e. the zero-filled days Fitbit returns for
+/// the dates before the first day with steps, e.g. before the device was set up. Kept, they show as misleading
+/// flat-zero years in the backfilled graphs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaceholderDays {
+    /// Emitted as any other day.
+    Keep,
+    /// Not emitted.
+    Skip,
+    /// Emitted as estimated: in `fitbit_steps_estimated` rather than `fitbit_steps`, which has no labels, and with
+    /// the `estimated=true` label in the historical dumps.
+    Estimated,
+}
+
+impl FromStr for PlaceholderDays {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(PlaceholderDays::Keep),
+            "skip" => Ok(PlaceholderDays::Skip),
+            "estimated" => Ok(PlaceholderDays::Estimated),
+            _ => Err(format!("Invalid placeholder days handling: {} (expected keep, skip or estimated)", s)),
+        }
+    }
+}
+
+/// The file format of the historical data export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Prometheus text exposition format, with timestamps.
+    Prom,
+    /// One row per data point with the columns `date,metric,value,labels`.
+    Csv,
+    /// An array of `{"date", "metric", "value", "labels"}` objects.
+    Json,
+    /// An Apple Health `export.xml` document, to import the history into Apple Health.
+    AppleHealthXml,
+    /// A Google Fit daily activity metrics CSV, as found in a Google Takeout export.
+    GoogleFitCsv,
+    /// A Parquet file with one row per `date,metric,value,user`, e.g. for DuckDB or Polars.
+    Parquet,
+}
+
+impl OutputFormat {
+    /// The file extension used for the default output file name.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Prom => "prom",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+            OutputFormat::AppleHealthXml => "xml",
+            OutputFormat::GoogleFitCsv => "csv",
+            OutputFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prom" => Ok(OutputFormat::Prom),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "apple-health-xml" => Ok(OutputFormat::AppleHealthXml),
+            "google-fit-csv" => Ok(OutputFormat::GoogleFitCsv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            _ => Err(format!("Invalid output format: {} (expected prom, csv, json, apple-health-xml, google-fit-csv or parquet)", s)),
+        }
+    }
+}
+
+/// A static DNS override given as `host=ip`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsOverride {
+    pub host: String,
+    pub ip: IpAddr,
+}
+
+impl FromStr for DnsOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, ip) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid DNS override: {} (expected host=ip)", s))?;
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|err| format!("Invalid IP address in DNS override {}: {}", s, err))?;
+        Ok(DnsOverride { host: host.to_string(), ip })
+    }
 }
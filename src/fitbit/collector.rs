@@ -0,0 +1,172 @@
+use tracing::{error, info};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Labels of the per-collector metrics, e.g. `fitbit_collector_enabled{collector="sleep"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct CollectorLabels {
+    pub collector: String,
+}
+
+#[derive(Debug, Default)]
+struct CollectorState {
+    consecutive_failures: u32,
+    disabled_until: Option<Instant>,
+    last_success: Option<Instant>,
+    forbidden: bool,
+    // Whether the last run failed, whatever the error
+    failing: bool,
+}
+
+/// Error budget of the collectors (steps, sleep, water...), each of them fetching one Fitbit resource.
+///
+/// When a collector fails `max_consecutive_failures` scrapes in a row (e.g. because the token lacks the scope
+/// of its resource), it's disabled for `cooldown` instead of consuming rate limit quota and log volume on every
+/// scrape. It's enabled again once the cooldown is over, and gets another `max_consecutive_failures` attempts.
+/// The errors shared by all the collectors (a rate limit, the scrape deadline, an expired token) don't count.
+///
+/// The state of each collector is exposed as `fitbit_collector_enabled`, `fitbit_collector_consecutive_failures`
+/// and `fitbit_collector_forbidden`.
+#[derive(Debug)]
+pub struct ErrorBudget {
+    max_consecutive_failures: u32,
+    cooldown: Duration,
+    states: Mutex<HashMap<String, CollectorState>>,
+    enabled: Family<CollectorLabels, Gauge>,
+    consecutive_failures: Family<CollectorLabels, Gauge>,
+    forbidden: Family<CollectorLabels, Gauge>,
+}
+
+impl ErrorBudget {
+    /// Creates a new error budget.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_consecutive_failures` - The number of consecutive failures after which a collector is disabled. 0 never disables collectors.
+    /// * `cooldown` - How long a collector stays disabled.
+    pub fn new(max_consecutive_failures: u32, cooldown: Duration) -> Self {
+        Self {
+            max_consecutive_failures,
+            cooldown,
+            states: Mutex::new(HashMap::new()),
+            enabled: Family::default(),
+            consecutive_failures: Family::default(),
+            forbidden: Family::default(),
+        }
+    }
+
+    /// Registers the per-collector metrics in the given registry.
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register("fitbit_collector_enabled", "Whether the collector is enabled (1) or disabled after too many consecutive failures (0)", self.enabled.clone());
+        registry.register("fitbit_collector_consecutive_failures", "Number of consecutive failed scrapes of the collector", self.consecutive_failures.clone());
+        registry.register("fitbit_collector_forbidden", "Whether the last run of the collector was rejected because the token lacks the scope of its resource (1) or not (0)", self.forbidden.clone());
+    }
+
+    /// Returns true if the collector should run, re-enabling it if its cooldown is over.
+    pub fn is_enabled(&self, collector: &str) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(collector.to_string()).or_default();
+        let enabled = match state.disabled_until {
+            Some(disabled_until) if Instant::now() < disabled_until => false,
+            Some(_) => {
+                info!("Cooldown of the {} collector is over. Enabling it again", collector);
+                state.disabled_until = None;
+                state.consecutive_failures = 0;
+                true
+            }
+            None => true,
+        };
+        self.update_gauges(collector, state);
+        enabled
+    }
+
+    /// Records a successful run of the collector.
+    pub fn record_success(&self, collector: &str) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(collector.to_string()).or_default();
+        state.consecutive_failures = 0;
+        state.last_success = Some(Instant::now());
+        state.forbidden = false;
+        state.failing = false;
+        self.update_gauges(collector, state);
+    }
+
+    /// Returns true if the collector succeeded less than `max_age` ago.
+    pub fn succeeded_within(&self, collector: &str, max_age: Duration) -> bool {
+        let states = self.states.lock().unwrap();
+        states
+            .get(collector)
+            .and_then(|state| state.last_success)
+            .is_some_and(|last_success| last_success.elapsed() < max_age)
+    }
+
+    /// Returns the time of the last successful run of the collector, if any.
+    pub fn last_success(&self, collector: &str) -> Option<Instant> {
+        self.states.lock().unwrap().get(collector).and_then(|state| state.last_success)
+    }
+
+    /// Marks the last run of the collector as failed, be it with an error counted against its budget or not.
+    ///
+    /// # Returns
+    ///
+    /// True if the collector just started failing, i.e. it never ran or its previous run succeeded.
+    pub fn start_failing(&self, collector: &str) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(collector.to_string()).or_default();
+        !std::mem::replace(&mut state.failing, true)
+    }
+
+    /// Records a failed run of the collector, disabling it if it ran out of budget.
+    ///
+    /// # Returns
+    ///
+    /// The message logged if the collector was disabled.
+    pub fn record_failure(&self, collector: &str) -> Option<String> {
+        self.record_error(collector, false)
+    }
+
+    /// Records a run of the collector rejected because the token lacks the scope of its resource. It counts as
+    /// a failure, and sets `fitbit_collector_forbidden` until the collector succeeds again.
+    ///
+    /// # Returns
+    ///
+    /// The message logged if the collector was disabled.
+    pub fn record_forbidden(&self, collector: &str) -> Option<String> {
+        self.record_error(collector, true)
+    }
+
+    fn record_error(&self, collector: &str, forbidden: bool) -> Option<String> {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(collector.to_string()).or_default();
+        state.consecutive_failures += 1;
+        state.forbidden = forbidden;
+        let mut disabled = None;
+        if self.max_consecutive_failures > 0 && state.consecutive_failures >= self.max_consecutive_failures {
+            let message = format!("The {} collector failed {} times in a row. Disabling it for {} seconds",
+                                  collector, state.consecutive_failures, self.cooldown.as_secs());
+            error!("{}", message);
+            state.disabled_until = Some(Instant::now() + self.cooldown);
+            disabled = Some(message);
+        }
+        self.update_gauges(collector, state);
+        disabled
+    }
+
+    fn update_gauges(&self, collector: &str, state: &CollectorState) {
+        let labels = CollectorLabels { collector: collector.to_string() };
+        self.enabled.get_or_create(&labels).set(state.disabled_until.is_none() as i64);
+        self.consecutive_failures.get_or_create(&labels).set(state.consecutive_failures as i64);
+        self.forbidden.get_or_create(&labels).set(state.forbidden as i64);
+    }
+}
+
+impl Default for ErrorBudget {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(60 * 60))
+    }
+}
@@ -0,0 +1,154 @@
+use hyper::client::connect::dns::Name;
+use tracing::{debug, error};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::registry::Registry;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Effectiveness of the DNS cache of the `CachingResolver`, to tune `--dns-cache-ttl`.
+///
+/// A lookup served from a fresh entry is a hit, any other lookup is a miss. An expired entry replaced by a new
+/// lookup (or used as a fallback when the lookup fails) is an eviction. The static overrides are not counted.
+#[derive(Debug, Clone, Default)]
+pub struct DnsCacheMetrics {
+    hits: Counter,
+    misses: Counter,
+    evictions: Counter,
+}
+
+impl DnsCacheMetrics {
+    /// Registers the DNS cache metrics in the given registry.
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register("fitbit_dns_cache_hits", "Number of DNS lookups served from a fresh cache entry", self.hits.clone());
+        registry.register("fitbit_dns_cache_misses", "Number of DNS lookups not served from a fresh cache entry", self.misses.clone());
+        registry.register("fitbit_dns_cache_evictions", "Number of DNS cache entries evicted after their TTL expired", self.evictions.clone());
+    }
+}
+
+/// The cached addresses of each host name, with the time they were resolved at.
+type DnsCache = Arc<Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>>;
+
+/// A DNS resolver for the outbound HTTP client that caches lookups and supports static overrides.
+///
+/// Users behind flaky DNS see intermittent scrape failures because every request to api.fitbit.com resolves the name
+/// again. This resolver keeps the resolved addresses for `ttl`, and if a lookup fails after the entry expired,
+/// the stale addresses are used instead of failing the request. A `ttl` of 0 doesn't cache anything, so that only the
+/// static overrides bypass the system resolver.
+///
+/// All the resolved addresses (IPv4 and IPv6) are returned in order, so that the connector can still race them
+/// (happy eyeballs).
+#[derive(Clone)]
+pub struct CachingResolver {
+    overrides: Arc<HashMap<String, Vec<SocketAddr>>>,
+    ttl: Duration,
+    cache: DnsCache,
+    metrics: DnsCacheMetrics,
+}
+
+impl CachingResolver {
+    /// Creates a new resolver.
+    ///
+    /// # Arguments
+    ///
+    /// * `overrides` - Static addresses per host name (e.g. "api.fitbit.com"), which bypass DNS entirely.
+    /// * `ttl` - How long a successful lookup is cached. 0 doesn't cache it.
+    /// * `metrics` - The metrics of the cache.
+    pub fn new(overrides: HashMap<String, Vec<SocketAddr>>, ttl: Duration, metrics: DnsCacheMetrics) -> Self {
+        Self {
+            overrides: Arc::new(overrides),
+            ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            metrics,
+        }
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+
+        if let Some(addrs) = self.overrides.get(&host) {
+            debug!("Using static DNS override for {}: {:?}", host, addrs);
+            let addrs: Addrs = Box::new(addrs.clone().into_iter());
+            return Box::pin(async move { Ok(addrs) });
+        }
+
+        let cached = self.cache.lock().unwrap().get(&host).cloned();
+        if let Some((resolved_at, addrs)) = &cached {
+            if resolved_at.elapsed() < self.ttl {
+                self.metrics.hits.inc();
+                let addrs: Addrs = Box::new(addrs.clone().into_iter());
+                return Box::pin(async move { Ok(addrs) });
+            }
+        }
+
+        self.metrics.misses.inc();
+        if cached.is_some() {
+            self.metrics.evictions.inc();
+        }
+
+        let cache = self.cache.clone();
+        let ttl = self.ttl;
+        Box::pin(async move {
+            // The port is replaced by the connector, so 0 is fine here.
+            match tokio::net::lookup_host((host.as_str(), 0)).await {
+                Ok(resolved) => {
+                    let addrs: Vec<SocketAddr> = resolved.collect();
+                    debug!("Resolved {}: {:?}", host, addrs);
+                    if !ttl.is_zero() {
+                        cache.lock().unwrap().insert(host.clone(), (Instant::now(), addrs.clone()));
+                    }
+                    let addrs: Addrs = Box::new(addrs.into_iter());
+                    Ok(addrs)
+                }
+                Err(err) => match cached {
+                    Some((_, addrs)) => {
+                        error!("DNS lookup for {} failed: {}. Using the stale cached addresses {:?}", host, err, addrs);
+                        let addrs: Addrs = Box::new(addrs.into_iter());
+                        Ok(addrs)
+                    }
+                    None => Err(err.into()),
+                },
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    async fn resolve(resolver: &CachingResolver) {
+        let addrs = resolver.resolve(Name::from_str("localhost").unwrap()).await.unwrap();
+        assert!(addrs.count() > 0);
+    }
+
+    #[tokio::test]
+    async fn cache_hits_misses_and_evictions() {
+        let metrics = DnsCacheMetrics::default();
+        let resolver = CachingResolver::new(HashMap::new(), Duration::from_secs(60), metrics.clone());
+        resolve(&resolver).await;
+        resolve(&resolver).await;
+
+        let expired = CachingResolver::new(HashMap::new(), Duration::from_millis(10), metrics.clone());
+        resolve(&expired).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        resolve(&expired).await;
+
+        assert_eq!(metrics.hits.get(), 1);
+        assert_eq!(metrics.misses.get(), 3);
+        assert_eq!(metrics.evictions.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_does_not_cache() {
+        let resolver = CachingResolver::new(HashMap::new(), Duration::ZERO, DnsCacheMetrics::default());
+        resolve(&resolver).await;
+        resolve(&resolver).await;
+        assert!(resolver.cache.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::{NaiveDate, Utc};
+use tracing::debug;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+
+use crate::fitbit::history::HistoricalSample;
+
+// Conversions of the historical samples into the import formats of other health ecosystems, so that the Fitbit
+// history can be migrated or mirrored there. Metrics without an equivalent in the target format are skipped.
+
+/// Apple Health record types and units of the exported metrics.
+/// FYI: https://developer.apple.com/documentation/healthkit/hkquantitytypeidentifier
+const APPLE_HEALTH_TYPES: &[(&str, &str, &str)] = &[
+    ("fitbit_steps", "HKQuantityTypeIdentifierStepCount", "count"),
+    ("fitbit_water_ml", "HKQuantityTypeIdentifierDietaryWater", "mL"),
+    ("fitbit_calories_in", "HKQuantityTypeIdentifierDietaryEnergyConsumed", "kcal"),
+    ("fitbit_daily_floors", "HKQuantityTypeIdentifierFlightsClimbed", "count"),
+    ("fitbit_resting_heart_rate_bpm", "HKQuantityTypeIdentifierRestingHeartRate", "count/min"),
+    ("fitbit_weight_grams", "HKQuantityTypeIdentifierBodyMass", "g"),
+];
+
+/// Google Fit daily activity metrics columns of the exported metrics, in column order.
+/// FYI: these are the column names of the "Daily activity metrics" CSV of a Google Takeout export.
+const GOOGLE_FIT_COLUMNS: &[(&str, &str)] = &[
+    ("fitbit_steps", "Step count"),
+];
+
+/// Encodes historical samples as an Apple Health `export.xml` document, with one `Record` per daily value.
+///
+/// Each record spans the whole day it belongs to, except the samples with a `time` label (the weigh-ins), which
+/// start and end at that time. As for the other formats, dates are treated as UTC.
+pub fn encode_apple_health_xml(samples: &[HistoricalSample]) -> String {
+    let now = Utc::now().format("%Y-%m-%d %H:%M:%S +0000").to_string();
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<HealthData locale=\"en_US\">\n");
+    let _ = writeln!(xml, " <ExportDate value=\"{}\"/>", now);
+    for sample in samples {
+        let (record_type, unit) = match APPLE_HEALTH_TYPES.iter().find(|(metric, _, _)| *metric == sample.metric) {
+            Some((_, record_type, unit)) => (record_type, unit),
+            None => {
+                debug!("Skipping {} which has no Apple Health equivalent", sample.metric);
+                continue;
+            }
+        };
+        let (start_time, end_time) = match sample.labels.get("time") {
+            Some(time) => (time.as_str(), time.as_str()),
+            None => ("00:00:00", "23:59:59"),
+        };
+        let _ = writeln!(
+            xml,
+            " <Record type=\"{}\" sourceName=\"Fitbit\" unit=\"{}\" creationDate=\"{}\" startDate=\"{} {} +0000\" endDate=\"{} {} +0000\" value=\"{}\"/>",
+            record_type, unit, now, sample.date, start_time, sample.date, end_time, sample.value,
+        );
+    }
+    xml.push_str("</HealthData>\n");
+    xml
+}
+
+/// Encodes historical samples as a Google Fit daily activity metrics CSV, with one row per date.
+///
+/// Values missing for a date are left empty, as in the CSV files of a Google Takeout export.
+///
+/// # Errors
+///
+/// Returns an error if a row cannot be written.
+pub fn encode_google_fit_csv(samples: &[HistoricalSample]) -> Result<String, Box<dyn Error>> {
+    let mut rows: BTreeMap<NaiveDate, Vec<Option<f64>>> = BTreeMap::new();
+    for sample in samples {
+        let column = match GOOGLE_FIT_COLUMNS.iter().position(|(metric, _)| *metric == sample.metric) {
+            Some(column) => column,
+            None => {
+                debug!("Skipping {} which has no Google Fit equivalent", sample.metric);
+                continue;
+            }
+        };
+        rows.entry(sample.date).or_insert_with(|| vec![None; GOOGLE_FIT_COLUMNS.len()])[column] = Some(sample.value);
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let mut header = vec!["Date"];
+    header.extend(GOOGLE_FIT_COLUMNS.iter().map(|(_, column)| *column));
+    writer.write_record(&header)?;
+    for (date, values) in rows {
+        let mut record = vec![date.format("%Y-%m-%d").to_string()];
+        record.extend(values.iter().map(|value| value.map(|value| value.to_string()).unwrap_or_default()));
+        writer.write_record(&record)?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Encodes historical samples as a Parquet file with the columns `date` (DATE), `metric`, `value` and `user`.
+///
+/// # Arguments
+///
+/// * `samples` - The samples to encode, one row each.
+/// * `user_id` - The ID of the Fitbit user, so that the exports of several users can be queried together.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be written.
+#[cfg(feature = "parquet")]
+pub fn encode_parquet(samples: &[HistoricalSample], user_id: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int32Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+
+    let schema = parse_message_type(
+        "message fitbit_historical_metrics {
+            REQUIRED INT32 date (DATE);
+            REQUIRED BYTE_ARRAY metric (UTF8);
+            REQUIRED DOUBLE value;
+            REQUIRED BYTE_ARRAY user (UTF8);
+        }",
+    )?;
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let dates: Vec<i32> = samples.iter().map(|sample| (sample.date - epoch).num_days() as i32).collect();
+    let metrics: Vec<ByteArray> = samples.iter().map(|sample| ByteArray::from(sample.metric.as_str())).collect();
+    let values: Vec<f64> = samples.iter().map(|sample| sample.value).collect();
+    let users: Vec<ByteArray> = samples.iter().map(|_| ByteArray::from(user_id)).collect();
+
+    let mut buffer = Vec::new();
+    let mut writer = SerializedFileWriter::new(&mut buffer, Arc::new(schema), Arc::new(WriterProperties::builder().build()))?;
+    let mut row_group = writer.next_row_group()?;
+    for column_index in 0..4 {
+        let mut column = row_group.next_column()?.ok_or("Missing Parquet column")?;
+        match column_index {
+            0 => column.typed::<Int32Type>().write_batch(&dates, None, None)?,
+            1 => column.typed::<ByteArrayType>().write_batch(&metrics, None, None)?,
+            2 => column.typed::<DoubleType>().write_batch(&values, None, None)?,
+            _ => column.typed::<ByteArrayType>().write_batch(&users, None, None)?,
+        };
+        column.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(buffer)
+}
+
+#[cfg(not(feature = "parquet"))]
+pub fn encode_parquet(_samples: &[HistoricalSample], _user_id: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("The parquet format was requested, but fitbit_exporter was built without the `parquet` feature".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> Vec<HistoricalSample> {
+        let date = NaiveDate::from_ymd_opt(2023, 3, 4).unwrap();
+        vec![
+            HistoricalSample::new(date, "fitbit_steps", 8123.0),
+            HistoricalSample::new(date, "fitbit_sleep_efficiency", 93.0),
+        ]
+    }
+
+    #[test]
+    fn apple_health_records_span_the_day() {
+        let xml = encode_apple_health_xml(&samples());
+        assert!(xml.contains("type=\"HKQuantityTypeIdentifierStepCount\" sourceName=\"Fitbit\" unit=\"count\""));
+        assert!(xml.contains("startDate=\"2023-03-04 00:00:00 +0000\" endDate=\"2023-03-04 23:59:59 +0000\" value=\"8123\""));
+        assert_eq!(xml.matches("<Record ").count(), 1);
+    }
+
+    #[test]
+    fn apple_health_weigh_ins_keep_their_time() {
+        let date = NaiveDate::from_ymd_opt(2023, 3, 4).unwrap();
+        let samples: Vec<_> = [("07:12:00", 71300.0), ("19:40:30", 71900.0)]
+            .into_iter()
+            .map(|(time, grams)| {
+                let mut sample = HistoricalSample::new(date, "fitbit_weight_grams", grams);
+                sample.labels.insert("time".to_string(), time.to_string());
+                sample
+            })
+            .collect();
+        let xml = encode_apple_health_xml(&samples);
+        assert!(xml.contains("startDate=\"2023-03-04 07:12:00 +0000\" endDate=\"2023-03-04 07:12:00 +0000\" value=\"71300\""));
+        assert!(xml.contains("startDate=\"2023-03-04 19:40:30 +0000\" endDate=\"2023-03-04 19:40:30 +0000\" value=\"71900\""));
+    }
+
+    #[test]
+    fn google_fit_csv_has_one_row_per_date() {
+        let csv = encode_google_fit_csv(&samples()).unwrap();
+        assert_eq!(csv, "Date,Step count\n2023-03-04,8123\n");
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_file_has_the_magic_bytes() {
+        let parquet = encode_parquet(&samples(), "ABC123").unwrap();
+        assert!(parquet.starts_with(b"PAR1") && parquet.ends_with(b"PAR1"));
+    }
+}
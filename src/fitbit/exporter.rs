@@ -0,0 +1,265 @@
+use chrono::Local;
+use prometheus_client::registry::Registry;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{debug, error};
+
+use crate::fitbit::{update_current_metrics, FitbitApi, FitbitMetrics, MetricsOptions};
+use crate::fitbit::schedule::PollSchedule;
+
+/// The interval of the collections of `Exporter::run`, unless set with `ExporterBuilder::with_schedule`.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The result of a custom collector or sink.
+pub type ExporterFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+/// A collector of the host application, run after the built-in collectors at each collection, e.g. to export a
+/// Fitbit resource the exporter doesn't cover.
+pub trait CustomCollector: Send + Sync {
+    /// The name of the collector, e.g. in the logs.
+    fn name(&self) -> &str;
+
+    /// Registers the metrics of the collector. Called again whenever the registry is rebuilt.
+    fn register(&self, registry: &mut Registry);
+
+    /// Updates the metrics of the collector.
+    fn collect<'a>(&'a self, client: &'a dyn FitbitApi) -> ExporterFuture<'a>;
+}
+
+/// A destination of the metrics of the host application, sent the metrics after each collection, e.g. a database.
+pub trait MetricsSink: Send + Sync {
+    /// The name of the sink, e.g. in the logs.
+    fn name(&self) -> &str;
+
+    fn send<'a>(&'a self, fitbit_metrics: &'a FitbitMetrics) -> ExporterFuture<'a>;
+}
+
+/// Builder of an `Exporter`, see `Exporter::builder`.
+pub struct ExporterBuilder {
+    client: Arc<RwLock<dyn FitbitApi>>,
+    metrics_options: MetricsOptions,
+    builtin_collectors: bool,
+    collectors: Vec<Arc<dyn CustomCollector>>,
+    sinks: Vec<Arc<dyn MetricsSink>>,
+    schedule: PollSchedule,
+    max_jitter: Duration,
+}
+
+impl ExporterBuilder {
+    pub fn with_metrics_options(mut self, metrics_options: MetricsOptions) -> Self {
+        self.metrics_options = metrics_options;
+        self
+    }
+
+    /// Runs only the custom collectors, e.g. when the host application exports its own selection of resources.
+    pub fn without_builtin_collectors(mut self) -> Self {
+        self.builtin_collectors = false;
+        self
+    }
+
+    pub fn with_collector(mut self, collector: impl CustomCollector + 'static) -> Self {
+        self.collectors.push(Arc::new(collector));
+        self
+    }
+
+    pub fn with_sink(mut self, sink: impl MetricsSink + 'static) -> Self {
+        self.sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// Sets the schedule of `Exporter::run`, with a random delay of up to `max_jitter` added to each collection.
+    pub fn with_schedule(mut self, schedule: PollSchedule, max_jitter: Duration) -> Self {
+        self.schedule = schedule;
+        self.max_jitter = max_jitter;
+        self
+    }
+
+    /// Builds the exporter, registering the metrics of the custom collectors.
+    pub fn build(self) -> Exporter {
+        let fitbit_metrics = Arc::new(FitbitMetrics::with_options(self.metrics_options));
+        for collector in &self.collectors {
+            let collector = collector.clone();
+            fitbit_metrics.register_external(move |registry| collector.register(registry));
+        }
+        Exporter {
+            client: self.client,
+            fitbit_metrics,
+            builtin_collectors: self.builtin_collectors,
+            collectors: self.collectors,
+            sinks: self.sinks,
+            schedule: self.schedule,
+            max_jitter: self.max_jitter,
+        }
+    }
+}
+
+/// The collection pipeline of the exporter, for host applications embedding the library instead of running the
+/// `fitbit_exporter` binary: the built-in collectors, the custom collectors and sinks of the host application, and
+/// the schedule of the collections, which can also be driven manually with `collect`.
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use tokio::sync::RwLock;
+/// use fitbit_exporter::FitbitClient;
+/// use fitbit_exporter::fitbit::exporter::Exporter;
+///
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let client = FitbitClient::new("client_id", "client_secret", &None, "access_token");
+/// let exporter = Exporter::builder(Arc::new(RwLock::new(client))).build();
+/// exporter.collect().await?;
+/// let fitbit_metrics = exporter.metrics().clone();
+/// # Ok(())
+/// # }
+/// ```
+pub struct Exporter {
+    client: Arc<RwLock<dyn FitbitApi>>,
+    fitbit_metrics: Arc<FitbitMetrics>,
+    builtin_collectors: bool,
+    collectors: Vec<Arc<dyn CustomCollector>>,
+    sinks: Vec<Arc<dyn MetricsSink>>,
+    schedule: PollSchedule,
+    max_jitter: Duration,
+}
+
+impl Exporter {
+    /// Returns a builder of an exporter of the metrics fetched by the client, with the built-in collectors, no custom
+    /// collector nor sink, and a collection every 5 minutes.
+    pub fn builder(client: Arc<RwLock<dyn FitbitApi>>) -> ExporterBuilder {
+        ExporterBuilder {
+            client,
+            metrics_options: MetricsOptions::default(),
+            builtin_collectors: true,
+            collectors: Vec::new(),
+            sinks: Vec::new(),
+            schedule: PollSchedule::Interval(DEFAULT_INTERVAL),
+            max_jitter: Duration::ZERO,
+        }
+    }
+
+    pub fn client(&self) -> &Arc<RwLock<dyn FitbitApi>> {
+        &self.client
+    }
+
+    /// The metrics, e.g. to serve them with `run_server`.
+    pub fn metrics(&self) -> &Arc<FitbitMetrics> {
+        &self.fitbit_metrics
+    }
+
+    /// Runs a collection: the built-in collectors, then the custom collectors, then sends the metrics to the sinks.
+    ///
+    /// A failed collector or sink doesn't prevent the others from running.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the collectors and sinks that failed, whose errors are logged.
+    pub async fn collect(&self) -> Result<(), Box<dyn Error>> {
+        let mut failed = Vec::new();
+        if self.builtin_collectors {
+            if let Err(err) = update_current_metrics(self.client.clone(), self.fitbit_metrics.clone()).await {
+                error!("[Exporter::collect] Error updating the metrics: {:?}", err);
+                failed.push("built-in collectors".to_string());
+            }
+        }
+
+        // Released before the sinks run, so that the token refreshes aren't blocked by a slow sink
+        {
+            let client = self.client.read().await;
+            for collector in &self.collectors {
+                if let Err(err) = collector.collect(&*client).await {
+                    error!("[Exporter::collect] Error running the collector {}: {}", collector.name(), err);
+                    failed.push(collector.name().to_string());
+                }
+            }
+        }
+        for sink in &self.sinks {
+            if let Err(err) = sink.send(&self.fitbit_metrics).await {
+                error!("[Exporter::collect] Error sending the metrics to {}: {}", sink.name(), err);
+                failed.push(sink.name().to_string());
+            }
+        }
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(format!("Failed: {}", failed.join(", ")).into()),
+        }
+    }
+
+    /// Runs the collections according to the schedule, the first one right away. A rate limit postpones the next
+    /// collection until it ends.
+    ///
+    /// It doesn't refresh the access token, which expires after 8 hours: with a `FitbitClient`, also spawn
+    /// `refresh_token_periodically` on the same client.
+    pub async fn run(&self) {
+        loop {
+            debug!("[Exporter::run] Collecting the metrics...");
+            // The failures are logged by `collect`
+            let _ = self.collect().await;
+            let delay = self.schedule.next_delay(Local::now(), self.max_jitter).max(self.fitbit_metrics.rate_limit_remaining().unwrap_or_default());
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::metrics::gauge::Gauge;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::fitbit::mock::MockFitbitApi;
+
+    struct StepsCollector(Gauge);
+
+    impl CustomCollector for StepsCollector {
+        fn name(&self) -> &str {
+            "custom_steps"
+        }
+
+        fn register(&self, registry: &mut Registry) {
+            registry.register("custom_steps", "Steps of the custom collector", self.0.clone());
+        }
+
+        fn collect<'a>(&'a self, client: &'a dyn FitbitApi) -> ExporterFuture<'a> {
+            Box::pin(async move {
+                self.0.set(client.fetch_steps().await?.as_i64());
+                Ok(())
+            })
+        }
+    }
+
+    struct CountingSink(Arc<AtomicUsize>);
+
+    impl MetricsSink for CountingSink {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        fn send<'a>(&'a self, _fitbit_metrics: &'a FitbitMetrics) -> ExporterFuture<'a> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_collectors_and_sinks_run_on_collect() {
+        let client: Arc<RwLock<dyn FitbitApi>> = Arc::new(RwLock::new(MockFitbitApi::new().with_response("fetch_steps", json!(8123))));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let exporter = Exporter::builder(client)
+            .without_builtin_collectors()
+            .with_collector(StepsCollector(Gauge::default()))
+            .with_sink(CountingSink(sent.clone()))
+            .build();
+
+        exporter.collect().await.unwrap();
+        let mut txt = String::new();
+        encode(&mut txt, &exporter.metrics().registry()).unwrap();
+        assert!(txt.contains("custom_steps 8123"));
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,820 @@
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use thiserror::Error;
+
+// prometheus-client encodes the registry in the OpenMetrics 1.0 text format, where sample timestamps are in
+// seconds. Scrapers that don't negotiate OpenMetrics get the Prometheus text format 0.0.4 instead, where timestamps
+// are in milliseconds, so that the historical points keep Fitbit's dates instead of being read 1000x too early.
+
+/// The exposition format of the metrics endpoints, negotiated with the `Accept` header of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpositionFormat {
+    /// OpenMetrics 1.0 text format.
+    OpenMetrics,
+    /// Prometheus text format 0.0.4.
+    Text,
+}
+
+impl ExpositionFormat {
+    /// Picks the format from the `Accept` header of a request.
+    ///
+    /// OpenMetrics is served when it's accepted with at least the same quality as `text/plain`, which is the case
+    /// for the default `Accept` header of Prometheus. Requests without an `Accept` header get the text format.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return ExpositionFormat::Text,
+        };
+
+        let mut openmetrics_quality: f32 = 0.0;
+        let mut text_quality: f32 = 0.0;
+        for media_range in accept.split(',') {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match media_type.as_str() {
+                "application/openmetrics-text" => openmetrics_quality = openmetrics_quality.max(quality),
+                "text/plain" | "text/*" | "*/*" => text_quality = text_quality.max(quality),
+                _ => {}
+            }
+        }
+
+        if openmetrics_quality > 0.0 && openmetrics_quality >= text_quality {
+            ExpositionFormat::OpenMetrics
+        } else {
+            ExpositionFormat::Text
+        }
+    }
+
+    /// The `Content-Type` header of the responses in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExpositionFormat::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            ExpositionFormat::Text => "text/plain; version=0.0.4; charset=utf-8",
+        }
+    }
+
+    /// Encodes the registry in this format.
+    pub fn encode(&self, registry: &Registry) -> Result<String, std::fmt::Error> {
+        let mut txt = String::new();
+        encode(&mut txt, registry)?;
+        Ok(self.convert(txt))
+    }
+
+    /// Encodes the registry in this format chunk by chunk, so that the whole exposition is never held in memory.
+    ///
+    /// The chunks end at a line boundary and are at least `chunk_size` bytes long, except for the last one
+    /// (or a single line longer than `chunk_size`).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the registry cannot be encoded, or if `on_chunk` fails, e.g. because the client is gone.
+    pub fn encode_chunks<F>(&self, registry: &Registry, chunk_size: usize, on_chunk: F) -> fmt::Result
+    where
+        F: FnMut(String) -> fmt::Result,
+    {
+        let mut writer = ChunkWriter { format: *self, buf: String::with_capacity(chunk_size), chunk_size, on_chunk };
+        encode(&mut writer, registry)?;
+        let rest = std::mem::take(&mut writer.buf);
+        if rest.is_empty() {
+            Ok(())
+        } else {
+            (writer.on_chunk)(self.convert(rest))
+        }
+    }
+
+    /// Encodes metric families, e.g. the ones loaded from the sample store, in this format.
+    pub fn encode_families(&self, families: &[MetricFamily]) -> String {
+        self.convert(encode_openmetrics(families))
+    }
+
+    /// Encodes the registry in this format, relabeled with `relabel`, after checking the relabeled exposition with
+    /// `validate_openmetrics` (`--strict-exposition`).
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidExposition` with the problems found, or if the registry cannot be encoded.
+    pub fn encode_strict(&self, registry: &Registry, relabel: &ExpositionRelabel) -> Result<String, InvalidExposition> {
+        let mut openmetrics = String::new();
+        encode(&mut openmetrics, registry).map_err(|err| InvalidExposition(vec![format!("Encoding error: {}", err)]))?;
+        let openmetrics = relabel.apply(openmetrics);
+        validate_openmetrics(&openmetrics)?;
+        Ok(self.convert(openmetrics))
+    }
+
+    /// Encodes metric families in this format, relabeled with `relabel`, after checking the relabeled exposition
+    /// with `validate_openmetrics`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidExposition` with the problems found.
+    pub fn encode_families_strict(&self, families: &[MetricFamily], relabel: &ExpositionRelabel) -> Result<String, InvalidExposition> {
+        let openmetrics = relabel.apply(encode_openmetrics(families));
+        validate_openmetrics(&openmetrics)?;
+        Ok(self.convert(openmetrics))
+    }
+
+    fn convert(&self, openmetrics: String) -> String {
+        match self {
+            ExpositionFormat::OpenMetrics => openmetrics,
+            ExpositionFormat::Text => openmetrics_to_text(&openmetrics),
+        }
+    }
+}
+
+/// Buffers the OpenMetrics exposition written by `encode`, and hands it over in chunks of whole lines, converted to
+/// the format of the response. The conversion to the text format is line by line, hence the line boundaries.
+struct ChunkWriter<F> {
+    format: ExpositionFormat,
+    buf: String,
+    chunk_size: usize,
+    on_chunk: F,
+}
+
+impl<F: FnMut(String) -> fmt::Result> fmt::Write for ChunkWriter<F> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buf.push_str(s);
+        if self.buf.len() >= self.chunk_size {
+            if let Some(end) = self.buf.rfind('\n') {
+                let rest = self.buf.split_off(end + 1);
+                let chunk = std::mem::replace(&mut self.buf, rest);
+                (self.on_chunk)(self.format.convert(chunk))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A metric family of an OpenMetrics exposition, e.g. the one of `fitbit_steps`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricFamily {
+    pub name: String,
+    /// The type of the `# TYPE` line, e.g. "gauge" or "counter".
+    pub metric_type: String,
+    pub help: String,
+    pub unit: Option<String>,
+    pub samples: Vec<Sample>,
+}
+
+/// A sample of a metric family.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// The name of the sample, which can have a suffix, e.g. `fitbit_webhook_notifications_total`.
+    pub name: String,
+    /// The labels as encoded between the braces, e.g. `stage="deep"`, or an empty string.
+    pub labels: String,
+    pub value: f64,
+    /// The timestamp in milliseconds, if the sample has one (e.g. the historical steps).
+    pub timestamp_ms: Option<i64>,
+}
+
+impl MetricFamily {
+    /// Builds a gauge family, e.g. `MetricFamily::gauge("fitbit_steps", vec![Sample::new("fitbit_steps", "", 8123.0, None)])`.
+    pub fn gauge(name: &str, samples: Vec<Sample>) -> Self {
+        MetricFamily { name: name.to_string(), metric_type: "gauge".to_string(), help: "Help".to_string(), unit: None, samples }
+    }
+}
+
+impl Sample {
+    /// Builds a sample, e.g. `Sample::new("fitbit_steps", "", 8123.0, None)`.
+    pub fn new(name: &str, labels: &str, value: f64, timestamp_ms: Option<i64>) -> Self {
+        Sample { name: name.to_string(), labels: labels.to_string(), value, timestamp_ms }
+    }
+}
+
+/// Parses an OpenMetrics exposition into metric families. Exemplars are dropped.
+pub fn parse_openmetrics(openmetrics: &str) -> Vec<MetricFamily> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+    for line in openmetrics.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let (keyword, rest) = comment.split_once(' ').unwrap_or((comment, ""));
+            let (name, value) = rest.split_once(' ').unwrap_or((rest, ""));
+            if !matches!(keyword, "HELP" | "TYPE" | "UNIT") {
+                continue;
+            }
+            if families.last().is_none_or(|family| family.name != name) {
+                families.push(MetricFamily {
+                    name: name.to_string(),
+                    metric_type: "unknown".to_string(),
+                    help: String::new(),
+                    unit: None,
+                    samples: Vec::new(),
+                });
+            }
+            let family = families.last_mut().unwrap();
+            match keyword {
+                "HELP" => family.help = value.to_string(),
+                "TYPE" => family.metric_type = value.to_string(),
+                _ => family.unit = Some(value.to_string()),
+            }
+        } else if !line.is_empty() {
+            if let (Some(family), Some(sample)) = (families.last_mut(), parse_sample(line)) {
+                family.samples.push(sample);
+            }
+        }
+    }
+    families
+}
+
+/// Encodes metric families in the OpenMetrics text format.
+pub fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let mut txt = String::new();
+    for family in families {
+        txt.push_str(&format!("# HELP {} {}\n", family.name, family.help));
+        txt.push_str(&format!("# TYPE {} {}\n", family.name, family.metric_type));
+        if let Some(unit) = &family.unit {
+            txt.push_str(&format!("# UNIT {} {}\n", family.name, unit));
+        }
+        for sample in &family.samples {
+            txt.push_str(&sample.name);
+            if !sample.labels.is_empty() {
+                txt.push_str(&format!("{{{}}}", sample.labels));
+            }
+            txt.push(' ');
+            txt.push_str(&format_value(sample.value));
+            if let Some(timestamp_ms) = sample.timestamp_ms {
+                txt.push_str(&format!(" {}", timestamp_ms as f64 / 1000.0));
+            }
+            txt.push('\n');
+        }
+    }
+    txt.push_str("# EOF\n");
+    txt
+}
+
+fn format_value(value: f64) -> String {
+    match value {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Parses a sample line, e.g. `fitbit_steps_by_date{date="2023-03-04"} 42 1677888000.5`.
+fn parse_sample(line: &str) -> Option<Sample> {
+    let (series, value, timestamp) = split_sample(line);
+    let (name, labels) = match series.split_once('{') {
+        Some((name, labels)) => (name, labels.strip_suffix('}')?),
+        None => (series, ""),
+    };
+    Some(Sample {
+        name: name.to_string(),
+        labels: labels.to_string(),
+        value: value.parse().ok()?,
+        timestamp_ms: timestamp.and_then(|timestamp| timestamp.parse::<f64>().ok()).map(|timestamp| (timestamp * 1000.0).round() as i64),
+    })
+}
+
+/// Parses the labels of a sample, e.g. `stage="deep",type="stages"`, into their names and unescaped values.
+pub fn parse_labels(labels: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut rest = labels.trim_start_matches(',');
+    while let Some((name, quoted)) = rest.split_once("=\"") {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        let mut end = quoted.len();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, escaped)) => value.push(escaped),
+                    None => {}
+                },
+                '"' => {
+                    end = index + 1;
+                    break;
+                }
+                _ => value.push(c),
+            }
+        }
+        parsed.push((name.trim().to_string(), value));
+        rest = quoted[end..].trim_start_matches(',');
+    }
+    parsed
+}
+
+/// The problems found in an exposition by `validate_openmetrics`, each with its line number.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("Invalid exposition: {}", .0.join("; "))]
+pub struct InvalidExposition(pub Vec<String>);
+
+/// The types of the `# TYPE` lines of OpenMetrics 1.0.
+const METRIC_TYPES: [&str; 8] = ["counter", "gauge", "histogram", "gaugehistogram", "stateset", "info", "summary", "unknown"];
+
+/// The suffixes of the samples of a family, e.g. `fitbit_webhook_notifications_total` in the
+/// `fitbit_webhook_notifications` family.
+const SAMPLE_SUFFIXES: [&str; 8] = ["_total", "_created", "_count", "_sum", "_bucket", "_gcount", "_gsum", "_info"];
+
+/// Checks an OpenMetrics exposition the way Prometheus parses it, so that a regression of the encoding fails loudly
+/// here instead of a scrape being rejected: the metric and label names, the escaping of the label values, the values
+/// and timestamps, the `# TYPE` lines, the families declared twice, the samples outside of their family, the
+/// duplicate samples (same series and timestamp), and the final `# EOF`.
+///
+/// # Errors
+///
+/// Returns `InvalidExposition` with every problem found.
+pub fn validate_openmetrics(openmetrics: &str) -> Result<(), InvalidExposition> {
+    let mut errors = Vec::new();
+    let mut families: HashSet<&str> = HashSet::new();
+    let mut family: Option<&str> = None;
+    let mut has_type = false;
+    let mut samples = HashSet::new();
+    // The last timestamp of each series, which must increase
+    let mut last_timestamps = HashMap::new();
+    let mut eof = false;
+    for (index, line) in openmetrics.lines().enumerate() {
+        let mut error = |message: String| errors.push(format!("line {}: {}", index + 1, message));
+        if eof {
+            error("content after # EOF".to_string());
+            break;
+        }
+        if line == "# EOF" {
+            eof = true;
+        } else if let Some(comment) = line.strip_prefix("# ") {
+            let (keyword, rest) = comment.split_once(' ').unwrap_or((comment, ""));
+            let (name, value) = rest.split_once(' ').unwrap_or((rest, ""));
+            if !matches!(keyword, "HELP" | "TYPE" | "UNIT") {
+                error(format!("unknown comment {}", keyword));
+                continue;
+            }
+            if !is_valid_name(name, true) {
+                error(format!("invalid metric name {:?}", name));
+            }
+            if family != Some(name) {
+                if !families.insert(name) {
+                    error(format!("family {} declared twice", name));
+                }
+                family = Some(name);
+                has_type = false;
+            }
+            if keyword == "TYPE" {
+                if has_type {
+                    error(format!("second TYPE of {}", name));
+                }
+                if !METRIC_TYPES.contains(&value) {
+                    error(format!("invalid type {:?} of {}", value, name));
+                }
+                has_type = true;
+            }
+        } else if line.is_empty() {
+            error("empty line".to_string());
+        } else if line.starts_with('#') {
+            error(format!("invalid comment {:?}", line));
+        } else {
+            let (series, value, timestamp) = split_sample(line);
+            let (name, labels) = match series.split_once('{') {
+                Some((name, labels)) => (name, labels.strip_suffix('}')),
+                None => (series, Some("")),
+            };
+            if !is_valid_name(name, true) {
+                error(format!("invalid metric name {:?}", name));
+            }
+            let in_family = family.is_some_and(|family| {
+                name.strip_prefix(family).is_some_and(|suffix| suffix.is_empty() || SAMPLE_SUFFIXES.contains(&suffix))
+            });
+            if !in_family {
+                error(format!("sample {} outside of its family", name));
+            }
+            let labels = match labels.map(validate_labels) {
+                Some(Ok(labels)) => labels,
+                Some(Err(message)) => {
+                    error(format!("{} in {}", message, series));
+                    continue;
+                }
+                None => {
+                    error(format!("unterminated labels in {}", series));
+                    continue;
+                }
+            };
+            if value.parse::<f64>().is_err() {
+                error(format!("invalid value {:?} of {}", value, series));
+            }
+            match timestamp.map(str::parse::<f64>) {
+                Some(Ok(parsed)) => {
+                    let last = last_timestamps.insert((name, labels.clone()), parsed);
+                    if last.is_some_and(|last| parsed < last) {
+                        error(format!("out-of-order timestamp {} of {}", timestamp.unwrap_or_default(), series));
+                    }
+                }
+                Some(Err(_)) => error(format!("invalid timestamp {:?} of {}", timestamp.unwrap_or_default(), series)),
+                None => {}
+            }
+            if !samples.insert((name, labels, timestamp)) {
+                error(format!("duplicate sample {} at {}", series, timestamp.unwrap_or("no timestamp")));
+            }
+        }
+    }
+    if !eof {
+        errors.push("missing # EOF".to_string());
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidExposition(errors))
+    }
+}
+
+/// Returns true if the name is a valid metric name (`[a-zA-Z_:][a-zA-Z0-9_:]*`), or label name without the colons.
+fn is_valid_name(name: &str, colons: bool) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || (colons && c == ':');
+    name.chars().next().is_some_and(|first| !first.is_ascii_digit() && valid_char(first)) && name.chars().all(valid_char)
+}
+
+/// Checks the labels of a sample, e.g. `stage="deep",type="stages"`: the names, the quoting and escaping of the
+/// values (only `\\`, `\"` and `\n`), and the duplicate names.
+///
+/// # Returns
+///
+/// The labels sorted by name, with their escaped values.
+fn validate_labels(labels: &str) -> Result<Vec<(String, String)>, String> {
+    let mut parsed = Vec::new();
+    let mut rest = labels;
+    while !rest.is_empty() {
+        let (name, quoted) = rest.split_once("=\"").ok_or_else(|| format!("invalid label {:?}", rest))?;
+        if !is_valid_name(name, false) {
+            return Err(format!("invalid label name {:?}", name));
+        }
+        let mut chars = quoted.char_indices();
+        let mut end = None;
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, '\\' | '"' | 'n')) => {}
+                    _ => return Err(format!("invalid escape in the value of {}", name)),
+                },
+                '"' => {
+                    end = Some(index);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let end = end.ok_or_else(|| format!("unterminated value of {}", name))?;
+        if parsed.iter().any(|(parsed_name, _)| parsed_name == name) {
+            return Err(format!("duplicate label {}", name));
+        }
+        parsed.push((name.to_string(), quoted[..end].to_string()));
+        rest = &quoted[end + 1..];
+        match rest.strip_prefix(',') {
+            Some(next) => rest = next,
+            None if rest.is_empty() => {}
+            None => return Err(format!("invalid label separator {:?}", rest)),
+        }
+    }
+    parsed.sort();
+    Ok(parsed)
+}
+
+/// Converts an OpenMetrics exposition to the Prometheus text format 0.0.4.
+///
+/// The `# EOF` and `# UNIT` lines and the exemplars are dropped, the `unknown` type is renamed to `untyped`,
+/// and the sample timestamps are converted from seconds to milliseconds.
+fn openmetrics_to_text(openmetrics: &str) -> String {
+    let mut txt = String::with_capacity(openmetrics.len());
+    for line in openmetrics.lines() {
+        if line == "# EOF" || line.starts_with("# UNIT ") {
+            continue;
+        }
+        if line.starts_with('#') {
+            match line.strip_suffix(" unknown").filter(|_| line.starts_with("# TYPE ")) {
+                Some(type_line) => txt.push_str(&format!("{} untyped", type_line)),
+                None => txt.push_str(line),
+            }
+        } else {
+            txt.push_str(&convert_sample(line));
+        }
+        txt.push('\n');
+    }
+    txt
+}
+
+/// Converts a sample line, e.g. `fitbit_steps 8123 1677888000 # {trace_id="..."} 1.0`, to the text format.
+fn convert_sample(line: &str) -> String {
+    let (series, value, timestamp) = split_sample(line);
+    match timestamp.and_then(|timestamp| timestamp.parse::<f64>().ok()) {
+        Some(timestamp) => format!("{} {} {}", series, value, (timestamp * 1000.0).round() as i64),
+        None => format!("{} {}", series, value),
+    }
+}
+
+/// Splits a sample line into the series (name and labels), the value and the timestamp, dropping the exemplar.
+fn split_sample(line: &str) -> (&str, &str, Option<&str>) {
+    // The label values may contain spaces, so the value is looked for after the closing brace of the labels.
+    let labels_end = match line.find('{') {
+        Some(start) if line[..start].find(' ').is_none() => closing_brace(line, start).map(|end| end + 1),
+        _ => None,
+    };
+    let name_end = labels_end.unwrap_or_else(|| line.find(' ').unwrap_or(line.len()));
+    let (series, rest) = line.split_at(name_end);
+
+    // Drop the exemplar
+    let rest = rest.split(" # ").next().unwrap_or_default();
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().unwrap_or_default();
+    (series, value, fields.next())
+}
+
+/// Returns the index of the brace closing the labels opened at `start`, skipping the quoted label values.
+fn closing_brace(line: &str, start: usize) -> Option<usize> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (index, c) in line[start..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '}' if !in_quotes => return Some(start + index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The prefix of the names of the registry, replaced by `ExpositionRelabel::prefix`.
+pub const DEFAULT_METRIC_PREFIX: &str = "fitbit_";
+
+/// The prefix of the served metric names, e.g. "health_" for `health_steps`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricPrefix(String);
+
+impl Default for MetricPrefix {
+    fn default() -> Self {
+        MetricPrefix(DEFAULT_METRIC_PREFIX.to_string())
+    }
+}
+
+impl std::str::FromStr for MetricPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Empty, or the start of a valid name
+        if !s.is_empty() && !is_valid_name(s, true) {
+            return Err(format!("Invalid metric prefix: {}", s));
+        }
+        Ok(MetricPrefix(s.to_string()))
+    }
+}
+
+/// The names of the labels of the exported metrics, which a constant label can't reuse without duplicating them.
+pub const RESERVED_LABEL_NAMES: [&str; 22] = [
+    "activity_name",
+    "activity_type",
+    "cache",
+    "category",
+    "classification",
+    "collection_type",
+    "collector",
+    "date",
+    "device",
+    "device_id",
+    "estimated",
+    "family",
+    "friend",
+    "goal",
+    "is_main_sleep",
+    "le",
+    "log_id",
+    "source",
+    "stage",
+    "time",
+    "user_id",
+    "value",
+];
+
+/// A constant label added to every sample, given as `name=value`, e.g. `instance=home`. Its name can't be one of
+/// `RESERVED_LABEL_NAMES`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstLabel {
+    pub name: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for ConstLabel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=').ok_or_else(|| format!("Invalid label: {} (expected name=value)", s))?;
+        if !is_valid_name(name, false) || name.starts_with("__") {
+            return Err(format!("Invalid label name: {}", name));
+        }
+        if RESERVED_LABEL_NAMES.contains(&name) {
+            return Err(format!("Label name already used by the metrics: {}", name));
+        }
+        Ok(ConstLabel { name: name.to_string(), value: value.to_string() })
+    }
+}
+
+/// The metric name prefix and the constant labels of the served expositions (`--metric-prefix`, `--const-label`),
+/// so that the metrics of several exporters scraped by the same Prometheus don't collide.
+///
+/// They're applied to the encoded exposition, so that the names of the registry and of the archived samples stay
+/// the same whatever the configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpositionRelabel {
+    /// The prefix replacing `DEFAULT_METRIC_PREFIX`, or `None` to keep it.
+    prefix: Option<String>,
+    const_labels: Vec<ConstLabel>,
+    /// The constant labels, encoded as between the braces of a sample, e.g. `instance="home"`.
+    labels: String,
+}
+
+impl ExpositionRelabel {
+    pub fn new(prefix: &MetricPrefix, labels: &[ConstLabel]) -> Self {
+        let encoded_labels = labels
+            .iter()
+            .map(|label| format!("{}=\"{}\"", label.name, label.value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+            .collect::<Vec<_>>()
+            .join(",");
+        ExpositionRelabel { prefix: (prefix.0 != DEFAULT_METRIC_PREFIX).then(|| prefix.0.clone()), const_labels: labels.to_vec(), labels: encoded_labels }
+    }
+
+    /// The constant labels, e.g. for the event sinks.
+    pub fn const_labels(&self) -> &[ConstLabel] {
+        &self.const_labels
+    }
+
+    /// Applies the prefix and the labels to an exposition in either format, or to a chunk of whole lines of it.
+    pub fn apply(&self, exposition: String) -> String {
+        if self.prefix.is_none() && self.labels.is_empty() {
+            return exposition;
+        }
+        let mut relabeled = String::with_capacity(exposition.len());
+        for line in exposition.lines() {
+            match line.strip_prefix("# ").and_then(|comment| comment.split_once(' ')) {
+                // `# HELP <name> ...`, `# TYPE <name> ...` or `# UNIT <name> ...`
+                Some((keyword @ ("HELP" | "TYPE" | "UNIT"), rest)) => {
+                    let (name, rest) = match rest.split_once(' ') {
+                        Some((name, rest)) => (name, format!(" {}", rest)),
+                        None => (rest, String::new()),
+                    };
+                    relabeled.push_str(&format!("# {} {}{}", keyword, self.rename(name), rest));
+                }
+                _ if line.starts_with('#') || line.is_empty() => relabeled.push_str(line),
+                _ => {
+                    let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+                    let (name, rest) = line.split_at(name_end);
+                    relabeled.push_str(&self.rename(name));
+                    match (self.labels.is_empty(), rest.strip_prefix('{')) {
+                        (true, _) => relabeled.push_str(rest),
+                        (false, Some(labels)) => relabeled.push_str(&format!("{{{},{}", self.labels, labels)),
+                        (false, None) => relabeled.push_str(&format!("{{{}}}{}", self.labels, rest)),
+                    }
+                }
+            }
+            relabeled.push('\n');
+        }
+        relabeled
+    }
+
+    /// Applies the prefix to a metric name, e.g. "fitbit_steps" becomes "health_steps".
+    pub fn rename(&self, name: &str) -> String {
+        match (&self.prefix, name.strip_prefix(DEFAULT_METRIC_PREFIX)) {
+            (Some(prefix), Some(rest)) => format!("{}{}", prefix, rest),
+            _ => name.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_default_accept_header_negotiates_openmetrics() {
+        let accept = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+        assert_eq!(ExpositionFormat::negotiate(Some(accept)), ExpositionFormat::OpenMetrics);
+        assert_eq!(ExpositionFormat::negotiate(Some("text/plain")), ExpositionFormat::Text);
+        assert_eq!(ExpositionFormat::negotiate(Some("text/plain, application/openmetrics-text;q=0.5")), ExpositionFormat::Text);
+        assert_eq!(ExpositionFormat::negotiate(None), ExpositionFormat::Text);
+    }
+
+    #[test]
+    fn timestamps_are_converted_to_milliseconds() {
+        let openmetrics = "# HELP fitbit_steps Number of steps.\n\
+                           # TYPE fitbit_steps gauge\n\
+                           fitbit_steps 8123 1677888000\n\
+                           fitbit_steps_by_date{date=\"2023-03-04 }\"} 42 1677888000.5 # {trace_id=\"abc\"} 1.0\n\
+                           fitbit_water_ml 1500.0\n\
+                           # EOF\n";
+        assert_eq!(
+            openmetrics_to_text(openmetrics),
+            "# HELP fitbit_steps Number of steps.\n\
+             # TYPE fitbit_steps gauge\n\
+             fitbit_steps 8123 1677888000000\n\
+             fitbit_steps_by_date{date=\"2023-03-04 }\"} 42 1677888000500\n\
+             fitbit_water_ml 1500.0\n"
+        );
+    }
+
+    #[test]
+    fn openmetrics_round_trip() {
+        let openmetrics = "# HELP fitbit_steps Total number of steps.\n\
+                           # TYPE fitbit_steps gauge\n\
+                           fitbit_steps 8123 1677888000.5\n\
+                           # HELP fitbit_sleep_stage_seconds Time spent in each sleep stage.\n\
+                           # TYPE fitbit_sleep_stage_seconds gauge\n\
+                           # UNIT fitbit_sleep_stage_seconds seconds\n\
+                           fitbit_sleep_stage_seconds{stage=\"deep\"} 4980.0\n\
+                           fitbit_sleep_stage_seconds{stage=\"light\"} 12000.5\n\
+                           # EOF\n";
+        let families = parse_openmetrics(openmetrics);
+        assert_eq!(families.len(), 2);
+        assert_eq!(families[0].samples[0].timestamp_ms, Some(1677888000500));
+        assert_eq!(families[1].unit.as_deref(), Some("seconds"));
+        assert_eq!(families[1].samples[0].labels, "stage=\"deep\"");
+        assert_eq!(parse_openmetrics(&encode_openmetrics(&families)), families);
+        assert_eq!(
+            parse_labels("stage=\"deep\",name=\"say \\\"hi\\\", x\""),
+            vec![("stage".to_string(), "deep".to_string()), ("name".to_string(), "say \"hi\", x".to_string())]
+        );
+    }
+
+    #[test]
+    fn relabel_renames_and_labels_every_sample() {
+        let labels: Vec<ConstLabel> = vec!["instance=home".parse().unwrap(), "person=al\"ice".parse().unwrap()];
+        let relabel = ExpositionRelabel::new(&"health_".parse().unwrap(), &labels);
+        let openmetrics = "# HELP fitbit_steps Total number of steps.\n\
+                           # TYPE fitbit_steps gauge\n\
+                           fitbit_steps 8123\n\
+                           fitbit_sleep_stage_seconds{stage=\"deep\"} 3600 1677888000\n\
+                           # EOF\n";
+        assert_eq!(
+            relabel.apply(openmetrics.to_string()),
+            "# HELP health_steps Total number of steps.\n\
+             # TYPE health_steps gauge\n\
+             health_steps{instance=\"home\",person=\"al\\\"ice\"} 8123\n\
+             health_sleep_stage_seconds{instance=\"home\",person=\"al\\\"ice\",stage=\"deep\"} 3600 1677888000\n\
+             # EOF\n"
+        );
+        assert_eq!(ExpositionRelabel::new(&MetricPrefix::default(), &[]), ExpositionRelabel::default());
+        assert!("1fitbit_".parse::<MetricPrefix>().is_err());
+        assert!("__name__=x".parse::<ConstLabel>().is_err());
+        assert!("instance".parse::<ConstLabel>().is_err());
+        // It would duplicate the label of the sleep stage metrics
+        assert!("stage=x".parse::<ConstLabel>().is_err());
+    }
+
+    #[test]
+    fn strict_validation_reports_malformed_expositions() {
+        let mut registry = Registry::default();
+        let steps = prometheus_client::metrics::gauge::MultiPointGauge::<i64>::default();
+        registry.register("fitbit_steps", "Total number of steps", steps.clone());
+        steps.push(8123, None);
+        steps.push(8000, Some(std::time::Duration::from_secs(1677801600)));
+        assert!(ExpositionFormat::Text.encode_strict(&registry, &ExpositionRelabel::default()).is_ok());
+        // The relabeled exposition is the one checked
+        let relabel = ExpositionRelabel::new(&"health_".parse().unwrap(), &["instance=home".parse().unwrap()]);
+        let relabeled = ExpositionFormat::OpenMetrics.encode_strict(&registry, &relabel).unwrap();
+        assert!(relabeled.starts_with("# HELP health_steps ") && relabeled.contains("\nhealth_steps{instance=\"home\"} "));
+
+        let openmetrics = "# HELP fitbit_steps Number of steps.\n\
+                           # TYPE fitbit_steps gauge\n\
+                           fitbit_steps 8123 1677888000\n\
+                           fitbit_steps 8124 1677888000\n\
+                           fitbit_steps 8000 1677801600\n\
+                           fitbit_sleep_stage_seconds{stage=\"deep\",stage=\"light\"} 4980.0\n\
+                           # TYPE fitbit_workouts_total counting\n\
+                           fitbit_workouts_total{type=\"run\\t\"} 1\n\
+                           # TYPE fitbit_steps gauge\n";
+        let InvalidExposition(errors) = validate_openmetrics(openmetrics).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "line 4: duplicate sample fitbit_steps at 1677888000",
+                "line 5: out-of-order timestamp 1677801600 of fitbit_steps",
+                "line 6: sample fitbit_sleep_stage_seconds outside of its family",
+                "line 6: duplicate label stage in fitbit_sleep_stage_seconds{stage=\"deep\",stage=\"light\"}",
+                "line 7: invalid type \"counting\" of fitbit_workouts_total",
+                "line 8: invalid escape in the value of type in fitbit_workouts_total{type=\"run\\t\"}",
+                "line 9: family fitbit_steps declared twice",
+                "missing # EOF",
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_end_at_line_boundaries() {
+        let mut registry = Registry::default();
+        let steps = prometheus_client::metrics::gauge::MultiPointGauge::<i64>::default();
+        registry.register("fitbit_steps", "Total number of steps", steps.clone());
+        for day in 0..20 {
+            steps.push(8000 + day, Some(std::time::Duration::from_secs(1677801600 + day as u64 * 86400)));
+        }
+
+        let mut chunks = Vec::new();
+        ExpositionFormat::Text
+            .encode_chunks(&registry, 64, |chunk| {
+                chunks.push(chunk);
+                Ok(())
+            })
+            .unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.ends_with('\n')));
+        assert_eq!(chunks.concat(), ExpositionFormat::Text.encode(&registry).unwrap());
+    }
+}
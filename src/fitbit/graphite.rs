@@ -0,0 +1,116 @@
+use chrono::Utc;
+use tracing::{debug, error};
+use prometheus_client::encoding::text::encode;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::fitbit::FitbitMetrics;
+use crate::fitbit::exposition::{parse_labels, parse_openmetrics, ExpositionRelabel, MetricFamily};
+
+/// Options of the push of the metrics to Graphite, built from the command line arguments
+/// (see `cmd::Args::graphite_options`).
+#[derive(Debug, Clone)]
+pub struct GraphiteOptions {
+    /// The address of the plaintext listener of Carbon, e.g. "graphite:2003".
+    pub address: String,
+    /// The prefix of the metric paths, e.g. "health.alice". An empty prefix is omitted.
+    pub prefix: String,
+    /// The delay between two pushes.
+    pub flush_interval: Duration,
+    /// The metric name prefix and the constant labels (sent as tags).
+    pub relabel: ExpositionRelabel,
+}
+
+/// Pushes the current values of the metrics to Graphite every `flush_interval`, in the plaintext protocol.
+///
+/// The values are the ones of the last refresh of the metrics, so the background poller has to run along.
+/// A failed push is logged and retried at the next flush.
+/// FYI: https://graphite.readthedocs.io/en/latest/feeding-carbon.html#the-plaintext-protocol
+///
+/// # Arguments
+///
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+/// * `options` - The `GraphiteOptions`.
+pub async fn push_to_graphite_periodically(fitbit_metrics: Arc<FitbitMetrics>, options: GraphiteOptions) {
+    loop {
+        tokio::time::sleep(options.flush_interval).await;
+        match push_to_graphite(&fitbit_metrics, &options).await {
+            Ok(lines) => debug!("[push_to_graphite_periodically] Pushed {} lines to {}", lines, options.address),
+            Err(err) => error!("[push_to_graphite_periodically] Error pushing the metrics to {}: {}", options.address, err),
+        }
+    }
+}
+
+async fn push_to_graphite(fitbit_metrics: &FitbitMetrics, options: &GraphiteOptions) -> Result<usize, Box<dyn Error>> {
+    let mut txt = String::new();
+    encode(&mut txt, &fitbit_metrics.registry())?;
+    let lines = encode_graphite(&parse_openmetrics(&options.relabel.apply(txt)), &options.prefix, Utc::now().timestamp());
+
+    let mut stream = TcpStream::connect(&options.address).await?;
+    stream.write_all(lines.concat().as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(lines.len())
+}
+
+/// Encodes the samples as plaintext lines, e.g. `health.fitbit_sleep_stage_seconds;stage=deep 4980 1677888000\n`.
+///
+/// The labels are sent as Graphite tags. The samples without a timestamp are stamped with `now` (in seconds),
+/// and the ones that are not a number are skipped, since Graphite can't store them.
+fn encode_graphite(families: &[MetricFamily], prefix: &str, now: i64) -> Vec<String> {
+    let mut lines = Vec::new();
+    for sample in families.iter().flat_map(|family| &family.samples) {
+        if sample.value.is_nan() {
+            continue;
+        }
+        let mut path = match prefix {
+            "" => sanitize(&sample.name),
+            _ => format!("{}.{}", prefix.trim_end_matches('.'), sanitize(&sample.name)),
+        };
+        for (name, value) in parse_labels(&sample.labels) {
+            // Graphite rejects tags with an empty value.
+            if !value.is_empty() {
+                path.push_str(&format!(";{}={}", sanitize(&name), sanitize(&value)));
+            }
+        }
+        let timestamp = sample.timestamp_ms.map(|timestamp_ms| timestamp_ms / 1000).unwrap_or(now);
+        lines.push(format!("{} {} {}\n", path, sample.value, timestamp));
+    }
+    lines
+}
+
+/// Replaces the characters that have a meaning in the plaintext protocol (spaces, dots, `;`, `=`...) by `_`.
+fn sanitize(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::exposition::Sample;
+
+    #[test]
+    fn samples_are_encoded_as_plaintext_lines() {
+        let families = vec![MetricFamily::gauge(
+            "fitbit_steps",
+            vec![
+                Sample::new("fitbit_steps", "", 8123.0, None),
+                Sample::new("fitbit_steps", "", 7000.0, Some(1677801600000)),
+                Sample::new("fitbit_activity_minutes", "name=\"Morning run\",type=\"\"", 42.5, None),
+                Sample::new("fitbit_steps", "", f64::NAN, None),
+            ],
+        )];
+
+        assert_eq!(
+            encode_graphite(&families, "health.alice.", 1677888000),
+            vec![
+                "health.alice.fitbit_steps 8123 1677888000\n",
+                "health.alice.fitbit_steps 7000 1677801600\n",
+                "health.alice.fitbit_activity_minutes;name=Morning_run 42.5 1677888000\n",
+            ]
+        );
+        assert_eq!(encode_graphite(&families, "", 1677888000)[0], "fitbit_steps 8123 1677888000\n");
+    }
+}
@@ -1,50 +1,757 @@
-use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use chrono::{Duration as ChronoDuration, FixedOffset, NaiveTime};
 use chrono::NaiveDate;
-use prometheus_client::registry::Registry;
 use std::error::Error;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::thread::sleep;
 use std::path::Path;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use prometheus_client::encoding::text::encode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
 use std::time::Duration;
 use tokio::sync::RwLock;
-use log::debug;
+use tracing::{debug, info, warn};
 
-use crate::fitbit::FitbitClient;
+use crate::fitbit::{FitbitApi, FitbitError};
 use crate::fitbit::FitbitMetrics;
+use crate::fitbit::client::retry_rate_limited;
 use crate::fitbit::cmd;
+use crate::fitbit::cmd::{OutputFormat, PlaceholderDays, TimestampPosition};
+use crate::fitbit::export::{encode_apple_health_xml, encode_google_fit_csv, encode_parquet};
+use crate::fitbit::exposition::validate_openmetrics;
+use crate::fitbit::models::{Bpm, Meters, Steps, WeightLog};
 
+/// Number of days fetched per request of a historical dump, and recorded at once in its checkpoint.
+const DUMP_CHUNK_DAYS: i64 = 90;
 
-pub async fn dump_historical_metrics(client: Arc<RwLock<FitbitClient>>, metrics: Arc<FitbitMetrics>, args: cmd::Args) -> Result<(), Box<dyn Error>> {
-    let yesterday = Utc::now().date_naive().pred_opt().unwrap();
+/// Number of days served by /history without a `start` parameter.
+const DEFAULT_HISTORY_DAYS: i64 = 30;
+
+/// Maximum number of days of a /history request, the longest range of the resting heart rate endpoint.
+/// FYI: https://dev.fitbit.com/build/reference/web-api/heartrate-timeseries/get-heartrate-timeseries-by-date-range/
+pub const MAX_HISTORY_DAYS: i64 = 365;
+
+/// A daily metric served by /history, selected with its `metrics` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryMetric {
+    /// The daily steps, pushed to `fitbit_steps`.
+    Steps,
+    /// The daily resting heart rate, pushed to `fitbit_resting_heart_rate_bpm`.
+    RestingHeartRate,
+    /// The daily floors climbed, pushed to `fitbit_daily_floors`.
+    Floors,
+    /// The daily elevation climbed, pushed to `fitbit_daily_elevation_meters`.
+    Elevation,
+}
+
+impl HistoryMetric {
+    /// The metric family the daily values are pushed to.
+    pub fn family(&self) -> &'static str {
+        match self {
+            HistoryMetric::Steps => "fitbit_steps",
+            HistoryMetric::RestingHeartRate => "fitbit_resting_heart_rate_bpm",
+            HistoryMetric::Floors => "fitbit_daily_floors",
+            HistoryMetric::Elevation => "fitbit_daily_elevation_meters",
+        }
+    }
+}
+
+impl FromStr for HistoryMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "steps" => Ok(HistoryMetric::Steps),
+            "resting_heart_rate" => Ok(HistoryMetric::RestingHeartRate),
+            "floors" => Ok(HistoryMetric::Floors),
+            "elevation" => Ok(HistoryMetric::Elevation),
+            _ => Err(format!("Invalid metric: {} (expected steps, resting_heart_rate, floors or elevation)", s)),
+        }
+    }
+}
+
+impl fmt::Display for HistoryMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryMetric::Steps => write!(f, "steps"),
+            HistoryMetric::RestingHeartRate => write!(f, "resting_heart_rate"),
+            HistoryMetric::Floors => write!(f, "floors"),
+            HistoryMetric::Elevation => write!(f, "elevation"),
+        }
+    }
+}
+
+/// The parameters of a /history request, e.g. `?start=2024-01-01&end=2024-01-31&metrics=steps,resting_heart_rate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryQuery {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub metrics: Vec<HistoryMetric>,
+}
+
+impl HistoryQuery {
+    /// Parses the query string of a /history request. `end` defaults to yesterday, `start` to 30 days before `end`,
+    /// and `metrics` to the steps.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message if a parameter is invalid, or if the range is reversed, ends after `today` or is
+    /// longer than `MAX_HISTORY_DAYS`.
+    pub fn parse(query: Option<&str>, today: NaiveDate) -> Result<Self, String> {
+        let mut start_date = None;
+        let mut end_date = None;
+        let mut metrics = vec![HistoryMetric::Steps];
+        for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            let date = || NaiveDate::parse_from_str(&value, "%Y-%m-%d").map_err(|_| format!("Invalid {}: {} (expected YYYY-MM-DD)", key, value));
+            match key.as_ref() {
+                "start" => start_date = Some(date()?),
+                "end" => end_date = Some(date()?),
+                "metrics" => metrics = value.split(',').map(str::parse).collect::<Result<_, _>>()?,
+                _ => return Err(format!("Unknown parameter: {} (expected start, end or metrics)", key)),
+            }
+        }
+
+        let end_date = end_date.unwrap_or_else(|| today.pred_opt().unwrap());
+        let start_date = start_date.unwrap_or(end_date - ChronoDuration::days(DEFAULT_HISTORY_DAYS));
+        if start_date > end_date {
+            return Err(format!("The start {} is after the end {}", start_date, end_date));
+        }
+        if end_date > today {
+            return Err(format!("The end {} is in the future", end_date));
+        }
+        if (end_date - start_date).num_days() >= MAX_HISTORY_DAYS {
+            return Err(format!("The range from {} to {} is longer than {} days", start_date, end_date, MAX_HISTORY_DAYS));
+        }
+        Ok(Self { start_date, end_date, metrics })
+    }
+}
+
+/// A single historical data point, as written by the CSV and JSON output formats (and converted by `export`).
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoricalSample {
+    pub date: NaiveDate,
+    pub metric: String,
+    pub value: f64,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl HistoricalSample {
+    pub fn new(date: NaiveDate, metric: &str, value: f64) -> Self {
+        Self {
+            date,
+            metric: metric.to_string(),
+            value,
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+/// Progress of a historical dump, saved after every fetched chunk so that a dump interrupted (e.g. by a rate limit)
+/// can be continued with `--resume` instead of starting over.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct DumpCheckpoint {
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    /// The progress of each metric, by name, e.g. "fitbit_steps".
+    metrics: BTreeMap<String, MetricProgress>,
+    /// The progress of the weigh-ins, which aren't daily values. Missing from the checkpoints of older versions.
+    #[serde(default)]
+    weight: WeightProgress,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct MetricProgress {
+    /// The end date of the last completed chunk, or `None` if no chunk was completed yet.
+    last_completed: Option<NaiveDate>,
+    /// The daily values fetched so far.
+    days: Vec<(NaiveDate, f64)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct WeightProgress {
+    /// The end date of the last completed chunk, or `None` if no chunk was completed yet.
+    last_completed: Option<NaiveDate>,
+    /// The weigh-ins fetched so far.
+    weight_logs: Vec<WeightLog>,
+}
+
+impl DumpCheckpoint {
+    fn new(start_date: NaiveDate, end_date: NaiveDate) -> Self {
+        Self { start_date, end_date, metrics: BTreeMap::new(), weight: WeightProgress::default() }
+    }
+
+    fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let json = fs::read(path).map_err(|err| format!("Cannot read the checkpoint file {}: {}", path.display(), err))?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// Saves the checkpoint to a temporary file first, so that an interruption never leaves a truncated checkpoint.
+    fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Fetches the daily values of a metric from `start_date` to `end_date` (inclusive).
+async fn fetch_daily_values(client: &dyn FitbitApi, metric: HistoryMetric, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, f64)>, FitbitError> {
+    Ok(match metric {
+        HistoryMetric::Steps => client.fetch_steps_range(start_date, end_date).await?.into_iter().map(|(date, steps)| (date, steps.0 as f64)).collect(),
+        HistoryMetric::RestingHeartRate => client.fetch_resting_heart_rate_range(start_date, end_date).await?.into_iter().map(|(date, bpm)| (date, bpm.0)).collect(),
+        HistoryMetric::Floors => client.fetch_floors_range(start_date, end_date).await?.into_iter().map(|(date, floors)| (date, floors as f64)).collect(),
+        HistoryMetric::Elevation => client.fetch_elevation_range(start_date, end_date).await?.into_iter().map(|(date, meters)| (date, meters.0)).collect(),
+    })
+}
+
+/// Fetches the daily values of a metric over the range of the checkpoint, in chunks of `DUMP_CHUNK_DAYS` days,
+/// starting after the last completed chunk. The checkpoint is saved after every chunk, and the rate limits are
+/// waited out (see `retry_rate_limited`).
+///
+/// # Errors
+///
+/// Returns an error if a chunk cannot be fetched, the checkpoint keeping the chunks fetched before, or if the
+/// checkpoint cannot be saved.
+async fn fetch_in_chunks(
+    client: &dyn FitbitApi,
+    checkpoint: &mut DumpCheckpoint,
+    checkpoint_file: &Path,
+    history_metric: HistoryMetric,
+) -> Result<Vec<(NaiveDate, f64)>, Box<dyn Error>> {
+    let metric = history_metric.family();
+    loop {
+        let progress = checkpoint.metrics.entry(metric.to_string()).or_default();
+        let chunk_start = progress.last_completed.map_or(checkpoint.start_date, |date| date + ChronoDuration::days(1));
+        if chunk_start > checkpoint.end_date {
+            break;
+        }
+        let chunk_end = (chunk_start + ChronoDuration::days(DUMP_CHUNK_DAYS - 1)).min(checkpoint.end_date);
+
+        let chunk = match retry_rate_limited(|| fetch_daily_values(client, history_metric, chunk_start, chunk_end)).await {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                warn!("The dump stopped at {} of {}, run it again with --resume to continue from there", chunk_start, metric);
+                return Err(err.into());
+            }
+        };
+        progress.days.extend(chunk);
+        progress.last_completed = Some(chunk_end);
+        checkpoint.save(checkpoint_file)?;
+        debug!("Fetched {} from {} to {}", metric, chunk_start, chunk_end);
+    }
+    Ok(checkpoint.metrics[metric].days.clone())
+}
+
+/// Same as `fetch_in_chunks`, for the weigh-ins, each chunk being fetched in requests of at most 31 days by
+/// `FitbitClient::fetch_weight_range`.
+///
+/// # Errors
+///
+/// Returns an error if a chunk cannot be fetched, the checkpoint keeping the chunks fetched before, or if the
+/// checkpoint cannot be saved.
+async fn fetch_weight_in_chunks(client: &dyn FitbitApi, checkpoint: &mut DumpCheckpoint, checkpoint_file: &Path) -> Result<Vec<WeightLog>, Box<dyn Error>> {
+    loop {
+        let chunk_start = checkpoint.weight.last_completed.map_or(checkpoint.start_date, |date| date + ChronoDuration::days(1));
+        if chunk_start > checkpoint.end_date {
+            break;
+        }
+        let chunk_end = (chunk_start + ChronoDuration::days(DUMP_CHUNK_DAYS - 1)).min(checkpoint.end_date);
+
+        let chunk = match retry_rate_limited(|| client.fetch_weight_range(chunk_start, chunk_end)).await {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                warn!("The dump stopped at {} of fitbit_weight_grams, run it again with --resume to continue from there", chunk_start);
+                return Err(err.into());
+            }
+        };
+        checkpoint.weight.weight_logs.extend(chunk);
+        checkpoint.weight.last_completed = Some(chunk_end);
+        checkpoint.save(checkpoint_file)?;
+        debug!("Fetched fitbit_weight_grams from {} to {}", chunk_start, chunk_end);
+    }
+    Ok(checkpoint.weight.weight_logs.clone())
+}
+
+pub async fn dump_historical_metrics(client: Arc<RwLock<dyn FitbitApi>>, metrics: Arc<FitbitMetrics>, args: cmd::Args) -> Result<(), Box<dyn Error>> {
+    // The last complete day of the user
+    let yesterday = metrics.user_today().pred_opt().unwrap();
     let start_date = args.start_date.unwrap_or_else(|| yesterday - ChronoDuration::days(365));
-    let end_date = args.end_date.unwrap_or_else(|| yesterday);
-    let output_file = args.output_file.unwrap_or_else(|| PathBuf::from("fitbit_historical_metrics.prom"));
-    debug!("start_date: {:?}, end_date: {:?}, output_file: {:?}", start_date, end_date, output_file);
+    let end_date = args.end_date.unwrap_or(yesterday);
+    let output_file = args.output_file.unwrap_or_else(|| PathBuf::from(format!("fitbit_historical_metrics.{}", args.format.extension())));
+    let checkpoint_file = args.checkpoint_file.clone().unwrap_or_else(|| {
+        let mut path = output_file.clone().into_os_string();
+        path.push(".checkpoint.json");
+        PathBuf::from(path)
+    });
+    debug!("start_date: {:?}, end_date: {:?}, output_file: {:?}, format: {:?}", start_date, end_date, output_file, args.format);
+
+    let mut checkpoint = if args.resume {
+        let checkpoint = DumpCheckpoint::load(&checkpoint_file)?;
+        if args.start_date.is_some_and(|date| date != checkpoint.start_date) || args.end_date.is_some_and(|date| date != checkpoint.end_date) {
+            return Err(format!(
+                "The dates differ from the ones of the interrupted dump ({} to {}), start a new dump without --resume",
+                checkpoint.start_date, checkpoint.end_date
+            )
+            .into());
+        }
+        info!("Resuming the dump from {} to {} from {}", checkpoint.start_date, checkpoint.end_date, checkpoint_file.display());
+        checkpoint
+    } else {
+        DumpCheckpoint::new(start_date, end_date)
+    };
 
     let read_locked_client = client.read().await;
 
-    let steps_range_data = read_locked_client.fetch_steps_range(start_date, end_date).await?;
-    for (date, steps) in steps_range_data {
-        // Currently, I treat the NativeDate as UTC. Possibly Fitbit user's timezone configuration can be used:
-        // https://dev.fitbit.com/build/reference/web-api/user/get-profile/
-        let datetime_utc = DateTime::<Utc>::from_utc(date.and_hms_opt(0, 0, 0).unwrap(), Utc);
-        let timestamp = datetime_utc.timestamp() as u64;
-        debug!("date: {:?}, steps: {}, converted timestamp: {:?}", date, steps, timestamp);
+    let mut samples: Vec<HistoricalSample> = Vec::new();
+
+    let steps_range_data = fetch_in_chunks(&*read_locked_client, &mut checkpoint, &checkpoint_file, HistoryMetric::Steps).await?;
+    let steps_range_data = steps_range_data.into_iter().map(|(date, steps)| (date, Steps(steps as u64))).collect();
+    for (date, steps, estimated) in push_steps_range(&metrics, steps_range_data, args.timestamp_position, args.placeholder_days) {
+        let mut sample = HistoricalSample::new(date, "fitbit_steps", steps.0 as f64);
+        if estimated {
+            sample.labels.insert("estimated".to_string(), "true".to_string());
+        }
+        samples.push(sample);
+    }
+
+    let resting_heart_rates = fetch_in_chunks(&*read_locked_client, &mut checkpoint, &checkpoint_file, HistoryMetric::RestingHeartRate).await?;
+    let resting_heart_rates: Vec<(NaiveDate, Bpm)> = resting_heart_rates.into_iter().map(|(date, bpm)| (date, Bpm(bpm))).collect();
+    for (date, bpm) in &resting_heart_rates {
+        samples.push(HistoricalSample::new(*date, HistoryMetric::RestingHeartRate.family(), bpm.0));
+    }
+    push_resting_heart_rates(&metrics, resting_heart_rates, args.timestamp_position);
 
-        metrics.steps.push(steps as i64, Some(Duration::from_secs(timestamp)));
+    let floors_range = fetch_in_chunks(&*read_locked_client, &mut checkpoint, &checkpoint_file, HistoryMetric::Floors).await?;
+    let floors_range = floors_range.into_iter().map(|(date, floors)| (date, floors as u64)).collect();
+    for (date, floors) in push_floors_range(&metrics, floors_range, args.timestamp_position) {
+        samples.push(HistoricalSample::new(date, HistoryMetric::Floors.family(), floors as f64));
+    }
+    let elevation_range = fetch_in_chunks(&*read_locked_client, &mut checkpoint, &checkpoint_file, HistoryMetric::Elevation).await?;
+    let elevation_range = elevation_range.into_iter().map(|(date, meters)| (date, Meters(meters))).collect();
+    for (date, meters) in push_elevation_range(&metrics, elevation_range, args.timestamp_position) {
+        samples.push(HistoricalSample::new(date, HistoryMetric::Elevation.family(), meters.0));
     }
 
-    let mut txt = String::new();
-    encode(&mut txt, &metrics.registry).unwrap();
+    let weight_logs = fetch_weight_in_chunks(&*read_locked_client, &mut checkpoint, &checkpoint_file).await?;
+    for weight_log in push_weight_logs(&metrics, weight_logs) {
+        let mut sample = HistoricalSample::new(weight_log.date, "fitbit_weight_grams", weight_log.weight.as_grams() as f64);
+        sample.labels.insert("time".to_string(), weight_log.time.format("%H:%M:%S").to_string());
+        sample.labels.insert("source".to_string(), weight_log.source().to_string());
+        samples.push(sample);
+    }
+
+    let txt = match args.format {
+        OutputFormat::Prom => {
+            let mut txt = String::new();
+            encode(&mut txt, &metrics.registry()).unwrap();
+            // Fails before writing anything, so that a malformed dump isn't imported
+            if args.strict_exposition {
+                validate_openmetrics(&txt)?;
+            }
+            txt
+        }
+        OutputFormat::Csv => encode_csv(&samples)?,
+        OutputFormat::Json => serde_json::to_string_pretty(&samples)?,
+        OutputFormat::AppleHealthXml => encode_apple_health_xml(&samples),
+        OutputFormat::GoogleFitCsv => encode_google_fit_csv(&samples)?,
+        OutputFormat::Parquet => {
+            // Binary format, written as is
+            let user_id = read_locked_client.fetch_user_id().await.unwrap_or_else(|err| {
+                warn!("Failed to fetch the user ID, exporting the rows with user \"-\": {}", err);
+                "-".to_string()
+            });
+            let mut file = File::create(&output_file)?;
+            file.write_all(&encode_parquet(&samples, &user_id)?)?;
+            remove_checkpoint(&checkpoint_file)?;
+            return Ok(());
+        }
+    };
     println!("=== [Command Line Mode] in the `dump_historical_metrics` > txt >>> ===\n{}", txt);
     println!("=== <<< txt");
 
     let mut file = File::create(&output_file)?;
     file.write_all(txt.as_bytes())?;
 
+    remove_checkpoint(&checkpoint_file)
+}
+
+/// Removes the checkpoint of a completed dump, if any.
+fn remove_checkpoint(checkpoint_file: &Path) -> Result<(), Box<dyn Error>> {
+    match fs::remove_file(checkpoint_file) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+
+/// Pushes the daily steps of a date range with their timestamp, handling its placeholder days as set by
+/// `placeholder_days`, i.e. the zero-filled days before the first day with steps. The days with steps are always
+/// pushed to `fitbit_steps`.
+///
+/// # Returns
+///
+/// The pushed days, each with whether it's a placeholder pushed as estimated (to `fitbit_steps_estimated`).
+pub fn push_steps_range(
+    metrics: &FitbitMetrics,
+    steps_range: Vec<(NaiveDate, Steps)>,
+    timestamp_position: TimestampPosition,
+    placeholder_days: PlaceholderDays,
+) -> Vec<(NaiveDate, Steps, bool)> {
+    let placeholders = steps_range.iter().take_while(|(_, steps)| steps.0 == 0).count();
+    if placeholders > 0 && placeholder_days != PlaceholderDays::Keep {
+        info!("The first {} days from {} have no steps, handled as placeholders: {:?}", placeholders, steps_range[0].0, placeholder_days);
+    }
+
+    let mut pushed = Vec::with_capacity(steps_range.len());
+    for (index, (date, steps)) in steps_range.into_iter().enumerate() {
+        let estimated = index < placeholders && placeholder_days == PlaceholderDays::Estimated;
+        if index < placeholders && placeholder_days == PlaceholderDays::Skip {
+            continue;
+        }
+        let timestamp = daily_timestamp(date, timestamp_position, metrics.utc_offset());
+        debug!("date: {:?}, steps: {}, converted timestamp: {:?}, estimated: {}", date, steps, timestamp, estimated);
+
+        metrics.push_historical_steps(steps.as_i64(), timestamp, estimated);
+        pushed.push((date, steps, estimated));
+    }
+    pushed
+}
+
+/// Pushes the daily resting heart rates of a date range with their timestamp, rounded to the beat. The days without
+/// a resting heart rate are already left out by `FitbitClient::fetch_resting_heart_rate_range`.
+///
+/// # Returns
+///
+/// The number of pushed days.
+pub fn push_resting_heart_rates(metrics: &FitbitMetrics, resting_heart_rates: Vec<(NaiveDate, Bpm)>, timestamp_position: TimestampPosition) -> usize {
+    for (date, bpm) in &resting_heart_rates {
+        metrics.push_historical_resting_heart_rate(bpm.0.round() as i64, daily_timestamp(*date, timestamp_position, metrics.utc_offset()));
+    }
+    resting_heart_rates.len()
+}
+
+/// Pushes the daily floors climbed of a date range with their timestamp.
+///
+/// # Returns
+///
+/// The pushed days.
+pub fn push_floors_range(metrics: &FitbitMetrics, floors_range: Vec<(NaiveDate, u64)>, timestamp_position: TimestampPosition) -> Vec<(NaiveDate, u64)> {
+    for (date, floors) in &floors_range {
+        metrics.push_historical_floors(*floors as i64, daily_timestamp(*date, timestamp_position, metrics.utc_offset()));
+    }
+    floors_range
+}
+
+/// Pushes the daily elevation climbed of a date range with their timestamp, rounded to the meter.
+///
+/// # Returns
+///
+/// The pushed days.
+pub fn push_elevation_range(metrics: &FitbitMetrics, elevation_range: Vec<(NaiveDate, Meters)>, timestamp_position: TimestampPosition) -> Vec<(NaiveDate, Meters)> {
+    for (date, meters) in &elevation_range {
+        metrics.push_historical_elevation(meters.0.round() as i64, daily_timestamp(*date, timestamp_position, metrics.utc_offset()));
+    }
+    elevation_range
+}
+
+/// Pushes the weigh-ins with the timestamp of their time in the user's timezone, rather than of their day, so that
+/// the weigh-ins of the same day are all kept.
+///
+/// # Returns
+///
+/// The pushed weigh-ins.
+pub fn push_weight_logs(metrics: &FitbitMetrics, weight_logs: Vec<WeightLog>) -> Vec<WeightLog> {
+    for weight_log in &weight_logs {
+        let timestamp = Duration::from_secs(metrics.user_timestamp(weight_log.logged_at()).max(0) as u64);
+        metrics.push_historical_weight(weight_log.weight.as_grams(), timestamp);
+    }
+    weight_logs
+}
+
+/// Fetches the metrics of a /history request and pushes them with their timestamp.
+///
+/// # Errors
+///
+/// Returns the error of the first failed fetch. The metrics fetched before it are pushed.
+pub async fn push_history(
+    fitbit_client: &dyn FitbitApi,
+    metrics: &FitbitMetrics,
+    query: &HistoryQuery,
+    timestamp_position: TimestampPosition,
+    placeholder_days: PlaceholderDays,
+) -> Result<(), FitbitError> {
+    for metric in &query.metrics {
+        match metric {
+            HistoryMetric::Steps => {
+                let steps_range = fitbit_client.fetch_steps_range(query.start_date, query.end_date).await?;
+                push_steps_range(metrics, steps_range, timestamp_position, placeholder_days);
+            }
+            HistoryMetric::RestingHeartRate => {
+                let resting_heart_rates = fitbit_client.fetch_resting_heart_rate_range(query.start_date, query.end_date).await?;
+                push_resting_heart_rates(metrics, resting_heart_rates, timestamp_position);
+            }
+            HistoryMetric::Floors => {
+                let floors_range = fitbit_client.fetch_floors_range(query.start_date, query.end_date).await?;
+                push_floors_range(metrics, floors_range, timestamp_position);
+            }
+            HistoryMetric::Elevation => {
+                let elevation_range = fitbit_client.fetch_elevation_range(query.start_date, query.end_date).await?;
+                push_elevation_range(metrics, elevation_range, timestamp_position);
+            }
+        }
+    }
     Ok(())
 }
+
+/// Converts the date of a daily value into the timestamp of its metric point.
+///
+/// # Arguments
+///
+/// * `date` - The date the daily value belongs to, in the user's timezone.
+/// * `position` - The time of day at which the value is stamped (midnight, noon or 23:59:59).
+/// * `utc_offset` - The offset of the user's timezone (see `FitbitMetrics::utc_offset`), so that the value is stamped
+///   within its day in the user's local time, whatever the timezone of Prometheus or Grafana.
+///
+/// # Returns
+///
+/// A `Duration` since the UNIX epoch, as expected by `MultiPointGauge::push`.
+pub fn daily_timestamp(date: NaiveDate, position: TimestampPosition, utc_offset: FixedOffset) -> Duration {
+    let time = match position {
+        TimestampPosition::Midnight => NaiveTime::MIN,
+        TimestampPosition::Noon => NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+        TimestampPosition::EndOfDay => NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+    };
+    let local_time = date.and_time(time) - utc_offset;
+    Duration::from_secs(local_time.and_utc().timestamp().max(0) as u64)
+}
+
+/// Encodes historical samples as CSV with the columns `date,metric,value,labels`.
+///
+/// Labels are flattened into a single column as `key=value` pairs separated by `;`, so that every row
+/// has the same shape regardless of the metric.
+///
+/// # Errors
+///
+/// Returns an error if a row cannot be written.
+fn encode_csv(samples: &[HistoricalSample]) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["date", "metric", "value", "labels"])?;
+    for sample in samples {
+        let labels = sample.labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>()
+            .join(";");
+        writer.write_record([
+            sample.date.format("%Y-%m-%d").to_string(),
+            sample.metric.clone(),
+            sample.value.to_string(),
+            labels,
+        ])?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::mock::MockFitbitApi;
+    use serde_json::json;
+
+    fn steps_range() -> Vec<(NaiveDate, Steps)> {
+        let date = |day: u32| NaiveDate::from_ymd_opt(2023, 3, day).unwrap();
+        vec![(date(1), Steps(0)), (date(2), Steps(0)), (date(3), Steps(8123)), (date(4), Steps(0))]
+    }
+
+    #[test]
+    fn leading_placeholder_days_are_skipped_or_estimated() {
+        let metrics = FitbitMetrics::new();
+        let pushed = push_steps_range(&metrics, steps_range(), TimestampPosition::Midnight, PlaceholderDays::Skip);
+        // The day without steps after the first day with steps is not a placeholder
+        assert_eq!(pushed.iter().map(|(_, steps, _)| steps.0).collect::<Vec<_>>(), vec![8123, 0]);
+        assert_eq!(metrics.steps.metric_points().len(), 2);
+
+        let metrics = FitbitMetrics::new();
+        let pushed = push_steps_range(&metrics, steps_range(), TimestampPosition::Midnight, PlaceholderDays::Estimated);
+        assert_eq!(pushed.iter().map(|(_, _, estimated)| *estimated).collect::<Vec<_>>(), vec![true, true, false, false]);
+        assert_eq!(metrics.steps.metric_points().len(), 2);
+        assert_eq!(metrics.steps_estimated.metric_points().len(), 2);
+
+        let metrics = FitbitMetrics::new();
+        assert_eq!(push_steps_range(&metrics, steps_range(), TimestampPosition::Midnight, PlaceholderDays::Keep).len(), 4);
+        assert!(metrics.steps_estimated.metric_points().is_empty());
+    }
+
+    #[test]
+    fn pushing_the_same_days_again_replaces_their_points() {
+        let metrics = FitbitMetrics::new();
+        push_steps_range(&metrics, steps_range(), TimestampPosition::Midnight, PlaceholderDays::Estimated);
+        let mut updated_range = steps_range();
+        updated_range[0].1 = Steps(512);
+        updated_range[3].1 = Steps(4000);
+        push_steps_range(&metrics, updated_range, TimestampPosition::Midnight, PlaceholderDays::Estimated);
+
+        let steps = metrics.steps.metric_points().iter().map(|(steps, _)| *steps).collect::<Vec<_>>();
+        assert_eq!(steps, vec![8123, 4000, 512, 0]);
+        assert!(metrics.steps_estimated.metric_points().is_empty());
+    }
+
+    #[test]
+    fn daily_values_are_stamped_in_the_local_time_of_the_user() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let utc = FixedOffset::east_opt(0).unwrap();
+        assert_eq!(daily_timestamp(date, TimestampPosition::Midnight, utc), Duration::from_secs(1704067200));
+        assert_eq!(daily_timestamp(date, TimestampPosition::Noon, utc), Duration::from_secs(1704067200 + 12 * 3600));
+        assert_eq!(daily_timestamp(date, TimestampPosition::EndOfDay, utc), Duration::from_secs(1704067200 + 86399));
+
+        // 2024-01-01T00:00:00-08:00 and 2024-01-01T23:59:59+09:00, both within the user's day
+        let pst = FixedOffset::west_opt(8 * 3600).unwrap();
+        assert_eq!(daily_timestamp(date, TimestampPosition::Midnight, pst), Duration::from_secs(1704096000));
+        let jst = FixedOffset::east_opt(9 * 3600).unwrap();
+        assert_eq!(daily_timestamp(date, TimestampPosition::EndOfDay, jst), Duration::from_secs(1704121199));
+
+        let metrics = FitbitMetrics::new();
+        metrics.set_utc_offset(pst);
+        push_steps_range(&metrics, vec![(date, Steps(8123))], TimestampPosition::EndOfDay, PlaceholderDays::Keep);
+        assert_eq!(*metrics.steps.metric_points(), vec![(8123, Some(Duration::from_secs(1704182399)))]);
+    }
+
+    #[test]
+    fn parse_history_query() {
+        let date = |month: u32, day: u32| NaiveDate::from_ymd_opt(2024, month, day).unwrap();
+        let today = date(3, 10);
+        assert_eq!(
+            HistoryQuery::parse(None, today),
+            Ok(HistoryQuery { start_date: date(2, 8), end_date: date(3, 9), metrics: vec![HistoryMetric::Steps] })
+        );
+        assert_eq!(
+            HistoryQuery::parse(Some("start=2024-01-01&end=2024-01-31&metrics=steps,resting_heart_rate"), today),
+            Ok(HistoryQuery { start_date: date(1, 1), end_date: date(1, 31), metrics: vec![HistoryMetric::Steps, HistoryMetric::RestingHeartRate] })
+        );
+        assert!(HistoryQuery::parse(Some("start=2024-02-30"), today).is_err());
+        assert!(HistoryQuery::parse(Some("start=2024-03-01&end=2024-02-01"), today).is_err());
+        assert!(HistoryQuery::parse(Some("end=2024-03-11"), today).is_err());
+        assert!(HistoryQuery::parse(Some("start=2023-01-01"), today).is_err());
+        assert_eq!(
+            HistoryQuery::parse(Some("metrics=floors,elevation"), today).map(|query| query.metrics),
+            Ok(vec![HistoryMetric::Floors, HistoryMetric::Elevation])
+        );
+        assert!(HistoryQuery::parse(Some("metrics=weight"), today).is_err());
+        assert!(HistoryQuery::parse(Some("days=7"), today).is_err());
+    }
+
+    #[test]
+    fn csv_rows_have_the_labels_flattened() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let mut weight = HistoricalSample::new(date, "fitbit_weight_grams", 72450.5);
+        weight.labels.insert("source".to_string(), "aria".to_string());
+        weight.labels.insert("log_id".to_string(), "1704096000000".to_string());
+        let mut battery = HistoricalSample::new(date, "fitbit_device_battery_level", 80.0);
+        battery.labels.insert("device".to_string(), "Charge 6, spare".to_string());
+
+        assert_eq!(
+            encode_csv(&[HistoricalSample::new(date, "fitbit_steps", 8123.0), weight, battery]).unwrap(),
+            "date,metric,value,labels\n\
+             2024-01-01,fitbit_steps,8123,\n\
+             2024-01-01,fitbit_weight_grams,72450.5,log_id=1704096000000;source=aria\n\
+             2024-01-01,fitbit_device_battery_level,80,\"device=Charge 6, spare\"\n"
+        );
+        // Only the header without samples
+        assert_eq!(encode_csv(&[]).unwrap(), "date,metric,value,labels\n");
+    }
+
+    #[tokio::test]
+    async fn history_pushes_the_selected_metrics() {
+        let api = MockFitbitApi::new().with_response("fetch_resting_heart_rate_range", json!([["2024-01-01", 57.6], ["2024-01-02", 58.0]]));
+        let metrics = FitbitMetrics::new();
+        metrics.set_utc_offset(FixedOffset::east_opt(0).unwrap());
+        let query = HistoryQuery::parse(Some("start=2024-01-01&end=2024-01-02&metrics=resting_heart_rate"), NaiveDate::from_ymd_opt(2024, 3, 10).unwrap()).unwrap();
+
+        push_history(&api, &metrics, &query, TimestampPosition::Midnight, PlaceholderDays::Keep).await.unwrap();
+        assert_eq!(api.calls(), vec!["fetch_resting_heart_rate_range"]);
+        assert_eq!(*metrics.resting_heart_rate.metric_points(), vec![(58, Some(Duration::from_secs(1704067200))), (58, Some(Duration::from_secs(1704153600)))]);
+        assert!(metrics.steps.metric_points().is_empty());
+
+        let api = MockFitbitApi::new()
+            .with_response("fetch_floors_range", json!([["2024-01-01", 12]]))
+            .with_response("fetch_elevation_range", json!([["2024-01-01", 36.58]]));
+        let query = HistoryQuery::parse(Some("start=2024-01-01&end=2024-01-01&metrics=floors,elevation"), NaiveDate::from_ymd_opt(2024, 3, 10).unwrap()).unwrap();
+        push_history(&api, &metrics, &query, TimestampPosition::Midnight, PlaceholderDays::Keep).await.unwrap();
+        assert_eq!(*metrics.daily_floors.metric_points(), vec![(12, Some(Duration::from_secs(1704067200)))]);
+        assert_eq!(*metrics.daily_elevation_meters.metric_points(), vec![(37, Some(Duration::from_secs(1704067200)))]);
+    }
+
+    #[tokio::test]
+    async fn resumed_dump_fetches_the_chunks_after_the_checkpoint() {
+        let checkpoint_file = std::env::temp_dir().join(format!("fitbit_exporter_checkpoint_{}.json", std::process::id()));
+        let date = |day: u32| NaiveDate::from_ymd_opt(2023, 1, 1).unwrap() + ChronoDuration::days(day as i64);
+        let api = MockFitbitApi::new().with_response("fetch_steps_range", json!([["2023-06-01", 8123]]));
+
+        // 200 days are fetched in 3 chunks, the checkpoint being saved after each of them
+        let mut checkpoint = DumpCheckpoint::new(date(0), date(199));
+        fetch_in_chunks(&api, &mut checkpoint, &checkpoint_file, HistoryMetric::Steps).await.unwrap();
+        assert_eq!(api.calls().len(), 3);
+        assert_eq!(DumpCheckpoint::load(&checkpoint_file).unwrap(), checkpoint);
+
+        // Interrupted after the first chunk
+        let progress = checkpoint.metrics.get_mut("fitbit_steps").unwrap();
+        progress.last_completed = Some(date(DUMP_CHUNK_DAYS as u32 - 1));
+        progress.days.truncate(1);
+        let api = MockFitbitApi::new().with_response("fetch_steps_range", json!([["2023-06-01", 8123]]));
+        let days = fetch_in_chunks(&api, &mut checkpoint, &checkpoint_file, HistoryMetric::Steps).await.unwrap();
+        assert_eq!(api.calls().len(), 2);
+        assert_eq!(days.len(), 3);
+
+        remove_checkpoint(&checkpoint_file).unwrap();
+        assert!(!checkpoint_file.exists());
+    }
+
+    #[tokio::test]
+    async fn dump_checkpoints_the_progress_of_each_metric() {
+        let checkpoint_file = std::env::temp_dir().join(format!("fitbit_exporter_metrics_checkpoint_{}.json", std::process::id()));
+        let date = |day: u32| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+        let api = MockFitbitApi::new()
+            .with_response("fetch_steps_range", json!([["2023-01-01", 8123]]))
+            .with_response("fetch_resting_heart_rate_range", json!([["2023-01-01", 57.6]]));
+
+        let mut checkpoint = DumpCheckpoint::new(date(1), date(31));
+        fetch_in_chunks(&api, &mut checkpoint, &checkpoint_file, HistoryMetric::Steps).await.unwrap();
+        let days = fetch_in_chunks(&api, &mut checkpoint, &checkpoint_file, HistoryMetric::RestingHeartRate).await.unwrap();
+        assert_eq!(days, vec![(date(1), 57.6)]);
+        assert_eq!(api.calls(), vec!["fetch_steps_range", "fetch_resting_heart_rate_range"]);
+        let saved = DumpCheckpoint::load(&checkpoint_file).unwrap();
+        assert_eq!(saved.metrics.keys().collect::<Vec<_>>(), vec!["fitbit_resting_heart_rate_bpm", "fitbit_steps"]);
+
+        // Both metrics are complete, so resuming fetches nothing
+        fetch_in_chunks(&api, &mut checkpoint, &checkpoint_file, HistoryMetric::RestingHeartRate).await.unwrap();
+        assert_eq!(api.calls().len(), 2);
+        remove_checkpoint(&checkpoint_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn weigh_ins_are_stamped_with_their_time() {
+        let checkpoint_file = std::env::temp_dir().join(format!("fitbit_exporter_weight_checkpoint_{}.json", std::process::id()));
+        let api = MockFitbitApi::new().with_response(
+            "fetch_weight_range",
+            json!([
+                {"logId": 1, "date": "2024-01-01", "time": "07:31:10", "weight": 72.6},
+                {"logId": 2, "date": "2024-01-01", "time": "21:05:00", "weight": 73.15}
+            ]),
+        );
+        let mut checkpoint = DumpCheckpoint::new(NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(), NaiveDate::from_ymd_opt(2024, 1, 31).unwrap());
+        let weight_logs = fetch_weight_in_chunks(&api, &mut checkpoint, &checkpoint_file).await.unwrap();
+        assert_eq!(DumpCheckpoint::load(&checkpoint_file).unwrap(), checkpoint);
+
+        let metrics = FitbitMetrics::new();
+        metrics.set_utc_offset(FixedOffset::east_opt(3600).unwrap());
+        assert_eq!(push_weight_logs(&metrics, weight_logs).len(), 2);
+        assert_eq!(
+            *metrics.weight_grams.metric_points(),
+            vec![(72600, Some(Duration::from_secs(1704090670))), (73150, Some(Duration::from_secs(1704139500)))]
+        );
+        remove_checkpoint(&checkpoint_file).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use prometheus_client::encoding::text::encode;
+use std::fmt::Write;
+
+use crate::fitbit::exposition::parse_openmetrics;
+use crate::fitbit::profile::COLLECTORS;
+use crate::fitbit::FitbitMetrics;
+
+/// An endpoint listed by the landing page: its path, and what it serves.
+pub type Endpoint = (&'static str, &'static str);
+
+/// Escapes the text for an HTML element or attribute.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Renders the landing page served at `/`, as other Prometheus exporters do: the endpoints, the request budget and
+/// the rate limit of the Fitbit API, the last successful run of each enabled collector, and the catalog of the
+/// exposed metric families.
+///
+/// # Arguments
+///
+/// * `fitbit_metrics` - The metrics, whose registry lists the exposed families.
+/// * `endpoints` - The endpoints served, e.g. `("/metrics", "The metrics")`.
+pub fn render_landing_page(fitbit_metrics: &FitbitMetrics, endpoints: &[Endpoint]) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Fitbit Exporter</title>\n</head>\n<body>\n");
+    let _ = writeln!(html, "<h1>Fitbit Exporter</h1>\n<p>Version {}</p>", env!("CARGO_PKG_VERSION"));
+
+    html.push_str("<h2>Endpoints</h2>\n<ul>\n");
+    for (path, description) in endpoints {
+        let _ = writeln!(html, "<li><a href=\"{}\">{}</a>: {}</li>", path, path, escape_html(description));
+    }
+    html.push_str("</ul>\n");
+
+    let budget = fitbit_metrics.scheduler.remaining_budget().map_or_else(|| "unlimited".to_string(), |remaining| remaining.to_string());
+    let rate_limit = match fitbit_metrics.rate_limit_remaining() {
+        Some(remaining) => format!("Rate limited for {} more seconds", remaining.as_secs()),
+        None => "Not rate limited".to_string(),
+    };
+    let _ = writeln!(html, "<h2>Fitbit API</h2>\n<ul>\n<li>Requests left in the budget of the last hour: {}</li>\n<li>{}</li>\n</ul>", budget, rate_limit);
+
+    html.push_str("<h2>Collectors</h2>\n<table>\n<tr><th>Collector</th><th>Last successful fetch</th></tr>\n");
+    for collector in COLLECTORS.iter().filter(|collector| fitbit_metrics.is_collector_enabled(collector)) {
+        let last_success = match fitbit_metrics.error_budget.last_success(collector) {
+            Some(last_success) => {
+                let elapsed = last_success.elapsed();
+                let time = Utc::now() - ChronoDuration::from_std(elapsed).unwrap_or_default();
+                format!("{} ({} seconds ago)", time.format("%Y-%m-%d %H:%M:%S UTC"), elapsed.as_secs())
+            }
+            None => "never".to_string(),
+        };
+        let _ = writeln!(html, "<tr><td>{}</td><td>{}</td></tr>", collector, last_success);
+    }
+    html.push_str("</table>\n");
+
+    let mut txt = String::new();
+    let families = match encode(&mut txt, &fitbit_metrics.registry()) {
+        Ok(()) => parse_openmetrics(&txt),
+        Err(_) => Vec::new(),
+    };
+    html.push_str("<h2>Metric families</h2>\n<table>\n<tr><th>Name</th><th>Type</th><th>Series</th><th>Help</th></tr>\n");
+    for family in &families {
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape_html(&family.name),
+            escape_html(&family.metric_type),
+            family.samples.len(),
+            escape_html(&family.help)
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::MetricsOptions;
+
+    #[test]
+    fn landing_page_lists_the_endpoints_collectors_and_families() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { collectors: Some(vec!["steps".to_string()]), ..Default::default() });
+        fitbit_metrics.error_budget.record_success("steps");
+
+        let html = render_landing_page(&fitbit_metrics, &[("/metrics", "The metrics, for Prometheus")]);
+        assert!(html.contains("<li><a href=\"/metrics\">/metrics</a>: The metrics, for Prometheus</li>"));
+        assert!(html.contains("Requests left in the budget of the last hour: unlimited"));
+        assert!(html.contains("<tr><td>steps</td><td>") && html.contains("(0 seconds ago)"));
+        assert!(!html.contains("<td>sleep</td>"));
+        assert!(html.contains("<tr><td>fitbit_steps</td><td>gauge</td>"));
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tracing::level_filters::LevelFilter;
+use tracing::{Level, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, Layer, Registry};
+
+use crate::fitbit::traces::{TraceBuffer, TraceLayer};
+
+/// The format of the log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The human-readable lines of tracing-subscriber, with the fields of the events and of their spans.
+    Text,
+    /// One JSON object per line, with the timestamp, the level, the target, the message and the fields of the event
+    /// (e.g. `status`), and the fields of its span under `span` (e.g. `endpoint`, `duration_ms` and `user` of the
+    /// requests), e.g. for Loki or ELK.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format: {} (expected text or json)", s)),
+        }
+    }
+}
+
+/// The log filter, in the syntax of `RUST_LOG` (e.g. "info,fitbit_exporter=debug"), which can be changed at runtime
+/// through `/admin/loglevel`, e.g. to enable the debug logs during an incident without losing the in-memory state
+/// by restarting.
+pub struct LogFilter {
+    directives: RwLock<String>,
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    /// Creates the filter with the given directives, the invalid ones being ignored, and the layer filtering the log
+    /// lines with it.
+    fn new(directives: &str) -> (Arc<Self>, reload::Layer<EnvFilter, Registry>) {
+        let (layer, handle) = reload::Layer::new(EnvFilter::builder().parse_lossy(directives));
+        (Arc::new(Self { directives: RwLock::new(directives.to_string()), handle }), layer)
+    }
+
+    /// Returns the current directives.
+    pub fn directives(&self) -> String {
+        self.directives.read().unwrap().clone()
+    }
+
+    /// Replaces the filter with the given directives.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message if the directives are empty or invalid.
+    ///
+    /// # Returns
+    ///
+    /// The most verbose level enabled by the new filter.
+    pub fn set_directives(&self, directives: &str) -> Result<LevelFilter, String> {
+        let directives = directives.trim();
+        if directives.is_empty() {
+            return Err("Empty log filter".to_string());
+        }
+        let filter = EnvFilter::builder().parse(directives).map_err(|err| format!("Invalid log filter {}: {}", directives, err))?;
+        let level = filter.max_level_hint().unwrap_or(LevelFilter::TRACE);
+        self.handle.reload(filter).map_err(|err| format!("Error setting the log filter: {}", err))?;
+        *self.directives.write().unwrap() = directives.to_string();
+        Ok(level)
+    }
+}
+
+impl fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogFilter").field("directives", &self.directives()).finish()
+    }
+}
+
+/// Builds the layer writing the log lines in the given format.
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// Initializes the logger with the filter of `RUST_LOG` (errors only if not set), writing to stderr. The records of
+/// the dependencies logging with the `log` crate are logged as well.
+///
+/// # Arguments
+///
+/// * `format` - The format of the log lines.
+/// * `traces` - The buffer in which the spans of the exporter are recorded, whatever the log filter, to be served by
+///   `/debug/traces`. `None` doesn't record them.
+///
+/// # Returns
+///
+/// The `LogFilter`, with which the filter can be changed at runtime.
+///
+/// # Panics
+///
+/// Panics if a logger is already set.
+pub fn init_logger(format: LogFormat, traces: Option<Arc<TraceBuffer>>) -> Arc<LogFilter> {
+    let (filter, filter_layer) = LogFilter::new(&std::env::var("RUST_LOG").unwrap_or_else(|_| "error".to_string()));
+    // As env_logger did: "always" and "never" force the colors on and off, "auto" colors the output of a terminal.
+    let ansi = match std::env::var("RUST_LOG_STYLE").as_deref() {
+        Ok("always") => true,
+        Ok("never") => false,
+        _ => std::io::stderr().is_terminal(),
+    };
+    // The records of `log` are all forwarded, and filtered by the `LogFilter`, so that the debug logs of the
+    // dependencies can be enabled at runtime as well.
+    tracing_log::LogTracer::init().expect("A logger is already set");
+    tracing_subscriber::registry()
+        .with(fmt_layer(format, std::io::stderr, ansi).with_filter(filter_layer))
+        .with(traces.map(|buffer| TraceLayer::new(buffer).with_filter(Targets::new().with_target("fitbit_exporter", Level::INFO))))
+        .try_init()
+        .expect("A subscriber is already set");
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value as JsonValue;
+    use std::io::Write;
+    use std::sync::Mutex;
+    use tracing::{info, info_span};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn set_directives() {
+        let (filter, _layer) = LogFilter::new("error");
+        assert_eq!(filter.set_directives(" warn,fitbit_exporter=debug "), Ok(LevelFilter::DEBUG));
+        assert_eq!(filter.directives(), "warn,fitbit_exporter=debug");
+        assert!(filter.set_directives("").is_err());
+        assert!(filter.set_directives("fitbit_exporter=loud").is_err());
+        assert_eq!(filter.directives(), "warn,fitbit_exporter=debug");
+    }
+
+    #[test]
+    fn json_lines_have_the_fields_of_the_event_and_of_its_span() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(LogFormat::Json, move || writer.clone(), false));
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!("request", endpoint = "/metrics", user = "ABCDEF").in_scope(|| info!(status = 200u16, "GET /metrics 200 OK"));
+        });
+
+        let json: JsonValue = serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["target"], "fitbit_exporter::fitbit::logging::tests");
+        assert_eq!(json["message"], "GET /metrics 200 OK");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["span"], serde_json::json!({"name": "request", "endpoint": "/metrics", "user": "ABCDEF"}));
+    }
+}
@@ -1,86 +1,976 @@
-use chrono::{NaiveDateTime, DateTime, Utc};
-use log::error;
-use prometheus_client::metrics::gauge::MultiPointGauge;
+use chrono::{Duration as ChronoDuration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, DateTime, Local, TimeZone, Utc};
+use tracing::{debug, error, info, warn};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::{Atomic, Counter};
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::{Gauge, MultiPointGauge};
+use prometheus_client::metrics::histogram::Histogram;
 use prometheus_client::registry::Registry;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::future::Future;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, Notify, OnceCell, RwLock};
+use tokio::time::{timeout_at, Instant};
 
-use crate::fitbit::{FitbitClient,FitbitError};
+use crate::fitbit::{FitbitApi, FitbitError};
+use crate::fitbit::cmd::{SleepNaps, TimestampPosition};
+use crate::fitbit::collector::{CollectorLabels, ErrorBudget};
+use crate::fitbit::events::{Event, EventBus};
+use crate::fitbit::exposition::parse_openmetrics;
+use crate::fitbit::history::daily_timestamp;
+use crate::fitbit::profile::{CollectorConfig, CollectorConfigUpdate, CollectorSelection, COLLECTORS};
+use crate::fitbit::recovery::{latest_and_baseline, recovery_score, RecoveryInputs, RecoveryWeights, BASELINE_DAYS};
+use crate::fitbit::schedule::PollSchedule;
+use crate::fitbit::scheduler::RequestScheduler;
+use crate::fitbit::storage::{run_blocking, SampleStore};
+use crate::fitbit::models::{ActivityGoals, ActivityLog, ActivitySummary, Badge, BreathingRateSummary, CardioScoreDay, CoreTemperatureLog, Device, EcgReading, HeartRateSample, IrnAlert, LeaderboardRank, LifetimeStats, Meters, SkinTemperatureDay, SleepLogResponse, Steps, Vo2Max};
+
+/// Number of recent activity logs (workouts) exposed as metrics.
+const RECENT_ACTIVITY_LOGS: u32 = 10;
+
+/// Label value of the bucket into which the series over `max_series_per_family` are collapsed.
+const OTHER: &str = "other";
+
+/// Default maximum number of distinct label sets per labelled family.
+const DEFAULT_MAX_SERIES_PER_FAMILY: usize = 100;
+
+/// The sources of the distances of the activity summary that are set by Fitbit, unlike those of the logged activities.
+const FITBIT_DISTANCE_SOURCES: [&str; 6] = ["tracker", "loggedActivities", "veryActive", "moderatelyActive", "lightlyActive", "sedentaryActive"];
+
+/// Registers metrics kept outside of `FitbitMetrics` in a registry, see `FitbitMetrics::register_external`.
+type RegisterExternal = Box<dyn Fn(&mut Registry) + Send + Sync>;
+
+/// Default maximum age of the last sync of the devices for `fitbit_synced_recently`.
+const DEFAULT_SYNCED_RECENTLY_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Number of recent irregular rhythm notifications fetched to count the new ones.
+const RECENT_IRN_ALERTS: u32 = 10;
+
+/// Upper bounds (in beats per minute) of the buckets of `fitbit_heart_rate_minutes`, from resting to peak heart rates.
+const HEART_RATE_BUCKETS: [f64; 10] = [50.0, 60.0, 70.0, 80.0, 90.0, 100.0, 120.0, 140.0, 160.0, 180.0];
+
+/// Labels of the per-date daily metrics, e.g. `fitbit_steps_by_date{date="2023-03-04"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DateLabels {
+    pub date: String,
+}
+
+/// Labels of the activity log (workout) metrics, e.g. `fitbit_activity_duration_seconds{activity_type="run",source="tracker",log_id="123"}`.
+///
+/// The activity type is the canonical one (see `ActivityLog::canonical_type`), so that the series don't change
+/// with the locale of the account. The raw name is only set with `activity_name_label`: an empty label is the same
+/// as no label for Prometheus. The log ID tells apart the workouts of the same type, e.g. two runs on the same day.
+/// The source (see `ActivityLog::source`) lets the queries filter out the manual entries, e.g. `source!="manual"`.
+/// The weigh-ins are only labelled with theirs (see `WeightLog::source`) in the dumps, as `fitbit_weight_grams` is a
+/// single series. The daily steps have no source: the API only returns their total, including the logged activities.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ActivityLabels {
+    pub activity_type: String,
+    pub source: String,
+    pub activity_name: String,
+    pub log_id: String,
+}
+
+/// Labels of the distances of the day by source, e.g. `fitbit_distance_by_source_meters{source="veryActive"}`.
+///
+/// The source is the one of the activity summary, as returned by Fitbit: "tracker", "loggedActivities", the
+/// intensities ("veryActive", "moderatelyActive", "lightlyActive", "sedentaryActive") or a logged activity type.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DistanceLabels {
+    pub source: String,
+}
+
+/// Labels of the goal metrics, e.g. `fitbit_goal_met{goal="steps"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct GoalLabels {
+    pub goal: String,
+}
+
+/// Labels of the device metrics, e.g. `fitbit_battery_low{device="Charge 5",device_id="123"}`. The ID tells apart
+/// the devices of the same model.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceLabels {
+    pub device: String,
+    pub device_id: String,
+}
+
+/// Labels of the friends leaderboard metrics, e.g. `fitbit_leaderboard_steps{friend="Jane D.",user_id="ABC123"}`.
+/// The user of the exporter is ranked among their friends, under their own display name. The encoded user ID tells
+/// apart the friends of the same display name.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct LeaderboardLabels {
+    pub friend: String,
+    pub user_id: String,
+}
+
+/// Labels of `fitbit_badges_total`, e.g. `fitbit_badges_total{category="DAILY_STEPS",value="25000"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct BadgeLabels {
+    pub category: String,
+    pub value: String,
+}
+
+/// Labels of `fitbit_series_collapsed`, e.g. `fitbit_series_collapsed{family="fitbit_activity"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct FamilyLabels {
+    pub family: String,
+}
+
+/// Labels of the per-log sleep metrics, e.g. `fitbit_sleep_log_asleep_seconds{log_id="40553264410",is_main_sleep="false"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct SleepLogLabels {
+    pub log_id: String,
+    pub is_main_sleep: String,
+}
+
+/// Labels of the ECG metrics, e.g. `fitbit_ecg_classification{classification="Normal Sinus Rhythm"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct EcgLabels {
+    pub classification: String,
+}
+
+/// Labels of the sleep stage metrics, e.g. `fitbit_sleep_stage_seconds{stage="deep"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct SleepStageLabels {
+    pub stage: String,
+}
 
 // #[derive(Clone)]
 pub struct FitbitMetrics {
-    pub registry: Registry,
+    // rebuilt when the enabled collectors change, see `update_collector_config`
+    registry: Mutex<Registry>,
+    external_metrics: Mutex<Vec<RegisterExternal>>,
     pub steps: MultiPointGauge,
+    // the placeholder days of the historical ranges, with `--placeholder-days estimated`
+    pub steps_estimated: MultiPointGauge,
+    // the daily resting heart rates pushed by /history with `metrics=resting_heart_rate` and by the dumps
+    pub resting_heart_rate: MultiPointGauge,
+    // the daily floors and elevation climbed, pushed by /history with `metrics=floors,elevation` and by the dumps
+    pub daily_floors: MultiPointGauge,
+    pub daily_elevation_meters: MultiPointGauge,
+    // the weigh-ins pushed by the dumps, stamped with their time
+    pub weight_grams: MultiPointGauge,
 
-/* 
-    // sleep metrics
-    pub sleep_minutes_deep: Gauge,
-    pub sleep_minutes_light: Gauge,
-    pub sleep_minutes_rem: Gauge,
-    pub sleep_minutes_wake: Gauge,
-    pub sleep_duration: Gauge,
+    // nutrition metrics
+    pub water_ml: Gauge<f64, AtomicU64>,
+    pub calories_in: Gauge<f64, AtomicU64>,
+    pub carbs_grams: Gauge<f64, AtomicU64>,
+    pub fat_grams: Gauge<f64, AtomicU64>,
+    pub protein_grams: Gauge<f64, AtomicU64>,
+    pub fiber_grams: Gauge<f64, AtomicU64>,
+    pub sodium_milligrams: Gauge<f64, AtomicU64>,
+
+    // sleep metrics. All the durations are exported in seconds.
+    pub sleep_stage_seconds: Family<SleepStageLabels, Gauge<f64, AtomicU64>>,
+    pub sleep_stage: MultiPointGauge,
+    pub sleep_duration_seconds: Gauge<f64, AtomicU64>,
     pub sleep_efficiency: Gauge,
-    pub sleep_start_time: Gauge,
-    pub sleep_end_time: Gauge,
-    pub total_time_in_bed: Gauge,
-    pub total_minutes_asleep: Gauge,
-    pub time_in_bed: Gauge,
-    pub minutes_asleep: Gauge,
-    pub minutes_awake: Gauge,
-    pub minutes_after_wakeup: Gauge,
-    pub is_main_sleep: Gauge,
- */
+    pub sleep_start_time_seconds: Gauge,
+    pub sleep_end_time_seconds: Gauge,
+    pub sleep_time_in_bed_seconds: Gauge<f64, AtomicU64>,
+    pub sleep_asleep_seconds: Gauge<f64, AtomicU64>,
+    pub sleep_awake_seconds: Gauge<f64, AtomicU64>,
+    pub sleep_after_wakeup_seconds: Gauge<f64, AtomicU64>,
+    pub sleep_is_main_sleep: Gauge,
+    pub sleep_total_time_in_bed_seconds: Gauge<f64, AtomicU64>,
+    pub sleep_total_asleep_seconds: Gauge<f64, AtomicU64>,
+    pub sleep_naps: Gauge,
+    pub sleep_nap_asleep_seconds: Gauge<f64, AtomicU64>,
+    pub sleep_nap_time_in_bed_seconds: Gauge<f64, AtomicU64>,
+    pub sleep_log_asleep_seconds: Family<SleepLogLabels, Gauge<f64, AtomicU64>>,
+    pub sleep_log_time_in_bed_seconds: Family<SleepLogLabels, Gauge<f64, AtomicU64>>,
+    pub sleep_log_start_time_seconds: Family<SleepLogLabels, Gauge>,
+
+    // activity metrics
+    pub calories_out: Gauge<f64, AtomicU64>,
+    pub distance_meters: Gauge<f64, AtomicU64>,
+    pub distance_by_source_meters: Family<DistanceLabels, Gauge<f64, AtomicU64>>,
+    pub floors: Gauge<f64, AtomicU64>,
+    pub active_duration_seconds: Gauge<f64, AtomicU64>,
+
+    // recent activity logs (workouts), labelled by activity type and log ID
+    pub activity_duration_seconds: Family<ActivityLabels, Gauge<f64, AtomicU64>>,
+    pub activity_calories: Family<ActivityLabels, Gauge<f64, AtomicU64>>,
+    pub activity_average_heart_rate_bpm: Family<ActivityLabels, Gauge<f64, AtomicU64>>,
+    pub activity_distance_meters: Family<ActivityLabels, Gauge<f64, AtomicU64>>,
+    pub activity_start_time_seconds: Family<ActivityLabels, Gauge>,
+
+    // also label the activity log metrics with the raw (localized) activity name
+    pub activity_name_label: bool,
+
+    // whether the naps count in the sleep totals, and are exported
+    pub sleep_nap_handling: SleepNaps,
+
+    // cap of the distinct label sets of the labelled families, and the number of series collapsed by the last update
+    pub max_series_per_family: Option<usize>,
+    pub series_collapsed: Family<FamilyLabels, Gauge>,
+
+    // daily goals, to be compared with the actual values above (and steps)
+    pub goal_steps: Gauge,
+    pub goal_calories_out: Gauge<f64, AtomicU64>,
+    pub goal_distance_meters: Gauge<f64, AtomicU64>,
+    pub goal_floors: Gauge<f64, AtomicU64>,
+    pub goal_active_duration_seconds: Gauge<f64, AtomicU64>,
+
+    // boolean (0 or 1) metrics of common conditions, for trivial alert rules
+    pub goal_met: Family<GoalLabels, Gauge>,
+    // the goals met as of the last update, to emit `Event::GoalAchieved` once they're reached. `None` until the first
+    // update, so that the goals already met when the exporter starts aren't announced again.
+    goals_met: Mutex<Option<HashSet<&'static str>>>,
+    pub synced_recently: Gauge,
+    pub synced_recently_max_age: Duration,
+    pub battery_low: Family<DeviceLabels, Gauge>,
+
+    // friends leaderboard: the steps of the last 7 days of the user and of their friends, and their rank
+    pub leaderboard_steps: Family<LeaderboardLabels, Gauge>,
+    pub leaderboard_rank: Family<LeaderboardLabels, Gauge>,
+
+    // badges earned over the lifetime of the account, counting the times each of them was earned
+    pub badges: Family<BadgeLabels, Counter>,
+
+    // lifetime totals, and the best days labelled by their date
+    pub lifetime_steps: Counter,
+    pub lifetime_distance_meters: Counter<f64, AtomicU64>,
+    pub lifetime_floors: Counter<f64, AtomicU64>,
+    pub best_day_steps: Family<DateLabels, Gauge<f64, AtomicU64>>,
+    pub best_day_distance_meters: Family<DateLabels, Gauge<f64, AtomicU64>>,
+    pub best_day_floors: Family<DateLabels, Gauge<f64, AtomicU64>>,
+
+    // latest ECG reading, labelled by its classification
+    pub ecg_classification: Family<EcgLabels, Gauge>,
+    pub ecg_average_heart_rate_bpm: Family<EcgLabels, Gauge<f64, AtomicU64>>,
+
+    // irregular rhythm notifications (AFib alerts), and the time of the newest one counted
+    pub irregular_rhythm_notifications: Counter,
+    pub irregular_rhythm_last_alert_time: Mutex<Option<NaiveDateTime>>,
+
+    // Cardio Fitness Score, labelled by the date it was computed on. Either the value or the range bounds are set.
+    pub vo2max: Family<DateLabels, Gauge<f64, AtomicU64>>,
+    pub vo2max_lower: Family<DateLabels, Gauge<f64, AtomicU64>>,
+    pub vo2max_upper: Family<DateLabels, Gauge<f64, AtomicU64>>,
+
+    // last night's breathing rate per sleep stage (deep, rem, light) and over the full sleep
+    pub breathing_rate: Family<SleepStageLabels, Gauge<f64, AtomicU64>>,
+
+    // minutes of today spent in each heart rate bucket, from the intraday heart rate, labelled by the date
+    pub heart_rate_minutes: Family<DateLabels, Histogram, fn() -> Histogram>,
+    // only fetch the intraday heart rate since the last sample observed (of today, in the user's timezone)
+    pub intraday_window: bool,
+    heart_rate_last_sample_time: Mutex<Option<NaiveDateTime>>,
+
+    // nightly skin temperature deviation, labelled by the date of the night, and the last logged core temperature
+    pub skin_temp_delta_celsius: Family<DateLabels, Gauge<f64, AtomicU64>>,
+    pub core_temp_celsius: Family<DateLabels, Gauge<f64, AtomicU64>>,
+
+    // time of the underlying data of the collectors, e.g. the last sync of the devices, as opposed to the fetch time
+    pub data_timestamp_seconds: Family<CollectorLabels, Gauge>,
+    pub collector_timeouts: Family<CollectorLabels, Counter>,
+    pub collector_budget_skips: Family<CollectorLabels, Counter>,
+
+    // runs the collectors concurrently, within the request budget
+    pub scheduler: RequestScheduler,
+
+    // end of the last rate limit of the Fitbit API, until which the collectors don't call it
+    rate_limited_until: Mutex<Option<Instant>>,
+
+    // Error budget of the collectors, which disables the ones failing repeatedly
+    pub error_budget: ErrorBudget,
+
+    // Operational events, e.g. a broken authorization, sent to the sinks of `dispatch_events`
+    pub events: EventBus,
+
+    // today's and yesterday's values of the daily metrics, labelled by date. Only registered with `expose_previous_day`.
+    pub expose_previous_day: bool,
+    pub steps_by_date: Family<DateLabels, Gauge>,
+    pub water_ml_by_date: Family<DateLabels, Gauge<f64, AtomicU64>>,
+    pub calories_in_by_date: Family<DateLabels, Gauge<f64, AtomicU64>>,
+
+    // recovery score derived from the resting heart rate, the HRV and the sleep. Only registered with `recovery_weights`.
+    pub recovery_weights: Option<RecoveryWeights>,
+    pub recovery_score: Gauge<f64, AtomicU64>,
+
+    // archive of the samples, from which /metrics is served when set
+    pub sample_store: Option<Arc<dyn SampleStore>>,
+
+    // offset of the user's timezone, in which the days of the daily values start. Defaults to the one of the host
+    // until it's known from the profile, see `set_utc_offset`.
+    utc_offset: Mutex<FixedOffset>,
+
+    // the enabled collectors, and the minimum delay between two updates of each collector, changeable at runtime.
+    // `None` enables every collector.
+    collectors: Mutex<Option<Vec<String>>>,
+    cadences: Mutex<HashMap<String, Duration>>,
+
+    // notified after each update of the metrics, e.g. for the StatsD emitter
+    pub refreshed: Notify,
+
+    // drop the historical points of the steps once served, instead of keeping them in memory
+    pub drop_served_history: bool,
+
+    // retention of the historical points, enforced by `prune_history`
+    history_max_age: Option<Duration>,
+    max_history_points: Option<usize>,
+}
+
+/// Options of the metrics, built from the command line arguments (see `cmd::Args::metrics_options`).
+#[derive(Debug, Default)]
+pub struct MetricsOptions {
+    /// Error budget of the collectors. Defaults to 5 consecutive failures and a 1 hour cooldown.
+    pub error_budget: ErrorBudget,
+    /// Also expose yesterday's values of the daily metrics (steps, water, calories in) with a `date` label, so that
+    /// queries over day boundaries don't miss the final value of the previous day. Costs 5 extra API calls per scrape.
+    pub expose_previous_day: bool,
+    /// Archive of the samples recorded after each update. When set, /metrics is served from it, so that the values
+    /// of the last update are still served after a restart. Defaults to `None`.
+    pub sample_store: Option<Arc<dyn SampleStore>>,
+    /// The enabled collectors (see `profile::COLLECTORS`): the others don't call the Fitbit API, and their metrics
+    /// are not exposed, which trades coverage for rate limit headroom. `None` enables every collector.
+    pub collectors: Option<Vec<String>>,
+    /// Drop the historical points of the steps (pushed by /history and the backfill) once they're served, instead
+    /// of accumulating them in memory, e.g. on a Raspberry Pi Zero. Prometheus has them by then.
+    pub drop_served_history: bool,
+    /// The weights of the components of the recovery score (see `recovery::recovery_score`). `None` doesn't
+    /// compute it, which saves 3 API calls per update.
+    pub recovery_weights: Option<RecoveryWeights>,
+    /// Also label the activity log metrics with the raw activity name, in the language of the account's locale, next
+    /// to the canonical `activity_type`.
+    pub activity_name_label: bool,
+    /// Only fetch the intraday heart rate since its last sample observed today, instead of the whole day on each
+    /// update, which makes the responses much smaller for the 1 minute data.
+    pub intraday_window: bool,
+    /// Whether the naps count in the sleep totals, and are exported. Defaults to counting them, as Fitbit does.
+    pub sleep_naps: SleepNaps,
+    /// The maximum number of distinct label sets of the labelled families (workouts, devices, friends, distance
+    /// sources). The series over it are collapsed into an `other` bucket, so that an account with a lot of them
+    /// doesn't blow up the cardinality of Prometheus. Defaults to 100. `Some(0)` doesn't cap them.
+    pub max_series_per_family: Option<usize>,
+    /// The maximum age of the last sync of the devices for `fitbit_synced_recently` to be 1. Defaults to 1 hour.
+    pub synced_recently_max_age: Option<Duration>,
+    /// The maximum number of collectors run at once by an update. Defaults to 1, i.e. one after the other.
+    pub max_concurrent_collectors: usize,
+    /// The maximum number of requests to the Fitbit API made by the collectors per hour, beyond which they keep their
+    /// last-known values. `None` doesn't limit them.
+    pub hourly_request_budget: Option<usize>,
+    /// The maximum age of the historical points (pushed by /history, the backfill and the rollover), beyond which
+    /// `prune_history` drops them. `None` keeps them whatever their age.
+    pub history_max_age: Option<Duration>,
+    /// The maximum number of historical points per family, beyond which `prune_history` drops the oldest ones.
+    /// `None` doesn't cap them.
+    pub max_history_points: Option<usize>,
+}
+
+impl MetricsOptions {
+    /// Returns true if the collector is enabled.
+    pub fn is_collector_enabled(&self, collector: &str) -> bool {
+        is_collector_enabled(&self.collectors, collector)
+    }
+}
+
+fn is_collector_enabled(collectors: &Option<Vec<String>>, collector: &str) -> bool {
+    collectors.as_ref().is_none_or(|collectors| collectors.iter().any(|enabled| enabled == collector))
+}
+
+impl Default for FitbitMetrics {
+    fn default() -> Self {
+        Self::with_options(MetricsOptions::default())
+    }
 }
 
 impl FitbitMetrics {
     pub fn new() -> Self {
-        let mut registry = Registry::default();
+        Self::default()
+    }
 
+    pub fn with_options(options: MetricsOptions) -> Self {
         let steps = MultiPointGauge::<i64>::default();
-        registry.register("fitbit_steps", "Total number of steps", steps.clone());
-
-/* 
-        let sleep_minutes_deep = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_sleep_minutes_deep", "Total minutes of deep sleep");
-        let sleep_minutes_light = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_sleep_minutes_light", "Total minutes of light sleep");
-        let sleep_minutes_rem = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_sleep_minutes_rem", "Total minutes of REM sleep");
-        let sleep_minutes_wake = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_sleep_minutes_wake", "Total minutes of wake time during sleep");
-        let sleep_duration = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_sleep_duration", "Total sleep duration in minutes");
-        let sleep_efficiency = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_sleep_efficiency", "Sleep efficiency percentage");
-        let sleep_start_time = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_sleep_start_time", "Sleep start time as UNIX timestamp");
-        let sleep_end_time = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_sleep_end_time", "Sleep end time as UNIX timestamp");
-        let total_time_in_bed = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_total_time_in_bed", "Total time in bed in minutes");
-        let total_minutes_asleep = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_total_minutes_asleep", "Total minutes asleep");
-        let time_in_bed = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_time_in_bed", "Time in bed in minutes");
-        let minutes_asleep = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_minutes_asleep", "Minutes asleep");
-        let minutes_awake = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_minutes_awake", "Minutes awake");
-        let minutes_after_wakeup = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_minutes_after_wakeup", "Minutes after wakeup");
-        let is_main_sleep = register_metric!(registry, Gauge::<i64, AtomicI64>::default(), "fitbit_is_main_sleep", "Is main sleep");
- */
-
-        Self {
-            registry,
+        let steps_estimated = MultiPointGauge::<i64>::default();
+        let resting_heart_rate = MultiPointGauge::<i64>::default();
+        let daily_floors = MultiPointGauge::<i64>::default();
+        let daily_elevation_meters = MultiPointGauge::<i64>::default();
+        let weight_grams = MultiPointGauge::<i64>::default();
+
+        let water_ml = Gauge::<f64, AtomicU64>::default();
+        let calories_in = Gauge::<f64, AtomicU64>::default();
+        let carbs_grams = Gauge::<f64, AtomicU64>::default();
+        let fat_grams = Gauge::<f64, AtomicU64>::default();
+        let protein_grams = Gauge::<f64, AtomicU64>::default();
+        let fiber_grams = Gauge::<f64, AtomicU64>::default();
+        let sodium_milligrams = Gauge::<f64, AtomicU64>::default();
+
+        let sleep_stage_seconds = Family::<SleepStageLabels, Gauge<f64, AtomicU64>>::default();
+        let sleep_stage = MultiPointGauge::<i64>::default();
+        let sleep_duration_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_efficiency = Gauge::default();
+        let sleep_start_time_seconds = Gauge::default();
+        let sleep_end_time_seconds = Gauge::default();
+        let sleep_time_in_bed_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_asleep_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_awake_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_after_wakeup_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_is_main_sleep = Gauge::default();
+        let sleep_total_time_in_bed_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_total_asleep_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_naps = Gauge::default();
+        let sleep_nap_asleep_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_nap_time_in_bed_seconds = Gauge::<f64, AtomicU64>::default();
+        let sleep_log_asleep_seconds = Family::<SleepLogLabels, Gauge<f64, AtomicU64>>::default();
+        let sleep_log_time_in_bed_seconds = Family::<SleepLogLabels, Gauge<f64, AtomicU64>>::default();
+        let sleep_log_start_time_seconds = Family::<SleepLogLabels, Gauge>::default();
+
+        let calories_out = Gauge::<f64, AtomicU64>::default();
+        let distance_meters = Gauge::<f64, AtomicU64>::default();
+        let distance_by_source_meters = Family::<DistanceLabels, Gauge<f64, AtomicU64>>::default();
+        let floors = Gauge::<f64, AtomicU64>::default();
+        let active_duration_seconds = Gauge::<f64, AtomicU64>::default();
+
+        let activity_duration_seconds = Family::<ActivityLabels, Gauge<f64, AtomicU64>>::default();
+        let activity_calories = Family::<ActivityLabels, Gauge<f64, AtomicU64>>::default();
+        let activity_average_heart_rate_bpm = Family::<ActivityLabels, Gauge<f64, AtomicU64>>::default();
+        let activity_distance_meters = Family::<ActivityLabels, Gauge<f64, AtomicU64>>::default();
+        let activity_start_time_seconds = Family::<ActivityLabels, Gauge>::default();
+
+        let goal_steps = Gauge::default();
+        let goal_calories_out = Gauge::<f64, AtomicU64>::default();
+        let goal_distance_meters = Gauge::<f64, AtomicU64>::default();
+        let goal_floors = Gauge::<f64, AtomicU64>::default();
+        let goal_active_duration_seconds = Gauge::<f64, AtomicU64>::default();
+        let goal_met = Family::<GoalLabels, Gauge>::default();
+
+        let synced_recently = Gauge::default();
+        let battery_low = Family::<DeviceLabels, Gauge>::default();
+
+        let leaderboard_steps = Family::<LeaderboardLabels, Gauge>::default();
+        let leaderboard_rank = Family::<LeaderboardLabels, Gauge>::default();
+
+        let badges = Family::<BadgeLabels, Counter>::default();
+
+        let lifetime_steps = Counter::default();
+        let lifetime_distance_meters = Counter::<f64, AtomicU64>::default();
+        let lifetime_floors = Counter::<f64, AtomicU64>::default();
+        let best_day_steps = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+        let best_day_distance_meters = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+        let best_day_floors = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+
+        let ecg_classification = Family::<EcgLabels, Gauge>::default();
+        let ecg_average_heart_rate_bpm = Family::<EcgLabels, Gauge<f64, AtomicU64>>::default();
+
+        let irregular_rhythm_notifications = Counter::default();
+
+        let vo2max = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+        let vo2max_lower = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+        let vo2max_upper = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+
+        let breathing_rate = Family::<SleepStageLabels, Gauge<f64, AtomicU64>>::default();
+
+        let heart_rate_minutes = Family::<DateLabels, Histogram, fn() -> Histogram>::new_with_constructor(|| Histogram::new(HEART_RATE_BUCKETS.into_iter()));
+
+        let skin_temp_delta_celsius = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+        let core_temp_celsius = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+
+        let collector_timeouts = Family::<CollectorLabels, Counter>::default();
+        let collector_budget_skips = Family::<CollectorLabels, Counter>::default();
+
+        let series_collapsed = Family::<FamilyLabels, Gauge>::default();
+
+        let data_timestamp_seconds = Family::<CollectorLabels, Gauge>::default();
+
+        let error_budget = options.error_budget;
+
+        let steps_by_date = Family::<DateLabels, Gauge>::default();
+        let water_ml_by_date = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+        let calories_in_by_date = Family::<DateLabels, Gauge<f64, AtomicU64>>::default();
+
+        let recovery_score = Gauge::<f64, AtomicU64>::default();
+
+        let fitbit_metrics = Self {
+            registry: Mutex::new(Registry::default()),
+            external_metrics: Mutex::new(Vec::new()),
             steps,
+            steps_estimated,
+            resting_heart_rate,
+            daily_floors,
+            daily_elevation_meters,
+            weight_grams,
+            water_ml,
+            calories_in,
+            carbs_grams,
+            fat_grams,
+            protein_grams,
+            fiber_grams,
+            sodium_milligrams,
 
-/* 
-            sleep_minutes_deep,
-            sleep_minutes_light,
-            sleep_minutes_rem,
-            sleep_minutes_wake,
-            sleep_duration,
+            sleep_stage_seconds,
+            sleep_stage,
+            sleep_duration_seconds,
             sleep_efficiency,
-            sleep_start_time,
-            sleep_end_time,
-            total_time_in_bed,
-            total_minutes_asleep,
-            time_in_bed,
-            minutes_asleep,
-            minutes_awake,
-            minutes_after_wakeup,
-            is_main_sleep,
- */
+            sleep_start_time_seconds,
+            sleep_end_time_seconds,
+            sleep_time_in_bed_seconds,
+            sleep_asleep_seconds,
+            sleep_awake_seconds,
+            sleep_after_wakeup_seconds,
+            sleep_is_main_sleep,
+            sleep_total_time_in_bed_seconds,
+            sleep_total_asleep_seconds,
+            sleep_naps,
+            sleep_nap_asleep_seconds,
+            sleep_nap_time_in_bed_seconds,
+            sleep_log_asleep_seconds,
+            sleep_log_time_in_bed_seconds,
+            sleep_log_start_time_seconds,
+
+            calories_out,
+            distance_meters,
+            distance_by_source_meters,
+            floors,
+            active_duration_seconds,
+
+            activity_duration_seconds,
+            activity_calories,
+            activity_average_heart_rate_bpm,
+            activity_distance_meters,
+            activity_start_time_seconds,
+            activity_name_label: options.activity_name_label,
+
+            sleep_nap_handling: options.sleep_naps,
+
+            max_series_per_family: match options.max_series_per_family.unwrap_or(DEFAULT_MAX_SERIES_PER_FAMILY) {
+                0 => None,
+                max => Some(max),
+            },
+            series_collapsed,
+
+            goal_steps,
+            goal_calories_out,
+            goal_distance_meters,
+            goal_floors,
+            goal_active_duration_seconds,
+
+            goal_met,
+            goals_met: Mutex::new(None),
+            synced_recently,
+            synced_recently_max_age: options.synced_recently_max_age.unwrap_or(DEFAULT_SYNCED_RECENTLY_MAX_AGE),
+            battery_low,
+
+            leaderboard_steps,
+            leaderboard_rank,
+
+            badges,
+
+            lifetime_steps,
+            lifetime_distance_meters,
+            lifetime_floors,
+            best_day_steps,
+            best_day_distance_meters,
+            best_day_floors,
+
+            ecg_classification,
+            ecg_average_heart_rate_bpm,
+
+            irregular_rhythm_notifications,
+            irregular_rhythm_last_alert_time: Mutex::new(None),
+
+            vo2max,
+            vo2max_lower,
+            vo2max_upper,
+
+            breathing_rate,
+
+            heart_rate_minutes,
+            intraday_window: options.intraday_window,
+            heart_rate_last_sample_time: Mutex::new(None),
+
+            skin_temp_delta_celsius,
+            core_temp_celsius,
+
+            data_timestamp_seconds,
+            collector_timeouts,
+            collector_budget_skips,
+            scheduler: RequestScheduler::new(options.max_concurrent_collectors, options.hourly_request_budget),
+            rate_limited_until: Mutex::new(None),
+
+            error_budget,
+            events: EventBus::default(),
+
+            expose_previous_day: options.expose_previous_day,
+            steps_by_date,
+            water_ml_by_date,
+            calories_in_by_date,
+
+            recovery_weights: options.recovery_weights,
+            recovery_score,
+
+            sample_store: options.sample_store,
+            utc_offset: Mutex::new(*Local::now().offset()),
+            collectors: Mutex::new(options.collectors),
+            cadences: Mutex::new(HashMap::new()),
+            refreshed: Notify::new(),
+            drop_served_history: options.drop_served_history,
+            history_max_age: options.history_max_age,
+            max_history_points: options.max_history_points,
+        };
+        *fitbit_metrics.registry.lock().unwrap() = fitbit_metrics.build_registry();
+        fitbit_metrics
+    }
+
+    /// Returns the offset of the user's timezone.
+    pub fn utc_offset(&self) -> FixedOffset {
+        *self.utc_offset.lock().unwrap()
+    }
+
+    /// Sets the offset of the user's timezone, e.g. from `UserProfile::utc_offset`.
+    pub fn set_utc_offset(&self, utc_offset: FixedOffset) {
+        *self.utc_offset.lock().unwrap() = utc_offset;
+    }
+
+    /// Returns the current time in the user's timezone.
+    pub fn user_now(&self) -> DateTime<FixedOffset> {
+        Utc::now().with_timezone(&self.utc_offset())
+    }
+
+    /// Returns today's date in the user's timezone, i.e. the day of Fitbit's "today".
+    pub fn user_today(&self) -> NaiveDate {
+        self.user_now().date_naive()
+    }
+
+    /// Returns the UNIX timestamp of a datetime that Fitbit reports in the user's timezone without an offset, e.g.
+    /// the start of a sleep stage.
+    pub fn user_timestamp(&self, datetime: NaiveDateTime) -> i64 {
+        (datetime - self.utc_offset()).and_utc().timestamp()
+    }
+
+    /// Returns the start of the time window of the intraday heart rate to fetch: the time of the last sample observed
+    /// `today`, with `intraday_window`. `None` fetches the whole day.
+    fn heart_rate_intraday_since(&self, today: NaiveDate) -> Option<NaiveTime> {
+        if !self.intraday_window {
+            return None;
         }
+        let last_sample_time = *self.heart_rate_last_sample_time.lock().unwrap();
+        last_sample_time.filter(|last| last.date() == today).map(|last| last.time())
+    }
+
+    /// Builds the registry of the metrics of the enabled collectors, and of the metrics registered by
+    /// `register_external`.
+    fn build_registry(&self) -> Registry {
+        let mut registry = Registry::default();
+        // The metrics of the disabled collectors are registered here, i.e. not exposed.
+        let mut unexposed = Registry::default();
+
+        let collector_registry = if self.is_collector_enabled("steps") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_steps", "Total number of steps", self.steps.clone());
+        collector_registry.register(
+            "fitbit_steps_estimated",
+            "Steps of the zero-filled placeholder days before the first day with steps",
+            self.steps_estimated.clone(),
+        );
+        registry.register(
+            "fitbit_resting_heart_rate_bpm",
+            "Resting heart rate of the day in beats per minute, pushed with the timestamp of the day by /history",
+            self.resting_heart_rate.clone(),
+        );
+        registry.register(
+            "fitbit_daily_floors",
+            "Floors climbed on the day, pushed with the timestamp of the day by /history",
+            self.daily_floors.clone(),
+        );
+        registry.register(
+            "fitbit_daily_elevation_meters",
+            "Elevation climbed on the day in meters, pushed with the timestamp of the day by /history",
+            self.daily_elevation_meters.clone(),
+        );
+        registry.register(
+            "fitbit_weight_grams",
+            "Body weight in grams, pushed with the time of each weigh-in by the dumps",
+            self.weight_grams.clone(),
+        );
+
+        let collector_registry = if self.is_collector_enabled("water") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_water_ml", "Total water consumed today in milliliters", self.water_ml.clone());
+        let collector_registry = if self.is_collector_enabled("food") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_calories_in", "Total calories logged as food today", self.calories_in.clone());
+        collector_registry.register("fitbit_carbs_grams", "Total carbohydrates logged today in grams", self.carbs_grams.clone());
+        collector_registry.register("fitbit_fat_grams", "Total fat logged today in grams", self.fat_grams.clone());
+        collector_registry.register("fitbit_protein_grams", "Total protein logged today in grams", self.protein_grams.clone());
+        collector_registry.register("fitbit_fiber_grams", "Total fiber logged today in grams", self.fiber_grams.clone());
+        collector_registry.register("fitbit_sodium_milligrams", "Total sodium logged today in milligrams", self.sodium_milligrams.clone());
+
+        let collector_registry = if self.is_collector_enabled("sleep") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_sleep_stage_seconds", "Time spent in each sleep stage (deep, light, rem, wake) in seconds", self.sleep_stage_seconds.clone());
+        collector_registry.register(
+            "fitbit_sleep_stage",
+            "Stage of the main sleep at the start of each of its intervals, with the timestamp of the interval: 1 (deep), 2 (light or asleep), 3 (rem or restless) or 4 (wake or awake)",
+            self.sleep_stage.clone(),
+        );
+        collector_registry.register("fitbit_sleep_duration_seconds", "Duration of the sleep log in seconds", self.sleep_duration_seconds.clone());
+        collector_registry.register("fitbit_sleep_efficiency", "Sleep efficiency percentage", self.sleep_efficiency.clone());
+        collector_registry.register("fitbit_sleep_start_time_seconds", "Sleep start time as UNIX timestamp", self.sleep_start_time_seconds.clone());
+        collector_registry.register("fitbit_sleep_end_time_seconds", "Sleep end time as UNIX timestamp", self.sleep_end_time_seconds.clone());
+        collector_registry.register("fitbit_sleep_time_in_bed_seconds", "Time in bed of the sleep log in seconds", self.sleep_time_in_bed_seconds.clone());
+        collector_registry.register("fitbit_sleep_asleep_seconds", "Time asleep of the sleep log in seconds", self.sleep_asleep_seconds.clone());
+        collector_registry.register("fitbit_sleep_awake_seconds", "Time awake of the sleep log in seconds", self.sleep_awake_seconds.clone());
+        collector_registry.register("fitbit_sleep_after_wakeup_seconds", "Time in bed after waking up in seconds", self.sleep_after_wakeup_seconds.clone());
+        collector_registry.register("fitbit_sleep_is_main_sleep", "Whether the sleep log is the main sleep (1) or a nap (0)", self.sleep_is_main_sleep.clone());
+        let totals_of = match self.sleep_nap_handling {
+            SleepNaps::Include => "all the sleep logs",
+            SleepNaps::Exclude | SleepNaps::Separate => "the main sleep",
+        };
+        collector_registry.register("fitbit_sleep_total_time_in_bed_seconds", format!("Total time in bed of {} of the day in seconds", totals_of), self.sleep_total_time_in_bed_seconds.clone());
+        collector_registry.register("fitbit_sleep_total_asleep_seconds", format!("Total time asleep of {} of the day in seconds", totals_of), self.sleep_total_asleep_seconds.clone());
+        if self.sleep_nap_handling != SleepNaps::Exclude {
+            collector_registry.register("fitbit_sleep_naps", "Number of naps (sleep logs other than the main sleep) of the day", self.sleep_naps.clone());
+            collector_registry.register("fitbit_sleep_nap_asleep_seconds", "Total time asleep of the naps of the day in seconds", self.sleep_nap_asleep_seconds.clone());
+            collector_registry.register("fitbit_sleep_nap_time_in_bed_seconds", "Total time in bed of the naps of the day in seconds", self.sleep_nap_time_in_bed_seconds.clone());
+        }
+        collector_registry.register("fitbit_sleep_log_asleep_seconds", "Time asleep of each sleep log of the day (main sleep and naps) in seconds", self.sleep_log_asleep_seconds.clone());
+        collector_registry.register("fitbit_sleep_log_time_in_bed_seconds", "Time in bed of each sleep log of the day (main sleep and naps) in seconds", self.sleep_log_time_in_bed_seconds.clone());
+        collector_registry.register("fitbit_sleep_log_start_time_seconds", "Start time of each sleep log of the day (main sleep and naps) as UNIX timestamp", self.sleep_log_start_time_seconds.clone());
+
+        let collector_registry = if self.is_collector_enabled("activity") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_calories_out", "Total calories burned today", self.calories_out.clone());
+        collector_registry.register("fitbit_distance_meters", "Total distance today in meters", self.distance_meters.clone());
+        collector_registry.register(
+            "fitbit_distance_by_source_meters",
+            "Distance today in meters by source: tracker, logged activities or intensity",
+            self.distance_by_source_meters.clone(),
+        );
+        collector_registry.register("fitbit_floors", "Total floors climbed today", self.floors.clone());
+        collector_registry.register("fitbit_active_duration_seconds", "Time spent fairly or very active today in seconds", self.active_duration_seconds.clone());
+
+        let collector_registry = if self.is_collector_enabled("activity_logs") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_activity_duration_seconds", "Active duration of the recent activity logs (workouts) in seconds", self.activity_duration_seconds.clone());
+        collector_registry.register("fitbit_activity_calories", "Calories burned during the recent activity logs (workouts)", self.activity_calories.clone());
+        collector_registry.register("fitbit_activity_average_heart_rate_bpm", "Average heart rate during the recent activity logs (workouts)", self.activity_average_heart_rate_bpm.clone());
+        collector_registry.register("fitbit_activity_distance_meters", "Distance of the recent activity logs (workouts) in meters", self.activity_distance_meters.clone());
+        collector_registry.register("fitbit_activity_start_time_seconds", "Start time of the recent activity logs (workouts) as UNIX timestamp", self.activity_start_time_seconds.clone());
+
+        let collector_registry = if self.is_collector_enabled("goals") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_goal_steps", "Daily goal of steps", self.goal_steps.clone());
+        collector_registry.register("fitbit_goal_calories_out", "Daily goal of calories burned", self.goal_calories_out.clone());
+        collector_registry.register("fitbit_goal_distance_meters", "Daily goal of distance in meters", self.goal_distance_meters.clone());
+        collector_registry.register("fitbit_goal_floors", "Daily goal of floors climbed", self.goal_floors.clone());
+        collector_registry.register("fitbit_goal_active_duration_seconds", "Daily goal of time spent fairly or very active in seconds", self.goal_active_duration_seconds.clone());
+        collector_registry.register("fitbit_goal_met", "Whether today's value reached the daily goal (1) or not (0), for the goals that are set", self.goal_met.clone());
+
+        let collector_registry = if self.is_collector_enabled("devices") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_synced_recently", "Whether a device synced recently (1) or not (0), see --synced-recently-max-age", self.synced_recently.clone());
+        collector_registry.register("fitbit_battery_low", "Whether the battery of the device is low or empty (1) or not (0)", self.battery_low.clone());
+
+        let collector_registry = if self.is_collector_enabled("leaderboard") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_leaderboard_steps", "Steps of the last 7 days of the user and of their friends, from the friends leaderboard", self.leaderboard_steps.clone());
+        collector_registry.register("fitbit_leaderboard_rank", "Rank of the user and of their friends on the friends leaderboard (1 is the most steps of the last 7 days)", self.leaderboard_rank.clone());
+
+        let collector_registry = if self.is_collector_enabled("badges") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_badges", "Number of times each badge was earned over the lifetime of the account, by badge type and threshold", self.badges.clone());
+
+        let collector_registry = if self.is_collector_enabled("lifetime") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_lifetime_steps", "Total steps over the lifetime of the account", self.lifetime_steps.clone());
+        collector_registry.register("fitbit_lifetime_distance_meters", "Total distance over the lifetime of the account in meters", self.lifetime_distance_meters.clone());
+        collector_registry.register("fitbit_lifetime_floors", "Total floors climbed over the lifetime of the account", self.lifetime_floors.clone());
+        collector_registry.register("fitbit_best_day_steps", "Steps of the best day, labelled by its date", self.best_day_steps.clone());
+        collector_registry.register("fitbit_best_day_distance_meters", "Distance of the best day in meters, labelled by its date", self.best_day_distance_meters.clone());
+        collector_registry.register("fitbit_best_day_floors", "Floors climbed on the best day, labelled by its date", self.best_day_floors.clone());
+
+        let collector_registry = if self.is_collector_enabled("ecg") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_ecg_classification", "Classification of the latest ECG reading, e.g. Normal Sinus Rhythm or Atrial Fibrillation (always 1)", self.ecg_classification.clone());
+        collector_registry.register("fitbit_ecg_average_heart_rate_bpm", "Average heart rate during the latest ECG reading", self.ecg_average_heart_rate_bpm.clone());
+
+        let collector_registry = if self.is_collector_enabled("irn") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_irregular_rhythm_notifications", "Number of irregular rhythm notifications (AFib alerts) received since the exporter started", self.irregular_rhythm_notifications.clone());
+
+        let collector_registry = if self.is_collector_enabled("cardio_score") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_vo2max", "Cardio Fitness Score (VO2 Max) in mL/kg/min, when Fitbit estimates a single value", self.vo2max.clone());
+        collector_registry.register("fitbit_vo2max_lower", "Lower bound of the Cardio Fitness Score (VO2 Max) in mL/kg/min, when Fitbit estimates a range", self.vo2max_lower.clone());
+        collector_registry.register("fitbit_vo2max_upper", "Upper bound of the Cardio Fitness Score (VO2 Max) in mL/kg/min, when Fitbit estimates a range", self.vo2max_upper.clone());
+
+        let collector_registry = if self.is_collector_enabled("breathing_rate") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_breathing_rate", "Average breathing rate of last night's main sleep in breaths per minute, per sleep stage (deep, rem, light) and over the full sleep", self.breathing_rate.clone());
+
+        let collector_registry = if self.is_collector_enabled("heart_rate_intraday") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_heart_rate_minutes", "Minutes of the day spent in each heart rate bucket (in beats per minute), from the intraday heart rate", self.heart_rate_minutes.clone());
+
+        let collector_registry = if self.is_collector_enabled("temperature") { &mut registry } else { &mut unexposed };
+        collector_registry.register("fitbit_skin_temp_delta_celsius", "Deviation of the nightly skin temperature from the personal baseline in degrees Celsius", self.skin_temp_delta_celsius.clone());
+        collector_registry.register("fitbit_core_temp_celsius", "Last core temperature logged by the user in degrees Celsius", self.core_temp_celsius.clone());
+
+        registry.register("fitbit_collector_timeout", "Number of runs of the collector skipped because the deadline of the scrape was exceeded", self.collector_timeouts.clone());
+        if self.scheduler.remaining_budget().is_some() {
+            registry.register("fitbit_collector_budget_skip", "Number of runs of the collector skipped because the request budget of the last hour was exhausted, see --hourly-request-budget", self.collector_budget_skips.clone());
+        }
+
+        if self.max_series_per_family.is_some() {
+            registry.register("fitbit_series_collapsed", "Number of series collapsed into the other bucket of the family by the last update, see --max-series-per-family", self.series_collapsed.clone());
+        }
+
+        registry.register("fitbit_data_timestamp_seconds", "Time of the underlying data of the collector as UNIX timestamp: the last sync of the devices (as of which the daily totals are), the end of the last sleep log or workout", self.data_timestamp_seconds.clone());
+
+        self.error_budget.register(&mut registry);
+
+        if self.expose_previous_day && self.is_collector_enabled("by_date") {
+            registry.register("fitbit_steps_by_date", "Total number of steps of the day given by the date label", self.steps_by_date.clone());
+            registry.register("fitbit_water_ml_by_date", "Total water consumed in milliliters on the day given by the date label", self.water_ml_by_date.clone());
+            registry.register("fitbit_calories_in_by_date", "Total calories logged as food on the day given by the date label", self.calories_in_by_date.clone());
+        }
+
+        if self.recovery_weights.is_some() && self.is_collector_enabled("recovery") {
+            registry.register("fitbit_recovery_score", "Recovery score from 0 to 100, the weighted average of the deviations of the resting heart rate and the HRV from their baselines and of the sleep efficiency", self.recovery_score.clone());
+        }
+
+        for register in self.external_metrics.lock().unwrap().iter() {
+            register(&mut registry);
+        }
+        registry
+    }
+
+    /// The registry of the exposed metrics. Locked while encoding it, e.g. until a config change is applied.
+    pub fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap()
+    }
+
+    /// Registers metrics kept outside of `FitbitMetrics`, e.g. the ones of the webhook, in the registry. `register`
+    /// is called again whenever the registry is rebuilt.
+    pub fn register_external(&self, register: impl Fn(&mut Registry) + Send + Sync + 'static) {
+        register(&mut self.registry());
+        self.external_metrics.lock().unwrap().push(Box::new(register));
+    }
+
+    /// Returns the current settings of the collectors.
+    pub fn collector_config(&self) -> CollectorConfig {
+        CollectorConfig {
+            collectors: COLLECTORS.iter().filter(|collector| self.is_collector_enabled(collector)).map(|collector| collector.to_string()).collect(),
+            cadences: self.cadences.lock().unwrap().iter().map(|(collector, cadence)| (collector.clone(), cadence.as_secs())).collect(),
+        }
+    }
+
+    /// Enables or disables collectors and changes their cadences at runtime, and rebuilds the registry so that the
+    /// metrics of the disabled collectors are no longer exposed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message, without changing anything, if the update is invalid (e.g. an unknown collector).
+    ///
+    /// # Returns
+    ///
+    /// The settings of the collectors after the update.
+    pub fn update_collector_config(&self, update: &CollectorConfigUpdate) -> Result<CollectorConfig, String> {
+        {
+            let mut collectors = self.collectors.lock().unwrap();
+            let mut cadences = self.cadences.lock().unwrap();
+            let (mut new_collectors, mut new_cadences) = (collectors.clone(), cadences.clone());
+            update.apply(&mut new_collectors, &mut new_cadences)?;
+            *collectors = new_collectors;
+            *cadences = new_cadences;
+        }
+        let registry = self.build_registry();
+        *self.registry() = registry;
+        let config = self.collector_config();
+        info!("Collectors updated: enabled {}, cadences {:?}", config.collectors.join(","), config.cadences);
+        Ok(config)
+    }
+
+    /// The minimum delay between two updates of the collector set through `update_collector_config`, if any.
+    pub fn cadence(&self, collector: &str) -> Option<Duration> {
+        self.cadences.lock().unwrap().get(collector).copied()
+    }
+
+    /// Drops the historical points of the daily metrics, i.e. the ones with a timestamp, if `drop_served_history` is
+    /// set. To be called once they're served. The current value of the steps is kept.
+    pub fn release_served_history(&self) {
+        if self.drop_served_history {
+            self.steps.metric_points().retain(|(_, timestamp)| timestamp.is_none());
+            self.steps_estimated.metric_points().clear();
+            self.resting_heart_rate.metric_points().clear();
+            self.daily_floors.metric_points().clear();
+            self.daily_elevation_meters.metric_points().clear();
+            self.weight_grams.metric_points().clear();
+        }
+    }
+
+    /// Pushes the steps of a past day with its timestamp, replacing the point of the same timestamp if any, so that
+    /// serving /history again or backfilling a day already pushed doesn't duplicate the samples. The day is moved
+    /// between `fitbit_steps` and `fitbit_steps_estimated` if it's no longer (or now) estimated.
+    pub fn push_historical_steps(&self, steps: i64, timestamp: Duration, estimated: bool) {
+        let (gauge, other) = if estimated { (&self.steps_estimated, &self.steps) } else { (&self.steps, &self.steps_estimated) };
+        other.metric_points().retain(|(_, point_timestamp)| *point_timestamp != Some(timestamp));
+        upsert_point(gauge, steps, timestamp);
+    }
+
+    /// Pushes the resting heart rate of a past day with its timestamp, replacing the point of the same timestamp if
+    /// any.
+    pub fn push_historical_resting_heart_rate(&self, bpm: i64, timestamp: Duration) {
+        upsert_point(&self.resting_heart_rate, bpm, timestamp);
+    }
+
+    /// Pushes the floors climbed on a past day with its timestamp, replacing the point of the same timestamp if any.
+    pub fn push_historical_floors(&self, floors: i64, timestamp: Duration) {
+        upsert_point(&self.daily_floors, floors, timestamp);
+    }
+
+    /// Pushes the elevation climbed on a past day with its timestamp, replacing the point of the same timestamp if
+    /// any.
+    pub fn push_historical_elevation(&self, meters: i64, timestamp: Duration) {
+        upsert_point(&self.daily_elevation_meters, meters, timestamp);
+    }
+
+    /// Pushes a weigh-in with the timestamp of its time, replacing the point of the same timestamp if any.
+    pub fn push_historical_weight(&self, grams: i64, timestamp: Duration) {
+        upsert_point(&self.weight_grams, grams, timestamp);
+    }
+
+    /// Drops the historical points older than `history_max_age`, and the oldest ones beyond `max_history_points` per
+    /// family, so that a long-running exporter doesn't accumulate them forever. The current value of the steps is
+    /// kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time, as a `Duration` since the UNIX epoch like the timestamps of the points.
+    ///
+    /// # Returns
+    ///
+    /// The number of dropped points.
+    pub fn prune_history(&self, now: Duration) -> usize {
+        let min_timestamp = self.history_max_age.map(|max_age| now.saturating_sub(max_age));
+        [&self.steps, &self.steps_estimated, &self.resting_heart_rate, &self.daily_floors, &self.daily_elevation_meters, &self.weight_grams]
+            .into_iter()
+            .map(|gauge| prune_points(&mut gauge.metric_points(), min_timestamp, self.max_history_points))
+            .sum()
+    }
+
+    /// Records a rate limit of the Fitbit API, which the collectors wait out until `retry_after` has elapsed.
+    pub fn record_rate_limit(&self, retry_after: Duration) {
+        let until = Instant::now() + retry_after;
+        let mut rate_limited_until = self.rate_limited_until.lock().unwrap();
+        *rate_limited_until = Some(rate_limited_until.map_or(until, |previous| previous.max(until)));
+    }
+
+    /// Returns the time left until the last rate limit of the Fitbit API ends, or `None` if it already ended.
+    pub fn rate_limit_remaining(&self) -> Option<Duration> {
+        let until = (*self.rate_limited_until.lock().unwrap())?;
+        Some(until.saturating_duration_since(Instant::now())).filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Returns true if the collector is enabled, i.e. it runs and its metrics are exposed.
+    pub fn is_collector_enabled(&self, collector: &str) -> bool {
+        is_collector_enabled(&self.collectors.lock().unwrap(), collector)
+    }
+
+    /// Records the current values of the registry in the sample store, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the registry cannot be encoded or the samples cannot be stored.
+    pub async fn record_samples(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(sample_store) = &self.sample_store {
+            let mut txt = String::new();
+            encode(&mut txt, &self.registry())?;
+            let families = parse_openmetrics(&txt);
+            let recorded_at_ms = Utc::now().timestamp_millis();
+            run_blocking(sample_store, move |sample_store| sample_store.record(&families, recorded_at_ms)).await?;
+        }
+        Ok(())
     }
 }
 
@@ -90,12 +980,14 @@ impl FitbitMetrics {
 /// This function is a generic utility for fetching data using a given future
 /// and updating a metric by applying a provided update function.
 ///
+/// The future borrows the client from the read guard of the caller: it must not take the lock again, as a writer
+/// queued in between (e.g. a token refresh) would wait for the caller's guard while the second read waits for it.
+///
 /// # Arguments
 ///
-/// * `fitbit_client` - An `Arc<RwLock<FitbitClient>>` containing the shared Fitbit client.
 /// * `data_future` - A future that resolves to a `Result<T, FitbitError>`, where `T` is the data to be fetched.
 /// * `update_metric` - A function that takes the fetched data `T` and returns a future `G` that resolves to `()`.
-///                     This function is responsible for updating the corresponding metric using the fetched data.
+///   This function is responsible for updating the corresponding metric using the fetched data.
 ///
 /// # Type Parameters
 ///
@@ -107,7 +999,6 @@ impl FitbitMetrics {
 ///
 /// Returns a `FitbitError` if there's an error while fetching the data or updating the metric.
 async fn process_future<T, F, G>(
-    fitbit_client: Arc<RwLock<FitbitClient>>,
     data_future: impl Future<Output = Result<T, FitbitError>>,
     callback: F,
 ) -> Result<(), FitbitError>
@@ -115,10 +1006,8 @@ where
     F: FnOnce(T) -> G,
     G: Future<Output = T>,
 {
-    let read_locked_client = fitbit_client.read().await;
     match data_future.await {
         Ok(data) => {
-            drop(read_locked_client); // Release the read lock explicitly before calling update_metric
             callback(data).await;
             Ok(())
         }
@@ -138,15 +1027,37 @@ where
 ///
 /// # Arguments
 ///
-/// * `fitbit_client` - An `Arc<RwLock<FitbitClient>>` containing the shared Fitbit client.
+/// * `fitbit_client` - An `Arc<RwLock<dyn FitbitApi>>` containing the shared Fitbit client.
 /// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
 ///
 /// # Errors
 ///
 /// Returns a boxed error if there's an issue while updating the metrics.
 pub async fn update_current_metrics(
-    fitbit_client: Arc<RwLock<FitbitClient>>,
+    fitbit_client: Arc<RwLock<dyn FitbitApi>>,
+    fitbit_metrics: Arc<FitbitMetrics>,
+) -> Result<(), Box<dyn Error>> {
+    update_selected_metrics(fitbit_client, fitbit_metrics, &CollectorSelection::all()).await
+}
+
+/// Updates the metrics of the selected collectors, e.g. the ones of the scrape profile given in `collect[]`.
+///
+/// The collectors that aren't selected, or that were updated more recently than the cadence of their profile,
+/// keep their last-known values without calling the Fitbit API.
+///
+/// # Arguments
+///
+/// * `fitbit_client` - An `Arc<RwLock<dyn FitbitApi>>` containing the shared Fitbit client.
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+/// * `selection` - The collectors to run (see `ScrapeProfiles::select`).
+///
+/// # Errors
+///
+/// Returns a boxed error if every collector that ran failed.
+pub async fn update_selected_metrics(
+    fitbit_client: Arc<RwLock<dyn FitbitApi>>,
     fitbit_metrics: Arc<FitbitMetrics>,
+    selection: &CollectorSelection,
 ) -> Result<(), Box<dyn Error>> {
     let read_locked_client = fitbit_client.read().await;
 
@@ -158,101 +1069,1993 @@ pub async fn update_current_metrics(
 
     // Update steps metric
     let steps_future = read_locked_client.fetch_steps();
-    process_future(fitbit_client.clone(), steps_future, {
+    let steps_collector = run_collector(&fitbit_metrics, selection, "steps", process_future(steps_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
         move |steps| async move {
-            match fitbit_metrics.steps.metric_points().len() {
-                0 => fitbit_metrics.steps.push(steps as i64, None),
-                1 => fitbit_metrics.steps.metric_points()[0] = (steps as i64, None),
-                _ => error!("Unexpected number of metric points for steps metric: {}",
-                            fitbit_metrics.steps.metric_points().len()),
-            }
+            set_current_steps(&fitbit_metrics, steps.as_i64());
             steps
         }
-    })
-    .await?;
+    }))
+;
 
-/* 
-    // Update sleep metric
-    let sleep_future = read_locked_client.fetch_sleep();
+    // Update water metric
+    let water_future = read_locked_client.fetch_water();
+    let water_collector = run_collector(&fitbit_metrics, selection, "water", process_future(water_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |water| async move {
+            fitbit_metrics.water_ml.set(water);
+            water
+        }
+    }))
+;
 
-    fetch_and_update_metric(fitbit_client.clone(), sleep_future, {
+    // Update food (calories in and nutrients) metrics
+    let food_future = read_locked_client.fetch_food_summary();
+    let food_collector = run_collector(&fitbit_metrics, selection, "food", process_future(food_future, {
         let fitbit_metrics = fitbit_metrics.clone();
-        move |sleep_json| async move {
-            if let Some(sleep) = sleep_json["sleep"].as_array().and_then(|arr| arr.get(0)) {
-                let summary = &sleep["levels"]["summary"];
-                fitbit_metrics.sleep_minutes_deep.set(summary["deep"]["minutes"].as_i64().unwrap_or(0));
-                fitbit_metrics.sleep_minutes_light.set(summary["light"]["minutes"].as_i64().unwrap_or(0));
-                fitbit_metrics.sleep_minutes_rem.set(summary["rem"]["minutes"].as_i64().unwrap_or(0));
-                fitbit_metrics.sleep_minutes_wake.set(summary["wake"]["minutes"].as_i64().unwrap_or(0));
-
-                fitbit_metrics.sleep_duration.set(sleep_json["summary"]["totalMinutesAsleep"].as_i64().unwrap_or(0));
-                fitbit_metrics.sleep_efficiency.set(sleep["efficiency"].as_i64().unwrap_or(0));
-                fitbit_metrics.total_time_in_bed.set(sleep_json["summary"]["totalTimeInBed"].as_i64().unwrap_or(0));
-                fitbit_metrics.total_minutes_asleep.set(sleep_json["summary"]["totalMinutesAsleep"].as_i64().unwrap_or(0));
-
-                if let (Some(start_time), Some(end_time)) = (
-                    sleep["startTime"].as_str(),
-                    sleep["endTime"].as_str(),
-                ) {
-                    fitbit_metrics.sleep_start_time.set(parse_datetime_to_unix_timestamp(start_time));
-                    fitbit_metrics.sleep_end_time.set(parse_datetime_to_unix_timestamp(end_time));
-                } else {
-                    error!("Start or end time not found in sleep data");
-                }
+        move |summary| async move {
+            fitbit_metrics.calories_in.set(summary.calories);
+            fitbit_metrics.carbs_grams.set(summary.carbs);
+            fitbit_metrics.fat_grams.set(summary.fat);
+            fitbit_metrics.protein_grams.set(summary.protein);
+            fitbit_metrics.fiber_grams.set(summary.fiber);
+            fitbit_metrics.sodium_milligrams.set(summary.sodium);
+            summary
+        }
+    }))
+;
 
-                fitbit_metrics.time_in_bed.set(sleep["timeInBed"].as_i64().unwrap_or(0));
-                fitbit_metrics.minutes_asleep.set(sleep["minutesAsleep"].as_i64().unwrap_or(0));
-                fitbit_metrics.minutes_awake.set(sleep["minutesAwake"].as_i64().unwrap_or(0));
-                fitbit_metrics.minutes_after_wakeup.set(sleep["minutesAfterWakeup"].as_i64().unwrap_or(0));
-                fitbit_metrics.is_main_sleep.set(sleep["isMainSleep"].as_bool().unwrap_or(false) as i64);
-                
-            } else {
-                error!("Sleep data not found or in unexpected format");
-            }
-            sleep_json
+    // Update sleep metrics. The sleep is fetched once per update, as the recovery score uses it as well.
+    let sleep = OnceCell::new();
+    let sleep_future = async { sleep.get_or_try_init(|| read_locked_client.fetch_sleep()).await.cloned() };
+    let sleep_collector = run_collector(&fitbit_metrics, selection, "sleep", process_future(sleep_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |sleep| async move {
+            update_sleep_metrics(&fitbit_metrics, &sleep);
+            sleep
         }
-    })
-    .await?;
- */
+    }))
+;
 
-    Ok(())
-}
+    // Update activity metrics (calories out, distance, floors, active minutes)
+    let activity_future = read_locked_client.fetch_activity_summary();
+    let activity_collector = run_collector(&fitbit_metrics, selection, "activity", process_future(activity_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |summary| async move {
+            fitbit_metrics.calories_out.set(summary.calories_out);
+            update_distance_metrics(&fitbit_metrics, &summary);
+            fitbit_metrics.floors.set(summary.floors.unwrap_or(0.0));
+            fitbit_metrics.active_duration_seconds.set(summary.active_minutes().as_seconds());
+            summary
+        }
+    }))
+;
+
+    // Update recent activity logs (workouts) metrics
+    let activity_logs_future = read_locked_client.fetch_activity_logs(RECENT_ACTIVITY_LOGS);
+    let activity_logs_collector = run_collector(&fitbit_metrics, selection, "activity_logs", process_future(activity_logs_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |activity_logs| async move {
+            update_activity_log_metrics(&fitbit_metrics, &activity_logs);
+            activity_logs
+        }
+    }))
+;
 
+    // Update daily goals
+    let goals_future = read_locked_client.fetch_activity_goals();
+    let goals_collector = run_collector(&fitbit_metrics, selection, "goals", process_future(goals_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |goals| async move {
+            update_goal_metrics(&fitbit_metrics, &goals);
+            goals
+        }
+    }))
+;
 
-/// Parses a datetime string in the format "%Y-%m-%dT%H:%M:%S%.f" and returns a UNIX timestamp.
-///
-/// # Arguments
-///
-/// * `datetime` - A string representing a datetime in the format "%Y-%m-%dT%H:%M:%S%.f".
-///
-/// # Example
-///
-/// ```
-/// let timestamp = parse_datetime_to_unix_timestamp("2023-03-04T03:47:00.000");
-/// ```
-///
-/// # Returns
-///
-/// A `i64` representing the UNIX timestamp of the provided datetime string, or 0 if an error occurs during parsing.
-///
-/// # Notes
-///
-/// The provided datetime string is expected to have a timezone-agnostic format. This function assumes the datetime
-/// is in UTC when converting to a UNIX timestamp.
-fn parse_datetime_to_unix_timestamp(datetime: &str) -> i64 {
-    // The expected format for the datetime string
-    let format = "%Y-%m-%dT%H:%M:%S%.f";
+    // Update the last sync time of the devices, which tells how fresh the daily totals are
+    let devices_future = read_locked_client.fetch_devices();
+    let devices_collector = run_collector(&fitbit_metrics, selection, "devices", process_future(devices_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |devices| async move {
+            update_device_metrics(&fitbit_metrics, &devices);
+            devices
+        }
+    }))
+;
+
+    // Update the friends leaderboard
+    let leaderboard_future = read_locked_client.fetch_leaderboard();
+    let leaderboard_collector = run_collector(&fitbit_metrics, selection, "leaderboard", process_future(leaderboard_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |ranks| async move {
+            update_leaderboard_metrics(&fitbit_metrics, &ranks);
+            ranks
+        }
+    }))
+;
 
-    // Attempt to parse the datetime string into a NaiveDateTime (which ignores the timezone)
-    match NaiveDateTime::parse_from_str(datetime, format) {
-        Ok(naive_dt) => {
-            // If parsing is successful, convert the NaiveDateTime to a DateTime<Utc>
-            let dt: DateTime<Utc> = DateTime::from_utc(naive_dt, Utc);
-            dt.timestamp() // Return the UNIX timestamp of the DateTime<Utc>
+    // Update the badges
+    let badges_future = read_locked_client.fetch_badges();
+    let badges_collector = run_collector(&fitbit_metrics, selection, "badges", process_future(badges_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |badges| async move {
+            update_badge_metrics(&fitbit_metrics, &badges);
+            badges
         }
-        Err(_) => {
-            error!("Failed to parse date-time string: {}", datetime);
-            0
+    }))
+;
+
+    // Update the lifetime totals and best days
+    let lifetime_future = read_locked_client.fetch_lifetime_stats();
+    let lifetime_collector = run_collector(&fitbit_metrics, selection, "lifetime", process_future(lifetime_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |stats| async move {
+            update_lifetime_metrics(&fitbit_metrics, &stats);
+            stats
         }
-    }
-}
\ No newline at end of file
+    }))
+;
+
+    // Update the latest ECG reading
+    let ecg_future = read_locked_client.fetch_ecg_readings(1);
+    let ecg_collector = run_collector(&fitbit_metrics, selection, "ecg", process_future(ecg_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |readings| async move {
+            update_ecg_metrics(&fitbit_metrics, &readings);
+            readings
+        }
+    }))
+;
+
+    // Count the new irregular rhythm notifications
+    let irn_future = read_locked_client.fetch_irn_alerts(RECENT_IRN_ALERTS);
+    let irn_collector = run_collector(&fitbit_metrics, selection, "irn", process_future(irn_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |alerts| async move {
+            update_irn_metrics(&fitbit_metrics, &alerts);
+            alerts
+        }
+    }))
+;
+
+    // Update the Cardio Fitness Score (VO2 Max)
+    let cardio_score_future = read_locked_client.fetch_cardio_score();
+    let cardio_score_collector = run_collector(&fitbit_metrics, selection, "cardio_score", process_future(cardio_score_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |cardio_score| async move {
+            update_cardio_score_metrics(&fitbit_metrics, cardio_score.as_ref());
+            cardio_score
+        }
+    }))
+;
+
+    // Update last night's breathing rate per sleep stage
+    let breathing_rate_future = read_locked_client.fetch_breathing_rate();
+    let breathing_rate_collector = run_collector(&fitbit_metrics, selection, "breathing_rate", process_future(breathing_rate_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |breathing_rate| async move {
+            update_breathing_rate_metrics(&fitbit_metrics, breathing_rate.as_ref());
+            breathing_rate
+        }
+    }))
+;
+
+    // Update the minutes spent in each heart rate bucket today. Fitbit's "today" is the user's.
+    let today = fitbit_metrics.user_today();
+    let heart_rate_since = fitbit_metrics.heart_rate_intraday_since(today);
+    let heart_rate_intraday_future = read_locked_client.fetch_heart_rate_intraday(heart_rate_since);
+    let heart_rate_intraday_collector = run_collector(&fitbit_metrics, selection, "heart_rate_intraday", process_future(heart_rate_intraday_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |samples| async move {
+            update_heart_rate_histogram(&fitbit_metrics, today, heart_rate_since, &samples);
+            samples
+        }
+    }))
+;
+
+    // Update the nightly skin temperature and the logged core temperature
+    let temperature_future = async {
+        let skin_temperature = read_locked_client.fetch_skin_temperature().await?;
+        let core_temperature = read_locked_client.fetch_core_temperature().await?;
+        Ok((skin_temperature, core_temperature))
+    };
+    let temperature_collector = run_collector(&fitbit_metrics, selection, "temperature", process_future(temperature_future, {
+        let fitbit_metrics = fitbit_metrics.clone();
+        move |(skin_temperature, core_temperature)| async move {
+            update_temperature_metrics(&fitbit_metrics, skin_temperature.as_ref(), core_temperature.as_ref());
+            (skin_temperature, core_temperature)
+        }
+    }))
+;
+
+    // Update today's and yesterday's daily metrics labelled by date
+    let by_date_collector = async {
+        if fitbit_metrics.expose_previous_day {
+            let daily_future = update_metrics_by_date(&*read_locked_client, &fitbit_metrics);
+            Some(run_collector(&fitbit_metrics, selection, "by_date", daily_future).await)
+        } else {
+            None
+        }
+    };
+
+    // Update the recovery score
+    let recovery_collector = async {
+        match fitbit_metrics.recovery_weights {
+            Some(weights) => {
+                let recovery_future = update_recovery_score(&*read_locked_client, &fitbit_metrics, &weights, &sleep);
+                Some(run_collector(&fitbit_metrics, selection, "recovery", recovery_future).await)
+            }
+            None => None,
+        }
+    };
+
+    // Run the collectors concurrently, as many at once as the scheduler allows (see `RequestScheduler`)
+    let (steps_result, water_result, food_result, sleep_result, activity_result, activity_logs_result, goals_result, devices_result, leaderboard_result, badges_result, lifetime_result, ecg_result, irn_result, cardio_score_result, breathing_rate_result, heart_rate_intraday_result, temperature_result, by_date_result, recovery_result) = tokio::join!(
+        steps_collector,
+        water_collector,
+        food_collector,
+        sleep_collector,
+        activity_collector,
+        activity_logs_collector,
+        goals_collector,
+        devices_collector,
+        leaderboard_collector,
+        badges_collector,
+        lifetime_collector,
+        ecg_collector,
+        irn_collector,
+        cardio_score_collector,
+        breathing_rate_collector,
+        heart_rate_intraday_collector,
+        temperature_collector,
+        by_date_collector,
+        recovery_collector,
+    );
+    let mut results = vec![steps_result, water_result, food_result, sleep_result, activity_result, activity_logs_result, goals_result, devices_result, leaderboard_result, badges_result, lifetime_result, ecg_result, irn_result, cardio_score_result, breathing_rate_result, heart_rate_intraday_result, temperature_result];
+    results.extend(by_date_result);
+    results.extend(recovery_result);
+
+    // Compare today's values with the goals, whichever of them were just updated
+    if fitbit_metrics.is_collector_enabled("goals") {
+        update_goal_met_metrics(&fitbit_metrics);
+    }
+
+    // A failing collector doesn't fail the whole scrape, since the others still have fresh data.
+    // The scrape fails only when every collector failed, e.g. because the access token expired. In that case, the
+    // values of the previous update are kept in the sample store.
+    if results.iter().all(|result| result.is_err()) {
+        if let Some(Err(err)) = results.into_iter().next() {
+            return Err(Box::new(err));
+        }
+    }
+
+    if let Err(err) = fitbit_metrics.record_samples().await {
+        error!("Failed to record the samples: {}", err);
+    }
+    fitbit_metrics.refreshed.notify_waiters();
+
+    Ok(())
+}
+
+
+/// Updates the metrics periodically according to the given schedule, independently of the scrapes.
+///
+/// With this poller, /metrics serves the last-known values instead of calling the Fitbit API on every scrape,
+/// so that the scrape latency stays flat and the API usage only depends on the schedule.
+/// The first update runs right away.
+///
+/// # Arguments
+///
+/// * `fitbit_client` - An `Arc<RwLock<dyn FitbitApi>>` that provides access to the shared Fitbit client.
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+/// * `schedule` - A `PollSchedule`, i.e. a fixed interval or cron expressions, e.g. more frequent during waking hours.
+/// * `max_jitter` - The maximum random delay added to each scheduled update.
+pub async fn poll_metrics_periodically(
+    fitbit_client: Arc<RwLock<dyn FitbitApi>>,
+    fitbit_metrics: Arc<FitbitMetrics>,
+    schedule: PollSchedule,
+    max_jitter: Duration,
+) {
+    loop {
+        debug!("[poll_metrics_periodically] Updating the metrics...");
+        match update_current_metrics(fitbit_client.clone(), fitbit_metrics.clone()).await {
+            Ok(_) => debug!("[poll_metrics_periodically] Metrics successfully updated."),
+            Err(err) => error!("[poll_metrics_periodically] Error updating metrics: {:?}", err),
+        }
+
+        // A rate limit postpones the next update until it ends, instead of extending it
+        let delay = schedule.next_delay(Local::now(), max_jitter).max(fitbit_metrics.rate_limit_remaining().unwrap_or_default());
+        debug!("[poll_metrics_periodically] Sleeping for {} seconds before the next update...", delay.as_secs());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+
+/// Runs a first update of the metrics in the background, e.g. at startup, so that the first scrape gets data
+/// instead of racing the initial calls to the Fitbit API.
+///
+/// # Arguments
+///
+/// * `fitbit_client` - An `Arc<RwLock<dyn FitbitApi>>` that provides access to the shared Fitbit client.
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+///
+/// # Returns
+///
+/// A `WarmUp`, with which the scrapes arriving during the update wait for it.
+pub fn warm_up_metrics(fitbit_client: Arc<RwLock<dyn FitbitApi>>, fitbit_metrics: Arc<FitbitMetrics>) -> WarmUp {
+    let (outcome_sender, outcome_receiver) = watch::channel(None);
+    tokio::spawn(async move {
+        debug!("[warm_up_metrics] Updating the metrics...");
+        let succeeded = match update_current_metrics(fitbit_client, fitbit_metrics).await {
+            Ok(_) => {
+                debug!("[warm_up_metrics] Metrics successfully updated.");
+                true
+            }
+            Err(err) => {
+                error!("[warm_up_metrics] Error updating metrics: {:?}", err);
+                false
+            }
+        };
+        let _ = outcome_sender.send(Some(succeeded));
+    });
+    WarmUp { outcome: outcome_receiver }
+}
+
+/// A first update of the metrics running in the background (see `warm_up_metrics`).
+#[derive(Debug, Clone)]
+pub struct WarmUp {
+    // `None` while the update is running, then whether it succeeded.
+    outcome: watch::Receiver<Option<bool>>,
+}
+
+impl WarmUp {
+    /// Waits for the warm-up to finish.
+    ///
+    /// # Returns
+    ///
+    /// True if the warm-up was still running and succeeded, i.e. the metrics have just been updated.
+    pub async fn wait(&self) -> bool {
+        let mut outcome = self.outcome.clone();
+        if outcome.borrow().is_some() {
+            return false;
+        }
+        // `changed` fails if the warm-up task is gone, e.g. it panicked, in which case there's nothing to wait for.
+        while outcome.borrow().is_none() {
+            if outcome.changed().await.is_err() {
+                return false;
+            }
+        }
+        let succeeded = outcome.borrow().unwrap_or(false);
+        succeeded
+    }
+}
+
+
+/// Updates the daily metrics labelled by date with today's and yesterday's values.
+///
+/// The dates are taken from the steps time series, so that "today" is the one of the user's timezone.
+/// The previous values are cleared, so that only the last two days are exposed.
+///
+/// # Errors
+///
+/// Returns a `FitbitError` if any of the requests fails.
+async fn update_metrics_by_date(fitbit_client: &dyn FitbitApi, fitbit_metrics: &FitbitMetrics) -> Result<(), FitbitError> {
+    let steps_last_week = fitbit_client.fetch_steps_last_week().await?;
+    let last_two_days = &steps_last_week[steps_last_week.len().saturating_sub(2)..];
+
+    let mut values = Vec::new();
+    for (date, steps) in last_two_days {
+        let water = fitbit_client.fetch_water_on(*date).await?;
+        let calories_in = fitbit_client.fetch_food_summary_on(*date).await?.calories;
+        values.push((*date, *steps, water, calories_in));
+    }
+
+    fitbit_metrics.steps_by_date.clear();
+    fitbit_metrics.water_ml_by_date.clear();
+    fitbit_metrics.calories_in_by_date.clear();
+    for (date, steps, water, calories_in) in values {
+        let labels = DateLabels { date: date.format("%Y-%m-%d").to_string() };
+        fitbit_metrics.steps_by_date.get_or_create(&labels).set(steps.as_i64());
+        fitbit_metrics.water_ml_by_date.get_or_create(&labels).set(water);
+        fitbit_metrics.calories_in_by_date.get_or_create(&labels).set(calories_in);
+    }
+
+    Ok(())
+}
+
+/// Rolls the daily metrics over at every midnight of the user's timezone (see `FitbitMetrics::utc_offset`), i.e.
+/// when Fitbit's "today" changes, so that yesterday's totals don't linger into the new day until the first update
+/// after the tracker synced. The final steps of the previous day are stamped in the same timezone.
+///
+/// # Arguments
+///
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+/// * `timestamp_position` - The time of day at which the final value of the previous day is stamped.
+pub async fn roll_over_daily_metrics_at_midnight(fitbit_metrics: Arc<FitbitMetrics>, timestamp_position: TimestampPosition) {
+    loop {
+        let now = fitbit_metrics.user_now();
+        let today = now.date_naive();
+        // The offset is fixed until the next update of the profile, so the next midnight always exists
+        let next_midnight = now.offset().from_local_datetime(&(today + ChronoDuration::days(1)).and_time(NaiveTime::MIN)).unwrap();
+        let delay = (next_midnight - now).to_std().unwrap_or_default();
+        debug!("Rolling over the daily metrics in {} seconds", delay.as_secs());
+        tokio::time::sleep(delay).await;
+        roll_over_daily_metrics(&fitbit_metrics, today, timestamp_position);
+    }
+}
+
+/// Prunes the historical points every `interval` (see `FitbitMetrics::prune_history`).
+///
+/// # Arguments
+///
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+/// * `interval` - The delay between two prunings.
+pub async fn prune_history_periodically(fitbit_metrics: Arc<FitbitMetrics>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let pruned = fitbit_metrics.prune_history(now);
+        if pruned > 0 {
+            debug!("Pruned {} historical points", pruned);
+        }
+    }
+}
+
+/// Deletes the archived samples older than `max_age` every `interval` (see `SampleStore::prune`).
+///
+/// # Arguments
+///
+/// * `sample_store` - The sample store to prune.
+/// * `max_age` - The retention of the archived samples.
+/// * `interval` - The delay between two prunings.
+pub async fn prune_sample_store_periodically(sample_store: Arc<dyn SampleStore>, max_age: Duration, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let before_ms = Utc::now().timestamp_millis() - max_age.as_millis() as i64;
+        match run_blocking(&sample_store, move |sample_store| sample_store.prune(before_ms)).await {
+            Ok(0) => {}
+            Ok(pruned) => debug!("Pruned {} archived samples", pruned),
+            Err(err) => error!("Failed to prune the archived samples: {}", err),
+        }
+    }
+}
+
+/// Resets the daily metrics (steps, water, food, activity) to 0, and pushes the final steps of the previous day
+/// with its timestamp. Only the steps can carry a timestamp: the final values of the others are in the `*_by_date`
+/// metrics with `--expose-previous-day`.
+///
+/// The historical points of the steps and of the resting heart rate pushed before (by /history or the backfill) are
+/// dropped, since they were served by then, so that the points don't accumulate day after day.
+fn roll_over_daily_metrics(fitbit_metrics: &FitbitMetrics, previous_day: NaiveDate, timestamp_position: TimestampPosition) {
+    {
+        let mut points = fitbit_metrics.steps.metric_points();
+        let final_steps = points.iter().find(|(_, timestamp)| timestamp.is_none()).map(|(steps, _)| *steps);
+        points.clear();
+        if let Some(final_steps) = final_steps {
+            points.push((final_steps, Some(daily_timestamp(previous_day, timestamp_position, fitbit_metrics.utc_offset()))));
+        }
+        points.push((0, None));
+    }
+    fitbit_metrics.steps_estimated.metric_points().clear();
+    fitbit_metrics.resting_heart_rate.metric_points().clear();
+    fitbit_metrics.daily_floors.metric_points().clear();
+    fitbit_metrics.daily_elevation_meters.metric_points().clear();
+    fitbit_metrics.weight_grams.metric_points().clear();
+
+    fitbit_metrics.water_ml.set(0.0);
+    fitbit_metrics.calories_in.set(0.0);
+    fitbit_metrics.carbs_grams.set(0.0);
+    fitbit_metrics.fat_grams.set(0.0);
+    fitbit_metrics.protein_grams.set(0.0);
+    fitbit_metrics.fiber_grams.set(0.0);
+    fitbit_metrics.sodium_milligrams.set(0.0);
+
+    fitbit_metrics.calories_out.set(0.0);
+    fitbit_metrics.distance_meters.set(0.0);
+    fitbit_metrics.distance_by_source_meters.clear();
+    fitbit_metrics.floors.set(0.0);
+    fitbit_metrics.active_duration_seconds.set(0.0);
+
+    debug!("Rolled over the daily metrics of {}", previous_day);
+}
+
+/// Drops the points with a timestamp older than `min_timestamp`, then the oldest ones beyond `max_points`. The point
+/// without a timestamp, i.e. the current value, is kept.
+///
+/// # Returns
+///
+/// The number of dropped points.
+fn prune_points(points: &mut Vec<(i64, Option<Duration>)>, min_timestamp: Option<Duration>, max_points: Option<usize>) -> usize {
+    let len = points.len();
+    if let Some(min_timestamp) = min_timestamp {
+        points.retain(|(_, timestamp)| timestamp.is_none_or(|timestamp| timestamp >= min_timestamp));
+    }
+    if let Some(max_points) = max_points {
+        let mut timestamps = points.iter().filter_map(|(_, timestamp)| *timestamp).collect::<Vec<_>>();
+        if timestamps.len() > max_points {
+            timestamps.sort_unstable_by(|a, b| b.cmp(a));
+            let oldest_kept = max_points.checked_sub(1).map(|index| timestamps[index]);
+            points.retain(|(_, timestamp)| match (timestamp, oldest_kept) {
+                (None, _) => true,
+                (Some(timestamp), Some(oldest_kept)) => *timestamp >= oldest_kept,
+                (Some(_), None) => false,
+            });
+        }
+    }
+    len - points.len()
+}
+
+/// Sets the value of the point of the gauge with the given timestamp, or pushes it if there is none.
+fn upsert_point(gauge: &MultiPointGauge, value: i64, timestamp: Duration) {
+    let mut points = gauge.metric_points();
+    match points.iter_mut().find(|(_, point_timestamp)| *point_timestamp == Some(timestamp)) {
+        Some(point) => point.0 = value,
+        None => points.push((value, Some(timestamp))),
+    }
+}
+
+/// Sets today's steps, i.e. the point of the steps without a timestamp, keeping the historical points.
+fn set_current_steps(fitbit_metrics: &FitbitMetrics, steps: i64) {
+    let mut points = fitbit_metrics.steps.metric_points();
+    match points.iter_mut().find(|(_, timestamp)| timestamp.is_none()) {
+        Some(point) => point.0 = steps,
+        None => points.push((steps, None)),
+    }
+}
+
+/// Fetches the inputs of the recovery score (the resting heart rates and the HRV of the last `BASELINE_DAYS` days
+/// up to the user's today, and today's sleep unless the sleep collector already fetched it in `sleep`) and updates
+/// `fitbit_recovery_score`. The score is left as is when none of its inputs is available yet, e.g. early in the
+/// morning before the tracker synced.
+///
+/// # Errors
+///
+/// Returns a `FitbitError` if any of the requests fails.
+async fn update_recovery_score(
+    fitbit_client: &dyn FitbitApi,
+    fitbit_metrics: &FitbitMetrics,
+    weights: &RecoveryWeights,
+    sleep: &OnceCell<SleepLogResponse>,
+) -> Result<(), FitbitError> {
+    let end_date = fitbit_metrics.user_today();
+    let start_date = end_date - ChronoDuration::days(BASELINE_DAYS - 1);
+    let resting_heart_rates = fitbit_client.fetch_resting_heart_rate_range(start_date, end_date).await?;
+    let hrv = fitbit_client.fetch_hrv_range(start_date, end_date).await?;
+    let sleep = sleep.get_or_try_init(|| fitbit_client.fetch_sleep()).await?;
+
+    let inputs = RecoveryInputs {
+        resting_heart_rate: latest_and_baseline(&resting_heart_rates.iter().map(|(_, bpm)| bpm.0).collect::<Vec<f64>>()),
+        hrv: latest_and_baseline(&hrv.iter().map(|(_, rmssd)| *rmssd).collect::<Vec<f64>>()),
+        sleep_efficiency: sleep.sleep.iter().find(|log| log.is_main_sleep).map(|log| log.efficiency as f64),
+    };
+    match recovery_score(&inputs, weights) {
+        Some(score) => {
+            fitbit_metrics.recovery_score.set(score);
+        }
+        None => debug!("Not enough data to compute the recovery score: {:?}", inputs),
+    }
+    Ok(())
+}
+
+/// Runs a collector unless its error budget disabled it, and records the outcome in the error budget.
+///
+/// # Arguments
+///
+/// * `fitbit_metrics` - The metrics holding the error budget.
+/// * `selection` - The collectors selected for this update, with their cadence.
+/// * `collector` - The name of the collector, used as the `collector` label.
+/// * `collect_future` - The future fetching the data and updating the metrics. It's dropped without being polled
+///   (hence without calling the Fitbit API) if the collector is disabled.
+///
+/// # Errors
+///
+/// Returns the error of the collector, after logging it. A disabled or skipped collector returns `Ok(())`, as does a
+/// collector over the deadline of the scrape, whose previous values are served.
+async fn run_collector(
+    fitbit_metrics: &FitbitMetrics,
+    selection: &CollectorSelection,
+    collector: &str,
+    collect_future: impl Future<Output = Result<(), FitbitError>>,
+) -> Result<(), FitbitError> {
+    if !selection.includes(collector) || !fitbit_metrics.is_collector_enabled(collector) {
+        return Ok(());
+    }
+    // The cadence set at runtime applies on top of the one of the scrape profile
+    if let Some(cadence) = selection.cadence(collector).max(fitbit_metrics.cadence(collector)) {
+        if fitbit_metrics.error_budget.succeeded_within(collector, cadence) {
+            debug!("Skipping the {} collector, updated less than {} seconds ago", collector, cadence.as_secs());
+            return Ok(());
+        }
+    }
+    if !fitbit_metrics.error_budget.is_enabled(collector) {
+        debug!("Skipping the disabled {} collector", collector);
+        return Ok(());
+    }
+    if let Some(remaining) = fitbit_metrics.rate_limit_remaining() {
+        debug!("Skipping the {} collector, rate limited for {} more seconds", collector, remaining.as_secs());
+        return Err(FitbitError::RateLimited(remaining));
+    }
+    let _permit = fitbit_metrics.scheduler.acquire().await;
+
+    // Once the deadline is exceeded, the remaining collectors are skipped without calling the Fitbit API, nor taking
+    // from the request budget. A timeout isn't a failure of the collector, nor of the scrape: one slow collector may
+    // have used up the whole deadline.
+    let deadline = selection.deadline();
+    let collect_result = if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        Err(FitbitError::DeadlineExceeded { collector: collector.to_string() })
+    } else {
+        // Like a collector updated recently, a collector over the request budget keeps its last-known values
+        let Some(reservation) = fitbit_metrics.scheduler.reserve(collector) else {
+            warn!("Skipping the {} collector, the request budget of the last hour is exhausted", collector);
+            fitbit_metrics.collector_budget_skips.get_or_create(&CollectorLabels { collector: collector.to_string() }).inc();
+            return Ok(());
+        };
+        let collect_future = async {
+            match deadline {
+                Some(deadline) => timeout_at(deadline, collect_future)
+                    .await
+                    .unwrap_or_else(|_| Err(FitbitError::DeadlineExceeded { collector: collector.to_string() })),
+                None => collect_future.await,
+            }
+        };
+        fitbit_metrics.scheduler.run(collector, reservation, collect_future).await
+    };
+    match collect_result {
+        // Neither is a failure of the collector: the rate limit is shared by all of them.
+        Err(FitbitError::RateLimited(retry_after)) => {
+            warn!("The {} collector was rate limited. Pausing the collectors for {} seconds", collector, retry_after.as_secs());
+            fitbit_metrics.record_rate_limit(retry_after);
+            fitbit_metrics.events.emit(Event::RateLimitExhausted { retry_after });
+            Err(FitbitError::RateLimited(retry_after))
+        }
+        Err(err @ FitbitError::DeadlineExceeded { .. }) => {
+            warn!("{}. Serving its previous values", err);
+            fitbit_metrics.collector_timeouts.get_or_create(&CollectorLabels { collector: collector.to_string() }).inc();
+            Ok(())
+        }
+        Ok(()) => {
+            fitbit_metrics.error_budget.record_success(collector);
+            Ok(())
+        }
+        // The failures are recorded in the event log when they start, so that a collector failing every scrape
+        // doesn't evict the other entries
+        Err(err @ FitbitError::InsufficientScope { .. }) => {
+            error!("The {} collector is forbidden: {}", collector, err);
+            if fitbit_metrics.error_budget.start_failing(collector) {
+                fitbit_metrics.events.log().record("collector_forbidden", format!("The {} collector is forbidden: {}", collector, err));
+            }
+            if let Some(message) = fitbit_metrics.error_budget.record_forbidden(collector) {
+                fitbit_metrics.events.log().record("collector_disabled", message);
+            }
+            Err(err)
+        }
+        // An expired (or revoked) token fails all the collectors until it's refreshed, so it doesn't count against their
+        // budget. It's only broken if it can't be refreshed, which `refresh_token_periodically` reports.
+        Err(err @ (FitbitError::AccessTokenExpired | FitbitError::InvalidAccessToken(_) | FitbitError::InvalidGrant)) => {
+            error!("The {} collector failed: {}", collector, err);
+            if fitbit_metrics.error_budget.start_failing(collector) {
+                fitbit_metrics.events.log().record("collector_failed", format!("The {} collector failed: {}", collector, err));
+            }
+            if matches!(err, FitbitError::InvalidGrant) {
+                fitbit_metrics.events.emit(Event::AuthBroken { reason: err.to_string() });
+            }
+            Err(err)
+        }
+        Err(err) => {
+            error!("The {} collector failed: {}", collector, err);
+            if fitbit_metrics.error_budget.start_failing(collector) {
+                fitbit_metrics.events.log().record("collector_failed", format!("The {} collector failed: {}", collector, err));
+            }
+            if let Some(message) = fitbit_metrics.error_budget.record_failure(collector) {
+                fitbit_metrics.events.log().record("collector_disabled", message);
+            }
+            Err(err)
+        }
+    }
+}
+
+
+/// Updates the sleep metrics from the sleep logs returned by `FitbitClient::fetch_sleep`.
+///
+/// Fitbit reports the `duration` of a sleep log in milliseconds, and the other durations (stages, time in bed,
+/// minutes asleep...) in minutes. All of them are converted to seconds, the base unit of Prometheus.
+///
+/// The unlabelled metrics are those of the main sleep, while the naps are summed separately. Each log of the day
+/// also has its own series of `fitbit_sleep_log_*`, labelled by its ID and whether it's the main sleep.
+///
+/// # Arguments
+///
+/// * `fitbit_metrics` - The metrics to update.
+/// * `sleep` - The response of the "Get Sleep Log by Date" endpoint.
+fn update_sleep_metrics(fitbit_metrics: &FitbitMetrics, sleep: &SleepLogResponse) {
+    match (fitbit_metrics.sleep_nap_handling, sleep.main_sleep()) {
+        (SleepNaps::Include, _) => {
+            fitbit_metrics.sleep_total_time_in_bed_seconds.set(sleep.summary.total_time_in_bed.as_seconds());
+            fitbit_metrics.sleep_total_asleep_seconds.set(sleep.summary.total_minutes_asleep.as_seconds());
+        }
+        (SleepNaps::Exclude | SleepNaps::Separate, main_sleep) => {
+            fitbit_metrics.sleep_total_time_in_bed_seconds.set(main_sleep.map_or(0.0, |log| log.time_in_bed.as_seconds()));
+            fitbit_metrics.sleep_total_asleep_seconds.set(main_sleep.map_or(0.0, |log| log.minutes_asleep.as_seconds()));
+        }
+    }
+
+    fitbit_metrics.sleep_log_asleep_seconds.clear();
+    fitbit_metrics.sleep_log_time_in_bed_seconds.clear();
+    fitbit_metrics.sleep_log_start_time_seconds.clear();
+    // The naps are left out of the per-log families as well when they're excluded
+    let main_sleep_id = sleep.main_sleep().map(|log| log.log_id);
+    let logs = sleep.sleep.iter().filter(|log| fitbit_metrics.sleep_nap_handling != SleepNaps::Exclude || Some(log.log_id) == main_sleep_id);
+    for log in logs {
+        let labels = SleepLogLabels { log_id: log.log_id.to_string(), is_main_sleep: log.is_main_sleep.to_string() };
+        fitbit_metrics.sleep_log_asleep_seconds.get_or_create(&labels).set(log.minutes_asleep.as_seconds());
+        fitbit_metrics.sleep_log_time_in_bed_seconds.get_or_create(&labels).set(log.time_in_bed.as_seconds());
+        fitbit_metrics.sleep_log_start_time_seconds.get_or_create(&labels).set(fitbit_metrics.user_timestamp(log.start_time));
+    }
+
+    fitbit_metrics.sleep_naps.set(sleep.naps().count() as i64);
+    fitbit_metrics.sleep_nap_asleep_seconds.set(sleep.naps().map(|log| log.minutes_asleep.as_seconds()).sum());
+    fitbit_metrics.sleep_nap_time_in_bed_seconds.set(sleep.naps().map(|log| log.time_in_bed.as_seconds()).sum());
+
+    if let Some(log) = sleep.main_sleep() {
+        for stage in ["deep", "light", "rem", "wake"] {
+            let minutes = log.levels.summary.get(stage).map(|level| level.minutes).unwrap_or_default();
+            fitbit_metrics.sleep_stage_seconds
+                .get_or_create(&SleepStageLabels { stage: stage.to_string() })
+                .set(minutes.as_seconds());
+        }
+
+        // One point per interval of the main sleep, stamped with its start, for the hypnogram of the night
+        let mut stage_points = fitbit_metrics.sleep_stage.metric_points();
+        stage_points.clear();
+        for interval in &log.levels.data {
+            if let Some(value) = sleep_stage_value(&interval.level) {
+                stage_points.push((value, Some(Duration::from_secs(fitbit_metrics.user_timestamp(interval.date_time).max(0) as u64))));
+            }
+        }
+        drop(stage_points);
+
+        fitbit_metrics.sleep_duration_seconds.set(log.duration.as_seconds());
+        fitbit_metrics.sleep_efficiency.set(log.efficiency);
+        fitbit_metrics.sleep_start_time_seconds.set(fitbit_metrics.user_timestamp(log.start_time));
+        fitbit_metrics.sleep_end_time_seconds.set(fitbit_metrics.user_timestamp(log.end_time));
+        fitbit_metrics.sleep_time_in_bed_seconds.set(log.time_in_bed.as_seconds());
+        fitbit_metrics.sleep_asleep_seconds.set(log.minutes_asleep.as_seconds());
+        fitbit_metrics.sleep_awake_seconds.set(log.minutes_awake.as_seconds());
+        fitbit_metrics.sleep_after_wakeup_seconds.set(log.minutes_after_wakeup.as_seconds());
+        fitbit_metrics.sleep_is_main_sleep.set(log.is_main_sleep as i64);
+    } else {
+        debug!("No sleep logged today");
+    }
+
+    // The data is as of the end of the last sleep log, e.g. the wake-up of the main sleep or the end of a nap
+    if let Some(end_time) = sleep.sleep.iter().map(|log| log.end_time).max() {
+        set_data_timestamp(fitbit_metrics, "sleep", fitbit_metrics.user_timestamp(end_time));
+    }
+}
+
+/// Returns the value of a sleep stage in `fitbit_sleep_stage`, ordered from the deepest to awake for hypnograms, or
+/// `None` for an unknown stage.
+fn sleep_stage_value(level: &str) -> Option<i64> {
+    match level {
+        "deep" => Some(1),
+        "light" | "asleep" => Some(2),
+        "rem" | "restless" => Some(3),
+        "wake" | "awake" => Some(4),
+        _ => None,
+    }
+}
+
+/// Updates the total distance of the day and its breakdown by source from the activity summary.
+///
+/// The "total" distance is only exported as `fitbit_distance_meters`. The previous breakdown is cleared, so that the
+/// sources no longer in the summary (e.g. a deleted activity log) aren't exposed.
+///
+/// The sources named after the logged activities are free-form, so the ones over `max_series_per_family` are summed
+/// into the other bucket. The sources of Fitbit (tracker, intensities...) come first, so that they're kept.
+fn update_distance_metrics(fitbit_metrics: &FitbitMetrics, summary: &ActivitySummary) {
+    fitbit_metrics.distance_meters.set(summary.total_distance().unwrap_or_default().0);
+    fitbit_metrics.distance_by_source_meters.clear();
+    let (mut distances, logged_activities): (Vec<_>, Vec<_>) = summary
+        .distances
+        .iter()
+        .filter(|distance| distance.activity != "total")
+        .partition(|distance| FITBIT_DISTANCE_SOURCES.contains(&distance.activity.as_str()));
+    distances.extend(logged_activities);
+
+    let collapsed_from = collapsed_from(fitbit_metrics, "fitbit_distance_by_source_meters", distances.len());
+    for (index, distance) in distances.iter().enumerate() {
+        let source = if index < collapsed_from { distance.activity.as_str() } else { OTHER };
+        fitbit_metrics
+            .distance_by_source_meters
+            .get_or_create(&DistanceLabels { source: source.to_string() })
+            .inc_by(Meters::from_kilometers(distance.distance).0);
+    }
+}
+
+/// Updates the activity log metrics from the logs returned by `FitbitClient::fetch_activity_logs`.
+///
+/// The previous values are cleared, so that only the recent workouts are exposed. The average heart rate and the
+/// distance are only exposed for the workouts that have them.
+fn update_activity_log_metrics(fitbit_metrics: &FitbitMetrics, activity_logs: &[ActivityLog]) {
+    fitbit_metrics.activity_duration_seconds.clear();
+    fitbit_metrics.activity_calories.clear();
+    fitbit_metrics.activity_average_heart_rate_bpm.clear();
+    fitbit_metrics.activity_distance_meters.clear();
+    fitbit_metrics.activity_start_time_seconds.clear();
+
+    // The data is as of the end of the most recent workout, approximated by its start time plus its active duration
+    let last_end_time = activity_logs
+        .iter()
+        .map(|activity_log| activity_log.start_time.timestamp() + activity_log.active_duration.as_seconds() as i64)
+        .max();
+    if let Some(last_end_time) = last_end_time {
+        set_data_timestamp(fitbit_metrics, "activity_logs", last_end_time);
+    }
+
+    // The workouts over the cap are summed into the other bucket. Their average heart rate and start time can't be
+    // summed, so they're not exposed.
+    let collapsed_from = collapsed_from(fitbit_metrics, "fitbit_activity", activity_logs.len());
+    let other = ActivityLabels { activity_type: OTHER.to_string(), source: OTHER.to_string(), activity_name: String::new(), log_id: OTHER.to_string() };
+    for activity_log in &activity_logs[collapsed_from..] {
+        fitbit_metrics.activity_duration_seconds.get_or_create(&other).inc_by(activity_log.active_duration.as_seconds());
+        fitbit_metrics.activity_calories.get_or_create(&other).inc_by(activity_log.calories);
+        if let Some(distance) = activity_log.distance() {
+            fitbit_metrics.activity_distance_meters.get_or_create(&other).inc_by(distance.0);
+        }
+    }
+
+    for activity_log in &activity_logs[..collapsed_from] {
+        let labels = ActivityLabels {
+            activity_type: activity_log.canonical_type(),
+            source: activity_log.source().to_string(),
+            activity_name: if fitbit_metrics.activity_name_label { activity_log.activity_name.clone() } else { String::new() },
+            log_id: activity_log.log_id.to_string(),
+        };
+        fitbit_metrics.activity_duration_seconds.get_or_create(&labels).set(activity_log.active_duration.as_seconds());
+        fitbit_metrics.activity_calories.get_or_create(&labels).set(activity_log.calories);
+        fitbit_metrics.activity_start_time_seconds.get_or_create(&labels).set(activity_log.start_time.timestamp());
+        if let Some(average_heart_rate) = activity_log.average_heart_rate {
+            fitbit_metrics.activity_average_heart_rate_bpm.get_or_create(&labels).set(average_heart_rate.0);
+        }
+        if let Some(distance) = activity_log.distance() {
+            fitbit_metrics.activity_distance_meters.get_or_create(&labels).set(distance.0);
+        }
+    }
+}
+
+/// Updates the goal metrics from the goals returned by `FitbitClient::fetch_activity_goals`.
+///
+/// Goals that aren't set are exported as 0. The distance goal is converted from kilometers to meters, and the
+/// active minutes goal to seconds.
+fn update_goal_metrics(fitbit_metrics: &FitbitMetrics, goals: &ActivityGoals) {
+    fitbit_metrics.goal_steps.set(goals.steps.map(Steps::as_i64).unwrap_or(0));
+    fitbit_metrics.goal_calories_out.set(goals.calories_out.unwrap_or(0.0));
+    fitbit_metrics.goal_distance_meters.set(goals.distance.map(Meters::from_kilometers).unwrap_or_default().0);
+    fitbit_metrics.goal_floors.set(goals.floors.unwrap_or(0.0));
+    fitbit_metrics.goal_active_duration_seconds.set(goals.active_minutes.unwrap_or_default().as_seconds());
+}
+
+/// Updates the metrics of the devices returned by `FitbitClient::fetch_devices`.
+///
+/// The daily totals (steps, activity...) are as of the last sync of the devices, exposed as the data timestamp
+/// of the `devices` collector.
+fn update_device_metrics(fitbit_metrics: &FitbitMetrics, devices: &[Device]) {
+    update_device_metrics_at(fitbit_metrics, devices, fitbit_metrics.user_now().naive_local());
+}
+
+/// Updates the metrics of the devices as of `now`, in the user's timezone like the sync times.
+fn update_device_metrics_at(fitbit_metrics: &FitbitMetrics, devices: &[Device], now: NaiveDateTime) {
+    let last_sync_time = devices.iter().map(|device| device.last_sync_time).max();
+    if let Some(last_sync_time) = last_sync_time {
+        set_data_timestamp(fitbit_metrics, "devices", fitbit_metrics.user_timestamp(last_sync_time));
+    }
+    let max_age = ChronoDuration::from_std(fitbit_metrics.synced_recently_max_age).unwrap_or(ChronoDuration::MAX);
+    fitbit_metrics.synced_recently.set(last_sync_time.is_some_and(|last_sync_time| now - last_sync_time <= max_age) as i64);
+
+    // Fitbit reports the battery as "High", "Medium", "Low" or "Empty". The other bucket is low if any of its devices is.
+    fitbit_metrics.battery_low.clear();
+    let collapsed_from = collapsed_from(fitbit_metrics, "fitbit_battery_low", devices.len());
+    for (index, device) in devices.iter().enumerate() {
+        let low = matches!(device.battery.as_deref(), Some("Low" | "Empty"));
+        let labels = match index < collapsed_from {
+            true => DeviceLabels { device: device.device_version.clone(), device_id: device.id.clone() },
+            false => DeviceLabels { device: OTHER.to_string(), device_id: OTHER.to_string() },
+        };
+        let gauge = fitbit_metrics.battery_low.get_or_create(&labels);
+        gauge.set(gauge.get().max(low as i64));
+    }
+}
+
+/// Updates the friends leaderboard metrics from the ranks returned by `FitbitClient::fetch_leaderboard`.
+///
+/// The friends over the cap are the lowest ranked ones, whose steps are summed into the other bucket. Their ranks
+/// can't be summed, so the other bucket has no rank.
+fn update_leaderboard_metrics(fitbit_metrics: &FitbitMetrics, ranks: &[LeaderboardRank]) {
+    fitbit_metrics.leaderboard_steps.clear();
+    fitbit_metrics.leaderboard_rank.clear();
+    let collapsed_from = collapsed_from(fitbit_metrics, "fitbit_leaderboard", ranks.len());
+    for rank in &ranks[collapsed_from..] {
+        fitbit_metrics.leaderboard_steps.get_or_create(&LeaderboardLabels { friend: OTHER.to_string(), user_id: OTHER.to_string() }).inc_by(rank.steps.as_i64());
+    }
+    for rank in &ranks[..collapsed_from] {
+        let labels = LeaderboardLabels { friend: rank.name.clone(), user_id: rank.user_id.clone() };
+        fitbit_metrics.leaderboard_steps.get_or_create(&labels).set(rank.steps.as_i64());
+        fitbit_metrics.leaderboard_rank.get_or_create(&labels).set(rank.rank as i64);
+    }
+}
+
+/// Updates the badge counters from the badges returned by `FitbitClient::fetch_badges`, so that earning a badge
+/// creates its series or increments it.
+///
+/// The badges are a fixed set defined by Fitbit, so their series aren't capped by --max-series-per-family.
+fn update_badge_metrics(fitbit_metrics: &FitbitMetrics, badges: &[Badge]) {
+    for badge in badges {
+        let labels = BadgeLabels { category: badge.badge_type.clone(), value: badge.value.to_string() };
+        raise_counter(&fitbit_metrics.badges.get_or_create(&labels), badge.times_achieved);
+    }
+}
+
+/// Updates the lifetime metrics from the stats returned by `FitbitClient::fetch_lifetime_stats`.
+fn update_lifetime_metrics(fitbit_metrics: &FitbitMetrics, stats: &LifetimeStats) {
+    raise_counter(&fitbit_metrics.lifetime_steps, stats.lifetime.steps);
+    raise_counter(&fitbit_metrics.lifetime_distance_meters, stats.lifetime.distance().0);
+    raise_counter(&fitbit_metrics.lifetime_floors, stats.lifetime.floors);
+
+    let best_days = [
+        (&fitbit_metrics.best_day_steps, stats.best.steps.as_ref().map(|day| (day.date, day.value))),
+        (&fitbit_metrics.best_day_distance_meters, stats.best.distance.as_ref().map(|day| (day.date, Meters::from_kilometers(day.value).0))),
+        (&fitbit_metrics.best_day_floors, stats.best.floors.as_ref().map(|day| (day.date, day.value))),
+    ];
+    for (family, best_day) in best_days {
+        // A new best day replaces the previous one
+        family.clear();
+        if let Some((date, value)) = best_day {
+            family.get_or_create(&DateLabels { date: date.format("%Y-%m-%d").to_string() }).set(value);
+        }
+    }
+}
+
+/// Raises the counter to the total reported by the Fitbit API. A counter can't decrease, so a total lower than
+/// counted (e.g. after deleting a logged activity) is ignored.
+fn raise_counter<N, A>(counter: &Counter<N, A>, total: N)
+where
+    N: PartialOrd + std::ops::Sub<Output = N> + Copy,
+    A: Atomic<N>,
+{
+    let counted = counter.get();
+    if total > counted {
+        counter.inc_by(total - counted);
+    }
+}
+
+/// Returns the index of the first of the `series` of a family that is collapsed into the other bucket, i.e. `series`
+/// if the family doesn't exceed `max_series_per_family`. The last series under the cap is left for the bucket.
+/// The number of collapsed series is exposed by `fitbit_series_collapsed`.
+fn collapsed_from(fitbit_metrics: &FitbitMetrics, family: &str, series: usize) -> usize {
+    let collapsed_from = match fitbit_metrics.max_series_per_family {
+        Some(max) if series > max => max.saturating_sub(1),
+        _ => series,
+    };
+    if collapsed_from < series {
+        debug!("Collapsing {} series of {} into the {} bucket", series - collapsed_from, family, OTHER);
+    }
+    fitbit_metrics.series_collapsed
+        .get_or_create(&FamilyLabels { family: family.to_string() })
+        .set((series - collapsed_from) as i64);
+    collapsed_from
+}
+
+/// Updates `fitbit_goal_met` from the goals and today's values. The goals that aren't set (0) are not exposed.
+///
+/// A goal reached since the previous update emits `Event::GoalAchieved`.
+fn update_goal_met_metrics(fitbit_metrics: &FitbitMetrics) {
+    let steps = fitbit_metrics.steps.metric_points().iter().find(|(_, timestamp)| timestamp.is_none()).map(|(steps, _)| *steps).unwrap_or(0);
+    let goals = [
+        ("steps", steps as f64, fitbit_metrics.goal_steps.get() as f64),
+        ("calories_out", fitbit_metrics.calories_out.get(), fitbit_metrics.goal_calories_out.get()),
+        ("distance", fitbit_metrics.distance_meters.get(), fitbit_metrics.goal_distance_meters.get()),
+        ("floors", fitbit_metrics.floors.get(), fitbit_metrics.goal_floors.get()),
+        ("active_duration", fitbit_metrics.active_duration_seconds.get(), fitbit_metrics.goal_active_duration_seconds.get()),
+    ];
+    fitbit_metrics.goal_met.clear();
+    let mut goals_met = HashSet::new();
+    for (goal, value, target) in goals {
+        if target > 0.0 {
+            fitbit_metrics.goal_met.get_or_create(&GoalLabels { goal: goal.to_string() }).set((value >= target) as i64);
+            if value >= target {
+                goals_met.insert(goal);
+            }
+        }
+    }
+
+    let previously_met = fitbit_metrics.goals_met.lock().unwrap().replace(goals_met.clone());
+    if let Some(previously_met) = previously_met {
+        for (goal, value, target) in goals.into_iter().filter(|(goal, _, _)| goals_met.contains(goal) && !previously_met.contains(goal)) {
+            fitbit_metrics.events.emit(Event::GoalAchieved { goal: goal.to_string(), value, target });
+        }
+    }
+}
+
+/// Updates the ECG metrics from the readings returned by `FitbitClient::fetch_ecg_readings`, newest first.
+///
+/// Only the latest reading is exposed, labelled by its classification. The previous values are cleared, so that
+/// a single classification is exposed at a time.
+fn update_ecg_metrics(fitbit_metrics: &FitbitMetrics, readings: &[EcgReading]) {
+    let Some(reading) = readings.first() else {
+        debug!("No ECG reading");
+        return;
+    };
+    fitbit_metrics.ecg_classification.clear();
+    fitbit_metrics.ecg_average_heart_rate_bpm.clear();
+    let labels = EcgLabels { classification: reading.result_classification.clone() };
+    fitbit_metrics.ecg_classification.get_or_create(&labels).set(1);
+    fitbit_metrics.ecg_average_heart_rate_bpm.get_or_create(&labels).set(reading.average_heart_rate.0);
+    set_data_timestamp(fitbit_metrics, "ecg", fitbit_metrics.user_timestamp(reading.start_time));
+}
+
+/// Counts the irregular rhythm notifications returned by `FitbitClient::fetch_irn_alerts` that are newer than the
+/// last one counted.
+///
+/// The alerts of the first update are not counted, since they were received before the exporter started: counting
+/// them again after each restart would look like new alerts to `increase()`. Without any alert yet, the time of the
+/// first update is the baseline instead, so that the first alert ever is counted. The time of the newest alert is
+/// exposed as the data timestamp of the `irn` collector.
+fn update_irn_metrics(fitbit_metrics: &FitbitMetrics, alerts: &[IrnAlert]) {
+    let mut last_alert_time = fitbit_metrics.irregular_rhythm_last_alert_time.lock().unwrap();
+    let Some(newest) = alerts.iter().map(|alert| alert.alert_time).max() else {
+        last_alert_time.get_or_insert_with(|| fitbit_metrics.user_now().naive_local());
+        return;
+    };
+    if let Some(last) = *last_alert_time {
+        let new_alerts = alerts.iter().filter(|alert| alert.alert_time > last).count();
+        fitbit_metrics.irregular_rhythm_notifications.inc_by(new_alerts as u64);
+    }
+    if last_alert_time.is_none_or(|last| newest > last) {
+        *last_alert_time = Some(newest);
+    }
+    set_data_timestamp(fitbit_metrics, "irn", fitbit_metrics.user_timestamp(newest));
+}
+
+/// Updates the VO2 Max metrics from the latest score returned by `FitbitClient::fetch_cardio_score`.
+///
+/// A single value is exposed as `fitbit_vo2max`, and a range as `fitbit_vo2max_lower` and `fitbit_vo2max_upper`.
+/// The previous values are cleared, so that only the latest score is exposed.
+fn update_cardio_score_metrics(fitbit_metrics: &FitbitMetrics, cardio_score: Option<&CardioScoreDay>) {
+    let Some(day) = cardio_score else {
+        debug!("No cardio score in the last 30 days");
+        return;
+    };
+    fitbit_metrics.vo2max.clear();
+    fitbit_metrics.vo2max_lower.clear();
+    fitbit_metrics.vo2max_upper.clear();
+    let labels = DateLabels { date: day.date_time.format("%Y-%m-%d").to_string() };
+    match day.value.vo2_max {
+        Vo2Max::Value(value) => {
+            fitbit_metrics.vo2max.get_or_create(&labels).set(value);
+        }
+        Vo2Max::Range { lower, upper } => {
+            fitbit_metrics.vo2max_lower.get_or_create(&labels).set(lower);
+            fitbit_metrics.vo2max_upper.get_or_create(&labels).set(upper);
+        }
+    }
+}
+
+/// Updates the heart rate histogram from the samples returned by `FitbitClient::fetch_heart_rate_intraday`, each of
+/// which is a minute of the day.
+///
+/// Without `since`, the samples are the whole day: the histogram is rebuilt, which drops the one of the previous day.
+/// With it, they're the time window from `since` on: only the minutes after it are added, since the minute at
+/// `since` was already observed.
+fn update_heart_rate_histogram(fitbit_metrics: &FitbitMetrics, date: NaiveDate, since: Option<NaiveTime>, samples: &[HeartRateSample]) {
+    if since.is_none() {
+        fitbit_metrics.heart_rate_minutes.clear();
+    }
+    let histogram = fitbit_metrics.heart_rate_minutes.get_or_create(&DateLabels { date: date.to_string() });
+    let new_samples: Vec<&HeartRateSample> = samples.iter().filter(|sample| since.is_none_or(|since| sample.time > since)).collect();
+    for sample in &new_samples {
+        histogram.observe(sample.value.0);
+    }
+    if let Some(newest) = new_samples.iter().map(|sample| sample.time).max() {
+        *fitbit_metrics.heart_rate_last_sample_time.lock().unwrap() = Some(date.and_time(newest));
+    }
+}
+
+/// Updates the breathing rate metrics from the summary returned by `FitbitClient::fetch_breathing_rate`.
+///
+/// The previous values are cleared, so that the stages without enough data last night are not exposed.
+fn update_breathing_rate_metrics(fitbit_metrics: &FitbitMetrics, breathing_rate: Option<&BreathingRateSummary>) {
+    let Some(breathing_rate) = breathing_rate else {
+        debug!("No breathing rate for last night yet");
+        return;
+    };
+    fitbit_metrics.breathing_rate.clear();
+    for (stage, rate) in breathing_rate.by_stage() {
+        fitbit_metrics.breathing_rate.get_or_create(&SleepStageLabels { stage: stage.to_string() }).set(rate);
+    }
+}
+
+/// Updates the temperature metrics from the latest skin and core temperatures.
+///
+/// Each is labelled by its date, and cleared first so that only the latest one is exposed. A missing temperature
+/// (e.g. no core temperature logged in the last 7 days) leaves its previous value, if any.
+fn update_temperature_metrics(fitbit_metrics: &FitbitMetrics, skin_temperature: Option<&SkinTemperatureDay>, core_temperature: Option<&CoreTemperatureLog>) {
+    if let Some(day) = skin_temperature {
+        fitbit_metrics.skin_temp_delta_celsius.clear();
+        fitbit_metrics.skin_temp_delta_celsius
+            .get_or_create(&DateLabels { date: day.date_time.format("%Y-%m-%d").to_string() })
+            .set(day.value.nightly_relative);
+    }
+    if let Some(log) = core_temperature {
+        fitbit_metrics.core_temp_celsius.clear();
+        fitbit_metrics.core_temp_celsius
+            .get_or_create(&DateLabels { date: log.date_time.format("%Y-%m-%d").to_string() })
+            .set(log.value);
+    }
+}
+
+/// Sets the time of the underlying data of a collector, e.g. the end of the last sleep log.
+fn set_data_timestamp(fitbit_metrics: &FitbitMetrics, collector: &str, timestamp: i64) {
+    fitbit_metrics.data_timestamp_seconds
+        .get_or_create(&CollectorLabels { collector: collector.to_string() })
+        .set(timestamp);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::exposition::{ExpositionFormat, ExpositionRelabel};
+    use crate::fitbit::mock::MockFitbitApi;
+    use crate::fitbit::profile::ScrapeProfiles;
+    use crate::fitbit::scheduler::count_request;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn sleep_durations_are_exported_in_seconds() {
+        let fitbit_metrics = FitbitMetrics::new();
+        fitbit_metrics.set_utc_offset(FixedOffset::east_opt(0).unwrap());
+        let sleep_json = json!({
+            "sleep": [{
+                "logId": 40_553_264_410u64,
+                "dateOfSleep": "2023-03-04",
+                "duration": 27_720_000,
+                "efficiency": 93,
+                "isMainSleep": true,
+                "startTime": "2023-03-04T00:12:00.000",
+                "endTime": "2023-03-04T07:54:00.000",
+                "timeInBed": 462,
+                "minutesAsleep": 420,
+                "minutesAwake": 42,
+                "minutesAfterWakeup": 3,
+                "levels": {
+                    "summary": {
+                        "deep": { "minutes": 70 },
+                        "light": { "minutes": 250 },
+                        "rem": { "minutes": 100 },
+                        "wake": { "minutes": 42 }
+                    },
+                    "data": [
+                        { "dateTime": "2023-03-04T00:12:00.000", "level": "wake", "seconds": 300 },
+                        { "dateTime": "2023-03-04T00:17:00.000", "level": "light", "seconds": 1800 },
+                        { "dateTime": "2023-03-04T00:47:00.000", "level": "deep", "seconds": 2400 }
+                    ]
+                }
+            }],
+            "summary": { "totalMinutesAsleep": 420, "totalTimeInBed": 462 }
+        });
+        let sleep: SleepLogResponse = serde_json::from_value(sleep_json).unwrap();
+
+        update_sleep_metrics(&fitbit_metrics, &sleep);
+
+        assert_eq!(fitbit_metrics.sleep_duration_seconds.get(), 27_720.0);
+        assert_eq!(fitbit_metrics.sleep_time_in_bed_seconds.get(), 462.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_asleep_seconds.get(), 420.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_awake_seconds.get(), 42.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_after_wakeup_seconds.get(), 180.0);
+        assert_eq!(fitbit_metrics.sleep_total_asleep_seconds.get(), 420.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_total_time_in_bed_seconds.get(), 462.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_stage_seconds.get_or_create(&SleepStageLabels { stage: "deep".to_string() }).get(), 70.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_stage_seconds.get_or_create(&SleepStageLabels { stage: "rem".to_string() }).get(), 100.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_start_time_seconds.get(), 1677888720);
+        assert_eq!(
+            *fitbit_metrics.sleep_stage.metric_points(),
+            vec![(4, Some(Duration::from_secs(1677888720))), (2, Some(Duration::from_secs(1677889020))), (1, Some(Duration::from_secs(1677890820)))]
+        );
+        assert_eq!(fitbit_metrics.sleep_is_main_sleep.get(), 1);
+        assert_eq!(fitbit_metrics.data_timestamp_seconds.get_or_create(&CollectorLabels { collector: "sleep".to_string() }).get(), 1677916440);
+    }
+
+    #[test]
+    fn sleep_stages_are_stamped_in_the_timezone_of_the_user() {
+        let fitbit_metrics = FitbitMetrics::new();
+        // Tokyo, where 00:12 on March 4th is 15:12 UTC on March 3rd
+        fitbit_metrics.set_utc_offset(FixedOffset::east_opt(9 * 3600).unwrap());
+        let sleep: SleepLogResponse = serde_json::from_value(json!({
+            "sleep": [{
+                "logId": 40_553_264_410u64,
+                "dateOfSleep": "2023-03-04",
+                "duration": 2_100_000,
+                "efficiency": 93,
+                "isMainSleep": true,
+                "startTime": "2023-03-04T00:12:00.000",
+                "endTime": "2023-03-04T00:47:00.000",
+                "timeInBed": 35,
+                "minutesAsleep": 30,
+                "minutesAwake": 5,
+                "minutesAfterWakeup": 0,
+                "levels": {
+                    "summary": {},
+                    "data": [
+                        { "dateTime": "2023-03-04T00:12:00.000", "level": "wake", "seconds": 300 },
+                        { "dateTime": "2023-03-04T00:17:00.000", "level": "light", "seconds": 1800 }
+                    ]
+                }
+            }],
+            "summary": { "totalMinutesAsleep": 30, "totalTimeInBed": 35 }
+        }))
+        .unwrap();
+
+        update_sleep_metrics(&fitbit_metrics, &sleep);
+
+        assert_eq!(*fitbit_metrics.sleep_stage.metric_points(), vec![(4, Some(Duration::from_secs(1677856320))), (2, Some(Duration::from_secs(1677856620)))]);
+    }
+
+    #[test]
+    fn naps_are_exported_apart_from_the_main_sleep() {
+        let fitbit_metrics = FitbitMetrics::new();
+        fitbit_metrics.set_utc_offset(FixedOffset::east_opt(0).unwrap());
+        let sleep_log = |log_id: u64, is_main_sleep: bool, start_time: &str, minutes_asleep: u64| json!({
+            "logId": log_id,
+            "dateOfSleep": "2023-03-04",
+            "duration": (minutes_asleep + 10) * 60_000,
+            "efficiency": 90,
+            "isMainSleep": is_main_sleep,
+            "startTime": start_time,
+            "endTime": start_time,
+            "timeInBed": minutes_asleep + 10,
+            "minutesAsleep": minutes_asleep,
+            "minutesAwake": 10,
+            "minutesAfterWakeup": 0
+        });
+        // The nap comes first, as Fitbit sorts the logs by start time, descending
+        let sleep: SleepLogResponse = serde_json::from_value(json!({
+            "sleep": [
+                sleep_log(2, false, "2023-03-04T14:00:00.000", 30),
+                sleep_log(1, true, "2023-03-04T00:00:00.000", 420)
+            ],
+            "summary": { "totalMinutesAsleep": 450, "totalTimeInBed": 470 }
+        })).unwrap();
+
+        update_sleep_metrics(&fitbit_metrics, &sleep);
+
+        assert_eq!(fitbit_metrics.sleep_asleep_seconds.get(), 420.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_is_main_sleep.get(), 1);
+        assert_eq!(fitbit_metrics.sleep_naps.get(), 1);
+        assert_eq!(fitbit_metrics.sleep_nap_asleep_seconds.get(), 30.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_total_asleep_seconds.get(), 450.0 * 60.0);
+        let nap = SleepLogLabels { log_id: "2".to_string(), is_main_sleep: "false".to_string() };
+        assert_eq!(fitbit_metrics.sleep_log_asleep_seconds.get_or_create(&nap).get(), 30.0 * 60.0);
+        assert_eq!(fitbit_metrics.sleep_log_start_time_seconds.get_or_create(&nap).get(), 1677938400);
+
+        // The totals of the main sleep, with the naps apart or left out
+        for sleep_naps in [SleepNaps::Separate, SleepNaps::Exclude] {
+            let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { sleep_naps, ..MetricsOptions::default() });
+            update_sleep_metrics(&fitbit_metrics, &sleep);
+            let mut txt = String::new();
+            encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+            assert!(txt.contains("fitbit_sleep_total_asleep_seconds 25200.0"));
+            assert!(txt.contains("fitbit_sleep_total_time_in_bed_seconds 25800.0"));
+            assert_eq!(txt.contains("fitbit_sleep_nap_time_in_bed_seconds 2400.0"), sleep_naps == SleepNaps::Separate);
+            assert_eq!(txt.contains("log_id=\"2\""), sleep_naps == SleepNaps::Separate);
+        }
+
+        // A nap-only day has no main sleep, and the nap is only counted as such
+        let nap_only: SleepLogResponse = serde_json::from_value(json!({
+            "sleep": [sleep_log(2, false, "2023-03-04T14:00:00.000", 30)],
+            "summary": { "totalMinutesAsleep": 30, "totalTimeInBed": 40 }
+        })).unwrap();
+        assert!(nap_only.main_sleep().is_none());
+        for sleep_naps in [SleepNaps::Separate, SleepNaps::Exclude] {
+            let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { sleep_naps, ..MetricsOptions::default() });
+            update_sleep_metrics(&fitbit_metrics, &nap_only);
+            assert_eq!(fitbit_metrics.sleep_total_asleep_seconds.get(), 0.0);
+            assert_eq!(fitbit_metrics.sleep_asleep_seconds.get(), 0.0);
+            assert_eq!(fitbit_metrics.sleep_nap_asleep_seconds.get(), 30.0 * 60.0);
+            assert_eq!(fitbit_metrics.sleep_log_asleep_seconds.get_or_create(&nap).get(), if sleep_naps == SleepNaps::Separate { 30.0 * 60.0 } else { 0.0 });
+        }
+    }
+
+    #[test]
+    fn ecg_and_irregular_rhythm_notifications() {
+        let fitbit_metrics = FitbitMetrics::new();
+        fitbit_metrics.set_utc_offset(FixedOffset::east_opt(0).unwrap());
+        let readings: Vec<EcgReading> = serde_json::from_value(json!([{
+            "startTime": "2023-03-04T17:12:30.222",
+            "averageHeartRate": 112,
+            "resultClassification": "Atrial Fibrillation",
+            "waveformSamples": [130, 162, 193]
+        }]))
+        .unwrap();
+        update_ecg_metrics(&fitbit_metrics, &readings);
+
+        let labels = EcgLabels { classification: "Atrial Fibrillation".to_string() };
+        assert_eq!(fitbit_metrics.ecg_classification.get_or_create(&labels).get(), 1);
+        assert_eq!(fitbit_metrics.ecg_average_heart_rate_bpm.get_or_create(&labels).get(), 112.0);
+
+        let alert = |time: &str| json!({ "alertTime": time, "detectedTime": time });
+        let alerts: Vec<IrnAlert> = serde_json::from_value(json!([alert("2023-03-03T02:00:00.000")])).unwrap();
+        update_irn_metrics(&fitbit_metrics, &alerts);
+        assert_eq!(fitbit_metrics.irregular_rhythm_notifications.get(), 0);
+
+        let alerts: Vec<IrnAlert> = serde_json::from_value(json!([
+            alert("2023-03-04T03:00:00.000"),
+            alert("2023-03-04T01:00:00.000"),
+            alert("2023-03-03T02:00:00.000"),
+        ]))
+        .unwrap();
+        update_irn_metrics(&fitbit_metrics, &alerts);
+        update_irn_metrics(&fitbit_metrics, &alerts);
+        assert_eq!(fitbit_metrics.irregular_rhythm_notifications.get(), 2);
+        let labels = CollectorLabels { collector: "irn".to_string() };
+        assert_eq!(fitbit_metrics.data_timestamp_seconds.get_or_create(&labels).get(), 1677898800);
+    }
+
+    #[test]
+    fn first_irregular_rhythm_notification_is_counted() {
+        let fitbit_metrics = FitbitMetrics::new();
+        fitbit_metrics.set_utc_offset(FixedOffset::west_opt(5 * 3600).unwrap());
+        update_irn_metrics(&fitbit_metrics, &[]);
+        update_irn_metrics(&fitbit_metrics, &[]);
+
+        // The user's first alert ever, a minute after the exporter started
+        let time = (fitbit_metrics.user_now() + ChronoDuration::minutes(1)).format("%Y-%m-%dT%H:%M:%S%.3f").to_string();
+        let alerts: Vec<IrnAlert> = serde_json::from_value(json!([{ "alertTime": time, "detectedTime": time }])).unwrap();
+        update_irn_metrics(&fitbit_metrics, &alerts);
+        update_irn_metrics(&fitbit_metrics, &alerts);
+        assert_eq!(fitbit_metrics.irregular_rhythm_notifications.get(), 1);
+    }
+
+    #[test]
+    fn daily_metrics_roll_over() {
+        let fitbit_metrics = FitbitMetrics::new();
+        fitbit_metrics.set_utc_offset(FixedOffset::east_opt(0).unwrap());
+        set_current_steps(&fitbit_metrics, 8123);
+        fitbit_metrics.calories_out.set(2345.0);
+
+        let previous_day = NaiveDate::from_ymd_opt(2023, 3, 4).unwrap();
+        roll_over_daily_metrics(&fitbit_metrics, previous_day, TimestampPosition::EndOfDay);
+
+        assert_eq!(
+            *fitbit_metrics.steps.metric_points(),
+            vec![(8123, Some(Duration::from_secs(1677974399))), (0, None)]
+        );
+        assert_eq!(fitbit_metrics.calories_out.get(), 0.0);
+
+        set_current_steps(&fitbit_metrics, 42);
+        assert_eq!(fitbit_metrics.steps.metric_points()[1], (42, None));
+    }
+
+    #[test]
+    fn historical_points_are_pruned_by_age_and_count() {
+        let day = |day: u64| Duration::from_secs(day * 24 * 60 * 60);
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions {
+            history_max_age: Some(day(30)),
+            max_history_points: Some(2),
+            ..Default::default()
+        });
+        set_current_steps(&fitbit_metrics, 42);
+        for date in [1, 50, 80, 90] {
+            fitbit_metrics.push_historical_steps(1000, day(date), false);
+        }
+        fitbit_metrics.push_historical_resting_heart_rate(58, day(95));
+
+        assert_eq!(fitbit_metrics.prune_history(day(100)), 2);
+        assert_eq!(*fitbit_metrics.steps.metric_points(), vec![(42, None), (1000, Some(day(80))), (1000, Some(day(90)))]);
+        assert_eq!(fitbit_metrics.resting_heart_rate.metric_points().len(), 1);
+        assert_eq!(fitbit_metrics.prune_history(day(100)), 0);
+    }
+
+    #[test]
+    fn distance_is_broken_down_by_source() {
+        let fitbit_metrics = FitbitMetrics::new();
+        let summary: ActivitySummary = serde_json::from_value(serde_json::json!({
+            "distances": [
+                {"activity": "total", "distance": 6.5},
+                {"activity": "tracker", "distance": 5.25},
+                {"activity": "loggedActivities", "distance": 1.25},
+                {"activity": "veryActive", "distance": 2.0}
+            ]
+        }))
+        .unwrap();
+        fitbit_metrics.distance_by_source_meters.get_or_create(&DistanceLabels { source: "Run".to_string() }).set(1000.0);
+        update_distance_metrics(&fitbit_metrics, &summary);
+
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+        assert!(txt.contains("fitbit_distance_meters 6500"));
+        assert!(txt.contains("fitbit_distance_by_source_meters{source=\"tracker\"} 5250"));
+        assert!(txt.contains("fitbit_distance_by_source_meters{source=\"loggedActivities\"} 1250"));
+        assert!(txt.contains("fitbit_distance_by_source_meters{source=\"veryActive\"} 2000"));
+        assert!(!txt.contains("source=\"total\""));
+        assert!(!txt.contains("source=\"Run\""));
+    }
+
+    #[test]
+    fn distance_sources_of_the_logged_activities_are_capped() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { max_series_per_family: Some(3), ..MetricsOptions::default() });
+        let summary: ActivitySummary = serde_json::from_value(serde_json::json!({
+            "distances": [
+                {"activity": "Run", "distance": 1.0},
+                {"activity": "Hike", "distance": 0.5},
+                {"activity": "tracker", "distance": 5.25},
+                {"activity": "loggedActivities", "distance": 1.5},
+                {"activity": "total", "distance": 6.75}
+            ]
+        }))
+        .unwrap();
+        update_distance_metrics(&fitbit_metrics, &summary);
+
+        let distance = |source: &str| fitbit_metrics.distance_by_source_meters.get_or_create(&DistanceLabels { source: source.to_string() }).get();
+        assert_eq!((distance("tracker"), distance("loggedActivities")), (5250.0, 1500.0));
+        assert_eq!(distance(OTHER), 1500.0);
+        assert_eq!(fitbit_metrics.series_collapsed.get_or_create(&FamilyLabels { family: "fitbit_distance_by_source_meters".to_string() }).get(), 2);
+    }
+
+    #[test]
+    fn alert_friendly_boolean_metrics() {
+        let fitbit_metrics = FitbitMetrics::new();
+        set_current_steps(&fitbit_metrics, 10500);
+        fitbit_metrics.goal_steps.set(10000);
+        fitbit_metrics.floors.set(3.0);
+        fitbit_metrics.goal_floors.set(10.0);
+        update_goal_met_metrics(&fitbit_metrics);
+
+        let goal_met = |goal: &str| fitbit_metrics.goal_met.get_or_create(&GoalLabels { goal: goal.to_string() }).get();
+        assert_eq!(goal_met("steps"), 1);
+        assert_eq!(goal_met("floors"), 0);
+
+        // Only the goals reached after the first update are announced
+        let mut events = fitbit_metrics.events.subscribe();
+        fitbit_metrics.floors.set(12.0);
+        update_goal_met_metrics(&fitbit_metrics);
+        update_goal_met_metrics(&fitbit_metrics);
+        assert_eq!(events.try_recv().unwrap(), Event::GoalAchieved { goal: "floors".to_string(), value: 12.0, target: 10.0 });
+        assert!(events.try_recv().is_err());
+
+        let devices: Vec<Device> = serde_json::from_value(json!([
+            { "id": "1", "deviceVersion": "Charge 5", "battery": "Low", "lastSyncTime": "2023-03-04T10:00:00.000" },
+            { "id": "2", "deviceVersion": "Aria", "battery": "High", "lastSyncTime": "2023-03-01T10:00:00.000" },
+            { "id": "3", "deviceVersion": "Charge 5", "battery": "High", "lastSyncTime": "2023-03-04T09:00:00.000" },
+        ]))
+        .unwrap();
+        let now = NaiveDate::from_ymd_opt(2023, 3, 4).unwrap().and_hms_opt(10, 30, 0).unwrap();
+        update_device_metrics_at(&fitbit_metrics, &devices, now);
+
+        assert_eq!(fitbit_metrics.synced_recently.get(), 1);
+        let battery_low = |device: &str, device_id: &str| {
+            let labels = DeviceLabels { device: device.to_string(), device_id: device_id.to_string() };
+            fitbit_metrics.battery_low.get_or_create(&labels).get()
+        };
+        assert_eq!((battery_low("Charge 5", "1"), battery_low("Aria", "2"), battery_low("Charge 5", "3")), (1, 0, 0));
+
+        update_device_metrics_at(&fitbit_metrics, &devices, now + ChronoDuration::hours(2));
+        assert_eq!(fitbit_metrics.synced_recently.get(), 0);
+    }
+
+    #[test]
+    fn data_timestamps_are_in_the_timezone_of_the_user() {
+        let fitbit_metrics = FitbitMetrics::new();
+        // Tokyo, 9 hours ahead of UTC
+        fitbit_metrics.set_utc_offset(FixedOffset::east_opt(9 * 3600).unwrap());
+        let data_timestamp = |collector: &str| fitbit_metrics.data_timestamp_seconds.get_or_create(&CollectorLabels { collector: collector.to_string() }).get();
+
+        let sleep: SleepLogResponse = serde_json::from_value(json!({
+            "sleep": [{
+                "logId": 1,
+                "dateOfSleep": "2023-03-04",
+                "duration": 27_720_000,
+                "efficiency": 93,
+                "isMainSleep": true,
+                "startTime": "2023-03-04T00:12:00.000",
+                "endTime": "2023-03-04T07:54:00.000",
+                "timeInBed": 462,
+                "minutesAsleep": 420,
+                "minutesAwake": 42,
+                "minutesAfterWakeup": 3,
+                "levels": { "summary": {}, "data": [] }
+            }],
+            "summary": { "totalMinutesAsleep": 420, "totalTimeInBed": 462 }
+        }))
+        .unwrap();
+        update_sleep_metrics(&fitbit_metrics, &sleep);
+        // 2023-03-03T15:12:00Z and 2023-03-03T22:54:00Z
+        assert_eq!(fitbit_metrics.sleep_start_time_seconds.get(), 1677856320);
+        assert_eq!(data_timestamp("sleep"), 1677884040);
+
+        let devices: Vec<Device> = serde_json::from_value(json!([{ "id": "1", "deviceVersion": "Charge 5", "battery": "High", "lastSyncTime": "2023-03-04T10:00:00.000" }])).unwrap();
+        update_device_metrics(&fitbit_metrics, &devices);
+        assert_eq!(data_timestamp("devices"), 1677891600);
+
+        let readings: Vec<EcgReading> = serde_json::from_value(json!([{ "startTime": "2023-03-04T17:12:30.222", "averageHeartRate": 112, "resultClassification": "Normal Sinus Rhythm" }])).unwrap();
+        update_ecg_metrics(&fitbit_metrics, &readings);
+        assert_eq!(data_timestamp("ecg"), 1677917550);
+
+        let alerts: Vec<IrnAlert> = serde_json::from_value(json!([{ "alertTime": "2023-03-04T03:00:00.000", "detectedTime": "2023-03-04T03:00:00.000" }])).unwrap();
+        update_irn_metrics(&fitbit_metrics, &alerts);
+        assert_eq!(data_timestamp("irn"), 1677866400);
+    }
+
+    #[test]
+    fn series_over_the_cap_are_collapsed() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { max_series_per_family: Some(2), ..MetricsOptions::default() });
+        let activity_log = |log_id: u64, calories: u64| json!({
+            "logId": log_id,
+            "activityName": "Walk",
+            "activityTypeId": 90013,
+            "startTime": "2023-03-04T07:00:00.000+01:00",
+            "activeDuration": 600_000,
+            "calories": calories
+        });
+        let activity_logs: Vec<ActivityLog> = serde_json::from_value(json!([activity_log(1, 50), activity_log(2, 60), activity_log(3, 70)])).unwrap();
+
+        update_activity_log_metrics(&fitbit_metrics, &activity_logs);
+
+        let labels = |activity_type: &str, source: &str, log_id: &str| ActivityLabels {
+            activity_type: activity_type.to_string(),
+            source: source.to_string(),
+            activity_name: String::new(),
+            log_id: log_id.to_string(),
+        };
+        assert_eq!(fitbit_metrics.activity_calories.get_or_create(&labels("walk", "unknown", "1")).get(), 50.0);
+        assert_eq!(fitbit_metrics.activity_calories.get_or_create(&labels(OTHER, OTHER, OTHER)).get(), 130.0);
+        assert_eq!(fitbit_metrics.activity_duration_seconds.get_or_create(&labels(OTHER, OTHER, OTHER)).get(), 1200.0);
+        assert_eq!(fitbit_metrics.series_collapsed.get_or_create(&FamilyLabels { family: "fitbit_activity".to_string() }).get(), 2);
+    }
+
+    #[test]
+    fn workouts_are_capped_by_default() {
+        let fitbit_metrics = FitbitMetrics::new();
+        let activity_logs: Vec<ActivityLog> = serde_json::from_value(json!((1..=101)
+            .map(|log_id| json!({
+                "logId": log_id,
+                "activityName": "Walk",
+                "activityTypeId": 90013,
+                "startTime": "2023-03-04T07:00:00.000+01:00",
+                "activeDuration": 600_000,
+                "calories": 50
+            }))
+            .collect::<Vec<_>>()))
+        .unwrap();
+
+        update_activity_log_metrics(&fitbit_metrics, &activity_logs);
+        assert_eq!(fitbit_metrics.series_collapsed.get_or_create(&FamilyLabels { family: "fitbit_activity".to_string() }).get(), 2);
+
+        let uncapped = FitbitMetrics::with_options(MetricsOptions { max_series_per_family: Some(0), ..MetricsOptions::default() });
+        update_activity_log_metrics(&uncapped, &activity_logs);
+        assert_eq!(uncapped.series_collapsed.get_or_create(&FamilyLabels { family: "fitbit_activity".to_string() }).get(), 0);
+    }
+
+    #[tokio::test]
+    async fn collectors_over_the_deadline_are_skipped() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { hourly_request_budget: Some(10), ..MetricsOptions::default() });
+        let selection = CollectorSelection::all().with_deadline(Instant::now() + Duration::from_millis(10));
+        let timeouts = |collector: &str| fitbit_metrics.collector_timeouts.get_or_create(&CollectorLabels { collector: collector.to_string() }).get();
+
+        let slow = run_collector(&fitbit_metrics, &selection, "sleep", async {
+            count_request();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        });
+        assert!(slow.await.is_ok());
+        // The deadline is exceeded, so the next collector doesn't even start, and is skipped rather than failed
+        let next = run_collector(&fitbit_metrics, &selection, "steps", async { panic!("The collector started after the deadline") });
+        assert!(next.await.is_ok());
+
+        assert_eq!((timeouts("sleep"), timeouts("steps"), timeouts("water")), (1, 1, 0));
+        assert!(fitbit_metrics.error_budget.is_enabled("sleep"));
+        // Only the request of the slow collector is charged to the budget
+        assert_eq!(fitbit_metrics.scheduler.remaining_budget(), Some(9));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_pauses_the_collectors() {
+        let fitbit_metrics = FitbitMetrics::new();
+        let selection = CollectorSelection::all();
+
+        let rate_limited = run_collector(&fitbit_metrics, &selection, "sleep", async { Err(FitbitError::RateLimited(Duration::from_secs(600))) });
+        assert!(matches!(rate_limited.await, Err(FitbitError::RateLimited(_))));
+        let remaining = fitbit_metrics.rate_limit_remaining().unwrap();
+        assert!(remaining > Duration::from_secs(590) && remaining <= Duration::from_secs(600));
+
+        // The next collectors don't call the API until the rate limit ends
+        let called = Arc::new(Mutex::new(false));
+        let next = run_collector(&fitbit_metrics, &selection, "steps", {
+            let called = called.clone();
+            async move {
+                *called.lock().unwrap() = true;
+                Ok(())
+            }
+        });
+        assert!(matches!(next.await, Err(FitbitError::RateLimited(_))));
+        assert!(!*called.lock().unwrap());
+        assert!(fitbit_metrics.error_budget.is_enabled("sleep"));
+    }
+
+    #[tokio::test]
+    async fn auth_errors_do_not_disable_the_collectors() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { error_budget: ErrorBudget::new(2, Duration::from_secs(3600)), ..MetricsOptions::default() });
+        let selection = CollectorSelection::all();
+        let mut events = fitbit_metrics.events.subscribe();
+
+        for _ in 0..3 {
+            let expired = run_collector(&fitbit_metrics, &selection, "sleep", async { Err(FitbitError::AccessTokenExpired) });
+            assert!(matches!(expired.await, Err(FitbitError::AccessTokenExpired)));
+        }
+        assert!(fitbit_metrics.error_budget.is_enabled("sleep"));
+        // The token is refreshed soon, it's not broken
+        assert!(events.try_recv().is_err());
+
+        // Any other error counts against the budget
+        for _ in 0..2 {
+            let failed = run_collector(&fitbit_metrics, &selection, "sleep", async { Err(FitbitError::InvalidData) });
+            assert!(failed.await.is_err());
+        }
+        assert!(!fitbit_metrics.error_budget.is_enabled("sleep"));
+
+        // Only the start of the failures is logged, until the collector succeeds again
+        let logged = |event: &str| fitbit_metrics.events.log().recent().iter().filter(|entry| entry.event == event).count();
+        assert_eq!((logged("collector_failed"), logged("collector_disabled")), (1, 1));
+        for result in [Err(FitbitError::InvalidData), Ok(()), Err(FitbitError::InvalidData)] {
+            let _ = run_collector(&fitbit_metrics, &selection, "steps", async { result }).await;
+        }
+        assert_eq!(logged("collector_failed"), 3);
+    }
+
+    #[tokio::test]
+    async fn selected_collectors_map_the_api_responses() {
+        let fitbit_metrics = Arc::new(FitbitMetrics::new());
+        let api = Arc::new(RwLock::new(
+            MockFitbitApi::new()
+                .with_response("fetch_water", json!(1250.0))
+                .with_response("fetch_sleep", json!({ "sleep": [], "summary": { "totalMinutesAsleep": 420, "totalTimeInBed": 462 } }))
+                .with_error("fetch_steps", || FitbitError::AccessTokenExpired),
+        ));
+        let selection = ScrapeProfiles::default().select(Some("collect[]=water&collect[]=sleep&collect[]=steps")).unwrap();
+
+        update_selected_metrics(api.clone(), fitbit_metrics.clone(), &selection).await.unwrap();
+
+        assert_eq!(fitbit_metrics.water_ml.get(), 1250.0);
+        assert_eq!(fitbit_metrics.sleep_total_asleep_seconds.get(), 420.0 * 60.0);
+        // The collectors that aren't selected don't call the API
+        let mut calls = api.read().await.calls();
+        calls.sort();
+        assert_eq!(calls, vec!["fetch_sleep", "fetch_steps", "fetch_water"]);
+    }
+
+    #[tokio::test]
+    async fn token_refresh_queued_during_an_update_does_not_deadlock() {
+        let fitbit_metrics = Arc::new(FitbitMetrics::new());
+        let api = Arc::new(RwLock::new(
+            MockFitbitApi::new()
+                .with_response("fetch_steps", json!(8123))
+                .with_response("fetch_water", json!(1250.0))
+                .with_delay(Duration::from_millis(50)),
+        ));
+        let selection = ScrapeProfiles::default().select(Some("collect[]=steps&collect[]=water")).unwrap();
+
+        // The refresh takes the write lock while the first collector is fetching, before the second one starts
+        let update = tokio::spawn({
+            let (api, fitbit_metrics) = (api.clone(), fitbit_metrics.clone());
+            async move { update_selected_metrics(api, fitbit_metrics, &selection).await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let refresh = tokio::spawn({
+            let api = api.clone();
+            async move {
+                let _write_locked_client = api.write().await;
+            }
+        });
+
+        let (update, refresh) = tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(update, refresh) }).await.expect("deadlock");
+        assert!(update.unwrap());
+        refresh.unwrap();
+        assert_eq!(fitbit_metrics.water_ml.get(), 1250.0);
+    }
+
+    #[tokio::test]
+    async fn recovery_score_reuses_the_sleep_of_the_sleep_collector() {
+        let weights = RecoveryWeights { resting_heart_rate: 0.0, hrv: 0.0, sleep: 1.0 };
+        let fitbit_metrics = Arc::new(FitbitMetrics::with_options(MetricsOptions { recovery_weights: Some(weights), ..MetricsOptions::default() }));
+        let main_sleep = json!({
+            "logId": 1, "dateOfSleep": "2024-03-10", "duration": 27720000, "efficiency": 92, "isMainSleep": true,
+            "startTime": "2024-03-09T23:10:00.000", "endTime": "2024-03-10T06:52:00.000", "timeInBed": 462,
+            "minutesAsleep": 420, "minutesAwake": 42, "minutesAfterWakeup": 0
+        });
+        let api = Arc::new(RwLock::new(
+            MockFitbitApi::new()
+                .with_response("fetch_sleep", json!({ "sleep": [main_sleep], "summary": { "totalMinutesAsleep": 420, "totalTimeInBed": 462 } }))
+                .with_response("fetch_resting_heart_rate_range", json!([]))
+                .with_response("fetch_hrv_range", json!([])),
+        ));
+        let selection = ScrapeProfiles::default().select(Some("collect[]=sleep&collect[]=recovery")).unwrap();
+
+        update_selected_metrics(api.clone(), fitbit_metrics.clone(), &selection).await.unwrap();
+
+        assert_eq!(fitbit_metrics.recovery_score.get(), 92.0);
+        let mut calls = api.read().await.calls();
+        calls.sort();
+        assert_eq!(calls, vec!["fetch_hrv_range", "fetch_resting_heart_rate_range", "fetch_sleep"]);
+    }
+
+    #[tokio::test]
+    async fn temperature_collector_exposes_the_latest_temperatures() {
+        let fitbit_metrics = Arc::new(FitbitMetrics::new());
+        let selection = ScrapeProfiles::default().select(Some("collect[]=temperature")).unwrap();
+        let api = Arc::new(RwLock::new(
+            MockFitbitApi::new()
+                .with_response("fetch_skin_temperature", json!({ "dateTime": "2024-03-10", "value": { "nightlyRelative": -0.4 } }))
+                .with_response("fetch_core_temperature", json!({ "dateTime": "2024-03-09T07:30:00", "value": 36.6 })),
+        ));
+        update_selected_metrics(api, fitbit_metrics.clone(), &selection).await.unwrap();
+
+        let date = |date: &str| DateLabels { date: date.to_string() };
+        assert_eq!(fitbit_metrics.skin_temp_delta_celsius.get_or_create(&date("2024-03-10")).get(), -0.4);
+        assert_eq!(fitbit_metrics.core_temp_celsius.get_or_create(&date("2024-03-09")).get(), 36.6);
+
+        // A new core temperature replaces the previous one, while a missing skin temperature leaves it
+        let api = Arc::new(RwLock::new(
+            MockFitbitApi::new()
+                .with_response("fetch_skin_temperature", json!(null))
+                .with_response("fetch_core_temperature", json!({ "dateTime": "2024-03-11T08:00:00", "value": 36.8 })),
+        ));
+        update_selected_metrics(api, fitbit_metrics.clone(), &selection).await.unwrap();
+
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+        assert!(txt.contains(r#"fitbit_skin_temp_delta_celsius{date="2024-03-10"} -0.4"#), "{}", txt);
+        assert!(txt.contains(r#"fitbit_core_temp_celsius{date="2024-03-11"} 36.8"#), "{}", txt);
+        assert!(!txt.contains("2024-03-09"), "{}", txt);
+    }
+
+    #[tokio::test]
+    async fn collectors_over_the_request_budget_are_skipped() {
+        let fitbit_metrics = Arc::new(FitbitMetrics::with_options(MetricsOptions { hourly_request_budget: Some(2), ..MetricsOptions::default() }));
+        let api = Arc::new(RwLock::new(
+            MockFitbitApi::new()
+                .with_response("fetch_steps", json!(8123))
+                .with_response("fetch_water", json!(1250.0))
+                .with_response("fetch_sleep", json!({ "sleep": [], "summary": { "totalMinutesAsleep": 420, "totalTimeInBed": 462 } })),
+        ));
+        let selection = ScrapeProfiles::default().select(Some("collect[]=steps&collect[]=water&collect[]=sleep")).unwrap();
+
+        update_selected_metrics(api.clone(), fitbit_metrics.clone(), &selection).await.unwrap();
+
+        // The collectors run one after the other by default, so the last one is over the budget
+        assert_eq!(api.read().await.calls(), vec!["fetch_steps", "fetch_water"]);
+        let skips = |collector: &str| fitbit_metrics.collector_budget_skips.get_or_create(&CollectorLabels { collector: collector.to_string() }).get();
+        assert_eq!((skips("water"), skips("sleep")), (0, 1));
+        assert_eq!(fitbit_metrics.scheduler.remaining_budget(), Some(0));
+    }
+
+    #[test]
+    fn leaderboard_is_labelled_by_friend() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { max_series_per_family: Some(3), ..Default::default() });
+        let ranks: Vec<LeaderboardRank> = serde_json::from_value(json!([
+            {"user_id": "A", "name": "John S.", "steps": 72500, "rank": 1},
+            {"user_id": "B", "name": "John S.", "steps": 61234, "rank": 2},
+            {"user_id": "C", "name": "Alex K.", "steps": 20000, "rank": 3},
+            {"user_id": "D", "name": "Sam T.", "steps": 1000, "rank": 4},
+        ]))
+        .unwrap();
+        update_leaderboard_metrics(&fitbit_metrics, &ranks);
+
+        let labels = |friend: &str, user_id: &str| LeaderboardLabels { friend: friend.to_string(), user_id: user_id.to_string() };
+        // Both John S. are ranked
+        assert_eq!(fitbit_metrics.leaderboard_steps.get_or_create(&labels("John S.", "A")).get(), 72500);
+        assert_eq!(fitbit_metrics.leaderboard_steps.get_or_create(&labels("John S.", "B")).get(), 61234);
+        assert_eq!(fitbit_metrics.leaderboard_rank.get_or_create(&labels("John S.", "B")).get(), 2);
+        assert_eq!(fitbit_metrics.leaderboard_steps.get_or_create(&labels(OTHER, OTHER)).get(), 21000);
+
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+        assert!(!txt.contains("Alex K.") && !txt.contains("fitbit_leaderboard_rank{friend=\"other\""));
+    }
+
+    #[test]
+    fn badges_count_the_times_they_were_earned() {
+        let fitbit_metrics = FitbitMetrics::new();
+        let badges = |times_achieved: u64| -> Vec<Badge> {
+            serde_json::from_value(json!([{"badgeType": "DAILY_STEPS", "value": 25000, "timesAchieved": times_achieved, "dateTime": "2023-03-01"}])).unwrap()
+        };
+        update_badge_metrics(&fitbit_metrics, &badges(3));
+        update_badge_metrics(&fitbit_metrics, &badges(4));
+
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+        assert!(txt.contains("fitbit_badges_total{category=\"DAILY_STEPS\",value=\"25000\"} 4"));
+    }
+
+    #[test]
+    fn lifetime_totals_and_best_days() {
+        let fitbit_metrics = FitbitMetrics::new();
+        let stats: LifetimeStats = serde_json::from_value(json!({
+            "lifetime": {"distance": 5612.5, "floors": 4421, "steps": 7894521},
+            "best": {"steps": {"date": "2022-06-18", "value": 41287}, "distance": {"date": "2022-06-18", "value": 31.5}},
+        }))
+        .unwrap();
+        update_lifetime_metrics(&fitbit_metrics, &stats);
+
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+        assert!(txt.contains("fitbit_lifetime_steps_total 7894521"));
+        assert!(txt.contains("fitbit_lifetime_distance_meters_total 5612500.0"));
+        assert!(txt.contains("fitbit_best_day_steps{date=\"2022-06-18\"} 41287.0"));
+        assert!(txt.contains("fitbit_best_day_distance_meters{date=\"2022-06-18\"} 31500.0"));
+        assert!(!txt.contains("fitbit_best_day_floors{"));
+    }
+
+    #[test]
+    fn heart_rate_minutes_are_counted_per_bucket() {
+        let fitbit_metrics = FitbitMetrics::new();
+        let samples: Vec<HeartRateSample> = serde_json::from_value(json!([
+            {"time": "07:00:00", "value": 57},
+            {"time": "07:01:00", "value": 61},
+            {"time": "07:02:00", "value": 74},
+            {"time": "07:03:00", "value": 141},
+        ]))
+        .unwrap();
+        update_heart_rate_histogram(&fitbit_metrics, NaiveDate::from_ymd_opt(2024, 5, 19).unwrap(), None, &samples);
+        update_heart_rate_histogram(&fitbit_metrics, NaiveDate::from_ymd_opt(2024, 5, 20).unwrap(), None, &samples);
+
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+        assert!(txt.contains("fitbit_heart_rate_minutes_bucket{le=\"60.0\",date=\"2024-05-20\"} 1"));
+        assert!(txt.contains("fitbit_heart_rate_minutes_bucket{le=\"80.0\",date=\"2024-05-20\"} 3"));
+        assert!(txt.contains("fitbit_heart_rate_minutes_count{date=\"2024-05-20\"} 4"));
+        assert!(!txt.contains("2024-05-19"));
+    }
+
+    #[test]
+    fn intraday_window_only_adds_the_new_minutes() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { intraday_window: true, ..MetricsOptions::default() });
+        let today = NaiveDate::from_ymd_opt(2024, 5, 20).unwrap();
+        let samples = |samples: Value| -> Vec<HeartRateSample> { serde_json::from_value(samples).unwrap() };
+
+        // The whole day at first
+        assert_eq!(fitbit_metrics.heart_rate_intraday_since(today), None);
+        update_heart_rate_histogram(&fitbit_metrics, today, None, &samples(json!([{"time": "07:00:00", "value": 57}, {"time": "07:01:00", "value": 74}])));
+
+        // Then the window from the last minute observed, which is returned again
+        let since = fitbit_metrics.heart_rate_intraday_since(today);
+        assert_eq!(since, NaiveTime::from_hms_opt(7, 1, 0));
+        update_heart_rate_histogram(&fitbit_metrics, today, since, &samples(json!([{"time": "07:01:00", "value": 74}, {"time": "07:02:00", "value": 141}])));
+        assert_eq!(fitbit_metrics.heart_rate_intraday_since(today), NaiveTime::from_hms_opt(7, 2, 0));
+
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+        assert!(txt.contains("fitbit_heart_rate_minutes_bucket{le=\"80.0\",date=\"2024-05-20\"} 2"));
+        assert!(txt.contains("fitbit_heart_rate_minutes_count{date=\"2024-05-20\"} 3"));
+
+        // The next day is fetched whole again
+        assert_eq!(fitbit_metrics.heart_rate_intraday_since(today.succ_opt().unwrap()), None);
+    }
+
+    #[test]
+    fn the_exposition_passes_strict_validation() {
+        let fitbit_metrics = FitbitMetrics::new();
+        set_current_steps(&fitbit_metrics, 8123);
+        fitbit_metrics.push_historical_steps(8000, Duration::from_secs(1677801600), false);
+        fitbit_metrics.push_historical_steps(8000, Duration::from_secs(1677801600), false);
+        fitbit_metrics.error_budget.record_success("steps");
+
+        assert!(ExpositionFormat::OpenMetrics.encode_strict(&fitbit_metrics.registry(), &ExpositionRelabel::default()).is_ok());
+    }
+
+    #[test]
+    fn disabled_collectors_are_not_exposed() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions {
+            collectors: Some(vec!["steps".to_string(), "sleep".to_string()]),
+            ..MetricsOptions::default()
+        });
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+
+        assert!(txt.contains("# TYPE fitbit_steps ") && txt.contains("# TYPE fitbit_sleep_efficiency "));
+        assert!(!txt.contains("fitbit_water_ml") && !txt.contains("fitbit_goal_steps"));
+        assert!(!fitbit_metrics.is_collector_enabled("water"));
+    }
+
+    #[test]
+    fn collector_config_changes_rebuild_the_registry() {
+        let fitbit_metrics = FitbitMetrics::new();
+        let webhook_notifications = Counter::<u64>::default();
+        fitbit_metrics.register_external({
+            let webhook_notifications = webhook_notifications.clone();
+            move |registry| registry.register("fitbit_webhook_notifications", "Help", webhook_notifications.clone())
+        });
+        let update: CollectorConfigUpdate = serde_json::from_value(json!({"disable": ["water"], "cadences": {"sleep": 3600}})).unwrap();
+
+        let config = fitbit_metrics.update_collector_config(&update).unwrap();
+
+        assert!(!config.collectors.contains(&"water".to_string()) && config.collectors.contains(&"steps".to_string()));
+        assert_eq!(fitbit_metrics.cadence("sleep"), Some(Duration::from_secs(3600)));
+        let mut txt = String::new();
+        encode(&mut txt, &fitbit_metrics.registry()).unwrap();
+        assert!(!txt.contains("fitbit_water_ml") && txt.contains("# TYPE fitbit_steps "));
+        assert!(txt.contains("fitbit_webhook_notifications"));
+    }
+
+    #[test]
+    fn activity_logs_are_labelled_by_type_source_and_log_id() {
+        let fitbit_metrics = FitbitMetrics::new();
+        let activity_logs: Vec<ActivityLog> = serde_json::from_value(json!([{
+            "logId": 5_423_456_789u64,
+            "activityName": "Run",
+            "startTime": "2023-03-04T07:00:00.000+01:00",
+            "activeDuration": 1_800_000,
+            "calories": 312,
+            "averageHeartRate": 151,
+            "distance": 5.2,
+            "distanceUnit": "Kilometer",
+            "logType": "auto_detected"
+        }, {
+            "logId": 5_423_456_790u64,
+            "activityName": "Yoga",
+            "startTime": "2023-03-04T19:00:00.000+01:00",
+            "activeDuration": 2_700_000,
+            "calories": 120,
+            "logType": "manual"
+        }]))
+        .unwrap();
+
+        update_activity_log_metrics(&fitbit_metrics, &activity_logs);
+
+        let run = ActivityLabels { activity_type: "unknown".to_string(), source: "tracker".to_string(), activity_name: String::new(), log_id: "5423456789".to_string() };
+        assert_eq!(fitbit_metrics.activity_duration_seconds.get_or_create(&run).get(), 1800.0);
+        assert_eq!(fitbit_metrics.activity_distance_meters.get_or_create(&run).get(), 5200.0);
+        assert_eq!(fitbit_metrics.activity_average_heart_rate_bpm.get_or_create(&run).get(), 151.0);
+        assert_eq!(fitbit_metrics.activity_start_time_seconds.get_or_create(&run).get(), 1677909600);
+
+        let yoga = ActivityLabels { activity_type: "unknown".to_string(), source: "manual".to_string(), activity_name: String::new(), log_id: "5423456790".to_string() };
+        assert_eq!(fitbit_metrics.activity_calories.get_or_create(&yoga).get(), 120.0);
+
+        // The end of the yoga session, the most recent workout
+        let labels = CollectorLabels { collector: "activity_logs".to_string() };
+        assert_eq!(fitbit_metrics.data_timestamp_seconds.get_or_create(&labels).get(), 1677955500);
+    }
+
+    #[test]
+    fn activity_types_of_a_localized_account_are_canonical() {
+        let fitbit_metrics = FitbitMetrics::with_options(MetricsOptions { activity_name_label: true, ..MetricsOptions::default() });
+        let activity_logs: Vec<ActivityLog> = serde_json::from_value(json!([{
+            "logId": 5_423_456_789u64,
+            "activityName": "Course",
+            "activityTypeId": 90009,
+            "startTime": "2023-03-04T07:00:00.000+01:00",
+            "activeDuration": 1_800_000,
+            "calories": 312,
+            "logType": "auto_detected"
+        }, {
+            "logId": 5_423_456_790u64,
+            "activityName": "Kickboxing",
+            "activityTypeId": 17151,
+            "startTime": "2023-03-04T19:00:00.000+01:00",
+            "activeDuration": 2_700_000,
+            "calories": 420,
+            "logType": "manual"
+        }]))
+        .unwrap();
+
+        update_activity_log_metrics(&fitbit_metrics, &activity_logs);
+
+        let run = ActivityLabels { activity_type: "run".to_string(), source: "tracker".to_string(), activity_name: "Course".to_string(), log_id: "5423456789".to_string() };
+        assert_eq!(fitbit_metrics.activity_calories.get_or_create(&run).get(), 312.0);
+        let kickboxing = ActivityLabels {
+            activity_type: "type_17151".to_string(),
+            source: "manual".to_string(),
+            activity_name: "Kickboxing".to_string(),
+            log_id: "5423456790".to_string(),
+        };
+        assert_eq!(fitbit_metrics.activity_calories.get_or_create(&kickboxing).get(), 420.0);
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::{NaiveDate, NaiveTime};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::fitbit::client::{ApiFuture, FitbitApi, FitbitError};
+use crate::fitbit::models::{ActivityGoals, ActivityLog, ActivitySummary, Badge, Bpm, BreathingRateSummary, CardioScoreDay, CoreTemperatureLog, Device, EcgReading, FoodSummary, HeartRateSample, IrnAlert, LeaderboardRank, LifetimeStats, Meters, SkinTemperatureDay, SleepLogResponse, Steps, UserProfile, WeightLog};
+use crate::fitbit::scheduler::count_request;
+
+/// The response of a method: the JSON of its return value, or the builder of its error.
+type MockResponse = Result<Value, fn() -> FitbitError>;
+
+/// A `FitbitApi` answering with canned responses, to test the mapping of the responses to the metrics without
+/// live tokens, e.g.
+///
+/// ```
+/// use fitbit_exporter::fitbit::mock::MockFitbitApi;
+/// use serde_json::json;
+///
+/// let api = MockFitbitApi::new().with_response("fetch_steps", json!(8123));
+/// ```
+///
+/// A response is given as the JSON of the return value of the method, in the format of the Fitbit API for the
+/// models (e.g. `SleepLogResponse`). A method without a response fails with `FitbitError::UnexpectedResponse`.
+#[derive(Debug, Default)]
+pub struct MockFitbitApi {
+    responses: HashMap<&'static str, MockResponse>,
+    // The methods called, in order
+    calls: Mutex<Vec<&'static str>>,
+    // How long each response takes
+    delay: Option<Duration>,
+}
+
+impl MockFitbitApi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers the calls of `method` (e.g. "fetch_steps") with `response`, whatever their arguments.
+    pub fn with_response(mut self, method: &'static str, response: Value) -> Self {
+        self.responses.insert(method, Ok(response));
+        self
+    }
+
+    /// Fails the calls of `method` with the error built by `error`, e.g. `|| FitbitError::AccessTokenExpired`.
+    pub fn with_error(mut self, method: &'static str, error: fn() -> FitbitError) -> Self {
+        self.responses.insert(method, Err(error));
+        self
+    }
+
+    /// Delays each response by `delay`, like the latency of the API, e.g. to interleave an update with a token
+    /// refresh.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Returns the methods called so far, in order.
+    pub fn calls(&self) -> Vec<&'static str> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    // The call is recorded when the future is polled, as a request of `FitbitClient` is only sent then.
+    fn respond<T: DeserializeOwned + Send + 'static>(&self, method: &'static str) -> ApiFuture<'_, T> {
+        Box::pin(async move {
+            self.calls.lock().unwrap().push(method);
+            count_request();
+            if let Some(delay) = self.delay {
+                tokio::time::sleep(delay).await;
+            }
+            let unexpected = |message: String| FitbitError::UnexpectedResponse { endpoint: method.to_string(), path: String::new(), message };
+            match self.responses.get(method) {
+                Some(Ok(response)) => serde_json::from_value(response.clone()).map_err(|err| unexpected(err.to_string())),
+                Some(Err(error)) => Err(error()),
+                None => Err(unexpected("No mock response".to_string())),
+            }
+        })
+    }
+}
+
+impl FitbitApi for MockFitbitApi {
+    fn fetch_steps(&self) -> ApiFuture<'_, Steps> {
+        self.respond("fetch_steps")
+    }
+
+    fn fetch_water(&self) -> ApiFuture<'_, f64> {
+        self.respond("fetch_water")
+    }
+
+    fn fetch_food_summary(&self) -> ApiFuture<'_, FoodSummary> {
+        self.respond("fetch_food_summary")
+    }
+
+    fn fetch_sleep(&self) -> ApiFuture<'_, SleepLogResponse> {
+        self.respond("fetch_sleep")
+    }
+
+    fn fetch_activity_summary(&self) -> ApiFuture<'_, ActivitySummary> {
+        self.respond("fetch_activity_summary")
+    }
+
+    fn fetch_activity_logs(&self, _limit: u32) -> ApiFuture<'_, Vec<ActivityLog>> {
+        self.respond("fetch_activity_logs")
+    }
+
+    fn fetch_activity_goals(&self) -> ApiFuture<'_, ActivityGoals> {
+        self.respond("fetch_activity_goals")
+    }
+
+    fn fetch_devices(&self) -> ApiFuture<'_, Vec<Device>> {
+        self.respond("fetch_devices")
+    }
+
+    fn fetch_leaderboard(&self) -> ApiFuture<'_, Vec<LeaderboardRank>> {
+        self.respond("fetch_leaderboard")
+    }
+
+    fn fetch_badges(&self) -> ApiFuture<'_, Vec<Badge>> {
+        self.respond("fetch_badges")
+    }
+
+    fn fetch_lifetime_stats(&self) -> ApiFuture<'_, LifetimeStats> {
+        self.respond("fetch_lifetime_stats")
+    }
+
+    fn fetch_heart_rate_intraday(&self, _since: Option<NaiveTime>) -> ApiFuture<'_, Vec<HeartRateSample>> {
+        self.respond("fetch_heart_rate_intraday")
+    }
+
+    fn fetch_ecg_readings(&self, _limit: u32) -> ApiFuture<'_, Vec<EcgReading>> {
+        self.respond("fetch_ecg_readings")
+    }
+
+    fn fetch_irn_alerts(&self, _limit: u32) -> ApiFuture<'_, Vec<IrnAlert>> {
+        self.respond("fetch_irn_alerts")
+    }
+
+    fn fetch_cardio_score(&self) -> ApiFuture<'_, Option<CardioScoreDay>> {
+        self.respond("fetch_cardio_score")
+    }
+
+    fn fetch_breathing_rate(&self) -> ApiFuture<'_, Option<BreathingRateSummary>> {
+        self.respond("fetch_breathing_rate")
+    }
+
+    fn fetch_skin_temperature(&self) -> ApiFuture<'_, Option<SkinTemperatureDay>> {
+        self.respond("fetch_skin_temperature")
+    }
+
+    fn fetch_core_temperature(&self) -> ApiFuture<'_, Option<CoreTemperatureLog>> {
+        self.respond("fetch_core_temperature")
+    }
+
+    fn fetch_water_on(&self, _date: NaiveDate) -> ApiFuture<'_, f64> {
+        self.respond("fetch_water_on")
+    }
+
+    fn fetch_food_summary_on(&self, _date: NaiveDate) -> ApiFuture<'_, FoodSummary> {
+        self.respond("fetch_food_summary_on")
+    }
+
+    fn fetch_steps_last_week(&self) -> ApiFuture<'_, Vec<(NaiveDate, Steps)>> {
+        self.respond("fetch_steps_last_week")
+    }
+
+    fn fetch_steps_range(&self, _start_date: NaiveDate, _end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Steps)>> {
+        self.respond("fetch_steps_range")
+    }
+
+    fn fetch_elevation_range(&self, _start_date: NaiveDate, _end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Meters)>> {
+        self.respond("fetch_elevation_range")
+    }
+
+    fn fetch_floors_range(&self, _start_date: NaiveDate, _end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, u64)>> {
+        self.respond("fetch_floors_range")
+    }
+
+    fn fetch_weight_range(&self, _start_date: NaiveDate, _end_date: NaiveDate) -> ApiFuture<'_, Vec<WeightLog>> {
+        self.respond("fetch_weight_range")
+    }
+
+    fn fetch_resting_heart_rate_range(&self, _start_date: NaiveDate, _end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, Bpm)>> {
+        self.respond("fetch_resting_heart_rate_range")
+    }
+
+    fn fetch_hrv_range(&self, _start_date: NaiveDate, _end_date: NaiveDate) -> ApiFuture<'_, Vec<(NaiveDate, f64)>> {
+        self.respond("fetch_hrv_range")
+    }
+
+    fn fetch_user_id(&self) -> ApiFuture<'_, String> {
+        self.respond("fetch_user_id")
+    }
+
+    fn fetch_profile(&self) -> ApiFuture<'_, UserProfile> {
+        self.respond("fetch_profile")
+    }
+}
@@ -1,12 +1,50 @@
+pub mod api;
+pub mod backfill;
+pub mod bodylog;
 pub mod cmd;
 pub mod client;
+pub mod collector;
+pub mod dns;
+pub mod events;
+pub mod export;
+pub mod exporter;
+pub mod exposition;
+pub mod graphite;
 pub mod metrics;
+pub mod mock;
+pub mod models;
+pub mod profile;
+pub mod pushgateway;
+pub mod recovery;
+pub mod query;
+pub mod reauthorization;
+pub mod report;
+pub mod schedule;
+pub mod scheduler;
+pub mod secrets;
 pub mod server;
+pub mod statsd;
+pub mod status;
+pub mod storage;
+pub mod traces;
 pub mod history; 
+pub mod landing;
+pub mod logging;
+pub mod webhook;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 // Re-export structs and functions
-pub use client::{FitbitClient, FitbitError};
-pub use metrics::{FitbitMetrics, update_current_metrics};
+pub use client::{ApiFuture, FitbitApi, FitbitClient, FitbitError, HttpOptions};
+pub use metrics::{FitbitMetrics, MetricsOptions, poll_metrics_periodically, prune_history_periodically, prune_sample_store_periodically, roll_over_daily_metrics_at_midnight, update_current_metrics, update_selected_metrics, warm_up_metrics};
+pub use profile::{ScrapeProfile, ScrapeProfiles};
 pub use server::run_server;
 pub use client::refresh_token_periodically;
-pub use history::dump_historical_metrics;
\ No newline at end of file
+pub use history::dump_historical_metrics;
+pub use webhook::{WebhookOptions, WebhookReceiver, register_webhook};
+pub use backfill::{BackfillOptions, backfill_gaps};
+pub use graphite::{GraphiteOptions, push_to_graphite_periodically};
+pub use pushgateway::{PushgatewayOptions, push_to_pushgateway_periodically};
+pub use statsd::{StatsdOptions, emit_to_statsd};
+pub use events::{EventBus, EventSinkConfig, dispatch_events};
+pub use exporter::{CustomCollector, Exporter, ExporterBuilder, MetricsSink};
\ No newline at end of file
@@ -0,0 +1,1135 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+// Unit newtypes for the values returned by the Fitbit API.
+//
+// Fitbit mixes units across endpoints (e.g. sleep `duration` is in milliseconds while `minutesAsleep` is in minutes,
+// distances are in km or miles depending on the locale), so values are wrapped in a type carrying their unit and
+// converted explicitly. Mixing up units then becomes a compile error instead of a wrong graph.
+
+/// A number of steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Steps(pub u64);
+
+impl Steps {
+    /// The value as expected by the integer gauges.
+    pub fn as_i64(self) -> i64 {
+        self.0 as i64
+    }
+}
+
+impl FromStr for Steps {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<u64>().map(Steps)
+    }
+}
+
+/// A distance in meters.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Meters(pub f64);
+
+impl Meters {
+    const METERS_PER_KILOMETER: f64 = 1000.0;
+    const METERS_PER_MILE: f64 = 1609.344;
+
+    pub fn from_kilometers(km: f64) -> Self {
+        Meters(km * Self::METERS_PER_KILOMETER)
+    }
+
+    pub fn from_miles(miles: f64) -> Self {
+        Meters(miles * Self::METERS_PER_MILE)
+    }
+
+    pub fn as_kilometers(self) -> f64 {
+        self.0 / Self::METERS_PER_KILOMETER
+    }
+
+    pub fn as_miles(self) -> f64 {
+        self.0 / Self::METERS_PER_MILE
+    }
+}
+
+impl FromStr for Meters {
+    type Err = std::num::ParseFloatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<f64>().map(Meters)
+    }
+}
+
+/// A duration in milliseconds, as used by e.g. the sleep log `duration` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Millis(pub u64);
+
+impl Millis {
+    pub fn from_seconds(seconds: u64) -> Self {
+        Millis(seconds * 1000)
+    }
+
+    pub fn from_minutes(minutes: u64) -> Self {
+        Millis(minutes * 60 * 1000)
+    }
+
+    /// The duration in seconds, which is the base unit of Prometheus metrics.
+    pub fn as_seconds(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+
+    pub fn as_minutes(self) -> f64 {
+        self.0 as f64 / (60.0 * 1000.0)
+    }
+}
+
+/// A duration in minutes, as used by most of the sleep log fields (`timeInBed`, `minutesAsleep`...).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Minutes(pub u64);
+
+impl Minutes {
+    pub fn as_millis(self) -> Millis {
+        Millis::from_minutes(self.0)
+    }
+
+    /// The duration in seconds, which is the base unit of Prometheus metrics.
+    pub fn as_seconds(self) -> f64 {
+        self.as_millis().as_seconds()
+    }
+}
+
+/// A body weight in kilograms, as returned without `Accept-Language`.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Kilograms(pub f64);
+
+impl Kilograms {
+    /// The weight in grams, as expected by the integer gauges.
+    pub fn as_grams(self) -> i64 {
+        (self.0 * 1000.0).round() as i64
+    }
+}
+
+/// A heart rate in beats per minute.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Bpm(pub f64);
+
+/// A VO2 Max in mL/kg/min, e.g. "46". Fitbit only returns a range, e.g. "44-48", when it has no GPS data of runs to
+/// estimate it precisely.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Vo2Max {
+    Value(f64),
+    Range { lower: f64, upper: f64 },
+}
+
+impl FromStr for Vo2Max {
+    type Err = std::num::ParseFloatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('-') {
+            Some((lower, upper)) => Ok(Vo2Max::Range { lower: lower.trim().parse()?, upper: upper.trim().parse()? }),
+            None => s.trim().parse().map(Vo2Max::Value),
+        }
+    }
+}
+
+impl TryFrom<String> for Vo2Max {
+    type Error = std::num::ParseFloatError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Typed models of the Fitbit API responses.
+//
+// Responses are deserialized into these structs instead of indexing `serde_json::Value`, so that a missing or
+// mistyped field is reported with its path (see `FitbitError::UnexpectedResponse`) instead of silently becoming 0.
+
+/// An entry of an activity time series, e.g. `{"dateTime": "2023-03-04", "value": "8123"}`.
+///
+/// Fitbit returns the values of the activity time series as strings, which are parsed into `T`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(bound(deserialize = "T: FromStr + Deserialize<'de>, T::Err: fmt::Display"))]
+pub struct TimeSeriesEntry<T> {
+    pub date_time: NaiveDate,
+    #[serde(deserialize_with = "string_or_number")]
+    pub value: T,
+}
+
+/// Response of the steps activity time series endpoints.
+/// https://dev.fitbit.com/build/reference/web-api/activity-timeseries/get-activity-timeseries-by-date/
+#[derive(Debug, Clone, Deserialize)]
+pub struct StepsSeries {
+    #[serde(rename = "activities-steps")]
+    pub steps: Vec<TimeSeriesEntry<Steps>>,
+}
+
+impl StepsSeries {
+    pub fn into_pairs(self) -> Vec<(NaiveDate, Steps)> {
+        self.steps.into_iter().map(|entry| (entry.date_time, entry.value)).collect()
+    }
+}
+
+/// Response of the elevation activity time series endpoints, in meters since the requests are sent without
+/// `Accept-Language`.
+/// https://dev.fitbit.com/build/reference/web-api/activity-timeseries/get-activity-timeseries-by-date-range/
+#[derive(Debug, Clone, Deserialize)]
+pub struct ElevationSeries {
+    #[serde(rename = "activities-elevation")]
+    pub elevation: Vec<TimeSeriesEntry<Meters>>,
+}
+
+impl ElevationSeries {
+    pub fn into_pairs(self) -> Vec<(NaiveDate, Meters)> {
+        self.elevation.into_iter().map(|entry| (entry.date_time, entry.value)).collect()
+    }
+}
+
+/// Response of the floors activity time series endpoints.
+/// https://dev.fitbit.com/build/reference/web-api/activity-timeseries/get-activity-timeseries-by-date-range/
+#[derive(Debug, Clone, Deserialize)]
+pub struct FloorsSeries {
+    #[serde(rename = "activities-floors")]
+    pub floors: Vec<TimeSeriesEntry<u64>>,
+}
+
+impl FloorsSeries {
+    pub fn into_pairs(self) -> Vec<(NaiveDate, u64)> {
+        self.floors.into_iter().map(|entry| (entry.date_time, entry.value)).collect()
+    }
+}
+
+/// Response of the body weight log endpoints.
+/// https://dev.fitbit.com/build/reference/web-api/body/get-weight-log/
+#[derive(Debug, Clone, Deserialize)]
+pub struct WeightLogList {
+    pub weight: Vec<WeightLog>,
+}
+
+/// A weigh-in, e.g. synced from an Aria scale or logged manually. Also saved in the checkpoints of the dumps.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WeightLog {
+    pub log_id: u64,
+    pub date: NaiveDate,
+    /// The time of the weigh-in, in the user's timezone.
+    pub time: NaiveTime,
+    pub weight: Kilograms,
+    /// "Aria" (or "AriaAir"...) for the scales, "Web" for the manual entries or "API" for the third-party apps.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+impl WeightLog {
+    /// Where the weigh-in comes from: "tracker" (a scale), "manual" (logged by hand), "api" (a third-party app) or
+    /// "unknown", e.g. for the checkpoints saved before the source was kept.
+    pub fn source(&self) -> &'static str {
+        match self.source.as_deref() {
+            Some(source) if source.starts_with("Aria") => "tracker",
+            Some("Web") => "manual",
+            Some("API") => "api",
+            _ => "unknown",
+        }
+    }
+
+    pub fn logged_at(&self) -> NaiveDateTime {
+        self.date.and_time(self.time)
+    }
+}
+
+/// Response of the water log endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/nutrition/get-water-log/
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaterLog {
+    pub summary: WaterSummary,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaterSummary {
+    /// Total water consumed in milliliters.
+    pub water: f64,
+}
+
+/// Response of the food log endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/nutrition/get-food-log/
+#[derive(Debug, Clone, Deserialize)]
+pub struct FoodLog {
+    #[serde(default)]
+    pub summary: FoodSummary,
+}
+
+/// Daily totals of the food log. Nutrients are in grams, except sodium which is in milligrams.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FoodSummary {
+    pub calories: f64,
+    pub carbs: f64,
+    pub fat: f64,
+    pub fiber: f64,
+    pub protein: f64,
+    pub sodium: f64,
+    pub water: f64,
+}
+
+/// Response of the sleep log by date endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/sleep/get-sleep-log-by-date/
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SleepLogResponse {
+    pub sleep: Vec<SleepLog>,
+    pub summary: SleepSummary,
+}
+
+impl SleepLogResponse {
+    /// Returns the main sleep, or `None` if no log is flagged as such (e.g. only a nap logged so far), so that a nap
+    /// isn't counted as the night.
+    pub fn main_sleep(&self) -> Option<&SleepLog> {
+        self.sleep.iter().find(|log| log.is_main_sleep)
+    }
+
+    /// Returns the naps, i.e. the logs other than the main sleep.
+    pub fn naps(&self) -> impl Iterator<Item = &SleepLog> {
+        self.sleep.iter().filter(|log| !log.is_main_sleep)
+    }
+}
+
+/// Totals of all the sleep logs of the day.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SleepSummary {
+    pub total_minutes_asleep: Minutes,
+    pub total_time_in_bed: Minutes,
+}
+
+/// A single sleep log (the main sleep or a nap).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepLog {
+    pub log_id: u64,
+    pub date_of_sleep: NaiveDate,
+    pub duration: Millis,
+    pub efficiency: i64,
+    pub is_main_sleep: bool,
+    #[serde(deserialize_with = "fitbit_datetime")]
+    pub start_time: NaiveDateTime,
+    #[serde(deserialize_with = "fitbit_datetime")]
+    pub end_time: NaiveDateTime,
+    pub time_in_bed: Minutes,
+    pub minutes_asleep: Minutes,
+    pub minutes_awake: Minutes,
+    pub minutes_after_wakeup: Minutes,
+    #[serde(default)]
+    pub levels: SleepLevels,
+}
+
+/// Sleep levels of a sleep log. The summary is keyed by stage: deep/light/rem/wake for "stages" logs,
+/// and asleep/restless/awake for "classic" logs.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SleepLevels {
+    pub summary: HashMap<String, SleepLevelSummary>,
+    /// The intervals of the log, in chronological order, e.g. 30 minutes of light sleep followed by 10 of deep sleep.
+    pub data: Vec<SleepLevelData>,
+}
+
+/// An interval of a sleep log spent in the same stage (level).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepLevelData {
+    #[serde(deserialize_with = "fitbit_datetime")]
+    pub date_time: NaiveDateTime,
+    pub level: String,
+    pub seconds: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SleepLevelSummary {
+    pub count: u64,
+    pub minutes: Minutes,
+}
+
+/// Response of the activity goals endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/activity/get-activity-goals/
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActivityGoalsResponse {
+    pub goals: ActivityGoals,
+}
+
+/// Daily activity goals of the user. A goal is missing when it doesn't apply, e.g. floors without an altimeter.
+/// The distance is in kilometers.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ActivityGoals {
+    pub active_minutes: Option<Minutes>,
+    pub calories_out: Option<f64>,
+    pub distance: Option<f64>,
+    pub floors: Option<f64>,
+    pub steps: Option<Steps>,
+}
+
+/// Response of the daily activity summary endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/activity/get-daily-activity-summary/
+#[derive(Debug, Clone, Deserialize)]
+pub struct DailyActivityResponse {
+    pub summary: ActivitySummary,
+}
+
+/// Daily activity totals. The distances are in kilometers.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ActivitySummary {
+    pub calories_out: f64,
+    pub distances: Vec<ActivityDistance>,
+    pub floors: Option<f64>,
+    pub fairly_active_minutes: Minutes,
+    pub very_active_minutes: Minutes,
+    pub steps: Steps,
+}
+
+impl ActivitySummary {
+    /// The total distance of the day, i.e. the one of the "total" activity.
+    pub fn total_distance(&self) -> Option<Meters> {
+        self.distances
+            .iter()
+            .find(|distance| distance.activity == "total")
+            .map(|distance| Meters::from_kilometers(distance.distance))
+    }
+
+    /// The active minutes as counted by the active minutes goal: fairly plus very active minutes.
+    pub fn active_minutes(&self) -> Minutes {
+        Minutes(self.fairly_active_minutes.0 + self.very_active_minutes.0)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActivityDistance {
+    pub activity: String,
+    pub distance: f64,
+}
+
+/// Response of the activity log list endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/activity/get-activity-log-list/
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActivityLogList {
+    pub activities: Vec<ActivityLog>,
+}
+
+/// Canonical names of the common activity types, by activity type ID.
+/// FYI: https://dev.fitbit.com/build/reference/web-api/activity/get-all-activity-types/
+const ACTIVITY_TYPES: [(u64, &str); 13] = [
+    (1071, "outdoor_bike"),
+    (2131, "weights"),
+    (3000, "workout"),
+    (15000, "sport"),
+    (20047, "elliptical"),
+    (20049, "treadmill"),
+    (52001, "yoga"),
+    (55001, "spinning"),
+    (90001, "bike"),
+    (90009, "run"),
+    (90012, "hike"),
+    (90013, "walk"),
+    (90024, "swim"),
+];
+
+/// A logged activity (workout), either recorded by the device or logged manually.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivityLog {
+    pub log_id: u64,
+    /// The name of the activity type, in the language of the account's locale.
+    pub activity_name: String,
+    #[serde(default)]
+    pub activity_type_id: Option<u64>,
+    pub start_time: DateTime<FixedOffset>,
+    /// The duration excluding the pauses.
+    pub active_duration: Millis,
+    pub calories: f64,
+    #[serde(default)]
+    pub average_heart_rate: Option<Bpm>,
+    /// The distance in `distance_unit`, for the activities that have one.
+    #[serde(default)]
+    pub distance: Option<f64>,
+    #[serde(default)]
+    pub distance_unit: Option<String>,
+    #[serde(default)]
+    pub steps: Option<Steps>,
+    /// How the activity was logged: "tracker", "auto_detected" (by the tracker), "mobile_run" or "manual".
+    #[serde(default)]
+    pub log_type: Option<String>,
+}
+
+impl ActivityLog {
+    /// Where the activity comes from: "tracker" (recorded or auto-detected by the device), "mobile" (recorded by
+    /// the phone app), "manual" (logged by hand) or "unknown".
+    pub fn source(&self) -> &'static str {
+        match self.log_type.as_deref() {
+            Some("tracker" | "auto_detected") => "tracker",
+            Some("mobile_run") => "mobile",
+            Some("manual") => "manual",
+            _ => "unknown",
+        }
+    }
+
+    /// A name of the activity type that doesn't depend on the account's locale, e.g. "run" for "Run" or "Course".
+    ///
+    /// The common types have a canonical name, the others are named after their ID, e.g. "type_17151". The logs
+    /// without a type ID are "unknown": their name is localized, so it can't tell their type.
+    pub fn canonical_type(&self) -> String {
+        match self.activity_type_id {
+            Some(id) => match ACTIVITY_TYPES.binary_search_by_key(&id, |(type_id, _)| *type_id) {
+                Ok(index) => ACTIVITY_TYPES[index].1.to_string(),
+                Err(_) => format!("type_{}", id),
+            },
+            None => "unknown".to_string(),
+        }
+    }
+
+    /// The distance of the activity, converted from its unit (kilometers, unless the user's locale says otherwise).
+    pub fn distance(&self) -> Option<Meters> {
+        let distance = self.distance?;
+        match self.distance_unit.as_deref() {
+            Some("Mile") => Some(Meters::from_miles(distance)),
+            _ => Some(Meters::from_kilometers(distance)),
+        }
+    }
+}
+
+/// Response of the heart rate time series endpoints.
+/// https://dev.fitbit.com/build/reference/web-api/heartrate-timeseries/get-heartrate-timeseries-by-date/
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeartRateSeries {
+    #[serde(rename = "activities-heart")]
+    pub days: Vec<HeartRateDay>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartRateDay {
+    pub date_time: NaiveDate,
+    pub value: HeartRateSummary,
+}
+
+/// Daily heart rate summary: resting heart rate (missing when not enough data was recorded) and heart rate zones.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HeartRateSummary {
+    pub resting_heart_rate: Option<Bpm>,
+    pub heart_rate_zones: Vec<HeartRateZone>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartRateZone {
+    pub name: String,
+    pub min: Bpm,
+    pub max: Bpm,
+    #[serde(default)]
+    pub minutes: Minutes,
+    #[serde(default)]
+    pub calories_out: f64,
+}
+
+/// Response of the heart rate intraday endpoint: the heart rate of each minute of the day with a reading.
+/// https://dev.fitbit.com/build/reference/web-api/intraday/get-heartrate-intraday-by-date/
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeartRateIntradayResponse {
+    #[serde(rename = "activities-heart-intraday")]
+    pub intraday: HeartRateIntraday,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HeartRateIntraday {
+    pub dataset: Vec<HeartRateSample>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeartRateSample {
+    pub time: NaiveTime,
+    pub value: Bpm,
+}
+
+/// Response of the HRV (heart rate variability) summary endpoints.
+/// https://dev.fitbit.com/build/reference/web-api/heartrate-variability/get-hrv-summary-by-interval/
+#[derive(Debug, Clone, Deserialize)]
+pub struct HrvSeries {
+    pub hrv: Vec<HrvDay>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HrvDay {
+    pub date_time: NaiveDate,
+    pub value: HrvSummary,
+}
+
+/// The RMSSD of the main sleep, in milliseconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HrvSummary {
+    pub daily_rmssd: f64,
+    #[serde(default)]
+    pub deep_rmssd: Option<f64>,
+}
+
+/// Response of the breathing rate intraday endpoint, broken down by sleep stage.
+/// https://dev.fitbit.com/build/reference/web-api/intraday/get-br-intraday-by-date/
+#[derive(Debug, Clone, Deserialize)]
+pub struct BreathingRateSeries {
+    #[serde(rename = "br")]
+    pub days: Vec<BreathingRateDay>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreathingRateDay {
+    /// The date of the sleep the breathing rate was measured during, i.e. the date the user woke up.
+    pub date_time: NaiveDate,
+    pub value: BreathingRateSummary,
+}
+
+/// The average breathing rates of the main sleep, per sleep stage and over the full sleep.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BreathingRateSummary {
+    pub deep_sleep_summary: Option<BreathingRate>,
+    pub rem_sleep_summary: Option<BreathingRate>,
+    pub light_sleep_summary: Option<BreathingRate>,
+    pub full_sleep_summary: Option<BreathingRate>,
+}
+
+impl BreathingRateSummary {
+    /// The breathing rates by stage ("deep", "rem", "light" and "full"), skipping the stages without enough data,
+    /// which Fitbit reports as a rate of 0 or less.
+    pub fn by_stage(&self) -> Vec<(&'static str, f64)> {
+        [
+            ("deep", &self.deep_sleep_summary),
+            ("rem", &self.rem_sleep_summary),
+            ("light", &self.light_sleep_summary),
+            ("full", &self.full_sleep_summary),
+        ]
+        .into_iter()
+        .filter_map(|(stage, summary)| summary.as_ref().map(|summary| (stage, summary.breathing_rate)))
+        .filter(|(_, breathing_rate)| *breathing_rate > 0.0)
+        .collect()
+    }
+}
+
+/// A breathing rate in breaths per minute.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreathingRate {
+    pub breathing_rate: f64,
+}
+
+/// Response of the skin temperature summary endpoints.
+/// https://dev.fitbit.com/build/reference/web-api/temperature/get-temperature-skin-summary-by-interval/
+#[derive(Debug, Clone, Deserialize)]
+pub struct SkinTemperatureSeries {
+    #[serde(rename = "tempSkin")]
+    pub days: Vec<SkinTemperatureDay>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkinTemperatureDay {
+    /// The date of the sleep the temperature was measured during, i.e. the date the user woke up.
+    pub date_time: NaiveDate,
+    pub value: SkinTemperature,
+}
+
+/// The skin temperature of the main sleep, relative to the user's baseline.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkinTemperature {
+    pub nightly_relative: f64,
+}
+
+/// Response of the core temperature summary endpoints, i.e. the temperatures logged by the user.
+/// https://dev.fitbit.com/build/reference/web-api/temperature/get-temperature-core-summary-by-interval/
+#[derive(Debug, Clone, Deserialize)]
+pub struct CoreTemperatureSeries {
+    #[serde(rename = "tempCore")]
+    pub logs: Vec<CoreTemperatureLog>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoreTemperatureLog {
+    /// The time of the measurement, in the user's timezone.
+    #[serde(deserialize_with = "fitbit_datetime")]
+    pub date_time: NaiveDateTime,
+    /// The temperature in Celsius, since the requests are sent without `Accept-Language`.
+    pub value: f64,
+}
+
+/// Response of the ECG log list endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/electrocardiogram/get-ecg-log-list/
+#[derive(Debug, Clone, Deserialize)]
+pub struct EcgLogList {
+    #[serde(rename = "ecgReadings")]
+    pub readings: Vec<EcgReading>,
+}
+
+/// An ECG reading taken with the ECG app. The waveform samples are not deserialized.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EcgReading {
+    /// The start of the reading, in the user's timezone.
+    #[serde(deserialize_with = "fitbit_datetime")]
+    pub start_time: NaiveDateTime,
+    pub average_heart_rate: Bpm,
+    /// The classification of the reading, e.g. "Normal Sinus Rhythm", "Atrial Fibrillation" or "Inconclusive".
+    pub result_classification: String,
+}
+
+/// Response of the irregular rhythm notifications (IRN) alert list endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/irregular-rhythm-notifications/get-irn-alerts-list/
+#[derive(Debug, Clone, Deserialize)]
+pub struct IrnAlertList {
+    pub alerts: Vec<IrnAlert>,
+}
+
+/// A notification of an irregular rhythm suggestive of atrial fibrillation (AFib).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IrnAlert {
+    /// The time the user was notified, in the user's timezone.
+    #[serde(deserialize_with = "fitbit_datetime")]
+    pub alert_time: NaiveDateTime,
+    /// The time the irregular rhythm was detected, in the user's timezone.
+    #[serde(deserialize_with = "fitbit_datetime")]
+    pub detected_time: NaiveDateTime,
+}
+
+/// Response of the Cardio Fitness Score (VO2 Max) endpoint.
+/// https://dev.fitbit.com/build/reference/web-api/cardio-fitness-score/get-vo2max-summary-by-interval/
+#[derive(Debug, Clone, Deserialize)]
+pub struct CardioScoreResponse {
+    #[serde(rename = "cardioScore")]
+    pub days: Vec<CardioScoreDay>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CardioScoreDay {
+    pub date_time: NaiveDate,
+    pub value: CardioScore,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CardioScore {
+    #[serde(rename = "vo2Max")]
+    pub vo2_max: Vo2Max,
+}
+
+/// A device (tracker or scale) paired with the account.
+/// https://dev.fitbit.com/build/reference/web-api/devices/get-devices/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: String,
+    pub device_version: String,
+    #[serde(default)]
+    pub battery: Option<String>,
+    #[serde(default)]
+    pub battery_level: Option<i64>,
+    /// The last time the device synced, in the user's timezone.
+    #[serde(deserialize_with = "fitbit_datetime")]
+    pub last_sync_time: NaiveDateTime,
+}
+
+/// Response of the friends leaderboard endpoint, in the JSON:API format: the users ranked by their steps of the last
+/// 7 days in `data`, and their profiles in `included`.
+/// https://dev.fitbit.com/build/reference/web-api/friends/get-friends-leaderboard/
+#[derive(Debug, Clone, Deserialize)]
+pub struct LeaderboardResponse {
+    pub data: Vec<LeaderboardUser>,
+    #[serde(default)]
+    pub included: Vec<LeaderboardPerson>,
+}
+
+/// A user of the leaderboard: a "ranked-user", or an "inactive-user" without steps nor rank.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LeaderboardUser {
+    /// The encoded ID of the user.
+    pub id: String,
+    #[serde(default)]
+    pub attributes: LeaderboardAttributes,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LeaderboardAttributes {
+    #[serde(rename = "step-summary")]
+    pub step_summary: Option<f64>,
+    #[serde(rename = "step-rank")]
+    pub step_rank: Option<u32>,
+}
+
+/// The profile of a user of the leaderboard.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LeaderboardPerson {
+    pub id: String,
+    pub attributes: LeaderboardPersonAttributes,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LeaderboardPersonAttributes {
+    /// The display name, e.g. "Jane D.".
+    pub name: String,
+    /// False for the user of the exporter, who is ranked among their friends.
+    #[serde(default)]
+    pub friend: bool,
+}
+
+/// A ranked user of the leaderboard, with their display name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LeaderboardRank {
+    /// The encoded ID of the user, which tells apart the friends of the same display name.
+    pub user_id: String,
+    /// The display name, or the encoded ID of the user if the leaderboard doesn't include their profile.
+    pub name: String,
+    /// The steps of the last 7 days.
+    pub steps: Steps,
+    /// The rank, 1 being the most steps.
+    pub rank: u32,
+}
+
+impl LeaderboardResponse {
+    /// Returns the ranked users with their display name, in the order of their rank. The inactive users are left out.
+    pub fn into_ranks(self) -> Vec<LeaderboardRank> {
+        let names: HashMap<String, String> = self.included.into_iter().map(|person| (person.id, person.attributes.name)).collect();
+        let mut ranks: Vec<LeaderboardRank> = self
+            .data
+            .into_iter()
+            .filter_map(|user| {
+                let (steps, rank) = (user.attributes.step_summary?, user.attributes.step_rank?);
+                let name = names.get(&user.id).cloned().unwrap_or_else(|| user.id.clone());
+                Some(LeaderboardRank { user_id: user.id, name, steps: Steps(steps.max(0.0).round() as u64), rank })
+            })
+            .collect();
+        ranks.sort_by_key(|rank| rank.rank);
+        ranks
+    }
+}
+
+/// Response of the lifetime stats endpoint: the totals and the best days over the lifetime of the account, both of
+/// everything ("total") and of the tracker only ("tracker"), of which the former is used. The distances are in
+/// kilometers.
+/// https://dev.fitbit.com/build/reference/web-api/activity/get-lifetime-stats/
+#[derive(Debug, Clone, Deserialize)]
+pub struct LifetimeStatsResponse {
+    pub best: LifetimeStatsOrigins<BestDays>,
+    pub lifetime: LifetimeStatsOrigins<LifetimeTotals>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LifetimeStatsOrigins<T> {
+    pub total: T,
+}
+
+/// The lifetime totals, including the activities logged manually.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LifetimeTotals {
+    pub distance: f64,
+    pub floors: f64,
+    pub steps: u64,
+}
+
+impl LifetimeTotals {
+    pub fn distance(&self) -> Meters {
+        Meters::from_kilometers(self.distance)
+    }
+}
+
+/// The best days, missing if there is no data yet (e.g. floors without an altimeter).
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BestDays {
+    pub distance: Option<BestDay>,
+    pub floors: Option<BestDay>,
+    pub steps: Option<BestDay>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BestDay {
+    pub date: NaiveDate,
+    pub value: f64,
+}
+
+/// The lifetime totals and best days of the user.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LifetimeStats {
+    pub lifetime: LifetimeTotals,
+    pub best: BestDays,
+}
+
+impl From<LifetimeStatsResponse> for LifetimeStats {
+    fn from(response: LifetimeStatsResponse) -> Self {
+        LifetimeStats { lifetime: response.lifetime.total, best: response.best.total }
+    }
+}
+
+/// Response of the badges endpoint: the badges earned over the lifetime of the account.
+/// https://dev.fitbit.com/build/reference/web-api/user/get-badges/
+#[derive(Debug, Clone, Deserialize)]
+pub struct BadgesResponse {
+    pub badges: Vec<Badge>,
+}
+
+/// A badge earned by the user, e.g. the 25,000 daily steps badge.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Badge {
+    /// The kind of the badge, e.g. "DAILY_STEPS" or "LIFETIME_DISTANCE".
+    pub badge_type: String,
+    /// The threshold of the badge, e.g. 25000 (steps) or 1997 (kilometers).
+    pub value: f64,
+    /// The number of times the badge was earned.
+    pub times_achieved: u64,
+    /// The last day the badge was earned.
+    pub date_time: NaiveDate,
+}
+
+/// The profile of the user, of which only the ID and the timezone offset are used.
+/// https://dev.fitbit.com/build/reference/web-api/user/get-profile/
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserProfileResponse {
+    pub user: UserProfile,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserProfile {
+    pub encoded_id: String,
+    /// The offset of the user's timezone from UTC, in milliseconds, e.g. `-28800000` for PST.
+    #[serde(rename = "offsetFromUTCMillis", default)]
+    pub offset_from_utc_millis: Option<i64>,
+}
+
+impl UserProfile {
+    /// Returns the offset of the user's timezone, or `None` if the profile doesn't have one or it's out of range.
+    pub fn utc_offset(&self) -> Option<FixedOffset> {
+        FixedOffset::east_opt(i32::try_from(self.offset_from_utc_millis? / 1000).ok()?)
+    }
+}
+
+/// A subscription to the notifications of a collection, as returned when creating it.
+/// https://dev.fitbit.com/build/reference/web-api/subscription/create-subscription/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    pub collection_type: String,
+    pub owner_id: String,
+    pub owner_type: String,
+    pub subscriber_id: String,
+    pub subscription_id: String,
+}
+
+/// Deserializes a value given either as a string (e.g. `"8123"`) or as a JSON number.
+fn string_or_number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    T::Err: fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber<T> {
+        String(String),
+        Number(T),
+    }
+
+    match StringOrNumber::<T>::deserialize(deserializer)? {
+        StringOrNumber::String(s) => s.parse::<T>().map_err(de::Error::custom),
+        StringOrNumber::Number(n) => Ok(n),
+    }
+}
+
+/// Deserializes a timezone-agnostic datetime in the format used by Fitbit, e.g. "2023-03-04T03:47:00.000".
+fn fitbit_datetime<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    NaiveDateTime::parse_from_str(&s, "%Y-%m-%dT%H:%M:%S%.f").map_err(de::Error::custom)
+}
+
+impl fmt::Display for Steps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} steps", self.0)
+    }
+}
+
+impl fmt::Display for Meters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} m", self.0)
+    }
+}
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ms", self.0)
+    }
+}
+
+impl fmt::Display for Minutes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} min", self.0)
+    }
+}
+
+impl fmt::Display for Bpm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bpm", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaderboard_ranks_have_the_display_names() {
+        let response: LeaderboardResponse = serde_json::from_str(include_str!("../../fixtures/1.1/user/-/leaderboard/friends.json")).unwrap();
+        assert_eq!(
+            response.into_ranks(),
+            vec![
+                LeaderboardRank { user_id: "DEF456".to_string(), name: "John S.".to_string(), steps: Steps(72500), rank: 1 },
+                LeaderboardRank { user_id: "ABC123".to_string(), name: "Jane D.".to_string(), steps: Steps(61234), rank: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn lifetime_stats_of_everything() {
+        let response: LifetimeStatsResponse = serde_json::from_str(include_str!("../../fixtures/1/user/-/activities.json")).unwrap();
+        let stats = LifetimeStats::from(response);
+        assert_eq!(stats.lifetime, LifetimeTotals { distance: 5612.37, floors: 4421.0, steps: 7894521 });
+        assert!((stats.lifetime.distance().0 - 5612370.0).abs() < 1e-6);
+        assert_eq!(stats.best.steps, Some(BestDay { date: NaiveDate::from_ymd_opt(2022, 6, 18).unwrap(), value: 41287.0 }));
+    }
+
+    #[test]
+    fn badges_have_their_type_and_threshold() {
+        let response: BadgesResponse = serde_json::from_str(include_str!("../../fixtures/1/user/-/badges.json")).unwrap();
+        assert_eq!(
+            response.badges[0],
+            Badge { badge_type: "DAILY_STEPS".to_string(), value: 25000.0, times_achieved: 3, date_time: NaiveDate::from_ymd_opt(2023, 3, 1).unwrap() }
+        );
+    }
+
+    #[test]
+    fn vo2_max_is_a_value_or_a_range() {
+        let response: CardioScoreResponse = serde_json::from_str(
+            r#"{"cardioScore":[{"dateTime":"2023-03-03","value":{"vo2Max":"44-48"}},{"dateTime":"2023-03-04","value":{"vo2Max":"46.5"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(response.days[0].value.vo2_max, Vo2Max::Range { lower: 44.0, upper: 48.0 });
+        assert_eq!(response.days[1].value.vo2_max, Vo2Max::Value(46.5));
+        assert!("high".parse::<Vo2Max>().is_err());
+    }
+
+    #[test]
+    fn breathing_rate_by_stage() {
+        let summary: BreathingRateSummary = serde_json::from_str(
+            r#"{"deepSleepSummary":{"breathingRate":14.8},"remSleepSummary":{"breathingRate":-1},"fullSleepSummary":{"breathingRate":15.2}}"#,
+        )
+        .unwrap();
+        assert_eq!(summary.by_stage(), vec![("deep", 14.8), ("full", 15.2)]);
+    }
+
+    #[test]
+    fn millis_to_seconds() {
+        assert_eq!(Millis(28_800_000).as_seconds(), 28_800.0);
+        assert_eq!(Millis(1_500).as_seconds(), 1.5);
+        assert_eq!(Millis::from_seconds(90).0, 90_000);
+    }
+
+    #[test]
+    fn minutes_to_millis_and_seconds() {
+        assert_eq!(Millis::from_minutes(480).0, 28_800_000);
+        assert_eq!(Millis::from_minutes(480).as_seconds(), 28_800.0);
+        assert_eq!(Millis(28_800_000).as_minutes(), 480.0);
+    }
+
+    #[test]
+    fn distance_conversions() {
+        assert_eq!(Meters::from_kilometers(1.5).0, 1500.0);
+        assert_eq!(Meters::from_miles(1.0).0, 1609.344);
+        assert!((Meters::from_miles(26.2).as_kilometers() - 42.1648).abs() < 1e-4);
+    }
+
+    #[test]
+    fn steps_series_values_are_parsed_from_strings() {
+        let json = serde_json::json!({
+            "activities-steps": [
+                { "dateTime": "2023-03-03", "value": "8123" },
+                { "dateTime": "2023-03-04", "value": "0" }
+            ]
+        });
+        let series: StepsSeries = serde_json::from_value(json).unwrap();
+        assert_eq!(series.into_pairs(), vec![
+            (NaiveDate::from_ymd_opt(2023, 3, 3).unwrap(), Steps(8123)),
+            (NaiveDate::from_ymd_opt(2023, 3, 4).unwrap(), Steps(0)),
+        ]);
+    }
+
+    #[test]
+    fn mismatched_field_is_reported_with_its_path() {
+        let json = serde_json::json!({
+            "activities-steps": [{ "dateTime": "2023-03-04", "value": "n/a" }]
+        });
+        let err = serde_path_to_error::deserialize::<_, StepsSeries>(json).unwrap_err();
+        assert_eq!(err.path().to_string(), "activities-steps[0].value");
+    }
+
+    #[test]
+    fn canonical_type_does_not_depend_on_the_locale() {
+        let activity_log = |activity_name: &str, activity_type_id: Option<u64>| -> ActivityLog {
+            serde_json::from_value(serde_json::json!({
+                "logId": 1,
+                "activityName": activity_name,
+                "activityTypeId": activity_type_id,
+                "startTime": "2023-03-04T07:00:00.000+01:00",
+                "activeDuration": 1_800_000,
+                "calories": 312
+            }))
+            .unwrap()
+        };
+        assert_eq!(activity_log("Run", Some(90009)).canonical_type(), "run");
+        assert_eq!(activity_log("Course", Some(90009)).canonical_type(), "run");
+        assert_eq!(activity_log("Laufen", Some(90009)).canonical_type(), "run");
+        assert_eq!(activity_log("Kickboxing", Some(17151)).canonical_type(), "type_17151");
+        assert_eq!(activity_log("Run", None).canonical_type(), "unknown");
+        assert_eq!(activity_log("Course", None).canonical_type(), "unknown");
+    }
+
+    #[test]
+    fn weight_log_source() {
+        let json = serde_json::json!({
+            "weight": [
+                { "logId": 1, "date": "2023-03-04", "time": "07:12:00", "weight": 71.3, "source": "Aria" },
+                { "logId": 2, "date": "2023-03-05", "time": "08:00:00", "weight": 71.1, "source": "Web" },
+                { "logId": 3, "date": "2023-03-06", "time": "07:30:00", "weight": 70.9 }
+            ]
+        });
+        let weight_logs: WeightLogList = serde_json::from_value(json).unwrap();
+        let sources: Vec<_> = weight_logs.weight.iter().map(WeightLog::source).collect();
+        assert_eq!(sources, vec!["tracker", "manual", "unknown"]);
+    }
+}
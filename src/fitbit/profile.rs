@@ -0,0 +1,315 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Names of the collectors run by `update_current_metrics`, each of them fetching one Fitbit resource.
+pub const COLLECTORS: [&str; 19] = ["steps", "water", "food", "sleep", "activity", "activity_logs", "goals", "devices", "leaderboard", "badges", "lifetime", "ecg", "irn", "cardio_score", "temperature", "breathing_rate", "heart_rate_intraday", "by_date", "recovery"];
+
+/// Collectors left out unless `--collectors` names them, since most tokens can't use them and they would fail every
+/// scrape: the leaderboard requires the `social` scope, which the tokens authorized for the earlier releases lack,
+/// and the intraday heart rate is only available to personal applications.
+pub const OPT_IN_COLLECTORS: [&str; 2] = ["leaderboard", "heart_rate_intraday"];
+
+/// Prefix of the `collect[]` query parameter values selecting a profile, e.g. `collect[]=profile:cheap`.
+const PROFILE_PREFIX: &str = "profile:";
+
+/// A named set of collectors, with the minimum delay between two updates of each of them.
+///
+/// A profile lets a Prometheus job scrape only some collectors (e.g. the daily totals every minute, and the sleep
+/// logs every hour in another job) by passing `collect[]=profile:<name>` in the `params` of the job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeProfile {
+    pub name: String,
+    pub collectors: Vec<String>,
+    /// When set, a collector updated less than `cadence` ago serves its last-known values instead of calling
+    /// the Fitbit API, e.g. when several Prometheus replicas scrape the same exporter.
+    pub cadence: Option<Duration>,
+}
+
+impl ScrapeProfile {
+    /// The built-in profiles, which can be overridden by profiles of the same name.
+    ///
+    /// * `cheap` - The daily totals, which change during the day and cost one request each, and the devices,
+    ///   which tell when they were last synced.
+    /// * `full` - Every collector, as when no `collect[]` parameter is given.
+    pub fn builtin() -> Vec<ScrapeProfile> {
+        vec![
+            ScrapeProfile {
+                name: "cheap".to_string(),
+                collectors: ["steps", "water", "food", "activity", "devices"].iter().map(|collector| collector.to_string()).collect(),
+                cadence: None,
+            },
+            ScrapeProfile {
+                name: "full".to_string(),
+                collectors: COLLECTORS.iter().map(|collector| collector.to_string()).collect(),
+                cadence: None,
+            },
+        ]
+    }
+}
+
+impl FromStr for ScrapeProfile {
+    type Err = String;
+
+    /// Parses `<name>=<collector>,<collector>...[@<cadence in seconds>]`, e.g. "sleep=sleep,goals@3600".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid scrape profile: {} (expected name=collector,...[@seconds])", s))?;
+        if name.is_empty() {
+            return Err(format!("Invalid scrape profile: {} (empty name)", s));
+        }
+
+        let (collectors, cadence) = match rest.split_once('@') {
+            Some((collectors, secs)) => {
+                let secs = secs
+                    .parse::<u64>()
+                    .map_err(|err| format!("Invalid cadence in scrape profile {}: {}", s, err))?;
+                (collectors, Some(Duration::from_secs(secs)))
+            }
+            None => (rest, None),
+        };
+
+        let collectors = collectors
+            .split(',')
+            .map(str::trim)
+            .map(|collector| {
+                if COLLECTORS.contains(&collector) {
+                    Ok(collector.to_string())
+                } else {
+                    Err(format!("Unknown collector in scrape profile {}: {} (expected one of {})", s, collector, COLLECTORS.join(", ")))
+                }
+            })
+            .collect::<Result<Vec<String>, String>>()?;
+
+        Ok(ScrapeProfile { name: name.to_string(), collectors, cadence })
+    }
+}
+
+/// The collectors to run for a scrape, with the cadence of the profile selecting each of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CollectorSelection {
+    // `None` selects every collector.
+    collectors: Option<HashMap<String, Option<Duration>>>,
+    // `None` lets the collectors run as long as they need.
+    deadline: Option<Instant>,
+}
+
+impl CollectorSelection {
+    /// Selects every collector, without cadence.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if the collector is selected.
+    pub fn includes(&self, collector: &str) -> bool {
+        match &self.collectors {
+            Some(collectors) => collectors.contains_key(collector),
+            None => true,
+        }
+    }
+
+    /// The minimum delay between two updates of the collector, if any.
+    pub fn cadence(&self, collector: &str) -> Option<Duration> {
+        self.collectors.as_ref().and_then(|collectors| collectors.get(collector).copied().flatten())
+    }
+
+    /// Sets the time by which all the collectors of the scrape must be done.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// The time by which all the collectors of the scrape must be done, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn insert(&mut self, collector: &str, cadence: Option<Duration>) {
+        let collectors = self.collectors.get_or_insert_with(HashMap::new);
+        let entry = collectors.entry(collector.to_string()).or_insert(cadence);
+        // A collector selected twice (e.g. by a profile and by its name) is updated as often as the shortest cadence.
+        *entry = match (*entry, cadence) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            _ => None,
+        };
+    }
+}
+
+/// The scrape profiles, by name.
+#[derive(Debug, Clone)]
+pub struct ScrapeProfiles {
+    profiles: HashMap<String, ScrapeProfile>,
+}
+
+impl ScrapeProfiles {
+    /// Creates the profiles from the built-in ones and the configured ones, which take precedence.
+    pub fn new(profiles: Vec<ScrapeProfile>) -> Self {
+        Self {
+            profiles: ScrapeProfile::builtin()
+                .into_iter()
+                .chain(profiles)
+                .map(|profile| (profile.name.clone(), profile))
+                .collect(),
+        }
+    }
+
+    /// Selects the collectors from the query string of a scrape.
+    ///
+    /// Each `collect[]` parameter is either a collector name (e.g. `collect[]=sleep`) or a profile
+    /// (e.g. `collect[]=profile:cheap`). Without `collect[]` parameters, every collector is selected.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message if a collector or a profile is unknown.
+    pub fn select(&self, query: Option<&str>) -> Result<CollectorSelection, String> {
+        let mut selection = CollectorSelection::all();
+        let values = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .filter(|(key, _)| key == "collect[]")
+            .map(|(_, value)| value.into_owned());
+
+        for value in values {
+            match value.strip_prefix(PROFILE_PREFIX) {
+                Some(name) => {
+                    let profile = self.profiles.get(name).ok_or_else(|| format!("Unknown scrape profile: {}", name))?;
+                    for collector in &profile.collectors {
+                        selection.insert(collector, profile.cadence);
+                    }
+                }
+                None if COLLECTORS.contains(&value.as_str()) => selection.insert(&value, None),
+                None => return Err(format!("Unknown collector: {}", value)),
+            }
+        }
+        Ok(selection)
+    }
+}
+
+impl Default for ScrapeProfiles {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+/// The collector settings changeable at runtime through `/admin/config`, e.g. to save the rate limit during a quota
+/// emergency without a restart.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CollectorConfig {
+    /// The enabled collectors (see `COLLECTORS`), in the order of `COLLECTORS`.
+    pub collectors: Vec<String>,
+    /// The minimum delay in seconds between two updates of each collector, whichever the scrape profile.
+    pub cadences: BTreeMap<String, u64>,
+}
+
+/// A change of the `CollectorConfig`, e.g. `{"disable": ["sleep"], "cadences": {"activity_logs": 3600}}`.
+///
+/// The collectors that are neither enabled nor disabled keep their state. A cadence of 0 removes the cadence of the
+/// collector.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CollectorConfigUpdate {
+    #[serde(default)]
+    pub enable: Vec<String>,
+    #[serde(default)]
+    pub disable: Vec<String>,
+    #[serde(default)]
+    pub cadences: BTreeMap<String, u64>,
+}
+
+impl CollectorConfigUpdate {
+    /// Applies the change to the enabled collectors (`None` enabling every collector) and to their cadences.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message, without changing anything, if a collector is unknown or both enabled and disabled.
+    pub fn apply(&self, collectors: &mut Option<Vec<String>>, cadences: &mut HashMap<String, Duration>) -> Result<(), String> {
+        for collector in self.enable.iter().chain(&self.disable).chain(self.cadences.keys()) {
+            if !COLLECTORS.contains(&collector.as_str()) {
+                return Err(format!("Unknown collector: {} (expected one of {})", collector, COLLECTORS.join(", ")));
+            }
+        }
+        if let Some(collector) = self.enable.iter().find(|collector| self.disable.contains(collector)) {
+            return Err(format!("The {} collector is both enabled and disabled", collector));
+        }
+
+        let enabled: Vec<String> = COLLECTORS
+            .iter()
+            .filter(|collector| {
+                let was_enabled = collectors.as_ref().is_none_or(|collectors| collectors.iter().any(|enabled| enabled == *collector));
+                (was_enabled || self.enable.iter().any(|enabled| enabled == *collector)) && !self.disable.iter().any(|disabled| disabled == *collector)
+            })
+            .map(|collector| collector.to_string())
+            .collect();
+        *collectors = if enabled.len() == COLLECTORS.len() { None } else { Some(enabled) };
+
+        for (collector, secs) in &self.cadences {
+            if *secs == 0 {
+                cadences.remove(collector);
+            } else {
+                cadences.insert(collector.clone(), Duration::from_secs(*secs));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrape_profile_from_str() {
+        assert_eq!(
+            "sleep=sleep, goals@3600".parse::<ScrapeProfile>(),
+            Ok(ScrapeProfile {
+                name: "sleep".to_string(),
+                collectors: vec!["sleep".to_string(), "goals".to_string()],
+                cadence: Some(Duration::from_secs(3600)),
+            })
+        );
+        assert!("sleep".parse::<ScrapeProfile>().is_err());
+        assert!("sleep=heart".parse::<ScrapeProfile>().is_err());
+        assert!("sleep=sleep@1h".parse::<ScrapeProfile>().is_err());
+    }
+
+    #[test]
+    fn select_collectors_and_profiles() {
+        let profiles = ScrapeProfiles::new(vec!["cheap=steps,water@60".parse().unwrap()]);
+
+        let all = profiles.select(None).unwrap();
+        assert!(COLLECTORS.iter().all(|collector| all.includes(collector)));
+
+        let selection = profiles.select(Some("collect%5B%5D=profile%3Acheap&collect[]=sleep&other=1")).unwrap();
+        assert!(selection.includes("steps") && selection.includes("water") && selection.includes("sleep"));
+        assert!(!selection.includes("food"));
+        assert_eq!(selection.cadence("water"), Some(Duration::from_secs(60)));
+        assert_eq!(selection.cadence("sleep"), None);
+
+        assert!(profiles.select(Some("collect[]=profile:unknown")).is_err());
+        assert!(profiles.select(Some("collect[]=heart")).is_err());
+    }
+
+    #[test]
+    fn collector_config_update() {
+        let mut collectors = None;
+        let mut cadences = HashMap::new();
+        let update: CollectorConfigUpdate = serde_json::from_str(r#"{"disable": ["sleep", "ecg"], "cadences": {"devices": 3600}}"#).unwrap();
+        update.apply(&mut collectors, &mut cadences).unwrap();
+        assert_eq!(collectors.as_ref().map(Vec::len), Some(COLLECTORS.len() - 2));
+        assert!(!collectors.as_ref().unwrap().contains(&"sleep".to_string()));
+        assert_eq!(cadences.get("devices"), Some(&Duration::from_secs(3600)));
+
+        let update: CollectorConfigUpdate = serde_json::from_str(r#"{"enable": ["sleep", "ecg"], "cadences": {"devices": 0}}"#).unwrap();
+        update.apply(&mut collectors, &mut cadences).unwrap();
+        assert_eq!(collectors, None);
+        assert!(cadences.is_empty());
+
+        let update = CollectorConfigUpdate { disable: vec!["heart".to_string()], ..Default::default() };
+        assert!(update.apply(&mut collectors, &mut cadences).is_err());
+        let update = CollectorConfigUpdate { enable: vec!["sleep".to_string()], disable: vec!["sleep".to_string()], ..Default::default() };
+        assert!(update.apply(&mut collectors, &mut cadences).is_err());
+        assert!(serde_json::from_str::<CollectorConfigUpdate>(r#"{"poll_interval": 60}"#).is_err());
+    }
+}
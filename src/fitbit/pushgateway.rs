@@ -0,0 +1,123 @@
+use tracing::{debug, error};
+use prometheus_client::encoding::text::encode;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+use crate::fitbit::FitbitMetrics;
+use crate::fitbit::exposition::{parse_openmetrics, ExpositionFormat, ExpositionRelabel, MetricFamily};
+
+/// Options of the push of the metrics to a Prometheus Pushgateway, built from the command line arguments
+/// (see `cmd::Args::pushgateway_options`).
+#[derive(Debug, Clone)]
+pub struct PushgatewayOptions {
+    /// The URL of the Pushgateway, e.g. "http://pushgateway:9091".
+    pub url: Url,
+    /// The `job` label of the pushed metrics.
+    pub job: String,
+    /// The `user` label grouping the pushed metrics, e.g. the encoded ID of the Fitbit user, so that the exporters
+    /// of several users can push to the same Pushgateway. `None` groups them by job only.
+    pub user: Option<String>,
+    /// The delay between two pushes.
+    pub interval: Duration,
+    /// The metric name prefix and the constant labels applied to the pushed metrics.
+    pub relabel: ExpositionRelabel,
+}
+
+impl PushgatewayOptions {
+    /// The URL of the group of the metrics, e.g. `http://pushgateway:9091/metrics/job/fitbit_exporter/user/ABCDEF`.
+    fn group_url(&self) -> Url {
+        let mut url = self.url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(["metrics", "job", &self.job]);
+            if let Some(user) = &self.user {
+                segments.extend(["user", user]);
+            }
+        }
+        url
+    }
+}
+
+/// Pushes the current values of the metrics to a Pushgateway every `interval`, e.g. for a Prometheus that can't
+/// scrape the machine running the exporter (a laptop behind a NAT).
+///
+/// Each push replaces the group of the previous one (`PUT`), so that the series that disappeared (e.g. an old
+/// workout) are dropped. A failed push is logged and retried at the next interval. The values are the ones of the
+/// last refresh of the metrics, so the background poller has to run along.
+/// FYI: https://github.com/prometheus/pushgateway#api
+///
+/// # Arguments
+///
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+/// * `options` - The `PushgatewayOptions`.
+pub async fn push_to_pushgateway_periodically(fitbit_metrics: Arc<FitbitMetrics>, options: PushgatewayOptions) {
+    let http_client = reqwest::Client::new();
+    let url = options.group_url();
+    loop {
+        tokio::time::sleep(options.interval).await;
+        match push_to_pushgateway(&http_client, &url, &fitbit_metrics, &options.relabel).await {
+            Ok(samples) => debug!("[push_to_pushgateway_periodically] Pushed {} samples to {}", samples, url),
+            Err(err) => error!("[push_to_pushgateway_periodically] Error pushing the metrics to {}: {}", url, err),
+        }
+    }
+}
+
+async fn push_to_pushgateway(http_client: &reqwest::Client, url: &Url, fitbit_metrics: &FitbitMetrics, relabel: &ExpositionRelabel) -> Result<usize, Box<dyn Error>> {
+    let mut txt = String::new();
+    encode(&mut txt, &fitbit_metrics.registry())?;
+    let families = current_samples(parse_openmetrics(&txt));
+    let samples = families.iter().map(|family| family.samples.len()).sum();
+
+    http_client
+        .put(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, ExpositionFormat::Text.content_type())
+        .body(relabel.apply(ExpositionFormat::Text.encode_families(&families)))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(samples)
+}
+
+/// Keeps the samples without a timestamp, since the Pushgateway rejects the pushes with timestamps (e.g. the
+/// historical steps), and drops the families left without samples.
+fn current_samples(families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+    families
+        .into_iter()
+        .filter_map(|mut family| {
+            family.samples.retain(|sample| sample.timestamp_ms.is_none());
+            (!family.samples.is_empty()).then_some(family)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::exposition::Sample;
+
+    #[test]
+    fn group_url_has_the_job_and_the_user() {
+        let mut options = PushgatewayOptions {
+            url: Url::parse("http://pushgateway:9091/").unwrap(),
+            job: "fitbit_exporter".to_string(),
+            user: Some("ABC DEF".to_string()),
+            interval: Duration::from_secs(60),
+            relabel: ExpositionRelabel::default(),
+        };
+        assert_eq!(options.group_url().as_str(), "http://pushgateway:9091/metrics/job/fitbit_exporter/user/ABC%20DEF");
+        options.user = None;
+        assert_eq!(options.group_url().as_str(), "http://pushgateway:9091/metrics/job/fitbit_exporter");
+    }
+
+    #[test]
+    fn timestamped_samples_are_not_pushed() {
+        let sample = |value: f64, timestamp_ms: Option<i64>| Sample::new("fitbit_steps", "", value, timestamp_ms);
+        let families = vec![
+            MetricFamily::gauge("fitbit_steps", vec![sample(8123.0, None), sample(7000.0, Some(1677801600000))]),
+            MetricFamily::gauge("fitbit_steps_history", vec![sample(7000.0, Some(1677801600000))]),
+        ];
+
+        assert_eq!(current_samples(families), vec![MetricFamily::gauge("fitbit_steps", vec![sample(8123.0, None)])]);
+    }
+}
@@ -0,0 +1,158 @@
+use chrono::{FixedOffset, NaiveDate};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::fitbit::exposition::Sample;
+use crate::fitbit::storage::{daily_values_by_series, day_range_ms, SampleStore};
+
+/// The aggregation of the daily values of a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryAggregation {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl QueryAggregation {
+    /// Aggregates the values, which must not be empty.
+    fn apply(&self, values: &[f64]) -> f64 {
+        match self {
+            QueryAggregation::Sum => values.iter().sum(),
+            QueryAggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            QueryAggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            QueryAggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            QueryAggregation::Count => values.len() as f64,
+        }
+    }
+}
+
+impl FromStr for QueryAggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(QueryAggregation::Sum),
+            "avg" => Ok(QueryAggregation::Avg),
+            "min" => Ok(QueryAggregation::Min),
+            "max" => Ok(QueryAggregation::Max),
+            "count" => Ok(QueryAggregation::Count),
+            _ => Err(format!("Invalid aggregation: {} (expected sum, avg, min, max or count)", s)),
+        }
+    }
+}
+
+/// A query of the daily values of a metric archived in the sample store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveQuery {
+    /// The metric family, e.g. "fitbit_steps". The "fitbit_" prefix can be omitted, e.g. "steps".
+    pub metric: String,
+    /// The first day of the query (inclusive), or `None` from the first archived sample.
+    pub start: Option<NaiveDate>,
+    /// The last day of the query (inclusive), or `None` up to the last archived sample.
+    pub end: Option<NaiveDate>,
+    pub aggregation: QueryAggregation,
+}
+
+impl ArchiveQuery {
+    fn family(&self) -> String {
+        match self.metric.starts_with("fitbit_") {
+            true => self.metric.clone(),
+            false => format!("fitbit_{}", self.metric),
+        }
+    }
+
+    /// Runs the query against the sample store, without calling the Fitbit API.
+    ///
+    /// The days are the ones of the user's timezone, and the value of a day is the one of its last sample, since the
+    /// daily totals grow during the day (see `storage::daily_values_by_series`).
+    ///
+    /// # Returns
+    ///
+    /// The aggregated value of each series of the family with samples in the range, keyed by its labels (an empty
+    /// string if unlabeled).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the samples cannot be read from the sample store.
+    pub fn run(&self, sample_store: &dyn SampleStore, utc_offset: FixedOffset) -> Result<BTreeMap<String, f64>, Box<dyn Error>> {
+        let (start_ms, end_ms) = day_range_ms(self.start, self.end, utc_offset);
+        let samples = sample_store.series(&self.family(), start_ms, end_ms)?;
+        Ok(self.aggregate(&samples, utc_offset))
+    }
+
+    fn aggregate(&self, samples: &[Sample], utc_offset: FixedOffset) -> BTreeMap<String, f64> {
+        daily_values_by_series(samples, utc_offset)
+            .into_iter()
+            .map(|(labels, days)| {
+                let values: Vec<f64> = days.into_values().collect();
+                (labels, self.aggregation.apply(&values))
+            })
+            .collect()
+    }
+
+    /// Renders the results of the query, one `<family>{<labels>} <value>` line per series.
+    pub fn render(&self, results: &BTreeMap<String, f64>) -> String {
+        let family = self.family();
+        let mut output = String::new();
+        for (labels, value) in results {
+            let _ = match labels.as_str() {
+                "" => writeln!(output, "{} {}", family, value),
+                labels => writeln!(output, "{}{{{}}} {}", family, labels, value),
+            };
+        }
+        output
+    }
+}
+
+/// Prints the results of the query against the sample store.
+///
+/// # Errors
+///
+/// Returns an error if the samples cannot be read from the sample store.
+pub fn print_archive_query(sample_store: &dyn SampleStore, query: &ArchiveQuery, utc_offset: FixedOffset) -> Result<(), Box<dyn Error>> {
+    let results = query.run(sample_store, utc_offset)?;
+    print!("{}", query.render(&results));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::storage::day_start_ms;
+
+    #[test]
+    fn aggregates_the_daily_values_of_each_series() {
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let sample = |labels: &str, day: u32, hour: i64, value: f64| {
+            Sample::new("fitbit_steps", labels, value, Some(day_start_ms(NaiveDate::from_ymd_opt(2024, 5, day).unwrap(), utc) + hour * 3600 * 1000))
+        };
+        // The last sample of the day is its total
+        let samples = vec![
+            sample("", 20, 12, 4000.0),
+            sample("", 20, 23, 12000.0),
+            sample("", 21, 23, 6000.0),
+            sample("user=\"b\"", 21, 23, 3000.0),
+        ];
+        let query = |aggregation| ArchiveQuery { metric: "steps".to_string(), start: None, end: None, aggregation };
+
+        let sum = query(QueryAggregation::Sum);
+        let results = sum.aggregate(&samples, utc);
+        assert_eq!(results.get(""), Some(&18000.0));
+        assert_eq!(sum.render(&results), "fitbit_steps 18000\nfitbit_steps{user=\"b\"} 3000\n");
+        assert_eq!(query(QueryAggregation::Avg).aggregate(&samples, utc).get(""), Some(&9000.0));
+        assert_eq!(query(QueryAggregation::Min).aggregate(&samples, utc).get(""), Some(&6000.0));
+        assert_eq!(query(QueryAggregation::Max).aggregate(&samples, utc).get(""), Some(&12000.0));
+        assert_eq!(query(QueryAggregation::Count).aggregate(&samples, utc).get(""), Some(&2.0));
+        assert!("median".parse::<QueryAggregation>().is_err());
+
+        // In Los Angeles, the sample of the 22nd at 03:00 UTC is the last one of the 21st
+        let los_angeles = FixedOffset::west_opt(7 * 3600).unwrap();
+        let late = [sample("", 21, 12, 6000.0), sample("", 22, 3, 9000.0)];
+        assert_eq!(query(QueryAggregation::Sum).aggregate(&late, utc).get(""), Some(&15000.0));
+        assert_eq!(query(QueryAggregation::Sum).aggregate(&late, los_angeles).get(""), Some(&9000.0));
+    }
+}
@@ -0,0 +1,131 @@
+use hyper::{header, Body, Response, StatusCode};
+use oauth2::PkceCodeVerifier;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+use url::Url;
+
+use crate::fitbit::{FitbitClient, FitbitError};
+
+/// The path of the endpoint redirecting to the Fitbit authorization page.
+pub const AUTHORIZE_PATH: &str = "/oauth2/authorize";
+
+/// Re-authorization of the exporter with the Authorization Code Flow, when the refresh token gets `invalid_grant`
+/// (e.g. it was revoked, or the new one was lost in a crash): `/oauth2/authorize` redirects to the Fitbit
+/// authorization page, which redirects back to the redirect URL (served by `/oauth2/callback` of the exporter) with
+/// the code exchanged for new tokens.
+pub struct Reauthorization {
+    client: Arc<RwLock<FitbitClient>>,
+    redirect_url: String,
+    scopes: Vec<String>,
+    // The state and the PKCE verifier of the last authorization started. A new one replaces it.
+    pending: Mutex<Option<(String, PkceCodeVerifier)>>,
+}
+
+impl fmt::Debug for Reauthorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reauthorization").field("redirect_url", &self.redirect_url).field("scopes", &self.scopes).finish_non_exhaustive()
+    }
+}
+
+impl Reauthorization {
+    /// # Arguments
+    ///
+    /// * `client` - The client whose tokens are replaced.
+    /// * `redirect_url` - The redirect URL registered for the application, e.g. "http://localhost:8080/oauth2/callback".
+    /// * `scopes` - The scopes to request.
+    pub fn new(client: Arc<RwLock<FitbitClient>>, redirect_url: &str, scopes: &[String]) -> Self {
+        Self { client, redirect_url: redirect_url.to_string(), scopes: scopes.to_vec(), pending: Mutex::new(None) }
+    }
+
+    /// The path of the redirect URL, served by the exporter.
+    pub fn callback_path(&self) -> String {
+        Url::parse(&self.redirect_url).map_or_else(|_| "/oauth2/callback".to_string(), |url| url.path().to_string())
+    }
+
+    /// The URL to open in a browser to re-authorize the exporter, at the origin of the redirect URL.
+    pub fn start_url(&self) -> String {
+        match Url::parse(&self.redirect_url).and_then(|url| url.join(AUTHORIZE_PATH)) {
+            Ok(url) => url.to_string(),
+            Err(_) => AUTHORIZE_PATH.to_string(),
+        }
+    }
+
+    /// Redirects to the Fitbit authorization page.
+    pub async fn authorize(&self) -> Response<Body> {
+        match self.client.read().await.authorization_url(&self.redirect_url, &self.scopes) {
+            Ok((url, state, pkce_verifier)) => {
+                *self.pending.lock().unwrap() = Some((state.secret().to_string(), pkce_verifier));
+                Response::builder().status(StatusCode::FOUND).header(header::LOCATION, url.to_string()).body(Body::empty()).unwrap()
+            }
+            Err(err) => text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid redirect URL: {}", err)),
+        }
+    }
+
+    /// Exchanges the code of the redirect from the Fitbit authorization page, e.g. `?code=...&state=...`.
+    pub async fn callback(&self, query: Option<&str>) -> Response<Body> {
+        let params: Vec<(String, String)> = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()).into_owned().collect();
+        let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+        if let Some(err) = param("error") {
+            return text_response(StatusCode::BAD_REQUEST, format!("The authorization was denied: {}", err));
+        }
+        let (Some(code), Some(state)) = (param("code"), param("state")) else {
+            return text_response(StatusCode::BAD_REQUEST, "Missing code or state".to_string());
+        };
+        let pkce_verifier = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.take() {
+                Some((expected_state, pkce_verifier)) if expected_state == state => pkce_verifier,
+                other => {
+                    *pending = other;
+                    return text_response(StatusCode::BAD_REQUEST, format!("Unknown or expired authorization, start over at {}", AUTHORIZE_PATH));
+                }
+            }
+        };
+        match self.client.write().await.exchange_authorization_code(code, pkce_verifier, &self.redirect_url).await {
+            Ok(()) => text_response(StatusCode::OK, "The exporter was re-authorized".to_string()),
+            // The tokens are adopted even if they couldn't be persisted
+            Err(err @ FitbitError::StorageError(_)) => text_response(StatusCode::OK, format!("The exporter was re-authorized, but the tokens will be lost on restart: {}", err)),
+            Err(err) => {
+                error!("The re-authorization failed: {}", err);
+                text_response(StatusCode::BAD_GATEWAY, format!("The re-authorization failed: {}", err))
+            }
+        }
+    }
+
+    /// Logs the URL to re-authorize the exporter at, e.g. after an `invalid_grant`.
+    pub fn prompt(&self) {
+        info!("Re-authorize the exporter at {}", self.start_url());
+    }
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder().status(status).header(header::CONTENT_TYPE, "text/plain; charset=utf-8").body(Body::from(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn callback_requires_the_state_of_the_pending_authorization() {
+        let client = Arc::new(RwLock::new(FitbitClient::new("client", "secret", &None, "access")));
+        let reauthorization = Reauthorization::new(client, "http://localhost:8080/oauth2/callback", &["activity".to_string()]);
+        assert_eq!(reauthorization.callback_path(), "/oauth2/callback");
+        assert_eq!(reauthorization.start_url(), "http://localhost:8080/oauth2/authorize");
+
+        let response = reauthorization.authorize().await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+        let param = |name: &str| location.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+        assert_eq!(param("redirect_uri").as_deref(), Some("http://localhost:8080/oauth2/callback"));
+        assert_eq!(param("code_challenge_method").as_deref(), Some("S256"));
+        assert_eq!(param("scope").as_deref(), Some("activity"));
+
+        // A forged state doesn't consume the pending authorization
+        assert_eq!(reauthorization.callback(Some("code=c&state=forged")).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(reauthorization.callback(Some("error=access_denied")).await.status(), StatusCode::BAD_REQUEST);
+        assert!(reauthorization.pending.lock().unwrap().as_ref().is_some_and(|(state, _)| Some(state) == param("state").as_ref()));
+    }
+}
@@ -0,0 +1,133 @@
+use std::str::FromStr;
+
+/// Number of days of the baselines of the resting heart rate and the HRV, including today.
+pub const BASELINE_DAYS: i64 = 30;
+
+/// Change of a component for a deviation of 1% from its baseline, in points of the score.
+const POINTS_PER_PERCENT: f64 = 2.5;
+
+/// Weights of the components of the recovery score, e.g. "rhr=0.4,hrv=0.4,sleep=0.2".
+///
+/// The weights are relative: they don't need to sum to 1. A component with a weight of 0 (or omitted) is ignored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveryWeights {
+    pub resting_heart_rate: f64,
+    pub hrv: f64,
+    pub sleep: f64,
+}
+
+impl FromStr for RecoveryWeights {
+    type Err = String;
+
+    /// Parses `<component>=<weight>,...`, where the components are `rhr`, `hrv` and `sleep`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = RecoveryWeights { resting_heart_rate: 0.0, hrv: 0.0, sleep: 0.0 };
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (component, weight) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid recovery weight: {} (expected component=weight)", pair))?;
+            let weight = weight
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|weight| weight.is_finite() && *weight >= 0.0)
+                .ok_or_else(|| format!("Invalid recovery weight: {} (expected a non-negative number)", pair))?;
+            match component.trim() {
+                "rhr" => weights.resting_heart_rate = weight,
+                "hrv" => weights.hrv = weight,
+                "sleep" => weights.sleep = weight,
+                other => return Err(format!("Unknown recovery component: {} (expected rhr, hrv or sleep)", other)),
+            }
+        }
+        if weights.resting_heart_rate + weights.hrv + weights.sleep == 0.0 {
+            return Err(format!("Invalid recovery weights: {} (at least one weight must be positive)", s));
+        }
+        Ok(weights)
+    }
+}
+
+/// The inputs of the recovery score. A missing input (e.g. no HRV recorded last night) leaves its component out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryInputs {
+    /// Today's resting heart rate and its baseline, in bpm.
+    pub resting_heart_rate: Option<(f64, f64)>,
+    /// Last night's HRV (RMSSD) and its baseline, in milliseconds.
+    pub hrv: Option<(f64, f64)>,
+    /// The efficiency of the main sleep, in percent. The Web API has no sleep score, so it stands in for it.
+    pub sleep_efficiency: Option<f64>,
+}
+
+/// Splits a daily series, oldest first, into its latest value and the average of the previous ones.
+///
+/// # Returns
+///
+/// `None` if there are less than 2 values, i.e. no baseline to compare today's value with.
+pub fn latest_and_baseline(values: &[f64]) -> Option<(f64, f64)> {
+    let (latest, previous) = values.split_last()?;
+    if previous.is_empty() {
+        return None;
+    }
+    Some((*latest, previous.iter().sum::<f64>() / previous.len() as f64))
+}
+
+/// Computes the recovery score, from 0 (not recovered) to 100, as the weighted average of its components.
+///
+/// Each component is 50 at the baseline, and moves by `POINTS_PER_PERCENT` per percent of deviation: up when the
+/// resting heart rate is lower or the HRV higher than usual. The sleep component is the sleep efficiency.
+///
+/// # Returns
+///
+/// `None` if none of the weighted components has its inputs.
+pub fn recovery_score(inputs: &RecoveryInputs, weights: &RecoveryWeights) -> Option<f64> {
+    let deviation = |(value, baseline): (f64, f64)| (value - baseline) / baseline * 100.0;
+    let components = [
+        (inputs.resting_heart_rate.filter(|(_, baseline)| *baseline > 0.0).map(|rhr| 50.0 - POINTS_PER_PERCENT * deviation(rhr)), weights.resting_heart_rate),
+        (inputs.hrv.filter(|(_, baseline)| *baseline > 0.0).map(|hrv| 50.0 + POINTS_PER_PERCENT * deviation(hrv)), weights.hrv),
+        (inputs.sleep_efficiency, weights.sleep),
+    ];
+
+    let (weighted_sum, total_weight) = components
+        .iter()
+        .filter_map(|(component, weight)| component.filter(|_| *weight > 0.0).map(|component| (component.clamp(0.0, 100.0), *weight)))
+        .fold((0.0, 0.0), |(sum, total), (component, weight)| (sum + component * weight, total + weight));
+    if total_weight == 0.0 {
+        None
+    } else {
+        Some(weighted_sum / total_weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_weights() {
+        assert_eq!(
+            "rhr=0.4, hrv=0.4,sleep=0.2".parse::<RecoveryWeights>(),
+            Ok(RecoveryWeights { resting_heart_rate: 0.4, hrv: 0.4, sleep: 0.2 })
+        );
+        assert_eq!("hrv=1".parse::<RecoveryWeights>().map(|weights| weights.sleep), Ok(0.0));
+        assert!("rhr=0".parse::<RecoveryWeights>().is_err());
+        assert!("rhr=-1,hrv=1".parse::<RecoveryWeights>().is_err());
+        assert!("strain=1".parse::<RecoveryWeights>().is_err());
+    }
+
+    #[test]
+    fn score_is_the_weighted_average_of_the_components() {
+        let weights = RecoveryWeights { resting_heart_rate: 1.0, hrv: 2.0, sleep: 1.0 };
+        let inputs = RecoveryInputs {
+            // 4% lower than usual: 60 points
+            resting_heart_rate: latest_and_baseline(&[50.0, 50.0, 48.0]),
+            // 40% higher than usual: clamped to 100 points
+            hrv: Some((42.0, 30.0)),
+            sleep_efficiency: Some(90.0),
+        };
+        assert_eq!(recovery_score(&inputs, &weights), Some((60.0 + 2.0 * 100.0 + 90.0) / 4.0));
+
+        let inputs = RecoveryInputs { hrv: None, ..inputs };
+        assert_eq!(recovery_score(&inputs, &weights), Some((60.0 + 90.0) / 2.0));
+        assert_eq!(recovery_score(&RecoveryInputs::default(), &weights), None);
+        assert_eq!(latest_and_baseline(&[48.0]), None);
+    }
+}
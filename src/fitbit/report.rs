@@ -0,0 +1,298 @@
+use chrono::{Datelike, Duration as ChronoDuration, FixedOffset, NaiveDate, Weekday};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::fitbit::exposition::Sample;
+use crate::fitbit::storage::{daily_values_by_series, day_range_ms, SampleStore};
+
+/// An ISO 8601 week, given as e.g. "2024-W21".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportWeek {
+    monday: NaiveDate,
+}
+
+impl ReportWeek {
+    /// The days of the week, from Monday to Sunday.
+    fn days(&self) -> impl Iterator<Item = NaiveDate> {
+        let monday = self.monday;
+        (0..7).map(move |day| monday + ChronoDuration::days(day))
+    }
+
+    fn sunday(&self) -> NaiveDate {
+        self.monday + ChronoDuration::days(6)
+    }
+}
+
+impl FromStr for ReportWeek {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid week: {} (expected an ISO week, e.g. 2024-W21)", s);
+        let (year, week) = s.split_once("-W").ok_or_else(invalid)?;
+        let year = year.parse().map_err(|_| invalid())?;
+        let week = week.parse().map_err(|_| invalid())?;
+        let monday = NaiveDate::from_isoywd_opt(year, week, Weekday::Mon).ok_or_else(invalid)?;
+        Ok(ReportWeek { monday })
+    }
+}
+
+impl std::fmt::Display for ReportWeek {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let week = self.monday.iso_week();
+        write!(f, "{}-W{:02}", week.year(), week.week())
+    }
+}
+
+/// The format of the weekly report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" => Ok(ReportFormat::Markdown),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(format!("Invalid report format: {} (expected markdown or html)", s)),
+        }
+    }
+}
+
+/// A daily metric of the report, and the metric of its goal if any.
+struct ReportedMetric {
+    label: &'static str,
+    family: &'static str,
+    goal_family: Option<&'static str>,
+    /// The factor converting the value of the metric to `unit`, e.g. 1/60 for seconds to minutes.
+    scale: f64,
+    unit: &'static str,
+    precision: usize,
+}
+
+const REPORTED_METRICS: [ReportedMetric; 8] = [
+    ReportedMetric { label: "Steps", family: "fitbit_steps", goal_family: Some("fitbit_goal_steps"), scale: 1.0, unit: "", precision: 0 },
+    ReportedMetric { label: "Distance", family: "fitbit_distance_meters", goal_family: Some("fitbit_goal_distance_meters"), scale: 0.001, unit: "km", precision: 1 },
+    ReportedMetric { label: "Floors", family: "fitbit_floors", goal_family: Some("fitbit_goal_floors"), scale: 1.0, unit: "", precision: 0 },
+    ReportedMetric { label: "Active time", family: "fitbit_active_duration_seconds", goal_family: Some("fitbit_goal_active_duration_seconds"), scale: 1.0 / 60.0, unit: "min", precision: 0 },
+    ReportedMetric { label: "Calories burned", family: "fitbit_calories_out", goal_family: Some("fitbit_goal_calories_out"), scale: 1.0, unit: "kcal", precision: 0 },
+    ReportedMetric { label: "Calories eaten", family: "fitbit_calories_in", goal_family: None, scale: 1.0, unit: "kcal", precision: 0 },
+    ReportedMetric { label: "Water", family: "fitbit_water_ml", goal_family: None, scale: 0.001, unit: "L", precision: 1 },
+    ReportedMetric { label: "Sleep", family: "fitbit_sleep_total_asleep_seconds", goal_family: None, scale: 1.0 / 3600.0, unit: "h", precision: 1 },
+];
+
+/// The summary of a daily metric over the week.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSummary {
+    pub label: &'static str,
+    pub unit: &'static str,
+    pub precision: usize,
+    /// The number of days with a value.
+    pub days: usize,
+    /// The sum of the daily values, in `unit`.
+    pub total: f64,
+    /// The average of the daily values over the days with a value, in `unit`.
+    pub average: f64,
+    /// The number of days the goal was met, and the number of days with both a value and a goal, if the metric has a
+    /// goal.
+    pub goal_met: Option<(usize, usize)>,
+}
+
+impl MetricSummary {
+    fn new(metric: &ReportedMetric, week: ReportWeek, samples: &[Sample], goal_samples: &[Sample], utc_offset: FixedOffset) -> Self {
+        let values = daily_values(samples, utc_offset);
+        let goals = daily_values(goal_samples, utc_offset);
+        let week_values: Vec<(NaiveDate, f64)> = week.days().filter_map(|day| values.get(&day).map(|value| (day, *value))).collect();
+
+        let total = week_values.iter().map(|(_, value)| value).sum::<f64>() * metric.scale;
+        let goal_met = metric.goal_family.map(|_| {
+            let with_goal: Vec<bool> = week_values
+                .iter()
+                .filter_map(|(day, value)| goals.get(day).map(|goal| value >= goal))
+                .collect();
+            (with_goal.iter().filter(|met| **met).count(), with_goal.len())
+        });
+        MetricSummary {
+            label: metric.label,
+            unit: metric.unit,
+            precision: metric.precision,
+            days: week_values.len(),
+            total,
+            average: if week_values.is_empty() { 0.0 } else { total / week_values.len() as f64 },
+            goal_met,
+        }
+    }
+
+    fn format(&self, value: f64) -> String {
+        match self.unit {
+            "" => format!("{:.*}", self.precision, value),
+            unit => format!("{:.*} {}", self.precision, value, unit),
+        }
+    }
+
+    fn format_goal_met(&self) -> String {
+        match self.goal_met {
+            Some((met, days)) if days > 0 => format!("{}/{} days", met, days),
+            _ => "-".to_string(),
+        }
+    }
+}
+
+/// Returns the value of each day of the unlabeled samples, see `storage::daily_values_by_series`.
+fn daily_values(samples: &[Sample], utc_offset: FixedOffset) -> BTreeMap<NaiveDate, f64> {
+    daily_values_by_series(samples, utc_offset).remove("").unwrap_or_default()
+}
+
+/// The weekly summary of the daily metrics: totals, averages and goal adherence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeeklyReport {
+    pub week: ReportWeek,
+    pub metrics: Vec<MetricSummary>,
+}
+
+impl WeeklyReport {
+    /// Builds the report of the week from the samples archived in the sample store, with the days of the week in the
+    /// user's timezone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the samples cannot be read from the sample store.
+    pub fn from_sample_store(sample_store: &dyn SampleStore, week: ReportWeek, utc_offset: FixedOffset) -> Result<Self, Box<dyn Error>> {
+        let (start_ms, end_ms) = day_range_ms(Some(week.monday), Some(week.sunday()), utc_offset);
+
+        let mut metrics = Vec::with_capacity(REPORTED_METRICS.len());
+        for metric in &REPORTED_METRICS {
+            let samples = sample_store.series(metric.family, start_ms, end_ms)?;
+            let goal_samples = match metric.goal_family {
+                Some(goal_family) => sample_store.series(goal_family, start_ms, end_ms)?,
+                None => Vec::new(),
+            };
+            metrics.push(MetricSummary::new(metric, week, &samples, &goal_samples, utc_offset));
+        }
+        Ok(WeeklyReport { week, metrics })
+    }
+
+    fn title(&self) -> String {
+        format!("Fitbit weekly summary {} ({} to {})", self.week, self.week.monday, self.week.sunday())
+    }
+
+    /// Renders the report in the format.
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Markdown => self.render_markdown(),
+            ReportFormat::Html => self.render_html(),
+        }
+    }
+
+    fn render_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n\n", self.title());
+        markdown.push_str("| Metric | Total | Daily average | Days | Goal met |\n");
+        markdown.push_str("|---|---:|---:|---:|---:|\n");
+        for metric in &self.metrics {
+            let _ = writeln!(
+                markdown,
+                "| {} | {} | {} | {} | {} |",
+                metric.label,
+                metric.format(metric.total),
+                metric.format(metric.average),
+                metric.days,
+                metric.format_goal_met()
+            );
+        }
+        markdown
+    }
+
+    fn render_html(&self) -> String {
+        let title = self.title();
+        let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n", title, title);
+        html.push_str("<table>\n<tr><th>Metric</th><th>Total</th><th>Daily average</th><th>Days</th><th>Goal met</th></tr>\n");
+        for metric in &self.metrics {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                metric.label,
+                metric.format(metric.total),
+                metric.format(metric.average),
+                metric.days,
+                metric.format_goal_met()
+            );
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+}
+
+/// Writes the report of the week, built from the sample store, to `output_file`, or prints it if `None`.
+///
+/// # Errors
+///
+/// Returns an error if the samples cannot be read or the file cannot be written.
+pub fn write_weekly_report(
+    sample_store: &dyn SampleStore,
+    week: ReportWeek,
+    format: ReportFormat,
+    output_file: Option<&Path>,
+    utc_offset: FixedOffset,
+) -> Result<(), Box<dyn Error>> {
+    let report = WeeklyReport::from_sample_store(sample_store, week, utc_offset)?.render(format);
+    match output_file {
+        Some(output_file) => std::fs::write(output_file, report)?,
+        None => print!("{}", report),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::storage::day_start_ms;
+
+    #[test]
+    fn parse_report_week() {
+        let week: ReportWeek = "2024-W21".parse().unwrap();
+        assert_eq!(week.monday, NaiveDate::from_ymd_opt(2024, 5, 20).unwrap());
+        assert_eq!(week.sunday(), NaiveDate::from_ymd_opt(2024, 5, 26).unwrap());
+        assert_eq!(week.to_string(), "2024-W21");
+        assert!("2024-21".parse::<ReportWeek>().is_err());
+        assert!("2024-W54".parse::<ReportWeek>().is_err());
+    }
+
+    #[test]
+    fn weekly_summary_of_the_daily_values() {
+        let week: ReportWeek = "2024-W21".parse().unwrap();
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let sample = |name: &str, day: u32, hour: i64, value: f64| {
+            Sample::new(name, "", value, Some(day_start_ms(NaiveDate::from_ymd_opt(2024, 5, day).unwrap(), utc) + hour * 3600 * 1000))
+        };
+        // The last sample of the day is its total, and the days outside of the week are ignored
+        let steps = vec![
+            sample("fitbit_steps", 19, 23, 20000.0),
+            sample("fitbit_steps", 20, 12, 4000.0),
+            sample("fitbit_steps", 20, 23, 12000.0),
+            sample("fitbit_steps", 21, 23, 6000.0),
+        ];
+        let goals = vec![sample("fitbit_goal_steps", 20, 12, 10000.0), sample("fitbit_goal_steps", 21, 12, 10000.0)];
+
+        let summary = MetricSummary::new(&REPORTED_METRICS[0], week, &steps, &goals, utc);
+        assert_eq!(summary.days, 2);
+        assert_eq!(summary.total, 18000.0);
+        assert_eq!(summary.average, 9000.0);
+        assert_eq!(summary.goal_met, Some((1, 2)));
+
+        let report = WeeklyReport { week, metrics: vec![summary] };
+        assert_eq!(
+            report.render(ReportFormat::Markdown),
+            "# Fitbit weekly summary 2024-W21 (2024-05-20 to 2024-05-26)\n\n\
+             | Metric | Total | Daily average | Days | Goal met |\n\
+             |---|---:|---:|---:|---:|\n\
+             | Steps | 18000 | 9000 | 2 | 1/2 days |\n"
+        );
+        assert!(report.render(ReportFormat::Html).contains("<tr><td>Steps</td><td>18000</td><td>9000</td><td>2</td><td>1/2 days</td></tr>"));
+    }
+}
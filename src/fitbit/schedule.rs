@@ -0,0 +1,211 @@
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, TimeZone, Timelike};
+use rand::Rng;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Upper bound of the search for the next matching minute, so that an expression that never matches
+// (e.g. "0 0 31 2 *") doesn't loop forever.
+const MAX_LOOKAHEAD_MINUTES: i64 = 366 * 24 * 60;
+
+/// When the background poller updates the metrics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PollSchedule {
+    /// At a fixed interval.
+    Interval(Duration),
+    /// At the minutes matching any of the cron expressions, e.g. every 5 minutes during waking hours
+    /// and every hour overnight.
+    Cron(Vec<CronExpression>),
+}
+
+impl PollSchedule {
+    /// Returns the delay from `now` until the next update, plus a random jitter of up to `max_jitter`.
+    ///
+    /// The jitter spreads the updates of several exporters (or several users) instead of hitting the API
+    /// right on the minute, and catches data synced a bit after the scheduled time.
+    pub fn next_delay(&self, now: DateTime<Local>, max_jitter: Duration) -> Duration {
+        let delay = match self {
+            PollSchedule::Interval(interval) => *interval,
+            PollSchedule::Cron(expressions) => expressions
+                .iter()
+                .filter_map(|expression| expression.next_after(now))
+                .min()
+                .and_then(|next| (next - now).to_std().ok())
+                .unwrap_or(Duration::from_secs(3600)),
+        };
+        let max_jitter_millis = max_jitter.as_millis() as u64;
+        if max_jitter_millis == 0 {
+            return delay;
+        }
+        delay + Duration::from_millis(rand::thread_rng().gen_range(0..=max_jitter_millis))
+    }
+}
+
+impl FromStr for PollSchedule {
+    type Err = String;
+
+    /// Parses semicolon separated cron expressions, e.g. "*/5 7-22 * * *; 0 23,0-6 * * *".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expressions = s
+            .split(';')
+            .map(str::trim)
+            .filter(|expression| !expression.is_empty())
+            .map(CronExpression::from_str)
+            .collect::<Result<Vec<CronExpression>, String>>()?;
+        if expressions.is_empty() {
+            return Err("Empty poll schedule".to_string());
+        }
+        Ok(PollSchedule::Cron(expressions))
+    }
+}
+
+/// A cron expression with the 5 usual fields: minute, hour, day of month, month and day of week (0 or 7 is Sunday).
+///
+/// Each field accepts `*`, values, ranges (`7-22`), steps (`*/5`, `0-30/10`) and comma separated lists of those.
+/// As in cron, when both the day of month and the day of week are restricted, a day matching either of them matches.
+/// The expressions are evaluated in the local timezone (see the `TZ` environment variable).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpression {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    day_of_month_restricted: bool,
+    day_of_week_restricted: bool,
+}
+
+impl CronExpression {
+    /// Returns true if the minute of the given time matches the expression.
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        self.minutes[time.minute() as usize]
+            && self.hours[time.hour() as usize]
+            && self.months[time.month() as usize]
+            && self.matches_day(time)
+    }
+
+    fn matches_day<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let day_of_month = self.days_of_month[time.day() as usize];
+        let day_of_week = self.days_of_week[time.weekday().num_days_from_sunday() as usize];
+        if self.day_of_month_restricted && self.day_of_week_restricted {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        }
+    }
+
+    /// Returns the first matching minute strictly after `time`, or `None` if nothing matches within a year.
+    pub fn next_after<Tz: TimeZone>(&self, time: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let start = time.with_second(0)?.with_nanosecond(0)?;
+        let mut next = start.clone() + ChronoDuration::minutes(1);
+        while next.clone() - start.clone() <= ChronoDuration::minutes(MAX_LOOKAHEAD_MINUTES) {
+            if !self.hours[next.hour() as usize] || !self.months[next.month() as usize] || !self.matches_day(&next) {
+                // Skip to the next hour
+                next = next.clone() + ChronoDuration::minutes(60 - next.minute() as i64);
+                continue;
+            }
+            if self.minutes[next.minute() as usize] {
+                return Some(next);
+            }
+            next += ChronoDuration::minutes(1);
+        }
+        None
+    }
+}
+
+impl FromStr for CronExpression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Invalid cron expression: {} (expected 5 fields: minute hour day month weekday)", s));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        // 7 is an alias of Sunday
+        days_of_week[0] |= days_of_week[7];
+
+        Ok(CronExpression {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            day_of_month_restricted: fields[2] != "*",
+            day_of_week_restricted: fields[4] != "*",
+        })
+    }
+}
+
+/// Parses a cron field into the allowed values, indexed by value (from 0 to `max`).
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let invalid = || format!("Invalid cron field: {} (expected values between {} and {})", field, min, max);
+    let parse_value = |value: &str| value.parse::<u32>().ok().filter(|value| (min..=max).contains(value)).ok_or_else(invalid);
+
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+                // A single value with a step (e.g. "5/15") runs up to the maximum, as in cron.
+                None if part.contains('/') => (parse_value(range)?, max),
+                None => {
+                    let value = parse_value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn at(datetime: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(datetime).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn next_after_during_waking_hours_and_overnight() {
+        let waking_hours: CronExpression = "*/5 7-22 * * *".parse().unwrap();
+        let overnight: CronExpression = "0 23,0-6 * * *".parse().unwrap();
+
+        assert_eq!(waking_hours.next_after(at("2023-03-04T10:02:30Z")), Some(at("2023-03-04T10:05:00Z")));
+        assert_eq!(waking_hours.next_after(at("2023-03-04T22:55:00Z")), Some(at("2023-03-05T07:00:00Z")));
+        assert_eq!(overnight.next_after(at("2023-03-04T22:55:00Z")), Some(at("2023-03-04T23:00:00Z")));
+        assert_eq!(overnight.next_after(at("2023-03-04T23:00:00Z")), Some(at("2023-03-05T00:00:00Z")));
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // The 1st of the month, or any Sunday
+        let expression: CronExpression = "0 12 1 * 7".parse().unwrap();
+        // 2023-03-04 is a Saturday
+        assert_eq!(expression.next_after(at("2023-03-04T00:00:00Z")), Some(at("2023-03-05T12:00:00Z")));
+        assert_eq!(expression.next_after(at("2023-03-26T13:00:00Z")), Some(at("2023-04-01T12:00:00Z")));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!("* * * *".parse::<CronExpression>().is_err());
+        assert!("60 * * * *".parse::<CronExpression>().is_err());
+        assert!("*/0 * * * *".parse::<CronExpression>().is_err());
+        assert!("0 22-7 * * *".parse::<CronExpression>().is_err());
+        assert!(" ; ".parse::<PollSchedule>().is_err());
+        assert_eq!("0 0 31 2 *".parse::<CronExpression>().unwrap().next_after(at("2023-03-04T00:00:00Z")), None);
+    }
+}
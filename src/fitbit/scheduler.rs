@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+/// Window of the request budget, the same as the one of the rate limit of the Fitbit API (150 requests per hour).
+/// FYI: https://dev.fitbit.com/build/reference/web-api/developer-guide/application-design/#Rate-Limits
+const BUDGET_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Number of requests expected from a collector that hasn't run yet: most of them make a single request.
+const DEFAULT_REQUEST_COST: usize = 1;
+
+tokio::task_local! {
+    // The requests made by the run of the collector being polled (see `RequestScheduler::run`)
+    static COLLECTOR_REQUESTS: Arc<AtomicUsize>;
+}
+
+/// Counts a request to the Fitbit API against the run of the collector making it, if any. The clients call it for
+/// every request they send, the retries included, since they all count against the rate limit.
+pub fn count_request() {
+    let _ = COLLECTOR_REQUESTS.try_with(|requests| requests.fetch_add(1, Ordering::Relaxed));
+}
+
+/// The requests of a collector reserved in the budget before it runs (see `RequestScheduler::reserve`).
+#[derive(Debug)]
+pub struct Reservation {
+    requests: usize,
+    at: Instant,
+}
+
+/// Schedules the collectors of the updates: runs up to `parallelism` of them at once, so that a scrape with many
+/// collectors isn't as slow as the sum of their requests, and skips the ones whose requests would exceed the budget
+/// of the last hour. The budget is shared by all the collectors, be they run by a scrape or by the poller.
+///
+/// The requests of a collector are counted while it runs, so that its cost follows what it actually fetches (e.g. 2
+/// for the skin and core temperatures, or one more when the token is refreshed and the request sent again).
+#[derive(Debug)]
+pub struct RequestScheduler {
+    permits: Semaphore,
+    hourly_budget: Option<usize>,
+    // The times of the requests made in the last hour, oldest first
+    requests: Mutex<VecDeque<Instant>>,
+    // The number of requests made by the last run of each collector
+    costs: Mutex<HashMap<String, usize>>,
+}
+
+impl RequestScheduler {
+    /// Creates a scheduler running up to `parallelism` collectors at once (at least 1), within `hourly_budget`
+    /// requests per hour. `None` doesn't limit the requests.
+    pub fn new(parallelism: usize, hourly_budget: Option<usize>) -> Self {
+        Self {
+            permits: Semaphore::new(parallelism.max(1)),
+            hourly_budget,
+            requests: Mutex::new(VecDeque::new()),
+            costs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the number of requests the next run of the collector is expected to make: the ones of its last run
+    /// that made any, or `DEFAULT_REQUEST_COST` before.
+    pub fn request_cost(&self, collector: &str) -> usize {
+        self.costs.lock().unwrap().get(collector).copied().unwrap_or(DEFAULT_REQUEST_COST)
+    }
+
+    /// Waits until the collector can run, i.e. until fewer than `parallelism` collectors are running.
+    ///
+    /// # Returns
+    ///
+    /// The permit to run the collector, to be held until it's done.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.permits.acquire().await.expect("The semaphore of the scheduler is never closed")
+    }
+
+    /// Reserves the expected requests of the collector (see `request_cost`) in the budget, right before running it.
+    ///
+    /// # Returns
+    ///
+    /// The reservation to pass to `run`, or `None` if the budget of the last hour has no room left for them.
+    pub fn reserve(&self, collector: &str) -> Option<Reservation> {
+        let now = Instant::now();
+        let Some(budget) = self.hourly_budget else {
+            return Some(Reservation { requests: 0, at: now });
+        };
+        let cost = self.request_cost(collector);
+        let mut requests = self.requests.lock().unwrap();
+        while requests.front().is_some_and(|time| now.duration_since(*time) >= BUDGET_WINDOW) {
+            requests.pop_front();
+        }
+        if requests.len() + cost > budget {
+            return None;
+        }
+        requests.extend(std::iter::repeat_n(now, cost));
+        Some(Reservation { requests: cost, at: now })
+    }
+
+    /// Runs the collector, counting its requests (see `count_request`). They become its expected cost, and replace
+    /// its reserved requests in the budget.
+    pub async fn run<T>(&self, collector: &str, reservation: Reservation, future: impl Future<Output = T>) -> T {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let output = COLLECTOR_REQUESTS.scope(counter.clone(), future).await;
+        let made = counter.load(Ordering::Relaxed);
+        // A run without requests, e.g. failing before the first one, doesn't tell the cost
+        if made > 0 {
+            self.costs.lock().unwrap().insert(collector.to_string(), made);
+        }
+        if self.hourly_budget.is_some() {
+            let mut requests = self.requests.lock().unwrap();
+            if made > reservation.requests {
+                requests.extend(std::iter::repeat_n(Instant::now(), made - reservation.requests));
+            } else {
+                // The reserved requests that weren't made are given back
+                let mut unused = reservation.requests - made;
+                requests.retain(|time| {
+                    if unused > 0 && *time == reservation.at {
+                        unused -= 1;
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+        }
+        output
+    }
+
+    /// Returns the number of requests left in the budget of the last hour, or `None` without budget.
+    pub fn remaining_budget(&self) -> Option<usize> {
+        let budget = self.hourly_budget?;
+        let now = Instant::now();
+        let requests = self.requests.lock().unwrap();
+        let recent = requests.iter().filter(|time| now.duration_since(**time) < BUDGET_WINDOW).count();
+        Some(budget.saturating_sub(recent))
+    }
+}
+
+impl Default for RequestScheduler {
+    /// Runs the collectors one at a time, without budget.
+    fn default() -> Self {
+        Self::new(1, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a collector making `requests` requests.
+    async fn run(scheduler: &RequestScheduler, collector: &str, requests: usize) -> bool {
+        match scheduler.reserve(collector) {
+            Some(reservation) => {
+                scheduler.run(collector, reservation, async { (0..requests).for_each(|_| count_request()) }).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_over_the_budget_are_refused_until_the_window_slides() {
+        let scheduler = RequestScheduler::new(2, Some(4));
+
+        assert!(run(&scheduler, "steps", 1).await);
+        assert!(run(&scheduler, "temperature", 2).await);
+        assert_eq!(scheduler.remaining_budget(), Some(1));
+        assert_eq!(scheduler.request_cost("temperature"), 2);
+        // The 2 requests of the last run of temperature don't fit, the single one of sleep does
+        assert!(!run(&scheduler, "temperature", 2).await);
+        assert!(run(&scheduler, "sleep", 1).await);
+        assert!(!run(&scheduler, "water", 1).await);
+
+        tokio::time::advance(BUDGET_WINDOW).await;
+        assert_eq!(scheduler.remaining_budget(), Some(4));
+        assert!(run(&scheduler, "water", 1).await);
+    }
+
+    #[tokio::test]
+    async fn budget_is_charged_with_the_requests_made() {
+        let scheduler = RequestScheduler::new(1, Some(10));
+
+        // 5 requests made for the 1 reserved before the first run
+        assert!(run(&scheduler, "by_date", 5).await);
+        assert_eq!((scheduler.remaining_budget(), scheduler.request_cost("by_date")), (Some(5), 5));
+        // The 5 reserved requests that weren't made are given back, and the cost is kept
+        assert!(run(&scheduler, "by_date", 0).await);
+        assert_eq!((scheduler.remaining_budget(), scheduler.request_cost("by_date")), (Some(5), 5));
+        // Requests made outside of a run aren't counted
+        count_request();
+        assert_eq!(scheduler.remaining_budget(), Some(5));
+    }
+
+    #[tokio::test]
+    async fn parallelism_bounds_the_running_collectors() {
+        let scheduler = RequestScheduler::new(2, None);
+
+        let first = scheduler.acquire().await;
+        let _second = scheduler.acquire().await;
+        assert!(tokio::time::timeout(Duration::from_millis(10), scheduler.acquire()).await.is_err());
+        drop(first);
+        let _third = scheduler.acquire().await;
+        assert_eq!(scheduler.remaining_budget(), None);
+    }
+}
@@ -0,0 +1,58 @@
+use std::env;
+use std::fs;
+use zeroize::Zeroizing;
+
+/// Reads a secret from the environment variable `name`, or from the file at the path given by `<name>_FILE`, e.g.
+/// FITBIT_CLIENT_SECRET_FILE=/run/secrets/fitbit_client_secret, so that the secret can be mounted as a Docker or
+/// Kubernetes secret instead of being visible in the environment of the process (`docker inspect`, `/proc/<pid>/environ`).
+///
+/// The trailing newline of the file is removed, as most editors and `kubectl create secret --from-file` keep it.
+///
+/// # Arguments
+///
+/// * `name` - The name of the environment variable, e.g. "FITBIT_CLIENT_SECRET".
+///
+/// # Returns
+///
+/// The secret, or `None` if neither the variable nor the file variable is set.
+///
+/// # Errors
+///
+/// Returns an error if both the variable and the file variable are set, or if the file cannot be read.
+pub fn read_secret_var(name: &str) -> Result<Option<Zeroizing<String>>, String> {
+    let file_name = format!("{}_FILE", name);
+    match (env::var(name), env::var(&file_name)) {
+        (Ok(_), Ok(_)) => Err(format!("Both {} and {} are set", name, file_name)),
+        (Ok(value), Err(_)) => Ok(Some(Zeroizing::new(value))),
+        (Err(_), Ok(path)) => {
+            let content = Zeroizing::new(fs::read_to_string(&path).map_err(|err| format!("Cannot read {} from {}: {}", name, path, err))?);
+            Ok(Some(Zeroizing::new(content.trim_end_matches(['\r', '\n']).to_string())))
+        }
+        (Err(_), Err(_)) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_from_variables_or_files() {
+        let path = env::temp_dir().join(format!("fitbit_exporter_secret_{}", std::process::id()));
+        fs::write(&path, "s3cret\n").unwrap();
+        env::set_var("FITBIT_TEST_SECRET_FILE", &path);
+        assert_eq!(read_secret_var("FITBIT_TEST_SECRET").unwrap().as_deref().map(String::as_str), Some("s3cret"));
+
+        env::set_var("FITBIT_TEST_SECRET", "s3cret");
+        assert!(read_secret_var("FITBIT_TEST_SECRET").is_err());
+        env::remove_var("FITBIT_TEST_SECRET_FILE");
+        assert_eq!(read_secret_var("FITBIT_TEST_SECRET").unwrap().as_deref().map(String::as_str), Some("s3cret"));
+        env::remove_var("FITBIT_TEST_SECRET");
+        assert!(read_secret_var("FITBIT_TEST_SECRET").unwrap().is_none());
+
+        env::set_var("FITBIT_TEST_SECRET_FILE", path.with_extension("missing"));
+        assert!(read_secret_var("FITBIT_TEST_SECRET").is_err());
+        env::remove_var("FITBIT_TEST_SECRET_FILE");
+        let _ = fs::remove_file(&path);
+    }
+}
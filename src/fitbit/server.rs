@@ -1,47 +1,309 @@
+use base64::engine::general_purpose;
+use base64::Engine;
 use hyper::{header, Body, Request, Response, Server, StatusCode};
+#[cfg(feature = "tls")]
+use hyper::server::conn::Http;
 use hyper::service::{make_service_fn, service_fn};
-use std::time::Duration;
-use chrono::{DateTime, Duration as ChronoDuration, Utc};
-use log::{debug, error, info};
+use tracing::field::Empty;
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 // use prometheus::{Encoder, TextEncoder};
 use std::convert::Infallible;
-use std::net::SocketAddr;
-use std::path::Path;
-use std::fs::read_to_string;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process::ExitStatus;
 use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
 use tokio::sync::RwLock;
-use prometheus_client::encoding::text::encode;
+use tokio::time::Instant;
+
+use crate::fitbit::{FitbitApi, FitbitMetrics, update_selected_metrics};
+use crate::fitbit::api::series_response;
+use crate::fitbit::bodylog::BodyLog;
+use crate::fitbit::logging::LogFilter;
+use crate::fitbit::cmd::{PlaceholderDays, TimestampPosition};
+use crate::fitbit::exposition::{ExpositionFormat, ExpositionRelabel, InvalidExposition};
+use crate::fitbit::history::{push_history, HistoryQuery};
+use crate::fitbit::landing::{render_landing_page, Endpoint};
+use crate::fitbit::metrics::WarmUp;
+use crate::fitbit::profile::{CollectorConfigUpdate, ScrapeProfiles};
+use crate::fitbit::reauthorization::{Reauthorization, AUTHORIZE_PATH};
+use crate::fitbit::status::{StartupSummary, StatusResponse};
+use crate::fitbit::storage::run_blocking;
+use crate::fitbit::traces::TraceBuffer;
+use crate::fitbit::webhook::{run_webhook_server, WebhookReceiver};
+#[cfg(feature = "tls")]
+use crate::fitbit::webhook::run_webhook_server_tls;
+
+/// Size of the chunks of a streamed /metrics response.
+const STREAM_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Maximum time to hand a chunk of a streamed /metrics response to a scraper, after which the response is aborted.
+/// The registry is locked while the response is streamed, so a stalled scraper would block the other scrapes and the
+/// collectors.
+const STREAM_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum duration of a run of the bearer token command (see `BearerTokenCommand::with_timeout`).
+pub const BEARER_TOKEN_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The address of the metrics endpoints.
+pub const LISTEN_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8080);
+
+/// Options of the HTTP server, built from the command line arguments (see `cmd::Args::server_options`).
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// The time of day at which daily values served by `/history` are stamped.
+    pub timestamp_position: TimestampPosition,
+    /// The handling of the placeholder days of the range served by `/history`.
+    pub placeholder_days: PlaceholderDays,
+    /// The credentials required to access the endpoints. `None` leaves the endpoints open.
+    pub auth: Option<MetricsAuth>,
+    /// The certificate and key to serve HTTPS with. `None` serves plain HTTP.
+    pub tls: Option<TlsOptions>,
+    /// The receiver of the Fitbit subscription notifications. `None` disables the webhook.
+    pub webhook: Option<Arc<WebhookReceiver>>,
+    /// Whether the metrics are updated by a background poller (see `poll_metrics_periodically`), in which case
+    /// /metrics serves the last-known values instead of calling the Fitbit API.
+    pub background_polling: bool,
+    /// The profiles selectable with `collect[]=profile:<name>` in the query string of /metrics.
+    pub scrape_profiles: Arc<ScrapeProfiles>,
+    /// The first update of the metrics started at startup. The scrapes arriving before it's done wait for it
+    /// instead of calling the Fitbit API concurrently.
+    pub warm_up: Option<WarmUp>,
+    /// The log of the bodies of the Fitbit API responses, whose level is set through `/admin/loglevel`.
+    pub body_log: Option<Arc<BodyLog>>,
+    /// The log filter, set through `/admin/loglevel`. `None` leaves the filter of `RUST_LOG` as is.
+    pub log_filter: Option<Arc<LogFilter>>,
+    /// The last traces, served by `/debug/traces`. `None` disables the endpoint.
+    pub traces: Option<Arc<TraceBuffer>>,
+    /// The summary of the configuration served by `/status`.
+    pub status: Option<Arc<StartupSummary>>,
+    /// Stream the /metrics response chunk by chunk instead of encoding it whole in memory first. Ignored with
+    /// `strict_exposition`, which checks the whole exposition before serving it.
+    pub stream_exposition: bool,
+    /// Check the exposition with `validate_openmetrics` before serving it, and respond with the problems found
+    /// instead.
+    pub strict_exposition: bool,
+    /// The total time given to the collectors of a scrape of /metrics. `None` waits for all of them.
+    pub scrape_deadline: Option<Duration>,
+    /// The metric name prefix and the constant labels applied to the expositions of /metrics and /history.
+    pub relabel: ExpositionRelabel,
+    /// The re-authorization served by `/oauth2/authorize` and the redirect URL. `None` disables the endpoints.
+    pub reauthorization: Option<Arc<Reauthorization>>,
+}
+
+/// Paths to the PEM encoded certificate chain and private key used to serve HTTPS.
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Credentials required to access the exporter endpoints, which expose personal health data.
+///
+/// When both are configured, a request is accepted if it matches either of them.
+#[derive(Clone, Default)]
+pub struct MetricsAuth {
+    /// HTTP Basic auth username and password.
+    pub basic: Option<(String, String)>,
+    /// Static bearer token, sent as `Authorization: Bearer <token>`.
+    pub bearer_token: Option<String>,
+    /// Bearer token printed by a command, e.g. read from Vault, and refreshed periodically.
+    pub bearer_token_command: Option<Arc<BearerTokenCommand>>,
+}
+
+// Implemented by hand to keep the secrets out of the logs.
+impl std::fmt::Debug for MetricsAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricsAuth")
+            .field("basic", &self.basic.as_ref().map(|(username, _)| username))
+            .field("bearer_token", &self.bearer_token.as_ref().map(|_| "***"))
+            .field("bearer_token_command", &self.bearer_token_command)
+            .finish()
+    }
+}
+
+impl MetricsAuth {
+    /// Returns true if the `Authorization` header of the request matches one of the configured credentials.
+    pub fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let header_value = match req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok()) {
+            Some(header_value) => header_value,
+            None => return false,
+        };
+
+        if let Some((username, password)) = &self.basic {
+            let expected = format!("Basic {}", general_purpose::STANDARD.encode(format!("{}:{}", username, password)));
+            if constant_time_eq(header_value.as_bytes(), expected.as_bytes()) {
+                return true;
+            }
+        }
+        let command_token = self.bearer_token_command.as_ref().and_then(|command| command.token());
+        for token in self.bearer_token.iter().chain(command_token.iter()) {
+            let expected = format!("Bearer {}", token);
+            if constant_time_eq(header_value.as_bytes(), expected.as_bytes()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The `WWW-Authenticate` challenge sent with 401 responses.
+    fn challenge(&self) -> &'static str {
+        if self.basic.is_some() {
+            "Basic realm=\"fitbit_exporter\""
+        } else {
+            "Bearer"
+        }
+    }
+}
+
+/// Compares two byte strings in constant time (for equal lengths), so that the credentials can't be guessed
+/// by measuring response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Errors of a run of the `BearerTokenCommand`.
+#[derive(Debug, Error)]
+pub enum BearerTokenError {
+    #[error("The bearer token command could not be run: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("The bearer token command timed out after {0:?}")]
+    TimedOut(Duration),
+
+    #[error("The bearer token command failed ({status}): {stderr}")]
+    Failed { status: ExitStatus, stderr: String },
 
-use crate::fitbit::{FitbitClient, FitbitMetrics, update_current_metrics};
+    #[error("The bearer token command printed invalid UTF-8")]
+    InvalidUtf8,
+
+    #[error("The bearer token command printed an empty token")]
+    EmptyToken,
+}
+
+/// A shell command whose standard output is the bearer token required to access the endpoints, e.g.
+/// `vault kv get -field=token secret/fitbit_exporter`, so that the token doesn't have to be written in the
+/// configuration.
+pub struct BearerTokenCommand {
+    command: String,
+    /// The output of the last successful run, or `None` before the first one.
+    token: std::sync::RwLock<Option<String>>,
+    /// Maximum duration of a run, after which the command is killed.
+    timeout: Duration,
+}
+
+// Implemented by hand to keep the token out of the logs.
+impl fmt::Debug for BearerTokenCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BearerTokenCommand").field("command", &self.command).field("token", &"***").finish()
+    }
+}
+
+impl BearerTokenCommand {
+    pub fn new(command: &str) -> Self {
+        BearerTokenCommand { command: command.to_string(), token: std::sync::RwLock::new(None), timeout: BEARER_TOKEN_COMMAND_TIMEOUT }
+    }
+
+    /// Kills the command if it doesn't exit within `timeout`, instead of `BEARER_TOKEN_COMMAND_TIMEOUT`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the token printed by the last successful run of the command.
+    pub fn token(&self) -> Option<String> {
+        self.token.read().unwrap().clone()
+    }
+
+    /// Runs the command with `sh -c`, and replaces the token with its trimmed standard output.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the command cannot be run, exits with a failure, prints nothing, or is killed after its
+    /// timeout, e.g. a hung Vault login. The previous token is kept in that case.
+    pub async fn refresh(&self) -> Result<(), BearerTokenError> {
+        // The child is killed when the timed out future drops it
+        let output = tokio::process::Command::new("sh").arg("-c").arg(&self.command).kill_on_drop(true).output();
+        let output = tokio::time::timeout(self.timeout, output).await.map_err(|_| BearerTokenError::TimedOut(self.timeout))??;
+        if !output.status.success() {
+            return Err(BearerTokenError::Failed { status: output.status, stderr: String::from_utf8_lossy(&output.stderr).trim().to_string() });
+        }
+        let token = String::from_utf8(output.stdout).map_err(|_| BearerTokenError::InvalidUtf8)?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(BearerTokenError::EmptyToken);
+        }
+        *self.token.write().unwrap() = Some(token.to_string());
+        Ok(())
+    }
+}
+
+/// Runs the bearer token command every `interval`, so that a rotated token is picked up. A failed run keeps the
+/// previous token.
+///
+/// # Arguments
+///
+/// * `command` - The `BearerTokenCommand` of the `MetricsAuth` of the server, already run once at startup.
+/// * `interval` - The time between two runs of the command.
+pub async fn refresh_bearer_token_periodically(command: Arc<BearerTokenCommand>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match command.refresh().await {
+            Ok(()) => debug!("Refreshed the bearer token from its command"),
+            Err(err) => warn!("Could not refresh the bearer token, keeping the previous one: {}", err),
+        }
+    }
+}
 
 /// Start and run an HTTP server that serves the Fitbit metrics for Prometheus to scrape.
 ///
 /// # Arguments
 ///
-/// * `client` - An `Arc<RwLock<FitbitClient>>` that provides access to the shared Fitbit client.
+/// * `client` - An `Arc<RwLock<dyn FitbitApi>>` that provides access to the shared Fitbit client.
 /// * `shared_fitbit_metrics` - An `Arc<FitbitMetrics>` that provides access to the shared Fitbit metrics.
+/// * `options` - The `ServerOptions`, e.g. the credentials required to access the endpoints.
 ///
 /// # Errors
 ///
 /// Returns an error if the server encounters an issue while running.
-pub async fn run_server(client: Arc<RwLock<FitbitClient>>, shared_fitbit_metrics: Arc<FitbitMetrics>) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run_server(client: Arc<RwLock<dyn FitbitApi>>, shared_fitbit_metrics: Arc<FitbitMetrics>, options: ServerOptions) -> Result<(), Box<dyn std::error::Error>> {
+    // Set up the HTTP server for Prometheus to scrape the metrics
+    let addr = LISTEN_ADDR;
+
+    // The webhook gets its own listener when a port is configured for it, and is served by this server otherwise.
+    if let Some(webhook) = &options.webhook {
+        if let Some(port) = webhook.options().port {
+            spawn_webhook_server(Arc::clone(webhook), port, options.tls.as_ref())?;
+        }
+    }
+
+    if let Some(tls_options) = options.tls.clone() {
+        return serve_tls(addr, &tls_options, client, shared_fitbit_metrics, options).await;
+    }
+
     // Use make_service_fn to create a new service function for each connection to the server.
     // The move |_| captures the `shared_*`, making them accessible within the closure.
     let make_svc = make_service_fn(move |_| {
         let cloned_fitbit_client = Arc::clone(&client);
         let cloned_fitbit_metrics = Arc::clone(&shared_fitbit_metrics);
+        let cloned_options = options.clone();
 
         async move {
             // Return an infallible service function that takes an incoming request and
             // calls the metrics_handler with the cloned Arc pointers.
             Ok::<_, Infallible>(service_fn(move |req| {
-                metrics_handler(req, cloned_fitbit_client.clone(), cloned_fitbit_metrics.clone())
+                metrics_handler(req, cloned_fitbit_client.clone(), cloned_fitbit_metrics.clone(), cloned_options.clone())
             }))
         }
     });
 
-    // Set up the HTTP server for Prometheus to scrape the metrics
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
     let server = Server::bind(&addr).serve(make_svc);
     info!("Server running on http://{}", addr);
 
@@ -50,62 +312,299 @@ pub async fn run_server(client: Arc<RwLock<FitbitClient>>, shared_fitbit_metrics
     Ok(())
 }
 
+/// Spawns the dedicated listener of the webhook, served over HTTPS with the certificate of the endpoints when TLS
+/// is configured.
+///
+/// # Errors
+///
+/// Returns an error if the certificate or key cannot be loaded.
+#[cfg(feature = "tls")]
+fn spawn_webhook_server(webhook: Arc<WebhookReceiver>, port: u16, tls_options: Option<&TlsOptions>) -> Result<(), Box<dyn std::error::Error>> {
+    match tls_options {
+        Some(tls_options) => {
+            let acceptor = crate::fitbit::tls::load_tls_acceptor(tls_options)?;
+            tokio::spawn(async move {
+                if let Err(err) = run_webhook_server_tls(webhook, port, acceptor).await {
+                    error!("An error occurred while running the webhook receiver: {}", err);
+                }
+            });
+        }
+        None => {
+            tokio::spawn(async move {
+                if let Err(err) = run_webhook_server(webhook, port).await {
+                    error!("An error occurred while running the webhook receiver: {}", err);
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn spawn_webhook_server(webhook: Arc<WebhookReceiver>, port: u16, tls_options: Option<&TlsOptions>) -> Result<(), Box<dyn std::error::Error>> {
+    if tls_options.is_some() {
+        return Err("TLS was requested, but fitbit_exporter was built without the `tls` feature".into());
+    }
+    tokio::spawn(async move {
+        if let Err(err) = run_webhook_server(webhook, port).await {
+            error!("An error occurred while running the webhook receiver: {}", err);
+        }
+    });
+    Ok(())
+}
+
+/// Serves the endpoints over HTTPS, terminating TLS with rustls.
+///
+/// Each accepted connection goes through the TLS handshake in its own task, so that a slow or broken client
+/// doesn't block the others.
+///
+/// # Errors
+///
+/// Returns an error if the certificate or key cannot be loaded, or if the address cannot be bound.
+#[cfg(feature = "tls")]
+async fn serve_tls(
+    addr: SocketAddr,
+    tls_options: &TlsOptions,
+    client: Arc<RwLock<dyn FitbitApi>>,
+    shared_fitbit_metrics: Arc<FitbitMetrics>,
+    options: ServerOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let acceptor = crate::fitbit::tls::load_tls_acceptor(tls_options)?;
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    info!("Server running on https://{}", addr);
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("Failed to accept a connection: {}", err);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let cloned_fitbit_client = Arc::clone(&client);
+        let cloned_fitbit_metrics = Arc::clone(&shared_fitbit_metrics);
+        let cloned_options = options.clone();
+        tokio::spawn(async move {
+            let tls_stream = match acceptor.accept(stream).await {
+                Ok(tls_stream) => tls_stream,
+                Err(err) => {
+                    debug!("TLS handshake with {} failed: {}", peer_addr, err);
+                    return;
+                }
+            };
+            let service = service_fn(move |req| {
+                metrics_handler(req, cloned_fitbit_client.clone(), cloned_fitbit_metrics.clone(), cloned_options.clone())
+            });
+            if let Err(err) = Http::new().serve_connection(tls_stream, service).await {
+                error!("An error occurred while serving {}: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+async fn serve_tls(
+    _addr: SocketAddr,
+    _tls_options: &TlsOptions,
+    _client: Arc<RwLock<dyn FitbitApi>>,
+    _shared_fitbit_metrics: Arc<FitbitMetrics>,
+    _options: ServerOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("TLS was requested, but fitbit_exporter was built without the `tls` feature".into())
+}
+
 
 /// Handles HTTP requests for the /metrics endpoint.
 ///
 /// This function serves Prometheus metrics by fetching data from the Fitbit API,
 /// updating the FitbitMetrics struct, and encoding the metrics for Prometheus.
+/// The metrics are encoded in OpenMetrics or in the Prometheus text format, depending on the `Accept` header.
+/// The `collect[]` query parameters restrict the update to some collectors or scrape profiles.
 ///
 /// # Arguments
 ///
 /// * `req` - The incoming HTTP request.
-/// * `fitbit_client` - An Arc<RwLock<FitbitClient>> to access the Fitbit API.
+/// * `fitbit_client` - An Arc<RwLock<dyn FitbitApi>> to access the Fitbit API.
 /// * `fitbit_metrics` - An Arc<FitbitMetrics> to store and update the metrics.
+/// * `options` - The `ServerOptions`. If credentials are configured, requests without them get a 401 response,
+///   except for the webhook.
 ///
 /// # Returns
 ///
-/// * A Result containing an HTTP Response, or an Infallible error.
+/// * A Result containing an HTTP Response, or an Infallible error. The request is handled within a `request` span
+///   with its path, status, duration and user, which are fields of the JSON logs (`--log-format json`) and of the
+///   traces (`--debug-traces`), e.g. to correlate the scrapes with the requests to the Fitbit API made within them.
 async fn metrics_handler(
     req: Request<Body>,
-    fitbit_client: Arc<RwLock<FitbitClient>>,
+    fitbit_client: Arc<RwLock<dyn FitbitApi>>,
     fitbit_metrics: Arc<FitbitMetrics>,
+    options: ServerOptions,
 ) -> Result<Response<Body>, Infallible> {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let user = options.status.as_ref().and_then(|status| status.user_id.clone()).unwrap_or_else(|| "-".to_string());
+    let span = info_span!("request", method = %method, endpoint = path.as_str(), user = user.as_str(), status = Empty, duration_ms = Empty);
+    let response = route_request(req, fitbit_client, fitbit_metrics, options).instrument(span.clone()).await?;
+    span.record("status", response.status().as_u16());
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    span.in_scope(|| info!("{} {} {}", method, path, response.status()));
+    Ok(response)
+}
+
+/// Routes the request to the handler of its path.
+async fn route_request(
+    req: Request<Body>,
+    fitbit_client: Arc<RwLock<dyn FitbitApi>>,
+    fitbit_metrics: Arc<FitbitMetrics>,
+    options: ServerOptions,
+) -> Result<Response<Body>, Infallible> {
+    // The webhook has its own authentication (see `WebhookReceiver`), since Fitbit can't send the metrics credentials.
+    if let Some(webhook) = &options.webhook {
+        if webhook.options().port.is_none() && webhook.matches(req.uri().path()) {
+            return Ok(webhook.handle(req).await);
+        }
+    }
+
+    // The redirect from the Fitbit authorization page can't send the credentials either. It's only accepted with
+    // the state of an authorization started through /oauth2/authorize, which requires them.
+    if let Some(reauthorization) = &options.reauthorization {
+        if req.method() == hyper::Method::GET && req.uri().path() == reauthorization.callback_path() {
+            return Ok(reauthorization.callback(req.uri().query()).await);
+        }
+    }
+
+    if let Some(auth) = &options.auth {
+        if !auth.is_authorized(&req) {
+            debug!("Rejecting unauthorized request to {}", req.uri().path());
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, auth.challenge())
+                .body(Body::from("Unauthorized"))
+                .unwrap());
+        }
+    }
+
+    // Re-authorizes the exporter, e.g. after the refresh token got `invalid_grant`. Anyone could otherwise switch the
+    // exporter to their own Fitbit account.
+    if let Some(reauthorization) = &options.reauthorization {
+        if req.method() == hyper::Method::GET && req.uri().path() == AUTHORIZE_PATH {
+            if options.auth.is_none() {
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("The re-authorization requires --metrics-bearer-token or --metrics-username"))
+                    .unwrap());
+            }
+            return Ok(reauthorization.authorize().await);
+        }
+    }
+
+    let format = ExpositionFormat::negotiate(req.headers().get(header::ACCEPT).and_then(|value| value.to_str().ok()));
+
     match (req.method(), req.uri().path()) {
+        // Lists the endpoints, the state of the collectors and the exposed metric families
+        (&hyper::Method::GET, "/") => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(render_landing_page(&fitbit_metrics, &endpoints(&fitbit_metrics, &options))))
+            .unwrap()),
         (&hyper::Method::GET, "/metrics") => {
+            let selection = match options.scrape_profiles.select(req.uri().query()) {
+                Ok(selection) => match options.scrape_deadline {
+                    Some(deadline) => selection.with_deadline(Instant::now() + deadline),
+                    None => selection,
+                },
+                Err(err) => return build_bad_request_response(err),
+            };
+
+            let warmed_up = match &options.warm_up {
+                Some(warm_up) => warm_up.wait().await,
+                None => false,
+            };
+
             // Update the metrics - fetch the latest data from the Fitbit API (considering changing the function name)
-            match update_current_metrics(fitbit_client.clone(), fitbit_metrics.clone()).await {
-                Err(err) => build_error_response(format!("Error updating metrics: {:?}", err)),
-                Ok(_) => {
+            // unless the background poller keeps them up to date, or the warm-up just did.
+            let update_result = if options.background_polling || warmed_up {
+                Ok(())
+            } else {
+                update_selected_metrics(fitbit_client.clone(), fitbit_metrics.clone(), &selection).await
+            };
+            // Formatted right away, since the error isn't `Send` and the stored samples are read asynchronously
+            let update_error = update_result.err().map(|err| format!("{:?}", err));
+            match (update_error, &fitbit_metrics.sample_store) {
+                (Some(err), None) => build_error_response(format!("Error updating metrics: {}", err)),
+                // The sample store still has the values of the last successful update, e.g. from before a restart.
+                (update_error, Some(sample_store)) => {
+                    if let Some(err) = update_error {
+                        error!("Error updating metrics, serving the stored samples: {}", err);
+                    }
+                    match run_blocking(sample_store, |sample_store| sample_store.latest()).await {
+                        Ok(families) if options.strict_exposition => match format.encode_families_strict(&families, &options.relabel) {
+                            Ok(txt) => build_text_response(txt, format),
+                            Err(err) => build_error_response(err.to_string()),
+                        },
+                        Ok(families) => build_text_response(options.relabel.apply(format.encode_families(&families)), format),
+                        Err(err) => build_error_response(format!("Error reading the stored samples: {:?}", err)),
+                    }
+                }
+                (None, None) if options.stream_exposition && !options.strict_exposition => Ok(build_streamed_response(fitbit_metrics, format, options.relabel.clone())),
+                (None, None) => {
                     // Encode the metrics for Prometheus
-                    let mut txt = String::new();
-                    encode(&mut txt, &fitbit_metrics.registry).unwrap();
-                    build_text_response(txt)
+                    match encode_registry(&fitbit_metrics, format, &options) {
+                        Ok(txt) => {
+                            fitbit_metrics.release_served_history();
+                            build_text_response(txt, format)
+                        }
+                        Err(err) => build_error_response(err.to_string()),
+                    }
                 }
             }
         },
-        // Retrieves 1y steps per day via Fitbit API (not from a .prom file). Controle by Prometheus scraping frequency.
+        // Serves the archived series as JSON, e.g. for the JSON datasources of Grafana.
+        (&hyper::Method::GET, "/api/series") => Ok(series_response(fitbit_metrics.sample_store.as_ref(), req.uri().query()).await),
+        // Serves the summary of the configuration logged at startup
+        (&hyper::Method::GET, "/status") => match &options.status {
+            Some(status) => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_string(&StatusResponse { summary: status, events: fitbit_metrics.events.log().recent() }).unwrap()))
+                .unwrap()),
+            None => Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not found")).unwrap()),
+        },
+        // Reads or sets the log filter, e.g. `PUT /admin/loglevel?level=info,fitbit_exporter=debug`.
+        (method, "/admin/loglevel") => loglevel_handler(method, req.uri().query(), &options),
+        // Serves the last traces as JSON, e.g. `GET /debug/traces?min_ms=1000` for the scrapes slower than a second.
+        (&hyper::Method::GET, "/debug/traces") => traces_handler(req.uri().query(), &options),
+        // Reads or changes the enabled collectors and their cadences, e.g. `PUT /admin/config {"disable": ["sleep"]}`.
+        (method, "/admin/config") => {
+            let method = method.clone();
+            config_handler(&method, req.into_body(), &fitbit_metrics, &options).await
+        }
+        // Retrieves the daily values of a date range via Fitbit API (not from a .prom file). Controle by Prometheus scraping frequency.
         (&hyper::Method::GET, "/history") => {
 
-        let yesterday = Utc::now().date_naive().pred_opt().unwrap();
-        let start_date = yesterday - ChronoDuration::days(30); // to get 1 month (days(30)) of data during testing. In production, use days(365)
+        // The range and the metrics are given by the query, e.g. `?start=2024-01-01&end=2024-01-31&metrics=steps`,
+        // and default to the steps of the last 30 days.
+        let query = match HistoryQuery::parse(req.uri().query(), fitbit_metrics.user_today()) {
+            Ok(query) => query,
+            Err(err) => return build_bad_request_response(err),
+        };
 
         let read_locked_client = fitbit_client.read().await;
 
-        let steps_range_data = read_locked_client.fetch_steps_range(start_date, yesterday).await;
-        for (date, steps) in steps_range_data {
-            // Currently, I treat the NativeDate as UTC. Possibly Fitbit user's timezone configuration can be used:
-            // https://dev.fitbit.com/build/reference/web-api/user/get-profile/
-            let datetime_utc = DateTime::<Utc>::from_utc(date.and_hms_opt(0, 0, 0).unwrap(), Utc);
-            let timestamp = datetime_utc.timestamp() as u64;
-            debug!("date: {:?}, steps: {}, converted timestamp: {:?}", date, steps, timestamp);
-
-            fitbit_metrics.steps.push(steps as i64, Some(Duration::from_secs(timestamp)));
+        match push_history(&*read_locked_client, &fitbit_metrics, &query, options.timestamp_position, options.placeholder_days).await {
+            Err(err) => build_error_response(format!("Error fetching historical metrics: {:?}", err)),
+            Ok(()) => match encode_registry(&fitbit_metrics, format, &options) {
+                Ok(txt) => {
+                    fitbit_metrics.release_served_history();
+                    build_text_response(txt, format)
+                }
+                Err(err) => build_error_response(err.to_string()),
+            },
         }
 
-        let mut txt = String::new();
-        encode(&mut txt, &fitbit_metrics.registry).unwrap();
-        build_text_response(txt)
-
 /* 
             // Read the contents of the .prom file
             let file_path = Path::new("fitbit_historical_metrics.prom");
@@ -124,10 +623,194 @@ async fn metrics_handler(
     }
 }
 
-fn build_text_response(txt: String) -> Result<Response<Body>, Infallible> {
+/// The endpoints enabled by the options, listed by the landing page.
+fn endpoints(fitbit_metrics: &FitbitMetrics, options: &ServerOptions) -> Vec<Endpoint> {
+    let mut endpoints = vec![
+        ("/metrics", "The metrics, in the Prometheus text or OpenMetrics format"),
+        ("/history", "The daily steps (or resting heart rates) of a date range, with their timestamps"),
+    ];
+    if fitbit_metrics.sample_store.is_some() {
+        endpoints.push(("/api/series", "The archived series, as JSON"));
+    }
+    if options.status.is_some() {
+        endpoints.push(("/status", "The summary of the configuration and the last significant events, as JSON"));
+    }
+    if options.log_filter.is_some() || options.body_log.is_some() {
+        endpoints.push(("/admin/loglevel", "The log filter"));
+    }
+    endpoints.push(("/admin/config", "The enabled collectors and their cadences, as JSON"));
+    if options.traces.is_some() {
+        endpoints.push(("/debug/traces", "The last traces of the requests, as JSON"));
+    }
+    if options.reauthorization.is_some() {
+        endpoints.push((AUTHORIZE_PATH, "Re-authorizes the exporter with Fitbit"));
+    }
+    endpoints
+}
+
+/// Reads (GET) or sets (PUT or POST) the log filter, and the level of the body log.
+///
+/// The `level` parameter takes the syntax of `RUST_LOG`. The body log gets the most verbose level of the filter,
+/// i.e. it's switched on by enabling the debug logs of any module.
+fn loglevel_handler(method: &hyper::Method, query: Option<&str>, options: &ServerOptions) -> Result<Response<Body>, Infallible> {
+    if options.log_filter.is_none() && options.body_log.is_none() {
+        return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not found")).unwrap());
+    }
+
+    match *method {
+        hyper::Method::GET => {
+            let mut txt = String::new();
+            if let Some(log_filter) = &options.log_filter {
+                txt.push_str(&format!("log: {}\n", log_filter.directives()));
+            }
+            if let Some(body_log) = &options.body_log {
+                txt.push_str(&format!("body log: {}\n", body_log.level()));
+            }
+            build_plain_response(txt)
+        }
+        hyper::Method::PUT | hyper::Method::POST => {
+            // A debug filter would log the tokens and the health data
+            if options.auth.is_none() {
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("Changing the log filter requires --metrics-bearer-token or --metrics-username"))
+                    .unwrap());
+            }
+            let directives = match url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()).find(|(key, _)| key == "level") {
+                Some((_, directives)) => directives.into_owned(),
+                None => return build_bad_request_response("Expected a level parameter, e.g. level=debug or level=info,fitbit_exporter=debug".to_string()),
+            };
+            let level = match &options.log_filter {
+                Some(log_filter) => log_filter.set_directives(&directives),
+                None => directives.parse::<LevelFilter>().map_err(|err| format!("Invalid level {}: {}", directives, err)),
+            };
+            match level {
+                Ok(level) => {
+                    info!("Setting the log filter to {}", directives);
+                    if let Some(body_log) = &options.body_log {
+                        body_log.set_level(level);
+                    }
+                    build_plain_response(format!("{}\n", directives))
+                }
+                Err(err) => build_bad_request_response(err),
+            }
+        }
+        _ => Ok(Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).body(Body::from("Method not allowed")).unwrap()),
+    }
+}
+
+/// Serves the traces recorded with `--debug-traces`, the most recent first, lasting at least the `min_ms` parameter
+/// of the query string (0 by default).
+fn traces_handler(query: Option<&str>, options: &ServerOptions) -> Result<Response<Body>, Infallible> {
+    let traces = match &options.traces {
+        Some(traces) => traces,
+        None => return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not found")).unwrap()),
+    };
+    let min_duration_ms = match url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()).find(|(key, _)| key == "min_ms") {
+        Some((_, min_ms)) => match min_ms.parse::<f64>() {
+            Ok(min_ms) => min_ms,
+            Err(_) => return build_bad_request_response(format!("Invalid min_ms: {}", min_ms)),
+        },
+        None => 0.0,
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&traces.recent(min_duration_ms)).unwrap()))
+        .unwrap())
+}
+
+/// Reads (GET) or changes (PUT or POST) the settings of the collectors, whose body is a `CollectorConfigUpdate`.
+///
+/// The registry is rebuilt on each change, so that the metrics of the disabled collectors disappear from the next
+/// scrape. Changes require the credentials of the metrics to be configured, since they affect every scraper.
+async fn config_handler(method: &hyper::Method, body: Body, fitbit_metrics: &FitbitMetrics, options: &ServerOptions) -> Result<Response<Body>, Infallible> {
+    let config = match *method {
+        hyper::Method::GET => fitbit_metrics.collector_config(),
+        hyper::Method::PUT | hyper::Method::POST => {
+            if options.auth.is_none() {
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("Changing the config requires --metrics-bearer-token or --metrics-username"))
+                    .unwrap());
+            }
+            let update = match hyper::body::to_bytes(body).await {
+                Ok(bytes) => match serde_json::from_slice::<CollectorConfigUpdate>(&bytes) {
+                    Ok(update) => update,
+                    Err(err) => return build_bad_request_response(format!("Invalid config: {}", err)),
+                },
+                Err(err) => return build_bad_request_response(format!("Error reading the body: {}", err)),
+            };
+            match fitbit_metrics.update_collector_config(&update) {
+                Ok(config) => config,
+                Err(err) => return build_bad_request_response(err),
+            }
+        }
+        _ => return Ok(Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).body(Body::from("Method not allowed")).unwrap()),
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&config).unwrap()))
+        .unwrap())
+}
+
+fn build_text_response(txt: String, format: ExpositionFormat) -> Result<Response<Body>, Infallible> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .body(Body::from(txt))
+        .unwrap())
+}
+
+/// Encodes the registry in the format of the response, relabeled with `relabel`, and checked with
+/// `validate_openmetrics` if `strict_exposition` is set.
+fn encode_registry(fitbit_metrics: &FitbitMetrics, format: ExpositionFormat, options: &ServerOptions) -> Result<String, InvalidExposition> {
+    if options.strict_exposition {
+        format.encode_strict(&fitbit_metrics.registry(), &options.relabel)
+    } else {
+        Ok(options.relabel.apply(format.encode(&fitbit_metrics.registry()).unwrap()))
+    }
+}
+
+/// Builds a response whose body is encoded from the registry while it's sent, in chunks of `STREAM_CHUNK_SIZE`.
+///
+/// The encoding runs on a blocking thread, which waits for each chunk to be sent before encoding the next one, for
+/// at most `STREAM_SEND_TIMEOUT`. If the encoding fails or times out midway, the body is aborted, so that the scraper
+/// sees an error instead of partial metrics.
+fn build_streamed_response(fitbit_metrics: Arc<FitbitMetrics>, format: ExpositionFormat, relabel: ExpositionRelabel) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let result = format.encode_chunks(&fitbit_metrics.registry(), STREAM_CHUNK_SIZE, |chunk| {
+            match runtime.block_on(tokio::time::timeout(STREAM_SEND_TIMEOUT, sender.send_data(relabel.apply(chunk).into()))) {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(_)) => Err(fmt::Error),
+                Err(_) => {
+                    warn!("The scraper didn't read the metrics for {:?}", STREAM_SEND_TIMEOUT);
+                    Err(fmt::Error)
+                }
+            }
+        });
+        match result {
+            Ok(()) => fitbit_metrics.release_served_history(),
+            Err(_) => {
+                error!("Error streaming the metrics, aborting the response");
+                sender.abort();
+            }
+        }
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .body(body)
+        .unwrap()
+}
+
+fn build_plain_response(txt: String) -> Result<Response<Body>, Infallible> {
     Ok(Response::builder()
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
         .body(Body::from(txt))
         .unwrap())
 }
@@ -138,4 +821,143 @@ fn build_error_response(err_msg: String) -> Result<Response<Body>, Infallible> {
         .status(StatusCode::INTERNAL_SERVER_ERROR)
         .body(Body::from(err_msg))
         .unwrap())
-}
\ No newline at end of file
+}
+
+fn build_bad_request_response(err_msg: String) -> Result<Response<Body>, Infallible> {
+    debug!("{}", err_msg);
+    Ok(Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(err_msg))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::cmd::Args;
+    use crate::fitbit::mock::MockFitbitApi;
+    use crate::fitbit::FitbitClient;
+    use structopt::StructOpt;
+
+    /// Builds the options of the server configured by the command line `args`, with the re-authorization enabled.
+    fn server_options(args: &[&str]) -> ServerOptions {
+        let mut options = Args::from_iter(["fitbit_exporter"].iter().chain(args)).server_options();
+        let client = Arc::new(RwLock::new(FitbitClient::new("client", "secret", &None, "access")));
+        options.reauthorization = Some(Arc::new(Reauthorization::new(client, "http://localhost:8080/oauth2/callback", &["activity".to_string()])));
+        options
+    }
+
+    /// Routes a request with the given `authorization` header, if any, through the server.
+    async fn send(options: ServerOptions, method: hyper::Method, path: &str, authorization: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let api: Arc<RwLock<dyn FitbitApi>> = Arc::new(RwLock::new(MockFitbitApi::new()));
+        route_request(request.body(Body::empty()).unwrap(), api, Arc::new(FitbitMetrics::new()), options).await.unwrap().status()
+    }
+
+    /// Routes a GET request without credentials through the server configured by the command line `args`.
+    async fn get(args: &[&str], path: &str) -> StatusCode {
+        send(server_options(args), hyper::Method::GET, path, None).await
+    }
+
+    #[tokio::test]
+    async fn reauthorization_requires_the_credentials_except_for_the_callback() {
+        let with_auth = ["--metrics-bearer-token", "s3cret"];
+        assert_eq!(get(&with_auth, AUTHORIZE_PATH).await, StatusCode::UNAUTHORIZED);
+        // Fitbit's redirect gets through, and is rejected for its unknown state
+        assert_eq!(get(&with_auth, "/oauth2/callback?code=c&state=forged").await, StatusCode::BAD_REQUEST);
+
+        assert_eq!(get(&[], AUTHORIZE_PATH).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn changing_the_log_level_requires_the_credentials() {
+        let path = std::env::temp_dir().join(format!("fitbit_exporter_loglevel_{}.log", std::process::id()));
+        let body_log = Arc::new(BodyLog::open(&path, LevelFilter::OFF).unwrap());
+        let options = |args: &[&str]| ServerOptions { body_log: Some(body_log.clone()), ..server_options(args) };
+        let with_auth = ["--metrics-bearer-token", "s3cret"];
+
+        assert_eq!(send(options(&[]), hyper::Method::GET, "/admin/loglevel", None).await, StatusCode::OK);
+        assert_eq!(send(options(&[]), hyper::Method::PUT, "/admin/loglevel?level=debug", None).await, StatusCode::FORBIDDEN);
+        assert_eq!(body_log.level(), LevelFilter::OFF);
+        assert_eq!(send(options(&with_auth), hyper::Method::PUT, "/admin/loglevel?level=debug", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(options(&with_auth), hyper::Method::PUT, "/admin/loglevel?level=debug", Some("Bearer s3cret")).await, StatusCode::OK);
+        assert_eq!(body_log.level(), LevelFilter::DEBUG);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn constant_time_eq_compares_the_whole_strings() {
+        assert!(constant_time_eq(b"Bearer s3cret", b"Bearer s3cret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"Bearer s3cret", b"Bearer s3creT"));
+        assert!(!constant_time_eq(b"Bearer s3cret", b"Bearer s3cre"));
+        assert!(!constant_time_eq(b"Bearer s3cret", b"Bearer s3cret2"));
+    }
+
+    #[test]
+    fn requests_are_authorized_by_either_credentials() {
+        let request = |authorization: Option<&str>| {
+            let mut request = Request::builder();
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        // "prometheus:hunter2" in base64
+        let basic = "Basic cHJvbWV0aGV1czpodW50ZXIy";
+        let auth = MetricsAuth { basic: Some(("prometheus".to_string(), "hunter2".to_string())), bearer_token: Some("s3cret".to_string()), ..Default::default() };
+
+        assert!(auth.is_authorized(&request(Some(basic))));
+        assert!(auth.is_authorized(&request(Some("Bearer s3cret"))));
+        assert!(!auth.is_authorized(&request(None)));
+        assert!(!auth.is_authorized(&request(Some("Bearer wrong"))));
+        assert!(!auth.is_authorized(&request(Some("bearer s3cret"))));
+        assert!(!auth.is_authorized(&request(Some("Basic cHJvbWV0aGV1czp3cm9uZw=="))));
+        assert!(!auth.is_authorized(&request(Some("s3cret"))));
+
+        let bearer_only = MetricsAuth { bearer_token: Some("s3cret".to_string()), ..Default::default() };
+        assert!(!bearer_only.is_authorized(&request(Some(basic))));
+        assert_eq!(bearer_only.challenge(), "Bearer");
+        assert_eq!(auth.challenge(), "Basic realm=\"fitbit_exporter\"");
+    }
+
+    #[tokio::test]
+    async fn changing_the_config_requires_the_credentials() {
+        let with_auth = ["--metrics-bearer-token", "s3cret"];
+        assert_eq!(get(&[], "/admin/config").await, StatusCode::OK);
+        // The body, which is invalid, isn't read without the credentials
+        assert_eq!(send(server_options(&[]), hyper::Method::PUT, "/admin/config", None).await, StatusCode::FORBIDDEN);
+        assert_eq!(send(server_options(&with_auth), hyper::Method::PUT, "/admin/config", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(server_options(&with_auth), hyper::Method::PUT, "/admin/config", Some("Bearer s3cret")).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn bearer_token_from_a_command() {
+        // The token isn't part of the command, so that it can be looked for in the Debug output
+        let command = Arc::new(BearerTokenCommand::new("printf 's3cre%s\\n' t"));
+        let auth = MetricsAuth { bearer_token_command: Some(command.clone()), ..Default::default() };
+        let request = |token: &str| Request::builder().header(header::AUTHORIZATION, format!("Bearer {}", token)).body(Body::empty()).unwrap();
+        assert!(!auth.is_authorized(&request("s3cret")));
+
+        command.refresh().await.unwrap();
+        assert!(auth.is_authorized(&request("s3cret")));
+        assert!(!auth.is_authorized(&request("other")));
+        assert!(!format!("{:?}", auth).contains("s3cret"));
+
+        // A failed run keeps the previous token
+        assert!(BearerTokenCommand::new("exit 1").refresh().await.is_err());
+        let failing = BearerTokenCommand { token: std::sync::RwLock::new(Some("s3cret".to_string())), ..BearerTokenCommand::new("printf ''") };
+        assert!(failing.refresh().await.is_err());
+        assert_eq!(failing.token().as_deref(), Some("s3cret"));
+
+        // A hung command is killed
+        let started = Instant::now();
+        let hung = BearerTokenCommand::new("sleep 10; echo s3cret").with_timeout(Duration::from_millis(100));
+        assert!(matches!(hung.refresh().await, Err(BearerTokenError::TimedOut(_))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(hung.token(), None);
+    }
+}
@@ -0,0 +1,158 @@
+use tracing::{debug, error};
+use prometheus_client::encoding::text::encode;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+use crate::fitbit::FitbitMetrics;
+use crate::fitbit::exposition::{parse_labels, parse_openmetrics, ExpositionRelabel, MetricFamily};
+
+/// Maximum size of a datagram, so that it isn't fragmented on a usual network (MTU of 1500 bytes).
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Options of the StatsD emitter, built from the command line arguments (see `cmd::Args::statsd_options`).
+#[derive(Debug, Clone)]
+pub struct StatsdOptions {
+    /// The address of the StatsD server (or Datadog agent), e.g. "localhost:8125".
+    pub address: String,
+    /// The prefix of the metric names, e.g. "health.". Prepended as is.
+    pub prefix: String,
+    /// Send the labels as DogStatsD tags, e.g. `fitbit_sleep_stage_seconds:4980|g|#stage:deep`. Otherwise, the label
+    /// values are appended to the name, e.g. `fitbit_sleep_stage_seconds.deep:4980|g`.
+    pub dogstatsd: bool,
+    /// The metric name prefix and the constant labels (sent as tags or appended to the name, like the others).
+    pub relabel: ExpositionRelabel,
+}
+
+/// Emits the metrics as StatsD gauges over UDP after every update of the metrics, be it by a scrape or by the
+/// background poller, which has to run along so that the metrics are emitted without scrapes.
+///
+/// The samples with their own timestamp (e.g. the historical steps) are skipped, since StatsD has no notion of it.
+/// A failed emission is logged and the next update is emitted anyway.
+/// FYI: https://docs.datadoghq.com/developers/dogstatsd/datagram_shell/
+///
+/// # Arguments
+///
+/// * `fitbit_metrics` - An `Arc<FitbitMetrics>` containing the shared Fitbit metrics.
+/// * `options` - The `StatsdOptions`.
+pub async fn emit_to_statsd(fitbit_metrics: Arc<FitbitMetrics>, options: StatsdOptions) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("[emit_to_statsd] Error binding the UDP socket: {}", err);
+            return;
+        }
+    };
+    loop {
+        fitbit_metrics.refreshed.notified().await;
+        match emit(&socket, &fitbit_metrics, &options).await {
+            Ok(datagrams) => debug!("[emit_to_statsd] Sent {} datagrams to {}", datagrams, options.address),
+            Err(err) => error!("[emit_to_statsd] Error sending the metrics to {}: {}", options.address, err),
+        }
+    }
+}
+
+async fn emit(socket: &UdpSocket, fitbit_metrics: &FitbitMetrics, options: &StatsdOptions) -> Result<usize, Box<dyn Error>> {
+    let mut txt = String::new();
+    encode(&mut txt, &fitbit_metrics.registry())?;
+    let datagrams = pack_datagrams(encode_statsd(&parse_openmetrics(&options.relabel.apply(txt)), options));
+    for datagram in &datagrams {
+        socket.send_to(datagram.as_bytes(), &options.address).await?;
+    }
+    Ok(datagrams.len())
+}
+
+/// Encodes the samples without a timestamp as gauge lines, e.g. `fitbit_steps:8123|g`.
+fn encode_statsd(families: &[MetricFamily], options: &StatsdOptions) -> Vec<String> {
+    let mut lines = Vec::new();
+    for sample in families.iter().flat_map(|family| &family.samples) {
+        if sample.timestamp_ms.is_some() || !sample.value.is_finite() {
+            continue;
+        }
+        // An empty label is the same as no label, e.g. `activity_name` without `--activity-name-label`
+        let labels: Vec<(String, String)> = parse_labels(&sample.labels).into_iter().filter(|(_, value)| !value.is_empty()).collect();
+        let mut name = format!("{}{}", options.prefix, sanitize(&sample.name));
+        let mut tags = String::new();
+        if options.dogstatsd {
+            let tag_list: Vec<String> = labels.iter().map(|(name, value)| format!("{}:{}", sanitize(name), sanitize(value))).collect();
+            if !tag_list.is_empty() {
+                tags = format!("|#{}", tag_list.join(","));
+            }
+        } else {
+            for (_, value) in &labels {
+                name.push('.');
+                name.push_str(&sanitize(value));
+            }
+        }
+        // A signed value is a delta in StatsD, so a negative gauge is reset to 0 first.
+        if sample.value < 0.0 {
+            lines.push(format!("{}:0|g{}", name, tags));
+        }
+        lines.push(format!("{}:{}|g{}", name, sample.value, tags));
+    }
+    lines
+}
+
+/// Packs the lines into newline separated datagrams of at most `MAX_DATAGRAM_SIZE` bytes.
+fn pack_datagrams(lines: Vec<String>) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM_SIZE => {
+                datagram.push('\n');
+                datagram.push_str(&line);
+            }
+            _ => datagrams.push(line),
+        }
+    }
+    datagrams
+}
+
+/// Replaces the characters that have a meaning in the StatsD protocol (`:`, `|`, `,`, `#`, spaces...) by `_`.
+fn sanitize(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' { c } else { '_' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::exposition::Sample;
+
+    #[test]
+    fn samples_are_encoded_as_gauges() {
+        let families = vec![MetricFamily::gauge(
+            "fitbit_steps",
+            vec![
+                Sample::new("fitbit_steps", "", 8123.0, None),
+                Sample::new("fitbit_steps", "", 7000.0, Some(1677801600000)),
+                Sample::new("fitbit_sleep_stage_seconds", "stage=\"deep\"", 4980.5, None),
+                Sample::new("fitbit_weight_change_kg", "", -1.5, None),
+            ],
+        )];
+
+        let mut options = StatsdOptions {
+            address: "localhost:8125".to_string(),
+            prefix: "health.".to_string(),
+            dogstatsd: true,
+            relabel: ExpositionRelabel::default(),
+        };
+        assert_eq!(
+            encode_statsd(&families, &options),
+            vec![
+                "health.fitbit_steps:8123|g",
+                "health.fitbit_sleep_stage_seconds:4980.5|g|#stage:deep",
+                "health.fitbit_weight_change_kg:0|g",
+                "health.fitbit_weight_change_kg:-1.5|g",
+            ]
+        );
+        options.dogstatsd = false;
+        assert_eq!(encode_statsd(&families, &options)[1], "health.fitbit_sleep_stage_seconds.deep:4980.5|g");
+    }
+
+    #[test]
+    fn lines_are_packed_into_datagrams() {
+        let line = "x".repeat(MAX_DATAGRAM_SIZE / 2 - 1);
+        let datagrams = pack_datagrams(vec![line.clone(), line.clone(), line.clone()]);
+        assert_eq!(datagrams, vec![format!("{}\n{}", line, line), line]);
+    }
+}
@@ -0,0 +1,76 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::fitbit::events::LoggedEvent;
+
+/// Summary of the effective configuration, logged at startup and served as JSON by `/status`, so that operators
+/// can verify the configuration from the logs alone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StartupSummary {
+    pub version: &'static str,
+    /// The address of the metrics endpoints, e.g. "0.0.0.0:8080".
+    pub listen_addr: String,
+    /// The port of the dedicated listener of the webhook, if any.
+    pub webhook_port: Option<u16>,
+    pub tls: bool,
+    pub auth: bool,
+    /// The collectors run by an update of the metrics.
+    pub collectors: Vec<String>,
+    /// When the metrics are updated, e.g. "on scrape" or "every 300s".
+    pub poll: String,
+    /// The encoded ID of the Fitbit user, or `None` if it couldn't be fetched.
+    pub user_id: Option<String>,
+}
+
+impl fmt::Display for StartupSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+        write!(
+            f,
+            "version={} listen={} tls={} auth={} collectors={} poll=\"{}\" user={}",
+            self.version,
+            self.listen_addr,
+            on_off(self.tls),
+            on_off(self.auth),
+            self.collectors.join(","),
+            self.poll,
+            self.user_id.as_deref().unwrap_or("-"),
+        )?;
+        if let Some(port) = self.webhook_port {
+            write!(f, " webhook_port={}", port)?;
+        }
+        Ok(())
+    }
+}
+
+/// The JSON served by `/status`: the fields of the `StartupSummary`, and the last significant events under
+/// `events`, the most recent first.
+#[derive(Debug, Serialize)]
+pub struct StatusResponse<'a> {
+    #[serde(flatten)]
+    pub summary: &'a StartupSummary,
+    pub events: Vec<LoggedEvent>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn startup_summary_display() {
+        let summary = StartupSummary {
+            version: "0.1.0",
+            listen_addr: "0.0.0.0:8080".to_string(),
+            webhook_port: None,
+            tls: false,
+            auth: true,
+            collectors: vec!["steps".to_string(), "sleep".to_string()],
+            poll: "every 300s".to_string(),
+            user_id: Some("ABC123".to_string()),
+        };
+        assert_eq!(
+            summary.to_string(),
+            "version=0.1.0 listen=0.0.0.0:8080 tls=off auth=on collectors=steps,sleep poll=\"every 300s\" user=ABC123"
+        );
+    }
+}
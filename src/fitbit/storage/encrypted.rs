@@ -0,0 +1,140 @@
+use base64::engine::general_purpose;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use super::{Storage, StorageError};
+
+/// The prefix of the encrypted values, followed by the nonce and the sealed value. The values without it were
+/// persisted before the encryption was enabled.
+const MAGIC: &[u8] = b"fitbit-aes256gcm:";
+
+/// The AES-256 key of an `EncryptedStorage`, given as 32 bytes encoded in base64, e.g. generated with
+/// `openssl rand -base64 32`.
+pub struct EncryptionKey(Zeroizing<Vec<u8>>);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(***)")
+    }
+}
+
+impl FromStr for EncryptionKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = Zeroizing::new(general_purpose::STANDARD.decode(s.trim()).map_err(|_| "Invalid encryption key: not base64".to_string())?);
+        match key.len() == AES_256_GCM.key_len() {
+            true => Ok(EncryptionKey(key)),
+            false => Err(format!("Invalid encryption key: {} bytes (expected {})", key.len(), AES_256_GCM.key_len())),
+        }
+    }
+}
+
+/// Storage encrypting the values of another storage with AES-256-GCM, so that a stolen token file (or a dump of the
+/// Redis database) cannot be used without the key.
+///
+/// Each value is sealed with a random nonce, and authenticated with its key, so that an encrypted value cannot be
+/// moved to another key. The plaintext values persisted before the encryption was enabled are still read, and
+/// encrypted when they're next written, e.g. at the next token refresh.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl fmt::Debug for EncryptedStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedStorage").field("inner", &self.inner).finish_non_exhaustive()
+    }
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, key: &EncryptionKey) -> Self {
+        // The length of the key is checked when it's parsed
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key.0).expect("Invalid AES-256 key length"));
+        Self { inner, key, rng: SystemRandom::new() }
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let Some(value) = self.inner.get(key)? else { return Ok(None) };
+        let Some(sealed) = value.strip_prefix(MAGIC) else {
+            warn!("The value of {} is not encrypted yet. It will be encrypted when it's next written.", key);
+            return Ok(Some(value));
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err(StorageError::Encryption(format!("The value of {} is truncated", key)));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| StorageError::Encryption(format!("Invalid nonce of {}", key)))?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::from(key.as_bytes()), &mut in_out)
+            .map_err(|_| StorageError::Encryption(format!("Cannot decrypt the value of {} (wrong key or tampered value)", key)))?;
+        Ok(Some(plaintext.to_vec()))
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| StorageError::Encryption("Cannot generate a nonce".to_string()))?;
+        let mut in_out = value.to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(key.as_bytes()), &mut in_out)
+            .map_err(|_| StorageError::Encryption(format!("Cannot encrypt the value of {}", key)))?;
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + in_out.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&in_out);
+        self.inner.put(key, &sealed)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.delete(key)
+    }
+
+    fn try_lock(&self, key: &str, ttl: Duration) -> Result<bool, StorageError> {
+        self.inner.try_lock(key, ttl)
+    }
+
+    fn unlock(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.unlock(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::storage::MemoryStorage;
+
+    #[test]
+    fn values_are_encrypted_and_authenticated() {
+        let key: EncryptionKey = general_purpose::STANDARD.encode([7u8; 32]).parse().unwrap();
+        let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        inner.put("legacy", b"{\"refresh_token\":\"r0\"}").unwrap();
+        let storage = EncryptedStorage::new(inner.clone(), &key);
+
+        storage.put("tokens", b"{\"refresh_token\":\"r1\"}").unwrap();
+        assert_eq!(storage.get("tokens").unwrap(), Some(b"{\"refresh_token\":\"r1\"}".to_vec()));
+        let sealed = inner.get("tokens").unwrap().unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert!(!sealed.windows(13).any(|window| window == b"refresh_token"));
+        // The plaintext values persisted before the encryption are still read
+        assert_eq!(storage.get("legacy").unwrap(), Some(b"{\"refresh_token\":\"r0\"}".to_vec()));
+
+        // A value moved to another key, or read with another key, is rejected
+        inner.put("moved", &sealed).unwrap();
+        assert!(matches!(storage.get("moved"), Err(StorageError::Encryption(_))));
+        let other_key: EncryptionKey = general_purpose::STANDARD.encode([8u8; 32]).parse().unwrap();
+        assert!(EncryptedStorage::new(inner, &other_key).get("tokens").is_err());
+        assert!("c2hvcnQ=".parse::<EncryptionKey>().is_err());
+    }
+}
@@ -0,0 +1,26 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::{Storage, StorageError};
+
+/// Storage kept in memory, e.g. for tests or when nothing needs to survive a restart.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        self.entries.lock().unwrap().insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
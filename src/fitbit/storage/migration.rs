@@ -0,0 +1,69 @@
+use tracing::info;
+use std::sync::Arc;
+
+use super::{Storage, StorageError};
+
+/// Version of the format of the persisted state written by this release.
+pub const STATE_VERSION: u32 = 1;
+
+/// Key of the version of the persisted state.
+const VERSION_KEY: &str = "version";
+
+/// An upgrade of the persisted state from one version to the next.
+type Migration = fn(&Arc<dyn Storage>) -> Result<(), StorageError>;
+
+/// The migrations, the one at index `i` upgrading the state from version `i` to `i + 1`.
+const MIGRATIONS: [Migration; STATE_VERSION as usize] = [stamp_unversioned_state];
+
+/// Upgrades the persisted state to `STATE_VERSION`, so that upgrading the exporter never requires deleting the
+/// state (e.g. the rotated tokens) and starting over.
+///
+/// Each migration is followed by saving the version it upgraded to, so that an interrupted upgrade resumes from
+/// the last completed migration.
+///
+/// # Errors
+///
+/// Returns `StorageError::UnsupportedVersion` if the state was written by a newer release, which this one cannot
+/// read, or any error of the storage or of a migration.
+///
+/// # Returns
+///
+/// The version the state was at before the upgrade.
+pub fn migrate(storage: &Arc<dyn Storage>) -> Result<u32, StorageError> {
+    let version = storage.get_json::<u32>(VERSION_KEY)?.unwrap_or(0);
+    if version > STATE_VERSION {
+        return Err(StorageError::UnsupportedVersion { found: version, supported: STATE_VERSION });
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Migrating the persisted state from version {} to {}", from, from + 1);
+        migration(storage)?;
+        storage.put_json(VERSION_KEY, &(from as u32 + 1))?;
+    }
+    Ok(version)
+}
+
+/// Version 0 is the unversioned state of the earlier releases (or no state at all), whose format is the same as
+/// version 1's. Only the version is stamped.
+fn stamp_unversioned_state(_storage: &Arc<dyn Storage>) -> Result<(), StorageError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitbit::storage::MemoryStorage;
+
+    #[test]
+    fn state_is_migrated_to_the_current_version() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        storage.put("tokens", b"{}").unwrap();
+
+        assert_eq!(migrate(&storage).unwrap(), 0);
+        assert_eq!(storage.get_json::<u32>(VERSION_KEY).unwrap(), Some(STATE_VERSION));
+        assert_eq!(storage.get("tokens").unwrap(), Some(b"{}".to_vec()));
+        assert_eq!(migrate(&storage).unwrap(), STATE_VERSION);
+
+        storage.put_json(VERSION_KEY, &(STATE_VERSION + 1)).unwrap();
+        assert!(matches!(migrate(&storage), Err(StorageError::UnsupportedVersion { .. })));
+    }
+}
@@ -0,0 +1,321 @@
+//! Key-value storage of the state that must outlive a scrape, or a restart: tokens, cached responses, backfill
+//! progress, streaks...
+//!
+//! The consumers only see the `Storage` trait, so that adding a backend touches this module only.
+//! Keys are namespaced by their consumer, e.g. "tokens" or "cache/steps". The format of the state is versioned,
+//! and upgraded on startup by the migrations of `migration`.
+//!
+//! The fetched samples are archived separately, by a `SampleStore` (see `SampleStoreConfig`).
+
+use chrono::{Duration as ChronoDuration, FixedOffset, NaiveDate, NaiveTime, TimeZone};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::fitbit::exposition::{MetricFamily, Sample};
+
+mod encrypted;
+mod file;
+mod memory;
+pub mod migration;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use self::encrypted::{EncryptedStorage, EncryptionKey};
+pub use self::file::FileStorage;
+pub use self::memory::MemoryStorage;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStorage;
+#[cfg(feature = "sled")]
+pub use self::sled::SledStorage;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteSampleStore;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("Storage backend error: {0}")]
+    Backend(String),
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Unsupported version {found} of the persisted state (this release supports up to version {supported})")]
+    UnsupportedVersion { found: u32, supported: u32 },
+}
+
+/// A key-value store shared by the consumers of persistent state.
+///
+/// Implementations must be safe to share between tasks. The operations are synchronous: the values are small and
+/// written rarely (e.g. once per token refresh), so blocking briefly is cheaper than an async trait. The callers on
+/// the async runtime run them with `spawn_blocking`, since a network backend (Redis) may block for a while.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Returns the value of the key, or `None` if it's not set.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Sets the value of the key, replacing the previous one.
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError>;
+
+    /// Removes the key. Removing a missing key is not an error.
+    fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Takes the lock named by the key for at most `ttl`, e.g. so that a single replica exchanges the shared refresh
+    /// token. Returns false if it's held by someone else.
+    ///
+    /// The backends that are local to a process grant it right away: the tokens of the process are only refreshed
+    /// by its `FitbitClient`, which serializes the refreshes.
+    fn try_lock(&self, _key: &str, _ttl: Duration) -> Result<bool, StorageError> {
+        Ok(true)
+    }
+
+    /// Releases a lock taken with `try_lock`.
+    fn unlock(&self, _key: &str) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+impl dyn Storage {
+    /// Returns the value of the key deserialized from JSON, or `None` if it's not set.
+    pub fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StorageError> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of the key serialized as JSON.
+    pub fn put_json<T: Serialize>(&self, key: &str, value: &T) -> Result<(), StorageError> {
+        self.put(key, &serde_json::to_vec(value)?)
+    }
+}
+
+/// An archive of the fetched samples, from which /metrics is served.
+///
+/// Each update of the metrics records a snapshot of the registry. The samples are deduplicated by series and
+/// timestamp, so that re-recording the same historical points (e.g. a backfill) doesn't duplicate them.
+///
+/// As for `Storage`, the operations are synchronous: the callers on the async runtime run them with `run_blocking`.
+pub trait SampleStore: fmt::Debug + Send + Sync {
+    /// Records the metric families of an update made at `recorded_at_ms` (milliseconds since the epoch).
+    fn record(&self, families: &[MetricFamily], recorded_at_ms: i64) -> Result<(), StorageError>;
+
+    /// Returns the metric families of the last recorded update, or an empty list if nothing was recorded yet.
+    fn latest(&self) -> Result<Vec<MetricFamily>, StorageError>;
+
+    /// Returns the most recent timestamp (in milliseconds) of the samples of the family that have their own
+    /// timestamp, e.g. the historical steps, or `None` if there are none.
+    fn last_timestamp(&self, family: &str) -> Result<Option<i64>, StorageError>;
+
+    /// Returns the samples of the family from `start_ms` to `end_ms` (inclusive), ordered by series and timestamp.
+    ///
+    /// Every returned sample has a timestamp: the samples recorded without one are stamped with the time of the
+    /// update that recorded them.
+    fn series(&self, family: &str, start_ms: i64, end_ms: i64) -> Result<Vec<Sample>, StorageError>;
+
+    /// Deletes the samples whose timestamp is before `before_ms`, so that the archive doesn't grow forever. Returns
+    /// the number of deleted samples.
+    fn prune(&self, before_ms: i64) -> Result<usize, StorageError>;
+}
+
+/// Runs an operation of the sample store on the blocking threads, since SQLite blocks on the disk.
+///
+/// # Errors
+///
+/// Returns the `StorageError` of the operation, or `StorageError::Backend` if it panicked.
+pub async fn run_blocking<T: Send + 'static>(
+    sample_store: &Arc<dyn SampleStore>,
+    operation: impl FnOnce(&dyn SampleStore) -> Result<T, StorageError> + Send + 'static,
+) -> Result<T, StorageError> {
+    let sample_store = sample_store.clone();
+    tokio::task::spawn_blocking(move || operation(&*sample_store))
+        .await
+        .map_err(|err| StorageError::Backend(err.to_string()))?
+}
+
+/// Returns the time (in milliseconds) at which the day starts in the user's timezone, e.g. to read the samples of a
+/// range of days with `SampleStore::series`.
+pub fn day_start_ms(date: NaiveDate, utc_offset: FixedOffset) -> i64 {
+    utc_offset.from_local_datetime(&date.and_time(NaiveTime::MIN)).unwrap().timestamp_millis()
+}
+
+/// Returns the range (in milliseconds, inclusive) of the days from `start` to `end` in the user's timezone, as given
+/// to `SampleStore::series`. An open bound covers all the archived samples on its side.
+pub fn day_range_ms(start: Option<NaiveDate>, end: Option<NaiveDate>, utc_offset: FixedOffset) -> (i64, i64) {
+    let start_ms = start.map_or(i64::MIN, |start| day_start_ms(start, utc_offset));
+    let end_ms = end.map_or(i64::MAX, |end| day_start_ms(end + ChronoDuration::days(1), utc_offset) - 1);
+    (start_ms, end_ms)
+}
+
+/// Returns the value of each day (in the user's timezone) of each series of the archived samples, keyed by their
+/// labels: the one of the last sample of the day, since the daily totals grow during the day.
+pub fn daily_values_by_series(samples: &[Sample], utc_offset: FixedOffset) -> BTreeMap<String, BTreeMap<NaiveDate, f64>> {
+    let mut last_samples: BTreeMap<String, BTreeMap<NaiveDate, (i64, f64)>> = BTreeMap::new();
+    for sample in samples {
+        let Some(timestamp_ms) = sample.timestamp_ms else { continue };
+        let Some(date) = utc_offset.timestamp_millis_opt(timestamp_ms).single().map(|datetime| datetime.date_naive()) else { continue };
+        let last = last_samples.entry(sample.labels.clone()).or_default().entry(date).or_insert((timestamp_ms, sample.value));
+        if timestamp_ms >= last.0 {
+            *last = (timestamp_ms, sample.value);
+        }
+    }
+    last_samples
+        .into_iter()
+        .map(|(labels, days)| (labels, days.into_iter().map(|(date, (_, value))| (date, value)).collect()))
+        .collect()
+}
+
+/// The sample store, given as "sqlite:<path>".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SampleStoreConfig {
+    /// A SQLite database at the given path (`sqlite` feature).
+    Sqlite(PathBuf),
+}
+
+impl SampleStoreConfig {
+    /// Opens the configured sample store.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError` if the store cannot be opened, e.g. the database is corrupted.
+    pub fn open(&self) -> Result<Arc<dyn SampleStore>, StorageError> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            SampleStoreConfig::Sqlite(path) => Ok(Arc::new(SqliteSampleStore::open(path)?)),
+            #[cfg(not(feature = "sqlite"))]
+            SampleStoreConfig::Sqlite(_) => Err(StorageError::Backend("fitbit_exporter was built without the `sqlite` feature".to_string())),
+        }
+    }
+}
+
+impl FromStr for SampleStoreConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("sqlite", path)) if !path.is_empty() => Ok(SampleStoreConfig::Sqlite(PathBuf::from(path))),
+            _ => Err(format!("Invalid sample store: {} (expected sqlite:<path>)", s)),
+        }
+    }
+}
+
+/// The storage backend, given as "memory", "file:<directory>", "sled:<path>" or "redis://<host>[:<port>][/<db>]".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageConfig {
+    /// Kept in memory, i.e. lost on restart.
+    Memory,
+    /// One file per key in the given directory.
+    File(PathBuf),
+    /// An embedded sled database at the given path (`sled` feature).
+    Sled(PathBuf),
+    /// A Redis server at the given URL (`redis` feature), to share the state between replicas.
+    Redis(String),
+}
+
+impl StorageConfig {
+    /// Opens the configured storage, and migrates its state to the current version (see `migration::migrate`).
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError` if the storage cannot be opened, e.g. the directory cannot be created, or if its
+    /// state cannot be migrated.
+    pub fn open(&self) -> Result<Arc<dyn Storage>, StorageError> {
+        let storage = self.open_backend()?;
+        migration::migrate(&storage)?;
+        Ok(storage)
+    }
+
+    fn open_backend(&self) -> Result<Arc<dyn Storage>, StorageError> {
+        match self {
+            StorageConfig::Memory => Ok(Arc::new(MemoryStorage::default())),
+            StorageConfig::File(dir) => Ok(Arc::new(FileStorage::open(dir)?)),
+            #[cfg(feature = "sled")]
+            StorageConfig::Sled(path) => Ok(Arc::new(SledStorage::open(path)?)),
+            #[cfg(not(feature = "sled"))]
+            StorageConfig::Sled(_) => Err(StorageError::Backend("fitbit_exporter was built without the `sled` feature".to_string())),
+            #[cfg(feature = "redis")]
+            StorageConfig::Redis(url) => Ok(Arc::new(RedisStorage::open(url)?)),
+            #[cfg(not(feature = "redis"))]
+            StorageConfig::Redis(_) => Err(StorageError::Backend("fitbit_exporter was built without the `redis` feature".to_string())),
+        }
+    }
+}
+
+impl FromStr for StorageConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(StorageConfig::Memory),
+            Some(("file", path)) if !path.is_empty() => Ok(StorageConfig::File(PathBuf::from(path))),
+            Some(("sled", path)) if !path.is_empty() => Ok(StorageConfig::Sled(PathBuf::from(path))),
+            Some(("redis", address)) if address.starts_with("//") => Ok(StorageConfig::Redis(s.to_string())),
+            _ => Err(format!("Invalid storage: {} (expected memory, file:<directory>, sled:<path> or redis://<host>)", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daily_values_are_grouped_by_the_days_of_the_user() {
+        let tokyo = FixedOffset::east_opt(9 * 3600).unwrap();
+        let date = |day: u32| NaiveDate::from_ymd_opt(2024, 5, day).unwrap();
+        let at = |day: u32, hour: i64| day_start_ms(date(day), tokyo) + hour * 3600 * 1000;
+        assert_eq!(day_start_ms(date(20), tokyo), 1716130800000);
+        assert_eq!(day_range_ms(Some(date(20)), Some(date(20)), tokyo), (at(20, 0), at(21, 0) - 1));
+
+        // The last sample of the day is its total. 2024-05-20T16:00:00Z is already the 21st in Tokyo.
+        let samples = vec![
+            Sample::new("fitbit_steps", "", 4000.0, Some(at(20, 12))),
+            Sample::new("fitbit_steps", "", 12000.0, Some(at(20, 23))),
+            Sample::new("fitbit_steps", "", 300.0, Some(1716220800000)),
+            Sample::new("fitbit_steps", "user=\"b\"", 3000.0, Some(at(21, 23))),
+        ];
+        let values = daily_values_by_series(&samples, tokyo);
+        assert_eq!(values[""], BTreeMap::from([(date(20), 12000.0), (date(21), 300.0)]));
+        assert_eq!(values["user=\"b\""], BTreeMap::from([(date(21), 3000.0)]));
+    }
+
+    #[test]
+    fn storage_config_from_str() {
+        assert_eq!("memory".parse(), Ok(StorageConfig::Memory));
+        assert_eq!("file:/var/lib/fitbit_exporter".parse(), Ok(StorageConfig::File(PathBuf::from("/var/lib/fitbit_exporter"))));
+        assert_eq!("sled:state.db".parse(), Ok(StorageConfig::Sled(PathBuf::from("state.db"))));
+        assert!("file:".parse::<StorageConfig>().is_err());
+        assert_eq!("redis://localhost:6379/0".parse(), Ok(StorageConfig::Redis("redis://localhost:6379/0".to_string())));
+        assert!("redis".parse::<StorageConfig>().is_err());
+        assert_eq!("sqlite:samples.db".parse(), Ok(SampleStoreConfig::Sqlite(PathBuf::from("samples.db"))));
+        assert!("samples.db".parse::<SampleStoreConfig>().is_err());
+    }
+
+    #[test]
+    fn json_values_round_trip() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        assert_eq!(storage.get_json::<Vec<u64>>("cache/steps").unwrap(), None);
+        storage.put_json("cache/steps", &vec![8123u64, 0]).unwrap();
+        assert_eq!(storage.get_json::<Vec<u64>>("cache/steps").unwrap(), Some(vec![8123, 0]));
+        storage.delete("cache/steps").unwrap();
+        storage.delete("cache/steps").unwrap();
+        assert_eq!(storage.get("cache/steps").unwrap(), None);
+    }
+}
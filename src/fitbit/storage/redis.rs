@@ -0,0 +1,93 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{Storage, StorageError};
+
+// Prefix of the keys, so that the exporter can share a Redis database with other applications.
+const KEY_PREFIX: &str = "fitbit_exporter:";
+
+// Deletes a lock only if it's still held by the given owner, and not by another replica that took it after it expired
+const UNLOCK_SCRIPT: &str = "if redis.call('GET', KEYS[1]) == ARGV[1] then return redis.call('DEL', KEYS[1]) else return 0 end";
+
+/// Storage in Redis, so that several exporter replicas (or the exporter and its sidecars) share the same state,
+/// e.g. the rotated tokens.
+///
+/// A single connection is reused for all the operations, and opened again on the next operation after an error.
+/// The locks are keys set with `SET NX PX`, whose value identifies the replica holding them.
+pub struct RedisStorage {
+    client: ::redis::Client,
+    connection: Mutex<Option<::redis::Connection>>,
+    lock_owner: String,
+}
+
+// Implemented by hand since the URL may contain a password.
+impl std::fmt::Debug for RedisStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStorage")
+            .field("addr", &self.client.get_connection_info().addr.to_string())
+            .finish()
+    }
+}
+
+impl RedisStorage {
+    /// Connects to Redis at the given URL, e.g. "redis://:password@localhost:6379/0".
+    ///
+    /// # Errors
+    ///
+    /// Returns `StorageError::Backend` if the URL is invalid or Redis cannot be reached.
+    pub fn open(url: &str) -> Result<Self, StorageError> {
+        let client = ::redis::Client::open(url).map_err(backend_error)?;
+        let connection = client.get_connection().map_err(backend_error)?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let lock_owner = format!("{}-{}", std::process::id(), started);
+        Ok(Self { client, connection: Mutex::new(Some(connection)), lock_owner })
+    }
+
+    /// Runs a command on the shared connection, reconnecting first if the previous command failed.
+    fn run<T>(&self, command: impl FnOnce(&mut ::redis::Connection) -> ::redis::RedisResult<T>) -> Result<T, StorageError> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(self.client.get_connection().map_err(backend_error)?);
+        }
+        let result = command(connection.as_mut().unwrap());
+        if result.is_err() {
+            *connection = None;
+        }
+        result.map_err(backend_error)
+    }
+}
+
+impl Storage for RedisStorage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        self.run(|connection| ::redis::cmd("GET").arg(format!("{}{}", KEY_PREFIX, key)).query(connection))
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        self.run(|connection| ::redis::cmd("SET").arg(format!("{}{}", KEY_PREFIX, key)).arg(value).query(connection))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.run(|connection| ::redis::cmd("DEL").arg(format!("{}{}", KEY_PREFIX, key)).query(connection))
+    }
+
+    fn try_lock(&self, key: &str, ttl: Duration) -> Result<bool, StorageError> {
+        let reply: Option<String> = self.run(|connection| {
+            ::redis::cmd("SET")
+                .arg(format!("{}{}", KEY_PREFIX, key))
+                .arg(&self.lock_owner)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .query(connection)
+        })?;
+        Ok(reply.is_some())
+    }
+
+    fn unlock(&self, key: &str) -> Result<(), StorageError> {
+        self.run(|connection| ::redis::cmd("EVAL").arg(UNLOCK_SCRIPT).arg(1).arg(format!("{}{}", KEY_PREFIX, key)).arg(&self.lock_owner).query(connection))
+    }
+}
+
+fn backend_error(err: ::redis::RedisError) -> StorageError {
+    StorageError::Backend(err.to_string())
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use super::{Storage, StorageError};
+
+/// Storage in an embedded sled database, for a single exporter that wants transactional writes without
+/// managing a directory of files.
+#[derive(Debug)]
+pub struct SledStorage {
+    db: ::sled::Db,
+}
+
+impl SledStorage {
+    /// Opens (or creates) the database at the given path.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StorageError::Backend` if the database cannot be opened, e.g. it's locked by another process.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        let db = ::sled::open(path).map_err(backend_error)?;
+        Ok(Self { db })
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.db.get(key).map_err(backend_error)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        self.db.insert(key, value).map_err(backend_error)?;
+        self.db.flush().map_err(backend_error)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.db.remove(key).map_err(backend_error)?;
+        self.db.flush().map_err(backend_error)?;
+        Ok(())
+    }
+}
+
+fn backend_error(err: ::sled::Error) -> StorageError {
+    StorageError::Backend(err.to_string())
+}
@@ -0,0 +1,267 @@
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{SampleStore, StorageError};
+use crate::fitbit::exposition::{MetricFamily, Sample};
+
+/// The migrations of the schema, the one at index `i` upgrading it from version `i` to `i + 1`. The version is kept
+/// in the `user_version` of the database. The databases created before the schema was versioned are at version 0,
+/// but already have the tables of version 1, hence the `IF NOT EXISTS`.
+const SCHEMA_MIGRATIONS: [&str; 2] = ["
+    CREATE TABLE IF NOT EXISTS families (
+        name TEXT PRIMARY KEY,
+        metric_type TEXT NOT NULL,
+        help TEXT NOT NULL,
+        unit TEXT,
+        position INTEGER NOT NULL,
+        recorded_at_ms INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS samples (
+        family TEXT NOT NULL,
+        name TEXT NOT NULL,
+        labels TEXT NOT NULL,
+        timestamp_ms INTEGER NOT NULL,
+        value REAL NOT NULL,
+        timestamped INTEGER NOT NULL,
+        recorded_at_ms INTEGER NOT NULL,
+        PRIMARY KEY (name, labels, timestamp_ms)
+    );
+    CREATE INDEX IF NOT EXISTS samples_recorded_at_ms ON samples (recorded_at_ms);
+", "
+    CREATE INDEX samples_timestamp_ms ON samples (timestamp_ms);
+"];
+
+/// Archive of the fetched samples in a SQLite database.
+///
+/// The samples without a timestamp are stamped with the time of the update that recorded them, so that the archive
+/// keeps one row per series and update. The samples with a timestamp (e.g. the historical steps) keep it, and are
+/// only updated when they're recorded again.
+#[derive(Debug)]
+pub struct SqliteSampleStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteSampleStore {
+    /// Opens (or creates) the database at the given path.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StorageError::Backend` if the database cannot be opened or its schema cannot be migrated, or
+    /// `StorageError::UnsupportedVersion` if it was created by a newer release.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        Self::with_connection(Connection::open(path).map_err(backend_error)?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self, StorageError> {
+        migrate_schema(&mut connection)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+}
+
+/// Upgrades the schema to the last version of `SCHEMA_MIGRATIONS`, each migration in its own transaction.
+fn migrate_schema(connection: &mut Connection) -> Result<(), StorageError> {
+    let supported = SCHEMA_MIGRATIONS.len() as u32;
+    let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(backend_error)?;
+    if version > supported {
+        return Err(StorageError::UnsupportedVersion { found: version, supported });
+    }
+    for (from, migration) in SCHEMA_MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let transaction = connection.transaction().map_err(backend_error)?;
+        transaction.execute_batch(migration).map_err(backend_error)?;
+        transaction.pragma_update(None, "user_version", from as u32 + 1).map_err(backend_error)?;
+        transaction.commit().map_err(backend_error)?;
+    }
+    Ok(())
+}
+
+impl SampleStore for SqliteSampleStore {
+    fn record(&self, families: &[MetricFamily], recorded_at_ms: i64) -> Result<(), StorageError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(backend_error)?;
+        {
+            let mut insert_family = transaction
+                .prepare_cached(
+                    "INSERT INTO families (name, metric_type, help, unit, position, recorded_at_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     ON CONFLICT (name) DO UPDATE SET metric_type = excluded.metric_type, help = excluded.help,
+                         unit = excluded.unit, position = excluded.position, recorded_at_ms = excluded.recorded_at_ms",
+                )
+                .map_err(backend_error)?;
+            let mut insert_sample = transaction
+                .prepare_cached(
+                    "INSERT INTO samples (family, name, labels, timestamp_ms, value, timestamped, recorded_at_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                     ON CONFLICT (name, labels, timestamp_ms) DO UPDATE SET family = excluded.family, value = excluded.value,
+                         timestamped = excluded.timestamped, recorded_at_ms = excluded.recorded_at_ms",
+                )
+                .map_err(backend_error)?;
+
+            for (position, family) in families.iter().enumerate() {
+                insert_family
+                    .execute(params![family.name, family.metric_type, family.help, family.unit, position as i64, recorded_at_ms])
+                    .map_err(backend_error)?;
+                for sample in &family.samples {
+                    insert_sample
+                        .execute(params![
+                            family.name,
+                            sample.name,
+                            sample.labels,
+                            sample.timestamp_ms.unwrap_or(recorded_at_ms),
+                            sample.value,
+                            sample.timestamp_ms.is_some(),
+                            recorded_at_ms,
+                        ])
+                        .map_err(backend_error)?;
+                }
+            }
+        }
+        transaction.commit().map_err(backend_error)
+    }
+
+    fn latest(&self) -> Result<Vec<MetricFamily>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut families = connection
+            .prepare(
+                "SELECT name, metric_type, help, unit FROM families
+                 WHERE recorded_at_ms = (SELECT MAX(recorded_at_ms) FROM families) ORDER BY position",
+            )
+            .map_err(backend_error)?
+            .query_map([], |row| {
+                Ok(MetricFamily {
+                    name: row.get(0)?,
+                    metric_type: row.get(1)?,
+                    help: row.get(2)?,
+                    unit: row.get(3)?,
+                    samples: Vec::new(),
+                })
+            })
+            .map_err(backend_error)?
+            .collect::<Result<Vec<MetricFamily>, rusqlite::Error>>()
+            .map_err(backend_error)?;
+
+        let mut statement = connection
+            .prepare(
+                "SELECT family, name, labels, value, timestamp_ms, timestamped FROM samples
+                 WHERE recorded_at_ms = (SELECT MAX(recorded_at_ms) FROM families) ORDER BY name, labels, timestamp_ms",
+            )
+            .map_err(backend_error)?;
+        let samples = statement
+            .query_map([], |row| {
+                let timestamped: bool = row.get(5)?;
+                Ok((
+                    row.get::<_, String>(0)?,
+                    Sample {
+                        name: row.get(1)?,
+                        labels: row.get(2)?,
+                        value: row.get(3)?,
+                        timestamp_ms: if timestamped { Some(row.get(4)?) } else { None },
+                    },
+                ))
+            })
+            .map_err(backend_error)?;
+        for sample in samples {
+            let (family_name, sample) = sample.map_err(backend_error)?;
+            if let Some(family) = families.iter_mut().find(|family| family.name == family_name) {
+                family.samples.push(sample);
+            }
+        }
+
+        Ok(families)
+    }
+
+    fn last_timestamp(&self, family: &str) -> Result<Option<i64>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .query_row(
+                "SELECT MAX(timestamp_ms) FROM samples WHERE family = ?1 AND timestamped",
+                params![family],
+                |row| row.get(0),
+            )
+            .map_err(backend_error)
+    }
+
+    fn series(&self, family: &str, start_ms: i64, end_ms: i64) -> Result<Vec<Sample>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT name, labels, value, timestamp_ms FROM samples
+                 WHERE family = ?1 AND timestamp_ms BETWEEN ?2 AND ?3 ORDER BY name, labels, timestamp_ms",
+            )
+            .map_err(backend_error)?;
+        let samples = statement
+            .query_map(params![family, start_ms, end_ms], |row| {
+                Ok(Sample { name: row.get(0)?, labels: row.get(1)?, value: row.get(2)?, timestamp_ms: Some(row.get(3)?) })
+            })
+            .map_err(backend_error)?
+            .collect::<Result<Vec<Sample>, rusqlite::Error>>()
+            .map_err(backend_error);
+        samples
+    }
+
+    fn prune(&self, before_ms: i64) -> Result<usize, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM samples WHERE timestamp_ms < ?1", params![before_ms]).map_err(backend_error)
+    }
+}
+
+fn backend_error(err: rusqlite::Error) -> StorageError {
+    StorageError::Backend(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn family(name: &str, samples: Vec<Sample>) -> MetricFamily {
+        MetricFamily::gauge(name, samples)
+    }
+
+    fn sample(labels: &str, value: f64, timestamp_ms: Option<i64>) -> Sample {
+        Sample::new("fitbit_steps", labels, value, timestamp_ms)
+    }
+
+    #[test]
+    fn latest_returns_the_last_recording_and_dedupes_timestamped_samples() {
+        let store = SqliteSampleStore::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        assert_eq!(store.latest().unwrap(), vec![]);
+
+        let backfill = sample("", 7000.0, Some(1677888000000));
+        store.record(&[family("fitbit_steps", vec![backfill.clone(), sample("date=\"2023-03-04\"", 42.0, None)])], 1000).unwrap();
+        store.record(&[family("fitbit_steps", vec![backfill.clone(), sample("", 8123.0, None)])], 2000).unwrap();
+
+        assert_eq!(store.latest().unwrap(), vec![family("fitbit_steps", vec![sample("", 8123.0, None), backfill])]);
+        assert_eq!(store.last_timestamp("fitbit_steps").unwrap(), Some(1677888000000));
+        assert_eq!(store.last_timestamp("fitbit_water_ml").unwrap(), None);
+        assert_eq!(
+            store.series("fitbit_steps", 0, 1500).unwrap(),
+            vec![sample("date=\"2023-03-04\"", 42.0, Some(1000))]
+        );
+
+        let connection = store.connection.lock().unwrap();
+        let rows: i64 = connection.query_row("SELECT COUNT(*) FROM samples", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 3);
+    }
+
+    #[test]
+    fn prune_deletes_the_samples_before_the_retention() {
+        let store = SqliteSampleStore::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let backfill = sample("", 7000.0, Some(500));
+        store.record(&[family("fitbit_steps", vec![backfill, sample("", 42.0, None)])], 1000).unwrap();
+        store.record(&[family("fitbit_steps", vec![sample("", 8123.0, None)])], 2000).unwrap();
+
+        assert_eq!(store.prune(1500).unwrap(), 2);
+        assert_eq!(store.series("fitbit_steps", 0, i64::MAX).unwrap(), vec![sample("", 8123.0, Some(2000))]);
+        assert_eq!(store.latest().unwrap(), vec![family("fitbit_steps", vec![sample("", 8123.0, None)])]);
+    }
+
+    #[test]
+    fn schema_is_versioned() {
+        let store = SqliteSampleStore::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let mut connection = store.connection.into_inner().unwrap();
+        let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_MIGRATIONS.len() as u32);
+
+        connection.pragma_update(None, "user_version", version + 1).unwrap();
+        assert!(matches!(migrate_schema(&mut connection), Err(StorageError::UnsupportedVersion { .. })));
+    }
+}
@@ -0,0 +1,52 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::fitbit::server::TlsOptions;
+
+/// Builds a `TlsAcceptor` from the PEM encoded certificate chain and private key given in `TlsOptions`.
+///
+/// # Errors
+///
+/// Returns an error if the files cannot be read, if they contain no certificate or no private key
+/// (PKCS#8, RSA or SEC1), or if the key doesn't match the certificate.
+pub fn load_tls_acceptor(tls_options: &TlsOptions) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let certs = load_certs(&tls_options.cert_path)?;
+    let key = load_private_key(&tls_options.key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(format!("No private key found in {}", path.display()).into())
+}
@@ -0,0 +1,177 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Number of traces kept by a `TraceBuffer`, beyond which the oldest ones are dropped.
+const TRACE_CAPACITY: usize = 256;
+
+/// A closed span, e.g. a request to /metrics, with the spans opened within it, e.g. its requests to the Fitbit API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpanRecord {
+    /// The name of the span, e.g. "fetch_data".
+    pub name: &'static str,
+    /// The module of the span, e.g. "fitbit_exporter::fitbit::client".
+    pub target: &'static str,
+    /// The time at which the span was opened.
+    pub start: DateTime<Utc>,
+    /// The time from the opening to the closing of the span, in milliseconds.
+    pub duration_ms: f64,
+    /// The fields of the span, e.g. `endpoint`, `status` and `outcome`.
+    pub fields: Map<String, JsonValue>,
+    /// The spans opened within the span, in the order in which they were closed.
+    pub children: Vec<SpanRecord>,
+}
+
+/// The last traces, i.e. the top-level spans with their children, served as JSON by `/debug/traces`, e.g. to find
+/// which requests to the Fitbit API slowed down a scrape.
+#[derive(Debug, Default)]
+pub struct TraceBuffer {
+    // The traces, oldest first
+    traces: Mutex<VecDeque<SpanRecord>>,
+}
+
+impl TraceBuffer {
+    fn push(&self, trace: SpanRecord) {
+        let mut traces = self.traces.lock().unwrap();
+        if traces.len() == TRACE_CAPACITY {
+            traces.pop_front();
+        }
+        traces.push_back(trace);
+    }
+
+    /// Returns the traces lasting at least `min_duration_ms`, the most recent first.
+    pub fn recent(&self, min_duration_ms: f64) -> Vec<SpanRecord> {
+        self.traces.lock().unwrap().iter().rev().filter(|trace| trace.duration_ms >= min_duration_ms).cloned().collect()
+    }
+}
+
+/// Collects the fields of a span as JSON values, the numbers and booleans as such.
+#[derive(Debug, Default)]
+struct JsonFields(Map<String, JsonValue>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), JsonValue::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), JsonValue::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), JsonValue::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), JsonValue::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), JsonValue::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), JsonValue::from(format!("{:?}", value)));
+    }
+}
+
+/// The data of an open span, kept in its extensions.
+struct OpenSpan {
+    start: DateTime<Utc>,
+    started: Instant,
+    fields: JsonFields,
+    children: Vec<SpanRecord>,
+}
+
+/// Records the closed spans in a `TraceBuffer`: the top-level ones as traces, the others as the children of their
+/// parent.
+pub struct TraceLayer {
+    buffer: Arc<TraceBuffer>,
+}
+
+impl TraceLayer {
+    pub fn new(buffer: Arc<TraceBuffer>) -> Self {
+        Self { buffer }
+    }
+}
+
+impl<S> Layer<S> for TraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = JsonFields::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(OpenSpan { start: Utc::now(), started: Instant::now(), fields, children: Vec::new() });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(open_span) = extensions.get_mut::<OpenSpan>() {
+            values.record(&mut open_span.fields);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(open_span) = span.extensions_mut().remove::<OpenSpan>() else { return };
+        let record = SpanRecord {
+            name: span.name(),
+            target: span.metadata().target(),
+            start: open_span.start,
+            duration_ms: open_span.started.elapsed().as_secs_f64() * 1000.0,
+            fields: open_span.fields.0,
+            children: open_span.children,
+        };
+        match span.parent() {
+            Some(parent) => {
+                let mut extensions = parent.extensions_mut();
+                if let Some(parent_span) = extensions.get_mut::<OpenSpan>() {
+                    parent_span.children.push(record);
+                }
+            }
+            None => self.buffer.push(record),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn spans_are_recorded_as_traces_with_their_children() {
+        let buffer = Arc::new(TraceBuffer::default());
+        let subscriber = tracing_subscriber::registry().with(TraceLayer::new(buffer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let request = info_span!("request", endpoint = "/metrics", status = tracing::field::Empty);
+            request.in_scope(|| {
+                info_span!("fetch_data", endpoint = "https://api.fitbit.com/1/user/-/profile.json", status = 200u16).in_scope(|| {});
+            });
+            request.record("status", 200u16);
+        });
+
+        let traces = buffer.recent(0.0);
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].name, "request");
+        assert_eq!(JsonValue::Object(traces[0].fields.clone()), serde_json::json!({"endpoint": "/metrics", "status": 200}));
+        assert_eq!(traces[0].children.len(), 1);
+        assert_eq!(traces[0].children[0].name, "fetch_data");
+        assert_eq!(traces[0].children[0].fields["status"], 200);
+        assert!(buffer.recent(60_000.0).is_empty());
+    }
+}
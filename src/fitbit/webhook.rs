@@ -0,0 +1,385 @@
+use base64::engine::general_purpose;
+use base64::Engine;
+use chrono::{NaiveDate, Utc};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use tracing::{debug, error, info};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use ring::hmac;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use url::Url;
+use zeroize::Zeroizing;
+
+use crate::fitbit::FitbitClient;
+
+// Number of attempts of the verification self-check, which can run before the server is listening.
+const VERIFICATION_CHECK_ATTEMPTS: u32 = 5;
+const VERIFICATION_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Options of the receiver of the Fitbit subscription notifications.
+/// FYI: https://dev.fitbit.com/build/reference/web-api/developer-guide/using-subscriptions/
+#[derive(Clone)]
+pub struct WebhookOptions {
+    /// The port of a dedicated listener for the webhook. `None` serves it from the metrics server.
+    pub port: Option<u16>,
+    /// The path prefix of the webhook, e.g. "/webhook". It must match the subscriber endpoint registered at Fitbit.
+    pub path: String,
+    /// The verification code of the subscriber, shown in the settings of the application at dev.fitbit.com.
+    pub verification_code: String,
+    /// The client secret of the application, which signs the notifications.
+    pub client_secret: Zeroizing<String>,
+    /// The external URL of the webhook, e.g. the one of a tunnel. When set, the webhook is checked through it
+    /// and the subscriptions are created at startup (see `register_webhook`).
+    pub public_url: Option<Url>,
+    /// The collections to subscribe to, e.g. "activities" or "sleep".
+    pub collections: Vec<String>,
+    /// The subscriber to notify. `None` uses the default subscriber of the application.
+    pub subscriber_id: Option<String>,
+}
+
+// Implemented by hand to keep the secrets out of the logs.
+impl std::fmt::Debug for WebhookOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookOptions")
+            .field("port", &self.port)
+            .field("path", &self.path)
+            .field("verification_code", &"***")
+            .field("client_secret", &"***")
+            .field("public_url", &self.public_url)
+            .field("collections", &self.collections)
+            .field("subscriber_id", &self.subscriber_id)
+            .finish()
+    }
+}
+
+/// A notification sent by Fitbit when new data of a subscribed collection is available.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    pub collection_type: String,
+    pub date: NaiveDate,
+    pub owner_id: String,
+    pub owner_type: String,
+    pub subscription_id: String,
+}
+
+/// Labels of the webhook metrics, e.g. `fitbit_webhook_notifications_total{collection_type="sleep"}`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct WebhookLabels {
+    pub collection_type: String,
+}
+
+/// Receiver of the Fitbit subscription notifications.
+///
+/// The webhook must be reachable from the internet, unlike /metrics which usually must not be. So it doesn't use
+/// the credentials of the metrics endpoints (Fitbit can't send them anyway) but its own: the verification code
+/// for the subscriber verification, and the `X-Fitbit-Signature` of each notification.
+#[derive(Debug)]
+pub struct WebhookReceiver {
+    options: WebhookOptions,
+    notifications: Family<WebhookLabels, Counter>,
+    last_notification: Family<WebhookLabels, Gauge>,
+}
+
+impl WebhookReceiver {
+    pub fn new(options: WebhookOptions) -> Self {
+        Self {
+            options,
+            notifications: Family::default(),
+            last_notification: Family::default(),
+        }
+    }
+
+    /// Registers the webhook metrics in the given registry.
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register("fitbit_webhook_notifications", "Number of notifications received from Fitbit", self.notifications.clone());
+        registry.register("fitbit_webhook_last_notification_timestamp_seconds", "UNIX timestamp of the last notification received from Fitbit", self.last_notification.clone());
+    }
+
+    pub fn options(&self) -> &WebhookOptions {
+        &self.options
+    }
+
+    /// Returns true if the path belongs to the webhook, i.e. it's the path prefix itself or below it.
+    pub fn matches(&self, path: &str) -> bool {
+        let prefix = self.options.path.trim_end_matches('/');
+        path == prefix || path.starts_with(&format!("{}/", prefix))
+    }
+
+    /// Handles a request to the webhook.
+    ///
+    /// * `GET <path>?verify=<code>` answers the subscriber verification: 204 for the right code, 404 otherwise.
+    /// * `POST <path>` receives notifications: 204 once processed, 404 if the signature is invalid.
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        match *req.method() {
+            Method::GET => self.verify(&req),
+            Method::POST => self.receive(req).await,
+            _ => empty_response(StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    fn verify(&self, req: &Request<Body>) -> Response<Body> {
+        let code = req.uri().query().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "verify")
+                .map(|(_, value)| value.into_owned())
+        });
+        match code {
+            Some(code) if code == self.options.verification_code => {
+                info!("Subscriber verification succeeded");
+                empty_response(StatusCode::NO_CONTENT)
+            }
+            _ => {
+                debug!("Subscriber verification failed");
+                empty_response(StatusCode::NOT_FOUND)
+            }
+        }
+    }
+
+    async fn receive(&self, req: Request<Body>) -> Response<Body> {
+        let signature = req.headers()
+            .get("X-Fitbit-Signature")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Failed to read the webhook notification: {}", err);
+                return empty_response(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        if !signature.is_some_and(|signature| is_valid_signature(&self.options.client_secret, &body, &signature)) {
+            // FYI: Fitbit expects a 404 for notifications with an invalid signature.
+            debug!("Rejecting webhook notification with a missing or invalid signature");
+            return empty_response(StatusCode::NOT_FOUND);
+        }
+
+        let notifications: Vec<Notification> = match serde_json::from_slice(&body) {
+            Ok(notifications) => notifications,
+            Err(err) => {
+                error!("Failed to parse the webhook notification: {}", err);
+                return empty_response(StatusCode::BAD_REQUEST);
+            }
+        };
+        for notification in notifications {
+            debug!("Received notification: {:?}", notification);
+            let labels = WebhookLabels { collection_type: notification.collection_type };
+            self.notifications.get_or_create(&labels).inc();
+            self.last_notification.get_or_create(&labels).set(Utc::now().timestamp());
+        }
+        empty_response(StatusCode::NO_CONTENT)
+    }
+}
+
+/// Verifies the `X-Fitbit-Signature` of a notification: the base64 encoded HMAC-SHA1 of the body,
+/// keyed with the client secret followed by `&`.
+fn is_valid_signature(client_secret: &str, body: &[u8], signature: &str) -> bool {
+    let signature = match general_purpose::STANDARD.decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, format!("{}&", client_secret).as_bytes());
+    hmac::verify(&key, body, &signature).is_ok()
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Runs a dedicated HTTP listener for the webhook, so that it can be exposed to the internet separately
+/// from the metrics endpoints. Any other path gets a 404 response.
+///
+/// # Errors
+///
+/// Returns an error if the server encounters an issue while running.
+pub async fn run_webhook_server(receiver: Arc<WebhookReceiver>, port: u16) -> Result<(), hyper::Error> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+
+    let make_svc = make_service_fn(move |_| {
+        let receiver = Arc::clone(&receiver);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| route_webhook(Arc::clone(&receiver), req)))
+        }
+    });
+
+    info!("Webhook receiver running on http://{}", addr);
+    Server::bind(&addr).serve(make_svc).await
+}
+
+/// Runs the dedicated listener of the webhook over HTTPS, e.g. with the certificate of the metrics endpoints, as
+/// Fitbit only sends the notifications to HTTPS subscriber endpoints.
+///
+/// # Errors
+///
+/// Returns an error if the address cannot be bound.
+#[cfg(feature = "tls")]
+pub async fn run_webhook_server_tls(receiver: Arc<WebhookReceiver>, port: u16, acceptor: tokio_rustls::TlsAcceptor) -> std::io::Result<()> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    info!("Webhook receiver running on https://{}", addr);
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("Failed to accept a webhook connection: {}", err);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let receiver = Arc::clone(&receiver);
+        tokio::spawn(async move {
+            let tls_stream = match acceptor.accept(stream).await {
+                Ok(tls_stream) => tls_stream,
+                Err(err) => {
+                    debug!("TLS handshake with {} failed: {}", peer_addr, err);
+                    return;
+                }
+            };
+            let service = service_fn(move |req: Request<Body>| route_webhook(Arc::clone(&receiver), req));
+            if let Err(err) = hyper::server::conn::Http::new().serve_connection(tls_stream, service).await {
+                error!("An error occurred while serving the webhook to {}: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+async fn route_webhook(receiver: Arc<WebhookReceiver>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if receiver.matches(req.uri().path()) {
+        Ok(receiver.handle(req).await)
+    } else {
+        Ok(empty_response(StatusCode::NOT_FOUND))
+    }
+}
+
+/// Automates the setup of the subscriptions when the webhook has a public URL (e.g. the one of a tunnel).
+///
+/// First, the subscriber verification that Fitbit runs when the subscriber endpoint is saved at dev.fitbit.com is
+/// replayed through the public URL, so that a tunnel or proxy misconfiguration shows up in the logs rather than as
+/// a failed verification in the dashboard. Then, the subscriptions of the configured collections are created.
+///
+/// # Arguments
+///
+/// * `fitbit_client` - An `Arc<RwLock<FitbitClient>>` used to create the subscriptions.
+/// * `receiver` - The webhook receiver holding the options.
+pub async fn register_webhook(fitbit_client: Arc<RwLock<FitbitClient>>, receiver: Arc<WebhookReceiver>) {
+    let options = receiver.options();
+    let public_url = match &options.public_url {
+        Some(public_url) => public_url,
+        None => return,
+    };
+    info!("Subscriber endpoint URL to register at dev.fitbit.com: {}", public_url);
+
+    if let Err(err) = check_verification(public_url, &options.verification_code).await {
+        error!("The webhook doesn't answer the subscriber verification through {}: {}", public_url, err);
+        return;
+    }
+    info!("The webhook answers the subscriber verification through {}", public_url);
+
+    let read_locked_client = fitbit_client.read().await;
+    for collection in &options.collections {
+        let subscription_id = format!("fitbit-exporter-{}", collection);
+        match read_locked_client.create_subscription(collection, &subscription_id, options.subscriber_id.as_deref()).await {
+            Ok(subscription) => info!("Subscribed to the {} collection as {}", collection, subscription.subscription_id),
+            Err(err) => error!("Failed to subscribe to the {} collection: {}", collection, err),
+        }
+    }
+}
+
+/// Replays the subscriber verification of Fitbit through the public URL: the right code must be answered with
+/// 204, and a wrong one with 404.
+///
+/// # Errors
+///
+/// Returns a description of the last failure if the check didn't pass within `VERIFICATION_CHECK_ATTEMPTS` attempts.
+async fn check_verification(public_url: &Url, verification_code: &str) -> Result<(), String> {
+    let mut last_failure = String::new();
+    for attempt in 0..VERIFICATION_CHECK_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(VERIFICATION_CHECK_INTERVAL).await;
+        }
+        let checks = [
+            (verification_url(public_url, verification_code), StatusCode::NO_CONTENT),
+            (verification_url(public_url, &format!("{}-invalid", verification_code)), StatusCode::NOT_FOUND),
+        ];
+        last_failure.clear();
+        for (url, expected) in checks {
+            match reqwest::get(url).await {
+                Ok(response) if response.status() == expected => {}
+                Ok(response) => last_failure = format!("expected status {}, got {}", expected, response.status()),
+                Err(err) => last_failure = err.to_string(),
+            }
+            if !last_failure.is_empty() {
+                break;
+            }
+        }
+        if last_failure.is_empty() {
+            return Ok(());
+        }
+        debug!("Subscriber verification check failed: {}", last_failure);
+    }
+    Err(last_failure)
+}
+
+/// Returns the URL Fitbit calls to verify the subscriber, i.e. the public URL with a `verify` query parameter.
+fn verification_url(public_url: &Url, verification_code: &str) -> Url {
+    let mut url = public_url.clone();
+    url.query_pairs_mut().append_pair("verify", verification_code);
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_is_hmac_sha1_of_the_body() {
+        let body = br#"[{"collectionType":"sleep","date":"2023-03-04","ownerId":"ABC123","ownerType":"user","subscriptionId":"1"}]"#;
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, b"secret&");
+        let signature = general_purpose::STANDARD.encode(hmac::sign(&key, body).as_ref());
+
+        assert!(is_valid_signature("secret", body, &signature));
+        assert!(!is_valid_signature("other", body, &signature));
+        assert!(!is_valid_signature("secret", b"[]", &signature));
+    }
+
+    #[test]
+    fn path_prefix_matching() {
+        let receiver = WebhookReceiver::new(WebhookOptions {
+            port: None,
+            path: "/webhook/".to_string(),
+            verification_code: "code".to_string(),
+            client_secret: Zeroizing::new("secret".to_string()),
+            public_url: None,
+            collections: Vec::new(),
+            subscriber_id: None,
+        });
+        assert!(receiver.matches("/webhook"));
+        assert!(receiver.matches("/webhook/fitbit"));
+        assert!(!receiver.matches("/webhooks"));
+        assert!(!receiver.matches("/metrics"));
+    }
+
+    #[test]
+    fn verification_url_keeps_the_public_url() {
+        let public_url = Url::parse("https://example.trycloudflare.com/webhook?tunnel=1").unwrap();
+        assert_eq!(
+            verification_url(&public_url, "c0de").as_str(),
+            "https://example.trycloudflare.com/webhook?tunnel=1&verify=c0de"
+        );
+    }
+}
@@ -1 +1,19 @@
-pub mod fitbit;
\ No newline at end of file
+//! Fitbit client and Prometheus metric mapping used by the `fitbit_exporter` binary.
+//!
+//! The library can be embedded in other Rust projects without running the exporter process, e.g.
+//!
+//! ```no_run
+//! use fitbit_exporter::{FitbitClient, FitbitMetrics};
+//!
+//! # async fn example() -> Result<(), fitbit_exporter::FitbitError> {
+//! let client = FitbitClient::new("client_id", "client_secret", &None, "access_token");
+//! let steps = client.fetch_steps().await?;
+//! let metrics = FitbitMetrics::new();
+//! metrics.steps.push(steps.as_i64(), None);
+//! # Ok(())
+//! # }
+//! ```
+pub mod fitbit;
+
+pub use fitbit::{Exporter, FitbitClient, FitbitError, FitbitMetrics, HttpOptions, MetricsOptions};
+pub use fitbit::{update_current_metrics, update_selected_metrics, warm_up_metrics, poll_metrics_periodically, run_server, refresh_token_periodically, dump_historical_metrics};
@@ -1,53 +1,265 @@
+use chrono::Local;
 use dotenv::dotenv;
-use std::env;
+use tracing::{error, info, warn};
 use std::error::Error;
 use std::sync::Arc;
 use std::time::Duration;
 use structopt::StructOpt;
 use tokio::sync::RwLock;
+use zeroize::Zeroizing;
 
-mod fitbit;
-use fitbit::{cmd, FitbitClient, FitbitMetrics, run_server, refresh_token_periodically, dump_historical_metrics};
+use fitbit_exporter::fitbit::bodylog::BodyLog;
+use fitbit_exporter::fitbit::client::SECONDARY_TOKENS_KEY;
+use fitbit_exporter::fitbit::cmd::Command;
+use fitbit_exporter::fitbit::logging::init_logger;
+use fitbit_exporter::fitbit::query::print_archive_query;
+use fitbit_exporter::fitbit::reauthorization::Reauthorization;
+use fitbit_exporter::fitbit::report::write_weekly_report;
+use fitbit_exporter::fitbit::secrets::read_secret_var;
+use fitbit_exporter::fitbit::server::refresh_bearer_token_periodically;
+use fitbit_exporter::fitbit::storage::{EncryptedStorage, EncryptionKey, Storage};
+use fitbit_exporter::fitbit::traces::TraceBuffer;
+use fitbit_exporter::fitbit::{backfill_gaps, cmd, dispatch_events, emit_to_statsd, prune_history_periodically, prune_sample_store_periodically, push_to_graphite_periodically, push_to_pushgateway_periodically, register_webhook, roll_over_daily_metrics_at_midnight, WebhookReceiver};
+use fitbit_exporter::{FitbitClient, FitbitError, FitbitMetrics, poll_metrics_periodically, warm_up_metrics, run_server, refresh_token_periodically, dump_historical_metrics};
 
-// FYI: The default access token expiration time is 8hr (28800). Defining a shorter refresh interval.
+// FYI: The default access token expiration time is 8hr (28800). Defining a shorter refresh interval, used until the
+// expiry of the access token is known from a token response.
 // See https://dev.fitbit.com/build/reference/web-api/developer-guide/authorization/
 const REFRESH_ACCESS_TOKEN_INTERVAL: Duration = Duration::from_secs(7 * 60 * 60);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    // Initialize the logger. to see debug messages, for example, set RUST_LOG=fitbit_exporter=debug when `cargo run` is executed.
-    env_logger::init();
-
     // Load environment variables from .env file
     dotenv().ok();
 
-    // Read the required environment variables
-    let client_id = env::var("FITBIT_CLIENT_ID").expect("FITBIT_CLIENT_ID not set");
-    let client_secret = env::var("FITBIT_CLIENT_SECRET").expect("FITBIT_CLIENT_SECRET not set");
-    let initial_access_token = env::var("FITBIT_ACCESS_TOKEN").expect("FITBIT_ACCESS_TOKEN not set");
+    let args = cmd::Args::from_args();
+
+    // Initialize the logger. to see debug messages, for example, set RUST_LOG=fitbit_exporter=debug when `cargo run` is executed.
+    // The filter can be changed at runtime through /admin/loglevel.
+    let traces = args.debug_traces.then(|| Arc::new(TraceBuffer::default()));
+    let log_filter = init_logger(args.log_format, traces.clone());
+
+    // The offline commands don't call the Fitbit API, so the timezone of the user isn't known from its profile
+    let offline_utc_offset = args.utc_offset.unwrap_or_else(|| *Local::now().offset());
+
+    // Write the weekly summary from the sample store
+    if let Some(Command::Report { week, format, output_file }) = &args.command {
+        let sample_store = args.sample_store.as_ref().ok_or("The weekly summary requires a sample store (--sample-store)")?.open()?;
+        return write_weekly_report(&*sample_store, *week, *format, output_file.as_deref(), offline_utc_offset);
+    }
+
+    // Query the sample store
+    if let Some(query) = args.command.as_ref().and_then(Command::archive_query) {
+        let sample_store = args.sample_store.as_ref().ok_or("The query requires a sample store (--sample-store)")?.open()?;
+        return print_archive_query(&*sample_store, &query, offline_utc_offset);
+    }
+
+    // Read the required environment variables, or the files given by their `_FILE` variables (see `read_secret_var`).
+    // The offline mode doesn't call the Fitbit API, so it doesn't need them.
+    let required_var = |name: &str| match read_secret_var(name) {
+        Ok(Some(value)) => value,
+        Ok(None) if args.offline.is_some() => Zeroizing::new(String::new()),
+        Ok(None) => panic!("{} not set", name),
+        Err(err) => panic!("{}", err),
+    };
+    let optional_var = |name: &str| read_secret_var(name).unwrap_or_else(|err| panic!("{}", err));
+    let client_id = required_var("FITBIT_CLIENT_ID");
+    let client_secret = required_var("FITBIT_CLIENT_SECRET");
+    let initial_access_token = required_var("FITBIT_ACCESS_TOKEN");
 
     // Set the refresh token if given via FITBIT_REFRESH_TOKEN. Otherwise set None.
     // The refresh token is only needed for the Authorization Code Flow (`response_type=code`) when calling https://www.fitbit.com/oauth2/authorize.
     // If the Inplicit Grant Flow is used (`response_type=token`) the refresh token is not needed.
-    let refresh_token: Option<String> = env::var("FITBIT_REFRESH_TOKEN").map_or(None, |refresh_token| Some(refresh_token));
+    let refresh_token: Option<String> = optional_var("FITBIT_REFRESH_TOKEN").map(|refresh_token| refresh_token.to_string());
 
     // Initialize and wrap the FitbitClient and FitbitMetrics instances in Arc (Atomic Reference Counting) to
     // allow safe sharing and handling of the instances across multiple threads.Gkj
     // Especially, FitbitClient is wrapped by RwLock as well to allow safe updating of the access token.
-    let fitbit_client = FitbitClient::new(&client_id, &client_secret, &refresh_token, &initial_access_token);
-    let shared_fitbit_client = Arc::new(RwLock::new(fitbit_client));
-    let shared_fitbit_metrics = Arc::new(FitbitMetrics::new());
+    let storage = args.storage.open()?;
+    // Encrypt the persisted tokens if a key is given via FITBIT_TOKEN_ENCRYPTION_KEY (or FITBIT_TOKEN_ENCRYPTION_KEY_FILE)
+    let token_store: Arc<dyn Storage> = match optional_var("FITBIT_TOKEN_ENCRYPTION_KEY") {
+        Some(key) => Arc::new(EncryptedStorage::new(storage, &key.parse::<EncryptionKey>()?)),
+        None => storage,
+    };
+    let http_options = args.http_options();
+    let dns_cache_metrics = http_options.dns_cache_metrics.clone();
+    let mut fitbit_client = FitbitClient::new(&client_id, &client_secret, &refresh_token, &initial_access_token)
+        .with_http_options(http_options.clone())?
+        .with_token_store(token_store.clone())?;
+    let body_log = args.http_body_log.as_ref().map(|path| BodyLog::open(path, args.http_body_log_level)).transpose()?.map(Arc::new);
+    if let Some(body_log) = &body_log {
+        fitbit_client = fitbit_client.with_body_log(body_log.clone());
+    }
+    // Fail over to a secondary application while the hourly quota of the primary one is exhausted, if its
+    // credentials are given via FITBIT_SECONDARY_CLIENT_ID, FITBIT_SECONDARY_CLIENT_SECRET,
+    // FITBIT_SECONDARY_ACCESS_TOKEN and optionally FITBIT_SECONDARY_REFRESH_TOKEN.
+    if let Some(secondary_client_id) = optional_var("FITBIT_SECONDARY_CLIENT_ID") {
+        let secondary_client_secret = required_var("FITBIT_SECONDARY_CLIENT_SECRET");
+        let secondary_access_token = required_var("FITBIT_SECONDARY_ACCESS_TOKEN");
+        let secondary_refresh_token: Option<String> = optional_var("FITBIT_SECONDARY_REFRESH_TOKEN").map(|refresh_token| refresh_token.to_string());
+        let mut secondary_client = FitbitClient::new(&secondary_client_id, &secondary_client_secret, &secondary_refresh_token, &secondary_access_token)
+            .with_http_options(http_options)?
+            .with_tokens_key(SECONDARY_TOKENS_KEY)
+            .with_token_store(token_store)?;
+        if let Some(body_log) = &body_log {
+            secondary_client = secondary_client.with_body_log(body_log.clone());
+        }
+        info!("Failing over to the secondary application {} when the primary one is rate limited", *secondary_client_id);
+        fitbit_client = fitbit_client.with_secondary(secondary_client);
+    }
+    if let Some(fixtures_dir) = &args.offline {
+        info!("Offline mode: reading the Fitbit API responses from {}", fixtures_dir.display());
+        fitbit_client = fitbit_client.with_fixtures(fixtures_dir);
+    }
+    // Check the access token before anything uses it, refreshing it right away if it already expired
+    // An invalid refresh token can be replaced through /oauth2/authorize if the re-authorization is enabled.
+    let mut reauthorization_required = false;
+    if args.offline.is_none() {
+        match fitbit_client.ensure_valid_access_token().await {
+            Ok(()) => {}
+            Err(FitbitError::InvalidGrant) if args.oauth_redirect_url.is_some() => {
+                error!("The access token is expired and the refresh token is invalid.");
+                reauthorization_required = true;
+            }
+            Err(err) => {
+                error!("The access token is expired and could not be refreshed: {}. Re-authorize the exporter and set FITBIT_ACCESS_TOKEN and FITBIT_REFRESH_TOKEN.", err);
+                return Err(err.into());
+            }
+        }
+    }
+    let token_metrics = fitbit_client.token_metrics();
+    let mut metrics_options = args.metrics_options();
+    metrics_options.sample_store = args.sample_store.as_ref().map(|sample_store| sample_store.open()).transpose()?;
+    let fitbit_metrics = FitbitMetrics::with_options(metrics_options);
+    // The token refreshes and the failovers are recorded in the event log served by /status
+    let shared_fitbit_client = Arc::new(RwLock::new(fitbit_client.with_event_log(fitbit_metrics.events.log())));
+    fitbit_metrics.register_external(move |registry| dns_cache_metrics.register(registry));
+    fitbit_metrics.register_external(move |registry| token_metrics.register(registry));
+
+    // Set up the receiver of the subscription notifications if the webhook is enabled.
+    let mut server_options = args.server_options();
+    if let Some(redirect_url) = &args.oauth_redirect_url {
+        if server_options.auth.is_none() {
+            return Err("The re-authorization (--oauth-redirect-url) requires --metrics-bearer-token or --metrics-username, since it replaces the Fitbit account of the exporter".into());
+        }
+        let reauthorization = Arc::new(Reauthorization::new(shared_fitbit_client.clone(), redirect_url, &args.oauth_scopes));
+        if reauthorization_required {
+            reauthorization.prompt();
+        }
+        server_options.reauthorization = Some(reauthorization);
+    }
+    server_options.body_log = body_log;
+    server_options.log_filter = Some(log_filter);
+    server_options.traces = traces;
+    if let Some(webhook_options) = args.webhook_options(&client_secret) {
+        let webhook = Arc::new(WebhookReceiver::new(webhook_options));
+        let registered_webhook = webhook.clone();
+        fitbit_metrics.register_external(move |registry| registered_webhook.register(registry));
+        server_options.webhook = Some(webhook);
+    }
+    let shared_fitbit_metrics = Arc::new(fitbit_metrics);
+
+    // Stamp the daily values in the user's timezone, rather than in the one of the host
+    let profile = match shared_fitbit_client.read().await.fetch_profile().await {
+        Ok(profile) => Some(profile),
+        Err(err) => {
+            warn!("Could not fetch the profile of the user: {}", err);
+            None
+        }
+    };
+    match args.utc_offset.or_else(|| profile.as_ref().and_then(|profile| profile.utc_offset())) {
+        Some(utc_offset) => shared_fitbit_metrics.set_utc_offset(utc_offset),
+        None => warn!("The timezone of the user is unknown, using the one of the host ({})", shared_fitbit_metrics.utc_offset()),
+    }
+
+    // Send the operational events to the sinks. Subscribed before anything runs, so that no event is missed.
+    if !args.event_sinks.is_empty() {
+        let sinks = args.event_sinks.iter().map(|sink| sink.open()).collect();
+        tokio::spawn(dispatch_events(shared_fitbit_metrics.events.subscribe(), sinks, args.exposition_relabel()));
+    }
 
-    let args = cmd::Args::from_args();
     if args.dump_historical_metrics {
         // Dump historical metrics to a file (.prom) instead of serving them via HTTP
         dump_historical_metrics(shared_fitbit_client, shared_fitbit_metrics, args).await?;
     } else {
+        // Nothing but the background poller refreshes the pushed metrics if Prometheus doesn't scrape the exporter
+        let pushers = args.metrics_pushers();
+        if !pushers.is_empty() && args.poll_schedule().is_none() {
+            return Err(format!("{} requires --poll-interval or --poll-schedule, since the scrapes may never refresh the pushed metrics", pushers.join(", ")).into());
+        }
+
         // Spawn a task to refresh the access token periodically
-        tokio::spawn(refresh_token_periodically(shared_fitbit_client.clone(), REFRESH_ACCESS_TOKEN_INTERVAL));
+        if args.offline.is_none() {
+            tokio::spawn(refresh_token_periodically(shared_fitbit_client.clone(), shared_fitbit_metrics.clone(), REFRESH_ACCESS_TOKEN_INTERVAL));
+        }
+
+        // Spawn a task to update the metrics in the background, if scrapes shouldn't call the Fitbit API.
+        // Otherwise, run a first update right away without blocking the listener, so that the first scrape gets data.
+        if let Some(poll_schedule) = args.poll_schedule() {
+            let max_jitter = Duration::from_secs(args.poll_jitter);
+            tokio::spawn(poll_metrics_periodically(shared_fitbit_client.clone(), shared_fitbit_metrics.clone(), poll_schedule, max_jitter));
+        } else {
+            server_options.warm_up = Some(warm_up_metrics(shared_fitbit_client.clone(), shared_fitbit_metrics.clone()));
+        }
+
+        // Reset the daily metrics at midnight, instead of serving yesterday's totals until the next sync
+        if args.midnight_rollover {
+            tokio::spawn(roll_over_daily_metrics_at_midnight(shared_fitbit_metrics.clone(), args.timestamp_position));
+        }
+
+        // Drop the historical points beyond the retention, so that they don't accumulate in memory
+        if let Some(interval) = args.history_prune_interval() {
+            tokio::spawn(prune_history_periodically(shared_fitbit_metrics.clone(), interval));
+        }
+
+        // Delete the archived samples beyond the retention, so that the database doesn't grow forever
+        if let (Some(sample_store), Some(max_age)) = (&shared_fitbit_metrics.sample_store, args.sample_store_max_age()) {
+            tokio::spawn(prune_sample_store_periodically(sample_store.clone(), max_age, Duration::from_secs(args.history_prune_interval)));
+        }
+
+        // Fetch the days missed while the exporter was down
+        if let Some(backfill_options) = args.backfill_options() {
+            tokio::spawn(backfill_gaps(shared_fitbit_client.clone(), shared_fitbit_metrics.clone(), backfill_options));
+        }
+
+        // Push the metrics to Graphite, for the users without Prometheus
+        if let Some(graphite_options) = args.graphite_options() {
+            tokio::spawn(push_to_graphite_periodically(shared_fitbit_metrics.clone(), graphite_options));
+        }
+
+        // Emit the metrics to StatsD, e.g. for the Datadog agent
+        if let Some(statsd_options) = args.statsd_options() {
+            tokio::spawn(emit_to_statsd(shared_fitbit_metrics.clone(), statsd_options));
+        }
+
+        // Run the bearer token command before listening, so that the endpoints never accept a missing token, and
+        // then periodically to pick up a rotated token
+        if let Some(command) = server_options.auth.as_ref().and_then(|auth| auth.bearer_token_command.clone()) {
+            command.refresh().await?;
+            tokio::spawn(refresh_bearer_token_periodically(command, Duration::from_secs(args.metrics_bearer_token_refresh_interval)));
+        }
+
+        // Check the webhook through its public URL and create the subscriptions, once the server is listening
+        if let (Some(webhook), None) = (&server_options.webhook, &args.offline) {
+            tokio::spawn(register_webhook(shared_fitbit_client.clone(), webhook.clone()));
+        }
+
+        // Log the effective configuration, also served by /status
+        let mut summary = args.startup_summary();
+        summary.user_id = profile.map(|profile| profile.encoded_id);
+        info!("Starting fitbit_exporter: {}", summary);
+
+        // Push the metrics to a Pushgateway, grouped by the user, for a Prometheus that can't scrape the exporter
+        if let Some(pushgateway_options) = args.pushgateway_options(summary.user_id.clone()) {
+            if pushgateway_options.user.is_none() {
+                warn!("Pushing the metrics to the Pushgateway without the user label, as the user ID is unknown");
+            }
+            tokio::spawn(push_to_pushgateway_periodically(shared_fitbit_metrics.clone(), pushgateway_options));
+        }
+        server_options.status = Some(Arc::new(summary));
 
         // Start the HTTP server to serve the metrics for Prometheus
-        run_server(shared_fitbit_client.clone(), shared_fitbit_metrics).await?;
+        run_server(shared_fitbit_client.clone(), shared_fitbit_metrics, server_options).await?;
     }
 
     Ok(())